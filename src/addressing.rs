@@ -0,0 +1,97 @@
+use crate::config::ReplyAddressing;
+
+fn strip_prefix_ignore_case<'a>(s: &'a str, prefix: &str) -> Option<&'a str> {
+    let head = s.get(..prefix.len())?;
+    head.eq_ignore_ascii_case(prefix)
+        .then(|| &s[prefix.len()..])
+}
+
+/// Takes off however many times the model started its reply by addressing itself, like
+/// "Charbot9000: " or "<Charbot9000> "
+///
+/// Models copy the way the conversation is written, so they sometimes do this more than once.
+pub fn strip_self_address<'a>(msg: &'a str, botname: &str) -> &'a str {
+    let prefixes = [
+        format!("{botname}:"),
+        format!("{botname},"),
+        format!("<{botname}>"),
+        format!("@{botname}"),
+    ];
+    let mut msg = msg.trim();
+    while let Some(rest) = prefixes
+        .iter()
+        .find_map(|prefix| strip_prefix_ignore_case(msg, prefix))
+    {
+        msg = rest.trim_start();
+    }
+    msg
+}
+
+/// Addresses a reply to `nick`, or not, according to `mode`
+///
+/// Whatever address the model put on the reply is taken off first, so it's never doubled up.
+/// `busy` is whether someone else has spoken since the question was asked.
+pub fn address(
+    reply: &str,
+    botname: &str,
+    nick: &str,
+    mode: ReplyAddressing,
+    busy: bool,
+) -> String {
+    let reply = strip_self_address(reply, botname);
+    let reply = strip_prefix_ignore_case(reply, &format!("{nick}:"))
+        .map(str::trim_start)
+        .unwrap_or(reply);
+    let addressed = match mode {
+        ReplyAddressing::Always => true,
+        ReplyAddressing::Never => false,
+        ReplyAddressing::Smart => busy,
+    };
+    if addressed {
+        format!("{nick}: {reply}")
+    } else {
+        reply.to_string()
+    }
+}
+
+#[test]
+fn test_strip_self_address() {
+    assert_eq!(strip_self_address("  hello ", "anna"), "hello");
+    assert_eq!(strip_self_address("anna: hello", "anna"), "hello");
+    assert_eq!(strip_self_address("<anna> hello", "anna"), "hello");
+    assert_eq!(
+        strip_self_address("Anna: <anna> anna, hello", "anna"),
+        "hello"
+    );
+    assert_eq!(strip_self_address("annabelle: hi", "anna"), "annabelle: hi");
+    assert_eq!(
+        strip_self_address("hi anna: there", "anna"),
+        "hi anna: there"
+    );
+    assert_eq!(strip_self_address("anna:", "anna"), "");
+}
+
+#[test]
+fn test_address() {
+    use ReplyAddressing::*;
+
+    assert_eq!(
+        address("anna: 42", "anna", "achin", Always, false),
+        "achin: 42"
+    );
+    assert_eq!(
+        address("achin: 42", "anna", "achin", Always, false),
+        "achin: 42"
+    );
+    assert_eq!(
+        address("anna: achin: 42", "anna", "achin", Never, true),
+        "42"
+    );
+    assert_eq!(address("42", "anna", "achin", Smart, false), "42");
+    assert_eq!(address("42", "anna", "achin", Smart, true), "achin: 42");
+    // only a leading address is taken off
+    assert_eq!(
+        address("agrif: said 42", "anna", "achin", Never, false),
+        "agrif: said 42"
+    );
+}
@@ -0,0 +1,212 @@
+use std::{collections::HashMap, fs::File, sync::Arc};
+
+use anyhow::Context;
+use async_trait::async_trait;
+use axum::{
+    extract::{Path, Request, State},
+    http::{header, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Redirect, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use serde::Deserialize;
+
+use crate::{config::ApiConfig, dashboard, webhooks, ChatMessageThing};
+
+/// The cookie the dashboard keeps the token in, since a browser can't send it as a header
+pub(crate) const TOKEN_COOKIE: &str = "anna_token";
+
+/// What the HTTP API needs from the running bot
+#[async_trait]
+pub trait BotControl: Send + Sync + 'static {
+    /// Sends a message to a channel or a nick
+    fn send(&self, target: &str, message: &str) -> anyhow::Result<()>;
+    fn join(&self, channel: &str) -> anyhow::Result<()>;
+    fn part(&self, channel: &str) -> anyhow::Result<()>;
+    /// Summarizes the current conversation in a channel, or None if there's no such channel
+    async fn summary(&self, channel: &str) -> anyhow::Result<Option<String>>;
+    /// Every channel the bot has state for
+    fn channels(&self) -> Vec<String>;
+    /// The stored conversation in a channel, oldest first, or None if there's no such channel
+    fn history(&self, channel: &str) -> Option<Vec<ChatMessageThing>>;
+    /// A channel's settings, by name, the way the commands that change them show them, or None
+    /// if there's no such channel
+    fn settings(&self, channel: &str) -> Option<Vec<(&'static str, String)>>;
+}
+
+#[derive(Clone)]
+pub(crate) struct ApiState {
+    pub(crate) control: Arc<dyn BotControl>,
+    pub(crate) token: Arc<str>,
+    /// Whether the API is reached over HTTPS, even though it's served over plain HTTP
+    pub(crate) https: bool,
+}
+
+/// An error from a handler, which is sent back as a 500 with the message
+pub(crate) struct ApiError(anyhow::Error);
+
+impl<E: Into<anyhow::Error>> From<E> for ApiError {
+    fn from(e: E) -> Self {
+        Self(e.into())
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", self.0)).into_response()
+    }
+}
+
+/// Starts the API in the background, if it's configured
+///
+/// It won't start without a token, so there's no way to expose the bot unauthenticated.
+pub fn spawn(config: ApiConfig, control: Arc<dyn BotControl>) {
+    let Some(listen) = config.listen else {
+        return;
+    };
+    let Some(token) = config.token.filter(|t| !t.is_empty()) else {
+        println!("Not starting the HTTP API on {listen}, because there's no token");
+        return;
+    };
+    let state = ApiState {
+        control,
+        token: token.into(),
+        https: config.https,
+    };
+    tokio::spawn(async move {
+        let listener = match tokio::net::TcpListener::bind(&listen).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                println!("Failed to start the HTTP API on {listen}: {e}");
+                return;
+            }
+        };
+        println!("HTTP API listening on {listen}");
+        if let Err(e) = axum::serve(listener, router(state)).await {
+            println!("HTTP API stopped: {e}");
+        }
+    });
+}
+
+fn router(state: ApiState) -> Router {
+    Router::new()
+        .route("/send", post(send))
+        .route("/join", post(join))
+        .route("/part", post(part))
+        .route("/usage", get(usage))
+        .route("/prompts/reload", post(reload_prompts))
+        .route("/channels/:channel/summary", get(summary))
+        .merge(dashboard::router())
+        .layer(middleware::from_fn_with_state(state.clone(), authenticate))
+        .merge(dashboard::login_router())
+        .merge(webhooks::router())
+        .with_state(state)
+}
+
+/// Compares without bailing out at the first difference, so the token can't be guessed a byte at
+/// a time from how long the comparison takes
+pub(crate) fn tokens_match(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+/// The token from the `Authorization` header, or from the dashboard's cookie
+fn given_token(req: &Request) -> Option<&str> {
+    let headers = req.headers();
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    bearer.or_else(|| {
+        headers
+            .get_all(header::COOKIE)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(';'))
+            .find_map(|cookie| {
+                let (name, value) = cookie.trim().split_once('=')?;
+                (name == TOKEN_COOKIE).then_some(value)
+            })
+    })
+}
+
+async fn authenticate(State(state): State<ApiState>, req: Request, next: Next) -> Response {
+    match given_token(&req) {
+        Some(given) if tokens_match(given, &state.token) => next.run(req).await,
+        _ if req.uri().path().starts_with("/ui") => Redirect::to("/ui/login").into_response(),
+        _ => (StatusCode::UNAUTHORIZED, "Missing or wrong token").into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+struct SendRequest {
+    target: String,
+    message: String,
+}
+
+async fn send(
+    State(state): State<ApiState>,
+    Json(req): Json<SendRequest>,
+) -> Result<StatusCode, ApiError> {
+    state.control.send(&req.target, &req.message)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize)]
+struct ChannelRequest {
+    channel: String,
+}
+
+async fn join(
+    State(state): State<ApiState>,
+    Json(req): Json<ChannelRequest>,
+) -> Result<StatusCode, ApiError> {
+    state.control.join(&req.channel)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn part(
+    State(state): State<ApiState>,
+    Json(req): Json<ChannelRequest>,
+) -> Result<StatusCode, ApiError> {
+    state.control.part(&req.channel)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn usage() -> String {
+    crate::keys::usage_report()
+}
+
+/// Prompts are read from prompts.json whenever they're used, so there's nothing to reload, but
+/// this makes sure an edited file is valid before it's needed, and lists what's in it
+async fn reload_prompts() -> Result<Json<Vec<String>>, ApiError> {
+    let file = File::open("prompts.json").context("Failed to open prompts.json")?;
+    let prompts: HashMap<String, String> =
+        serde_json::from_reader(file).context("prompts.json is invalid")?;
+    let mut names: Vec<String> = prompts.into_keys().collect();
+    names.sort();
+    Ok(Json(names))
+}
+
+async fn summary(
+    State(state): State<ApiState>,
+    Path(channel): Path<String>,
+) -> Result<Response, ApiError> {
+    Ok(match state.control.summary(&channel).await? {
+        Some(summary) => summary.into_response(),
+        None => (StatusCode::NOT_FOUND, format!("No channel {channel}")).into_response(),
+    })
+}
+
+#[test]
+fn test_tokens_match() {
+    assert!(tokens_match("hunter2", "hunter2"));
+    assert!(!tokens_match("hunter3", "hunter2"));
+    assert!(!tokens_match("hunter", "hunter2"));
+    assert!(!tokens_match("", "hunter2"));
+}
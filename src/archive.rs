@@ -0,0 +1,272 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::PathBuf,
+    sync::Mutex,
+};
+
+use anyhow::{bail, Context};
+use chrono::{DateTime, NaiveDate, Utc};
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+
+use crate::retention::parse_age;
+
+const ARCHIVE_DIR: &str = "archive";
+
+/// Held while appending, so lines from different tasks don't get interleaved
+static WRITE_LOCK: Mutex<()> = Mutex::new(());
+
+/// One line of channel traffic, as it's kept in the archive
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ArchivedLine {
+    pub date: DateTime<Utc>,
+    pub nick: String,
+    pub text: String,
+}
+
+impl std::fmt::Display for ArchivedLine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "[{}] <{}> {}",
+            self.date.format("%Y-%m-%d %H:%M"),
+            self.nick,
+            self.text
+        )
+    }
+}
+
+/// A directory per channel, each with a JSON-lines file per day
+struct Archive {
+    dir: PathBuf,
+}
+
+impl Archive {
+    fn channel_dir(&self, channel: &str) -> PathBuf {
+        // channel names can't have these in them on IRC, but Matrix room ids are less careful
+        self.dir.join(channel.replace(['/', '\\', '\0'], "_"))
+    }
+
+    fn append(&self, channel: &str, line: &ArchivedLine) -> anyhow::Result<()> {
+        let dir = self.channel_dir(channel);
+        fs::create_dir_all(&dir)?;
+        let path = dir.join(format!("{}.jsonl", line.date.format("%Y-%m-%d")));
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        writeln!(file, "{}", serde_json::to_string(line)?)?;
+        Ok(())
+    }
+
+    /// Finds the most recent lines matching a search, newest first
+    fn search(
+        &self,
+        channel: &str,
+        search: &Search,
+        limit: usize,
+    ) -> anyhow::Result<Vec<ArchivedLine>> {
+        let dir = self.channel_dir(channel);
+        if !dir.exists() {
+            return Ok(Vec::new());
+        }
+        let since_day = search.since.map(|since| since.date_naive());
+        let mut days: Vec<PathBuf> = fs::read_dir(dir)?
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| p.extension().is_some_and(|ext| ext == "jsonl"))
+            .filter(|p| {
+                // days before the search starts don't need to be read at all
+                let day = p
+                    .file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .and_then(|stem| NaiveDate::parse_from_str(stem, "%Y-%m-%d").ok());
+                match (day, since_day) {
+                    (Some(day), Some(since_day)) => day >= since_day,
+                    _ => true,
+                }
+            })
+            .collect();
+        // the names sort by date, so the newest day comes first
+        days.sort();
+        days.reverse();
+
+        let mut found = Vec::new();
+        for day in days {
+            let mut matches: Vec<ArchivedLine> = BufReader::new(fs::File::open(day)?)
+                .lines()
+                .map_while(Result::ok)
+                .filter_map(|line| serde_json::from_str::<ArchivedLine>(&line).ok())
+                .filter(|line| search.matches(line))
+                .collect();
+            matches.reverse();
+            found.extend(matches);
+            if found.len() >= limit {
+                found.truncate(limit);
+                break;
+            }
+        }
+        Ok(found)
+    }
+}
+
+/// What to look for in an archive
+#[derive(Debug, Clone)]
+pub struct Search {
+    /// Matched against what was said, ignoring case
+    pub pattern: Regex,
+    /// Only lines from this nick (ignoring case)
+    pub nick: Option<String>,
+    /// Only lines from this time on
+    pub since: Option<DateTime<Utc>>,
+}
+
+impl Search {
+    /// Parses `<query> [--nick=<nick>] [--since=<when>]`
+    ///
+    /// The query is a regex.  `when` is either a date (`2024-03-01`) or how long ago, like `90m`,
+    /// `6h`, `2d` or `1w`.
+    pub fn parse(args: &str, now: DateTime<Utc>) -> anyhow::Result<Self> {
+        let mut query = Vec::new();
+        let mut nick = None;
+        let mut since = None;
+        for word in args.split_whitespace() {
+            if let Some(value) = word.strip_prefix("--nick=") {
+                nick = Some(value.to_string());
+            } else if let Some(value) = word.strip_prefix("--since=") {
+                since = Some(parse_since(value, now).with_context(|| {
+                    format!("Can't tell when --since={value} is (try 6h, 2d, or 2024-03-01)")
+                })?);
+            } else {
+                query.push(word);
+            }
+        }
+        if query.is_empty() && nick.is_none() {
+            bail!("What should I look for?");
+        }
+        let pattern = RegexBuilder::new(&query.join(" "))
+            .case_insensitive(true)
+            .size_limit(1 << 20)
+            .build()?;
+        Ok(Self {
+            pattern,
+            nick,
+            since,
+        })
+    }
+
+    fn matches(&self, line: &ArchivedLine) -> bool {
+        self.nick
+            .as_ref()
+            .is_none_or(|nick| nick.eq_ignore_ascii_case(&line.nick))
+            && self.since.is_none_or(|since| line.date >= since)
+            && self.pattern.is_match(&line.text)
+    }
+}
+
+fn parse_since(value: &str, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    if let Ok(day) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        return Some(day.and_hms_opt(0, 0, 0)?.and_utc());
+    }
+    now.checked_sub_signed(parse_age(value)?)
+}
+
+/// Appends a line to a channel's archive
+///
+/// This never fails; problems writing the archive are only printed.
+pub fn record(channel: &str, nick: &str, text: &str) {
+    let archive = Archive {
+        dir: PathBuf::from(ARCHIVE_DIR),
+    };
+    let line = ArchivedLine {
+        date: Utc::now(),
+        nick: nick.to_string(),
+        text: text.to_string(),
+    };
+    let _lock = WRITE_LOCK.lock().expect("archive lock is poisoned");
+    if let Err(e) = archive.append(channel, &line) {
+        println!("Failed to write to the {channel} archive: {e}");
+    }
+}
+
+/// Searches a channel's archive, newest first
+///
+/// This can read a lot of days' worth of files, so it's done on a blocking thread.
+pub async fn search(
+    channel: &str,
+    search: Search,
+    limit: usize,
+) -> anyhow::Result<Vec<ArchivedLine>> {
+    let archive = Archive {
+        dir: PathBuf::from(ARCHIVE_DIR),
+    };
+    let channel = channel.to_string();
+    tokio::task::spawn_blocking(move || archive.search(&channel, &search, limit)).await?
+}
+
+#[test]
+fn test_archive_grep() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let archive = Archive {
+        dir: dir.path().to_path_buf(),
+    };
+    for (channel, date, nick, text) in [
+        ("#test", "2024-03-01T10:00:00Z", "achin", "the first pizza"),
+        ("#test", "2024-03-01T11:00:00Z", "agrif", "no pizza today"),
+        ("#test", "2024-03-02T09:00:00Z", "achin", "PIZZA again"),
+        ("#other", "2024-03-02T09:00:00Z", "achin", "pizza elsewhere"),
+    ] {
+        let line = ArchivedLine {
+            date: date.parse()?,
+            nick: nick.to_string(),
+            text: text.to_string(),
+        };
+        archive.append(channel, &line)?;
+    }
+
+    let now = "2024-03-02T12:00:00Z".parse()?;
+    let search = |args: &str| Search::parse(args, now).unwrap();
+    let found = archive.search("#test", &search("pizza"), 10)?;
+    let texts: Vec<&str> = found.iter().map(|l| l.text.as_str()).collect();
+    assert_eq!(texts, ["PIZZA again", "no pizza today", "the first pizza"]);
+    assert_eq!(
+        found[0].to_string(),
+        "[2024-03-02 09:00] <achin> PIZZA again"
+    );
+
+    assert_eq!(archive.search("#test", &search("pizza"), 2)?.len(), 2);
+    assert_eq!(
+        archive.search("#test", &search("--nick=AGRIF"), 10)?.len(),
+        1
+    );
+    assert_eq!(
+        archive
+            .search("#test", &search("pizza --nick=achin"), 10)?
+            .len(),
+        2
+    );
+    assert_eq!(
+        archive
+            .search("#test", &search("pizza --since=12h"), 10)?
+            .len(),
+        1
+    );
+    assert_eq!(
+        archive
+            .search("#test", &search("pizza --since=2024-03-01"), 10)?
+            .len(),
+        3
+    );
+    assert!(archive.search("#nowhere", &search("pizza"), 10)?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn test_parse_search() {
+    let now = "2024-03-02T12:00:00Z".parse().unwrap();
+    let search = Search::parse("free  pizza --nick=achin --since=2d", now).unwrap();
+    assert_eq!(search.pattern.as_str(), "free pizza");
+    assert_eq!(search.nick.as_deref(), Some("achin"));
+    assert_eq!(search.since, Some("2024-02-29T12:00:00Z".parse().unwrap()));
+    assert!(Search::parse("", now).is_err());
+    assert!(Search::parse("pizza --since=soon", now).is_err());
+    assert!(Search::parse("(unclosed", now).is_err());
+}
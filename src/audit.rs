@@ -0,0 +1,240 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::PathBuf,
+    sync::Mutex,
+};
+
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::Serialize;
+
+use crate::config::get_config;
+
+const AUDIT_DIR: &str = "audit";
+/// Once the current log gets this big, it's rotated out
+const MAX_FILE_BYTES: u64 = 10 * 1024 * 1024;
+/// How many rotated logs are kept, besides the current one
+const MAX_FILES: usize = 10;
+
+/// The most recent entry, pretty-printed, for `!lastreq`
+///
+/// This lock is also held while writing, so entries from different tasks don't get interleaved.
+static LAST_ENTRY: Mutex<Option<String>> = Mutex::new(None);
+
+/// A directory of size-capped JSON-lines files
+struct AuditLog {
+    dir: PathBuf,
+    max_file_bytes: u64,
+    max_files: usize,
+}
+
+impl AuditLog {
+    fn current(&self) -> PathBuf {
+        self.dir.join("current.jsonl")
+    }
+
+    fn append(&self, line: &str) -> anyhow::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let size = fs::metadata(self.current()).map(|m| m.len()).unwrap_or(0);
+        if size > 0 && size + line.len() as u64 > self.max_file_bytes {
+            self.rotate()?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.current())?;
+        writeln!(file, "{line}")?;
+        Ok(())
+    }
+
+    /// Counts the entries on each day since `since`, across the current and rotated logs
+    fn daily_counts(&self, since: NaiveDate) -> anyhow::Result<BTreeMap<NaiveDate, usize>> {
+        #[derive(serde::Deserialize)]
+        struct Dated {
+            date: DateTime<Utc>,
+        }
+
+        let mut counts = BTreeMap::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != "jsonl") {
+                continue;
+            }
+            for line in BufReader::new(fs::File::open(path)?).lines() {
+                let Ok(entry) = serde_json::from_str::<Dated>(&line?) else {
+                    continue;
+                };
+                let day = entry.date.date_naive();
+                if day >= since {
+                    *counts.entry(day).or_insert(0) += 1;
+                }
+            }
+        }
+        Ok(counts)
+    }
+
+    fn rotate(&self) -> anyhow::Result<()> {
+        let rotated = format!("audit-{}.jsonl", Utc::now().format("%Y%m%d-%H%M%S%.9f"));
+        fs::rename(self.current(), self.dir.join(rotated))?;
+
+        let mut old: Vec<PathBuf> = fs::read_dir(&self.dir)?
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| {
+                p.file_name()
+                    .and_then(|n| n.to_str())
+                    .is_some_and(|n| n.starts_with("audit-"))
+            })
+            .collect();
+        // the names sort by date, oldest first
+        old.sort();
+        while old.len() > self.max_files {
+            fs::remove_file(old.remove(0))?;
+        }
+        Ok(())
+    }
+}
+
+/// Replaces any of the given secrets in some text
+fn redact(text: &str, secrets: &[&str]) -> String {
+    secrets
+        .iter()
+        .filter(|s| !s.is_empty())
+        .fold(text.to_string(), |text, secret| {
+            text.replace(secret, "[REDACTED]")
+        })
+}
+
+/// Records a request to the API, and what came back
+///
+/// This never fails; problems writing the log are only printed.
+pub fn record<Req: Serialize, Resp: Serialize, E: std::fmt::Display>(
+    endpoint: &str,
+    request: &Req,
+    response: &Result<Resp, E>,
+) {
+    let (response, error) = match response {
+        Ok(resp) => (serde_json::to_value(resp).ok(), None),
+        Err(e) => (None, Some(e.to_string())),
+    };
+    let entry = serde_json::json!({
+        "date": Utc::now(),
+        "endpoint": endpoint,
+        "request": request,
+        "response": response,
+        "error": error,
+    });
+
+    let api_key = get_config().ok().and_then(|c| c.backend.api_key);
+    let pooled_keys = crate::keys::all_keys();
+    let mut secrets = vec![
+        crate::secrets::OPENAPI_KEY,
+        api_key.as_deref().unwrap_or_default(),
+    ];
+    secrets.extend(pooled_keys.iter().map(|key| key.as_str()));
+    let line = redact(&entry.to_string(), &secrets);
+    let pretty = serde_json::to_string_pretty(&entry)
+        .map(|p| redact(&p, &secrets))
+        .unwrap_or_else(|_| line.clone());
+
+    let log = AuditLog {
+        dir: PathBuf::from(AUDIT_DIR),
+        max_file_bytes: MAX_FILE_BYTES,
+        max_files: MAX_FILES,
+    };
+    let mut last = LAST_ENTRY.lock().expect("audit lock is poisoned");
+    if let Err(e) = log.append(&line) {
+        println!("Failed to write to the audit log: {e}");
+    }
+    *last = Some(pretty);
+}
+
+/// The most recently recorded request and response, pretty-printed
+pub fn last_entry() -> Option<String> {
+    LAST_ENTRY.lock().expect("audit lock is poisoned").clone()
+}
+
+/// How many requests were made on each of the last `days` days, from the audit logs
+///
+/// Days without any requests are included, with a count of 0.
+pub fn daily_counts(days: u32) -> anyhow::Result<Vec<(NaiveDate, usize)>> {
+    let log = AuditLog {
+        dir: PathBuf::from(AUDIT_DIR),
+        max_file_bytes: MAX_FILE_BYTES,
+        max_files: MAX_FILES,
+    };
+    let today = Utc::now().date_naive();
+    let since = today - chrono::Duration::days(days.saturating_sub(1) as i64);
+    let counts = if log.dir.exists() {
+        // hold the lock so the logs aren't rotated out from under us
+        let _lock = LAST_ENTRY.lock().expect("audit lock is poisoned");
+        log.daily_counts(since)?
+    } else {
+        BTreeMap::new()
+    };
+    Ok(since
+        .iter_days()
+        .take_while(|day| *day <= today)
+        .map(|day| (day, counts.get(&day).copied().unwrap_or(0)))
+        .collect())
+}
+
+#[test]
+fn test_audit_rotation() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let log = AuditLog {
+        dir: dir.path().to_path_buf(),
+        max_file_bytes: 100,
+        max_files: 2,
+    };
+    for idx in 0..10 {
+        log.append(&format!(
+            "{{\"entry\": {idx}, \"padding\": \"................\"}}"
+        ))?;
+    }
+    let mut names: Vec<String> = fs::read_dir(dir.path())?
+        .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    names.sort();
+    assert_eq!(names.len(), 3);
+    assert_eq!(names[2], "current.jsonl");
+    assert!(fs::metadata(log.current())?.len() <= 100);
+    // the newest entry is in the current file
+    assert!(fs::read_to_string(log.current())?.contains("\"entry\": 9"));
+    Ok(())
+}
+
+#[test]
+fn test_daily_counts() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let log = AuditLog {
+        dir: dir.path().to_path_buf(),
+        max_file_bytes: 150,
+        max_files: 5,
+    };
+    for date in [
+        "2024-03-01T10:00:00Z",
+        "2024-03-01T23:00:00Z",
+        "2024-03-03T01:00:00Z",
+    ] {
+        log.append(&serde_json::json!({"date": date, "endpoint": "chat"}).to_string())?;
+    }
+    log.append("not json")?;
+    let day = |d| NaiveDate::from_ymd_opt(2024, 3, d).unwrap();
+    let counts = log.daily_counts(day(1))?;
+    assert_eq!(
+        counts.into_iter().collect::<Vec<_>>(),
+        [(day(1), 2), (day(3), 1)]
+    );
+    assert_eq!(log.daily_counts(day(2))?.len(), 1);
+    Ok(())
+}
+
+#[test]
+fn test_redact() {
+    assert_eq!(
+        redact("key sk-abc123 and sk-abc123", &["sk-abc123", ""]),
+        "key [REDACTED] and [REDACTED]"
+    );
+}
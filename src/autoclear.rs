@@ -0,0 +1,81 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+/// When a channel's conversation should be cleared without anyone asking
+///
+/// Stale context tends to confuse the next question.  Everything is off by default, so the
+/// conversation is only trimmed by age.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AutoClear {
+    /// Clear the conversation after this many hours without any messages (0 to never clear)
+    pub idle_hours: i64,
+    /// Clear the conversation when the channel topic changes
+    pub on_topic_change: bool,
+    /// Keep a summary of the old conversation, instead of dropping it entirely
+    pub summarize: bool,
+}
+
+fn parse_bool(value: &str) -> anyhow::Result<bool> {
+    match value {
+        "y" | "yes" | "true" | "on" => Ok(true),
+        "n" | "no" | "false" | "off" => Ok(false),
+        _ => anyhow::bail!("Expected on or off, not {value:?}"),
+    }
+}
+
+impl AutoClear {
+    /// Checks if a conversation whose last message was at `last_activity` should be cleared
+    pub fn is_stale(&self, last_activity: Option<DateTime<Utc>>, now: DateTime<Utc>) -> bool {
+        match last_activity {
+            Some(last) if self.idle_hours > 0 => now - last > Duration::hours(self.idle_hours),
+            _ => false,
+        }
+    }
+
+    /// Updates a setting from a string of the form "key=value"
+    pub fn update(&mut self, cmd: &str) -> anyhow::Result<()> {
+        let (key, value) = cmd.split_once('=').unwrap_or((cmd, ""));
+        match key {
+            "idle" => self.idle_hours = value.parse::<i64>()?.max(0),
+            "topic" => self.on_topic_change = parse_bool(value)?,
+            "summarize" => self.summarize = parse_bool(value)?,
+            _ => anyhow::bail!("Unknown setting {key:?} (try idle, topic or summarize)"),
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for AutoClear {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let on_off = |b: bool| if b { "on" } else { "off" };
+        write!(
+            f,
+            "idle={}h topic={} summarize={}",
+            self.idle_hours,
+            on_off(self.on_topic_change),
+            on_off(self.summarize)
+        )
+    }
+}
+
+#[test]
+fn test_autoclear() {
+    let mut ac = AutoClear::default();
+    let now = Utc::now();
+    assert!(!ac.is_stale(Some(now - Duration::days(30)), now));
+
+    ac.update("idle=6").unwrap();
+    assert!(!ac.is_stale(None, now));
+    assert!(!ac.is_stale(Some(now - Duration::hours(5)), now));
+    assert!(ac.is_stale(Some(now - Duration::hours(7)), now));
+
+    ac.update("idle=0").unwrap();
+    assert!(!ac.is_stale(Some(now - Duration::days(30)), now));
+
+    ac.update("topic=on").unwrap();
+    ac.update("summarize=yes").unwrap();
+    assert_eq!(ac.to_string(), "idle=0h topic=on summarize=on");
+    assert!(ac.update("topic=maybe").is_err());
+    assert!(ac.update("volume=11").is_err());
+}
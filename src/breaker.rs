@@ -0,0 +1,129 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use chrono::{DateTime, Duration, Utc};
+
+/// Consecutive failures before a backend is given a rest
+const FAILURES_TO_OPEN: u32 = 5;
+
+/// How often a request is let through to see if a resting backend is back
+const PROBE_SECS: i64 = 60;
+
+#[derive(Debug, Default)]
+struct Breaker {
+    /// Failures in a row
+    failures: u32,
+    /// When requests stopped being sent, if they have
+    opened_at: Option<DateTime<Utc>>,
+    last_probe: Option<DateTime<Utc>>,
+}
+
+/// Each backend's breaker, keyed by its API base URL
+static BREAKERS: Mutex<Option<HashMap<String, Breaker>>> = Mutex::new(None);
+
+/// The error for a request that wasn't sent (or didn't get a real answer) because the backend
+/// keeps failing
+#[derive(Debug)]
+pub struct BackendUnavailable {
+    /// Only the failure that opened the breaker is worth telling anyone about, so the channel
+    /// gets one notice rather than an error for every request
+    pub announce: bool,
+}
+
+impl std::fmt::Display for BackendUnavailable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "The AI backend is temporarily unavailable, so I'm taking a break from asking it"
+        )
+    }
+}
+
+impl std::error::Error for BackendUnavailable {}
+
+fn with_breaker<T>(backend: &str, f: impl FnOnce(&mut Breaker) -> T) -> T {
+    let mut breakers = BREAKERS.lock().expect("breakers lock is poisoned");
+    let breaker = breakers
+        .get_or_insert_with(HashMap::new)
+        .entry(backend.to_string())
+        .or_default();
+    f(breaker)
+}
+
+/// Checks whether a request can be sent to a backend
+///
+/// While the breaker is open, one request is let through every `PROBE_SECS` to see whether the
+/// backend has recovered.
+pub fn admit(backend: &str, now: DateTime<Utc>) -> Result<(), BackendUnavailable> {
+    with_breaker(backend, |breaker| {
+        let Some(opened_at) = breaker.opened_at else {
+            return Ok(());
+        };
+        let last = breaker.last_probe.unwrap_or(opened_at);
+        if now - last >= Duration::seconds(PROBE_SECS) {
+            breaker.last_probe = Some(now);
+            return Ok(());
+        }
+        Err(BackendUnavailable { announce: false })
+    })
+}
+
+/// Whether requests to a backend are on hold
+pub fn is_open(backend: &str) -> bool {
+    with_breaker(backend, |breaker| breaker.opened_at.is_some())
+}
+
+/// Records how a request went
+///
+/// Returns the error to give instead of the request's own, when the backend is (now) considered
+/// down.
+pub fn record(backend: &str, failed: bool, now: DateTime<Utc>) -> Option<BackendUnavailable> {
+    with_breaker(backend, |breaker| {
+        if !failed {
+            if breaker.opened_at.is_some() {
+                println!("{backend} is answering again, resuming requests");
+            }
+            *breaker = Breaker::default();
+            return None;
+        }
+        breaker.failures += 1;
+        if breaker.opened_at.is_some() {
+            // a probe that failed
+            return Some(BackendUnavailable { announce: false });
+        }
+        if breaker.failures < FAILURES_TO_OPEN {
+            return None;
+        }
+        println!(
+            "{backend} failed {} times in a row, pausing requests",
+            breaker.failures
+        );
+        breaker.opened_at = Some(now);
+        Some(BackendUnavailable { announce: true })
+    })
+}
+
+#[test]
+fn test_breaker() {
+    let backend = "https://test.invalid/v1";
+    let now = Utc::now();
+    for _ in 1..FAILURES_TO_OPEN {
+        assert!(admit(backend, now).is_ok());
+        assert!(record(backend, true, now).is_none());
+    }
+    assert!(record(backend, true, now).is_some_and(|e| e.announce));
+    assert!(is_open(backend));
+    assert!(admit(backend, now).is_err_and(|e| !e.announce));
+    assert!(admit(backend, now + Duration::seconds(30)).is_err());
+
+    // a failed probe keeps it open, quietly
+    let probe = now + Duration::seconds(PROBE_SECS);
+    assert!(admit(backend, probe).is_ok());
+    assert!(record(backend, true, probe).is_some_and(|e| !e.announce));
+    assert!(admit(backend, probe + Duration::seconds(1)).is_err());
+
+    let probe = probe + Duration::seconds(PROBE_SECS);
+    assert!(admit(backend, probe).is_ok());
+    assert!(record(backend, false, probe).is_none());
+    assert!(!is_open(backend));
+    assert!(admit(backend, probe).is_ok());
+}
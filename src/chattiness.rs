@@ -0,0 +1,180 @@
+use std::collections::VecDeque;
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Phrases that suggest people don't want to hear from the bot right now
+const NEGATIVE_PHRASES: &[&str] = &[
+    "shut up",
+    "stfu",
+    "go away",
+    "be quiet",
+    "nobody asked",
+    "no one asked",
+    "bad bot",
+    "stop talking",
+    "not now",
+    "annoying",
+];
+
+/// How long after an unsolicited message a reply is considered a reaction to it
+const REACTION_WINDOW_MINUTES: i64 = 5;
+
+/// Longest the bot can be muted for in one go
+pub const MAX_MUTE_MINUTES: i64 = 24 * 60;
+
+/// Limits on how often the bot may speak in a channel without being asked
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Chattiness {
+    /// Minimum time between two unsolicited messages
+    pub min_secs_between: i64,
+    /// Maximum number of unsolicited messages in any hour
+    pub max_per_hour: usize,
+    /// How long to stay quiet after a negative reaction
+    pub mute_minutes: i64,
+    /// When recent unsolicited messages were sent
+    pub recent: VecDeque<DateTime<Utc>>,
+    pub muted_until: Option<DateTime<Utc>>,
+}
+
+impl Default for Chattiness {
+    fn default() -> Self {
+        Self {
+            min_secs_between: 30 * 60,
+            max_per_hour: 2,
+            mute_minutes: 2 * 60,
+            recent: VecDeque::new(),
+            muted_until: None,
+        }
+    }
+}
+
+/// A very cheap sentiment check
+pub fn is_negative(msg: &str) -> bool {
+    let msg = msg.to_lowercase();
+    NEGATIVE_PHRASES.iter().any(|phrase| msg.contains(phrase))
+}
+
+impl Chattiness {
+    /// Checks if an unsolicited message may be sent now
+    ///
+    /// If not, the reason is returned as an error
+    pub fn may_speak(&self, now: DateTime<Utc>) -> Result<(), String> {
+        if let Some(until) = self.muted_until {
+            if now < until {
+                return Err(format!("muted until {}", until.format("%H:%M UTC")));
+            }
+        }
+        if let Some(last) = self.recent.back() {
+            if now - *last < Duration::seconds(self.min_secs_between) {
+                return Err(format!(
+                    "last unsolicited message was only {}s ago",
+                    (now - *last).num_seconds()
+                ));
+            }
+        }
+        let past_hour = self
+            .recent
+            .iter()
+            .filter(|date| now - **date < Duration::hours(1))
+            .count();
+        if past_hour >= self.max_per_hour {
+            return Err(format!(
+                "already sent {past_hour} unsolicited messages this hour"
+            ));
+        }
+        Ok(())
+    }
+
+    /// Records that an unsolicited message was sent
+    pub fn record(&mut self, now: DateTime<Utc>) {
+        self.recent.push_back(now);
+        while let Some(date) = self.recent.front() {
+            if now - *date > Duration::hours(1) {
+                self.recent.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// Mutes the bot for between a minute and `MAX_MUTE_MINUTES`, returning how long that is
+    pub fn mute(&mut self, now: DateTime<Utc>, minutes: i64) -> i64 {
+        let minutes = minutes.clamp(1, MAX_MUTE_MINUTES);
+        self.muted_until = Duration::try_minutes(minutes).and_then(|d| now.checked_add_signed(d));
+        minutes
+    }
+
+    /// Looks at a channel message for a negative reaction to a recent unsolicited message
+    ///
+    /// Returns true if this caused the bot to mute itself
+    pub fn observe(&mut self, msg: &str, now: DateTime<Utc>) -> bool {
+        let reacting = matches!(self.recent.back(),
+            Some(last) if now - *last < Duration::minutes(REACTION_WINDOW_MINUTES));
+        if reacting && is_negative(msg) {
+            self.mute(now, self.mute_minutes);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Updates a setting from a string of the form "key=value"
+    pub fn update(&mut self, cmd: &str) -> anyhow::Result<()> {
+        let (key, value) = cmd.split_once('=').unwrap_or((cmd, ""));
+        match key {
+            "gap" => self.min_secs_between = value.parse::<i64>()?.max(0),
+            "perhour" => self.max_per_hour = value.parse()?,
+            "mute" => self.mute_minutes = value.parse::<i64>()?.clamp(1, MAX_MUTE_MINUTES),
+            _ => anyhow::bail!("Unknown setting {key:?} (try gap, perhour or mute)"),
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for Chattiness {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "gap={}s perhour={} mute={}m",
+            self.min_secs_between, self.max_per_hour, self.mute_minutes
+        )?;
+        if let Some(until) = self.muted_until {
+            if Utc::now() < until {
+                write!(f, " (muted until {})", until.format("%H:%M UTC"))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_chattiness() {
+    let mut c = Chattiness::default();
+    let now = Utc::now();
+    assert!(c.may_speak(now).is_ok());
+
+    c.record(now);
+    assert!(c.may_speak(now + Duration::minutes(10)).is_err());
+    assert!(c.may_speak(now + Duration::minutes(31)).is_ok());
+
+    // two messages in the past hour
+    c.update("gap=0").unwrap();
+    c.record(now + Duration::minutes(1));
+    assert!(c.may_speak(now + Duration::minutes(2)).is_err());
+    assert!(c.may_speak(now + Duration::minutes(61)).is_ok());
+
+    // a negative reaction mutes the bot, but only shortly after it spoke
+    assert!(!c.observe("shut up bot", now + Duration::minutes(10)));
+    assert!(c.observe("ugh, shut up bot", now + Duration::minutes(2)));
+    assert!(c.may_speak(now + Duration::minutes(61)).is_err());
+
+    // mutes are kept to a sensible length, however long is asked for
+    assert_eq!(c.mute(now, i64::MAX), MAX_MUTE_MINUTES);
+    assert_eq!(c.mute(now, -5), 1);
+
+    assert!(c.update("perhour=5").is_ok());
+    assert_eq!(c.max_per_hour, 5);
+    assert!(c.update("volume=11").is_err());
+}
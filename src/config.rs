@@ -0,0 +1,376 @@
+use std::{collections::HashMap, fs::File, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{keys::KeyPoolConfig, plugins::PluginPolicy, sandbox::RunnerConfig};
+
+const CONFIG_PATH: &str = "config.json";
+
+/// Bot-wide settings, read from `config.json`
+///
+/// Every field has a default, so a missing file (or a file that only sets a few keys) is fine.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BotConfig {
+    /// Per-plugin capabilities, keyed by plugin name
+    pub plugins: HashMap<String, PluginPolicy>,
+    /// Where chat completions are sent
+    pub backend: BackendConfig,
+    pub tts: TtsConfig,
+    /// Paste services to try, in order, when up.em32.site isn't working
+    pub upload_fallbacks: Vec<UploaderConfig>,
+    /// How long uploads are kept by paste services that can be asked to expire them (see
+    /// `UploaderConfig::expires_header`)
+    pub upload_expiry_hours: Option<u32>,
+    /// Sandboxed interpreters for `!run`, keyed by language
+    pub runners: HashMap<String, RunnerConfig>,
+    /// Models that can be picked with `!chat --model=`
+    pub models: ModelAllowlist,
+    /// OpenAI keys to spread requests across, instead of the one in `secrets.rs`
+    pub key_pool: KeyPoolConfig,
+    /// Which OpenAI organization and project requests are billed to
+    pub openai: OpenAIAccountConfig,
+    /// Where audio is sent to be transcribed
+    pub transcription: TranscriptionConfig,
+    /// Lines from the same nick this close together are stored as one message, since people
+    /// often split a thought across a few quick lines.  Defaults to 20 seconds, 0 turns it off.
+    pub merge_window_secs: Option<u64>,
+    /// The HTTP API for controlling the bot remotely
+    pub api: ApiConfig,
+    /// Matrix rooms to serve alongside the IRC channels
+    pub matrix: MatrixConfig,
+    pub discord: DiscordConfig,
+    /// Bots that pass along messages from other networks, whose lines are treated as coming from
+    /// whoever they relay
+    pub relays: Vec<RelayConfig>,
+    /// Whether chat replies start with the asker's nick
+    pub reply_addressing: ReplyAddressing,
+    /// Per-channel settings that belong to whoever runs the bot, keyed by channel name
+    pub channels: HashMap<String, ChannelConfig>,
+    /// How outgoing HTTP requests get out, for deployments behind a proxy
+    pub network: NetworkConfig,
+}
+
+/// Who's asking for something, for deciding whether they're allowed to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    Everyone,
+    Owner,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ModelAllowlist {
+    /// Models that anyone can use
+    pub everyone: Vec<String>,
+    /// More models that only the bot owner can use.  `*` allows any model.
+    pub owner: Vec<String>,
+}
+
+impl Default for ModelAllowlist {
+    fn default() -> Self {
+        Self {
+            everyone: vec!["gpt-4o".to_string(), "gpt-4o-mini".to_string()],
+            owner: vec!["*".to_string()],
+        }
+    }
+}
+
+impl ModelAllowlist {
+    pub fn allows(&self, permission: Permission, model: &str) -> bool {
+        let model = model.trim();
+        let listed = |list: &[String]| {
+            list.iter()
+                .any(|m| m == "*" || m.eq_ignore_ascii_case(model))
+        };
+        listed(&self.everyone) || (permission == Permission::Owner && listed(&self.owner))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReplyAddressing {
+    Always,
+    Never,
+    /// Only when someone else has spoken since the question, so it's not clear who the reply
+    /// is for
+    #[default]
+    Smart,
+}
+
+/// How speech with several paragraphs is posted
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TtsLayout {
+    /// One file with all of it
+    #[default]
+    Single,
+    /// A file for each paragraph, and a message listing them
+    Paragraphs,
+    /// One file, and a text file with the time each paragraph starts at
+    Chapters,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TtsConfig {
+    /// Longest reply (estimated from its length) that will be turned into audio
+    pub max_duration_secs: u32,
+    /// Summarize replies that are too long, instead of cutting them off
+    pub summarize_long: bool,
+    pub layout: TtsLayout,
+}
+
+impl Default for TtsConfig {
+    fn default() -> Self {
+        Self {
+            max_duration_secs: 5 * 60,
+            summarize_long: false,
+            layout: TtsLayout::Single,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendKind {
+    #[default]
+    OpenAI,
+    /// openrouter.ai, which serves models from many vendors through an OpenAI-compatible API
+    OpenRouter,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BackendConfig {
+    pub kind: BackendKind,
+    /// Overrides the default API base URL for this kind of backend
+    pub api_base: Option<String>,
+    /// The API key to use.  If not set, the OpenAI key from `secrets.rs` is used.
+    pub api_key: Option<String>,
+    /// Extra headers to send with every request (OpenRouter uses `HTTP-Referer` and `X-Title`)
+    pub headers: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TranscriptionBackend {
+    /// OpenAI's whisper API, which is paid for by the minute
+    #[default]
+    OpenAI,
+    /// A server on our own hardware with an OpenAI-compatible `/audio/transcriptions` endpoint,
+    /// like the whisper.cpp server or faster-whisper-server
+    Local,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TranscriptionConfig {
+    pub backend: TranscriptionBackend,
+    /// Base URL of the local server, like `http://localhost:8080/v1`
+    pub api_base: Option<String>,
+    /// The model to ask the local server for, if not `whisper-1`
+    pub model: Option<String>,
+    /// Largest file the local server is given.  It doesn't have the API's 25MB limit, so this
+    /// defaults to 200MB.
+    pub max_bytes: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OpenAIAccountConfig {
+    /// Sent as the `OpenAI-Organization` header, for keys that belong to several organizations
+    pub organization: Option<String>,
+    /// Sent as the `OpenAI-Project` header, so usage shows up under the right project
+    pub project: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChannelConfig {
+    /// A sentence or two about what the channel is for, added to the system prompt
+    pub description: Option<String>,
+    /// Add the channel's current topic to the system prompt
+    pub topic_in_prompt: bool,
+}
+
+/// Applied to every HTTP client, including the ones for the OpenAI API (see `http::client_builder`)
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NetworkConfig {
+    /// Where HTTP and HTTPS requests are sent, like `http://proxy.example.com:3128`.  Without
+    /// this, the usual `HTTPS_PROXY` and `HTTP_PROXY` environment variables are used.
+    pub proxy: Option<String>,
+    /// Hosts that are reached directly rather than through `proxy`, comma separated like the
+    /// `NO_PROXY` environment variable
+    pub no_proxy: Option<String>,
+    /// PEM files of extra certificate authorities to trust, for proxies that intercept TLS
+    pub root_certificates: Vec<String>,
+    /// How long looking up a host's addresses can take.  Defaults to 5 seconds.
+    pub dns_timeout_ms: Option<u64>,
+    /// How long checking what a link is (an image, say) can spend connecting, shared between
+    /// the host's addresses.  Defaults to 2 seconds.
+    pub connect_timeout_ms: Option<u64>,
+}
+
+impl NetworkConfig {
+    pub fn dns_timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.dns_timeout_ms.unwrap_or(5000))
+    }
+    pub fn connect_timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.connect_timeout_ms.unwrap_or(2000))
+    }
+}
+
+/// The HTTP admin API is only started when both of these are set
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ApiConfig {
+    /// Address to listen on, like `127.0.0.1:8080`
+    pub listen: Option<String>,
+    /// Every request needs this in an `Authorization: Bearer` header
+    pub token: Option<String>,
+    /// Whether the API is reached over HTTPS, through a proxy that handles TLS, so the
+    /// dashboard's login cookie is only ever sent over HTTPS.  A proxy that sends
+    /// `X-Forwarded-Proto: https` is noticed without this.
+    pub https: bool,
+    /// Services that can post announcements to `/hooks/<name>`
+    pub webhooks: Vec<WebhookConfig>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WebhookConfig {
+    /// The last part of the URL the service posts to
+    pub name: String,
+    /// Posts have to be signed with this (like GitHub's `X-Hub-Signature-256`), or send it as a
+    /// bearer token, for services that can't sign
+    pub secret: String,
+    /// Where the announcements go
+    pub channels: Vec<String>,
+    /// Have the model squash payloads that aren't recognized into one line, rather than posting
+    /// the start of the raw JSON
+    pub summarize: bool,
+}
+
+/// The bot only connects to Matrix when there's an access token
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MatrixConfig {
+    /// Base URL of the homeserver, like `https://matrix.org`
+    pub homeserver: String,
+    /// The bot's account, like `@charbot:matrix.org`
+    pub user_id: String,
+    pub access_token: Option<String>,
+    /// Rooms to join, by alias (like `#anna:matrix.org`), so they're named like IRC channels
+    pub rooms: Vec<String>,
+}
+
+/// The bot only connects to Discord when there's a token
+///
+/// The bot's application needs the Message Content intent turned on, or every message it sees
+/// will be empty.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DiscordConfig {
+    /// The bot token from the Discord developer portal
+    pub token: Option<String>,
+}
+
+/// A bridge bot, like one relaying a Matrix room or a Discord channel into IRC
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RelayConfig {
+    pub nick: String,
+    /// The bot's host, like `matrix/bridge`.  When set, someone else using the bot's nick isn't
+    /// taken for it.
+    #[serde(default)]
+    pub host: Option<String>,
+    /// A regex with `nick` and `text` groups, for finding who said a relayed line.  Without one,
+    /// `[nick] text` and `<nick> text` are recognized.
+    #[serde(default, with = "optional_regex")]
+    pub pattern: Option<regex::Regex>,
+}
+
+/// Reads a regex from its pattern, so a bad one makes the config invalid rather than being
+/// found out later
+mod optional_regex {
+    use regex::Regex;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(regex: &Option<Regex>, s: S) -> Result<S::Ok, S::Error> {
+        match regex {
+            Some(regex) => s.serialize_some(regex.as_str()),
+            None => s.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Regex>, D::Error> {
+        Option::<String>::deserialize(d)?
+            .map(|pattern| Regex::new(&pattern))
+            .transpose()
+            .map_err(D::Error::custom)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum UploadMethod {
+    #[default]
+    Put,
+    Post,
+}
+
+/// A paste service that takes the content as the request body, and replies with its URL
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UploaderConfig {
+    pub url: String,
+    #[serde(default)]
+    pub method: UploadMethod,
+    /// Request header that asks the service to delete the upload after some number of hours
+    #[serde(default)]
+    pub expires_header: Option<String>,
+    /// Whether uploads can be taken down by sending a DELETE to their URL
+    #[serde(default)]
+    pub deletable: bool,
+    /// Response header with a token for deleting the upload, which is sent back in the same
+    /// header with the DELETE
+    #[serde(default)]
+    pub token_header: Option<String>,
+}
+
+impl BotConfig {
+    pub fn plugin_policy(&self, name: &str) -> PluginPolicy {
+        self.plugins.get(name).cloned().unwrap_or_default()
+    }
+    pub fn merge_window(&self) -> chrono::Duration {
+        chrono::Duration::seconds(self.merge_window_secs.unwrap_or(20) as i64)
+    }
+}
+
+#[test]
+fn test_model_allowlist() {
+    let models = ModelAllowlist::default();
+    assert!(models.allows(Permission::Everyone, "gpt-4o-mini"));
+    assert!(models.allows(Permission::Everyone, "GPT-4o"));
+    assert!(!models.allows(Permission::Everyone, "o1"));
+    assert!(models.allows(Permission::Owner, "o1"));
+
+    let models = ModelAllowlist {
+        everyone: Vec::new(),
+        owner: vec!["claude".to_string()],
+    };
+    assert!(!models.allows(Permission::Everyone, "claude"));
+    assert!(models.allows(Permission::Owner, "claude"));
+    assert!(!models.allows(Permission::Owner, "gpt-4o"));
+}
+
+/// Loads the config from disk
+///
+/// Like `get_prompt`, this re-reads the file every time, so edits take effect without a restart.
+pub fn get_config() -> anyhow::Result<BotConfig> {
+    if !Path::new(CONFIG_PATH).exists() {
+        return Ok(BotConfig::default());
+    }
+    let file = File::open(CONFIG_PATH)?;
+    Ok(serde_json::from_reader(file)?)
+}
@@ -0,0 +1,170 @@
+use std::sync::OnceLock;
+
+use chrono::{DateTime, Duration, Utc};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+/// Most quantities that are converted from a single message
+const MAX_PER_MESSAGE: usize = 2;
+
+/// Whether quantities mentioned in a channel get converted to the other system of units
+///
+/// Off by default, since it's the kind of thing that gets annoying quickly.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AutoConvert {
+    pub enabled: bool,
+    /// Most messages with conversions in an hour
+    pub max_per_hour: usize,
+    /// When the recent conversions were posted
+    #[serde(skip)]
+    recent: Vec<DateTime<Utc>>,
+}
+
+impl Default for AutoConvert {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_per_hour: 3,
+            recent: Vec::new(),
+        }
+    }
+}
+
+impl AutoConvert {
+    /// Checks whether a conversion can be posted now, and counts it if so
+    pub fn allow(&mut self, now: DateTime<Utc>) -> bool {
+        self.recent.retain(|t| now - *t < Duration::hours(1));
+        if !self.enabled || self.recent.len() >= self.max_per_hour {
+            return false;
+        }
+        self.recent.push(now);
+        true
+    }
+
+    /// Updates a setting from "on", "off", or "max=N"
+    pub fn update(&mut self, cmd: &str) -> anyhow::Result<()> {
+        match cmd.split_once('=') {
+            None if cmd == "on" => self.enabled = true,
+            None if cmd == "off" => self.enabled = false,
+            Some(("max", value)) => self.max_per_hour = value.parse()?,
+            _ => anyhow::bail!("Usage: !convert [on|off] [max=<conversions per hour>]"),
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for AutoConvert {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let state = if self.enabled { "on" } else { "off" };
+        write!(f, "{state} max={}/hour", self.max_per_hour)
+    }
+}
+
+/// A quantity found in a message, ready to be converted
+#[derive(Debug, Clone, PartialEq)]
+pub struct Quantity {
+    /// How it was written, like "450 km"
+    pub text: String,
+    /// The numbat expression that converts it
+    pub expr: String,
+}
+
+fn quantity_regex() -> &'static Regex {
+    static REGEX: OnceLock<Regex> = OnceLock::new();
+    REGEX.get_or_init(|| {
+        // a bare F or C has to be capitalized, so "5 c" (and similar) isn't taken as a temperature
+        Regex::new(
+            r"(?:^|[\s(~])(-?\d[\d,]*(?:\.\d+)?) ?((?i:km/h|kph|km|miles?|mi|kg|lbs?|pounds?|ft|feet|foot|mph)|°[FC]|[FC])\b",
+        )
+        .expect("the quantity regex is valid")
+    })
+}
+
+/// Finds quantities like "it's 450 km away" or "72F" in a message
+pub fn find_quantities(msg: &str) -> Vec<Quantity> {
+    quantity_regex()
+        .captures_iter(msg)
+        .filter_map(|caps| {
+            let number = caps[1].replace(',', "");
+            number.parse::<f64>().ok()?;
+            let unit = &caps[2];
+            let expr = match unit.to_lowercase().trim_start_matches('°') {
+                "km" => format!("{number} km -> mi"),
+                "mi" | "mile" | "miles" => format!("{number} mi -> km"),
+                "kg" => format!("{number} kg -> lb"),
+                "lb" | "lbs" | "pound" | "pounds" => format!("{number} lb -> kg"),
+                "ft" | "feet" | "foot" => format!("{number} ft -> m"),
+                "mph" => format!("{number} mph -> km/h"),
+                "km/h" | "kph" => format!("{number} km/h -> mph"),
+                "f" => format!("to_celsius(from_fahrenheit({number}))"),
+                "c" => format!("to_fahrenheit(from_celsius({number}))"),
+                _ => return None,
+            };
+            let text =
+                caps[0].trim_start_matches(|c: char| c.is_whitespace() || c == '(' || c == '~');
+            Some(Quantity {
+                text: text.to_string(),
+                expr,
+            })
+        })
+        .take(MAX_PER_MESSAGE)
+        .collect()
+}
+
+/// Puts the result of a conversion next to the quantity it came from, like "450 km ≈ 279.6 mi"
+pub fn describe(quantity: &Quantity, result: &str) -> String {
+    let result = result.trim().trim_start_matches('=').trim();
+    // temperatures come back as plain numbers, so they need their unit added
+    let suffix = if quantity.expr.starts_with("to_celsius") {
+        " °C"
+    } else if quantity.expr.starts_with("to_fahrenheit") {
+        " °F"
+    } else {
+        ""
+    };
+    format!("{} ≈ {result}{suffix}", quantity.text)
+}
+
+#[test]
+fn test_find_quantities() {
+    let found = find_quantities("it's 450 km away, and 1,200 miles back");
+    assert_eq!(
+        found,
+        [
+            Quantity {
+                text: "450 km".to_string(),
+                expr: "450 km -> mi".to_string(),
+            },
+            Quantity {
+                text: "1,200 miles".to_string(),
+                expr: "1200 mi -> km".to_string(),
+            },
+        ]
+    );
+    assert_eq!(
+        find_quantities("it's 72F out")[0].expr,
+        "to_celsius(from_fahrenheit(72))"
+    );
+    assert_eq!(find_quantities("(-4.5°C)")[0].text, "-4.5°C");
+    assert!(find_quantities("I have 5 cats and 3 fish").is_empty());
+    assert!(find_quantities("section 5c").is_empty());
+    assert!(find_quantities("the i5 kmart").is_empty());
+}
+
+#[test]
+fn test_auto_convert() {
+    let mut convert = AutoConvert::default();
+    let now = Utc::now();
+    assert!(!convert.allow(now));
+
+    convert.update("on").unwrap();
+    convert.update("max=2").unwrap();
+    assert!(convert.allow(now));
+    assert!(convert.allow(now));
+    assert!(!convert.allow(now));
+    assert!(convert.allow(now + Duration::minutes(61)));
+    assert_eq!(convert.to_string(), "on max=2/hour");
+    assert!(convert.update("max=lots").is_err());
+    assert!(convert.update("loud").is_err());
+}
@@ -0,0 +1,246 @@
+use std::{collections::BTreeMap, fs::File};
+
+use async_openai::types::ChatCompletionRequestMessage;
+use axum::{
+    extract::{Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{Html, IntoResponse, Redirect, Response},
+    routing::get,
+    Form, Router,
+};
+use serde::Deserialize;
+
+use crate::{
+    api::{tokens_match, ApiError, ApiState, TOKEN_COOKIE},
+    audit, keys, set_prompt, ChatMessageThing,
+};
+
+/// How many days of requests the usage graph covers
+const USAGE_DAYS: u32 = 14;
+
+/// Pages that need the token
+pub(crate) fn router() -> Router<ApiState> {
+    Router::new()
+        .route("/ui", get(index))
+        .route("/ui/channels/:channel", get(channel))
+        .route("/ui/usage", get(usage))
+        .route("/ui/prompts", get(prompts).post(save_prompt))
+}
+
+/// The login page, which is the only one that doesn't need the token
+pub(crate) fn login_router() -> Router<ApiState> {
+    Router::new().route("/ui/login", get(login_form).post(login))
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Channel names start with #, so they have to be escaped to go in a URL
+fn channel_link(channel: &str) -> String {
+    let path = url::form_urlencoded::byte_serialize(channel.as_bytes()).collect::<String>();
+    format!("<a href=\"/ui/channels/{path}\">{}</a>", escape(channel))
+}
+
+fn page(title: &str, body: &str) -> Html<String> {
+    Html(format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{title} - anna</title>\
+         <style>\
+         body {{ font-family: sans-serif; max-width: 60em; margin: auto; padding: 1em; }}\
+         td {{ vertical-align: top; padding: 0.2em 0.5em; }}\
+         textarea {{ width: 100%; height: 8em; }}\
+         .bar {{ background: #58c; height: 1em; }}\
+         </style></head><body>\
+         <nav><a href=\"/ui\">Channels</a> | <a href=\"/ui/usage\">Usage</a> | \
+         <a href=\"/ui/prompts\">Prompts</a></nav><h1>{title}</h1>{body}</body></html>",
+        title = escape(title)
+    ))
+}
+
+async fn index(State(state): State<ApiState>) -> Html<String> {
+    let mut channels = state.control.channels();
+    channels.sort();
+    let items: String = channels
+        .iter()
+        .map(|channel| format!("<li>{}</li>", channel_link(channel)))
+        .collect();
+    page("Channels", &format!("<ul>{items}</ul>"))
+}
+
+fn role(msg: &ChatCompletionRequestMessage) -> &'static str {
+    match msg {
+        ChatCompletionRequestMessage::System(_) => "system",
+        ChatCompletionRequestMessage::User(_) => "user",
+        ChatCompletionRequestMessage::Assistant(_) => "assistant",
+        ChatCompletionRequestMessage::Tool(_) => "tool",
+        ChatCompletionRequestMessage::Function(_) => "function",
+    }
+}
+
+fn history_row(cmt: &ChatMessageThing) -> String {
+    let mut text = escape(cmt.get_as_irc_format().unwrap_or_default());
+    for image in &cmt.archived_images {
+        text.push_str(&format!(" <a href=\"{}\">[image]</a>", escape(&image.url)));
+    }
+    format!(
+        "<tr><td>{}</td><td>{}</td><td>{text}</td></tr>",
+        cmt.date.format("%Y-%m-%d %H:%M"),
+        role(&cmt.msg)
+    )
+}
+
+async fn channel(State(state): State<ApiState>, Path(channel): Path<String>) -> Response {
+    let (Some(settings), Some(history)) = (
+        state.control.settings(&channel),
+        state.control.history(&channel),
+    ) else {
+        return (StatusCode::NOT_FOUND, page("No such channel", "")).into_response();
+    };
+    let settings: String = settings
+        .into_iter()
+        .map(|(name, value)| format!("<tr><td>{name}</td><td>{}</td></tr>", escape(&value)))
+        .collect();
+    let rows: String = history.iter().rev().map(history_row).collect();
+    page(
+        &channel,
+        &format!(
+            "<h2>Settings</h2><table>{settings}</table>\
+             <h2>History</h2><p>{} messages, newest first</p><table>{rows}</table>",
+            history.len()
+        ),
+    )
+    .into_response()
+}
+
+async fn usage() -> Result<Html<String>, ApiError> {
+    let counts = audit::daily_counts(USAGE_DAYS)?;
+    let most = counts
+        .iter()
+        .map(|(_, count)| *count)
+        .max()
+        .unwrap_or(0)
+        .max(1);
+    let bars: String = counts
+        .iter()
+        .map(|(day, count)| {
+            let width = count * 100 / most;
+            format!(
+                "<tr><td>{day}</td><td>{count}</td>\
+                 <td style=\"width: 30em\"><div class=\"bar\" style=\"width: {width}%\"></div></td></tr>"
+            )
+        })
+        .collect();
+    let keys: String = keys::usage_report()
+        .split("; ")
+        .map(|line| format!("<li>{}</li>", escape(line)))
+        .collect();
+    Ok(page(
+        "Usage",
+        &format!(
+            "<h2>Requests per day</h2><table>{bars}</table>\
+             <h2>Since startup</h2><ul>{keys}</ul>"
+        ),
+    ))
+}
+
+async fn prompts() -> Result<Html<String>, ApiError> {
+    let prompts: BTreeMap<String, String> = match File::open("prompts.json") {
+        Ok(file) => serde_json::from_reader(file)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+        Err(e) => return Err(e.into()),
+    };
+    let forms: String = prompts
+        .iter()
+        .map(|(name, prompt)| {
+            format!(
+                "<form method=\"post\"><h3>{name}</h3>\
+                 <input type=\"hidden\" name=\"name\" value=\"{name}\">\
+                 <textarea name=\"prompt\">{}</textarea><button>Save</button></form>",
+                escape(prompt),
+                name = escape(name)
+            )
+        })
+        .collect();
+    Ok(page(
+        "Prompts",
+        &format!(
+            "{forms}<form method=\"post\"><h3>New prompt</h3>\
+             <input name=\"name\" placeholder=\"name\">\
+             <textarea name=\"prompt\"></textarea><button>Add</button></form>"
+        ),
+    ))
+}
+
+#[derive(Deserialize)]
+struct PromptForm {
+    name: String,
+    prompt: String,
+}
+
+async fn save_prompt(Form(form): Form<PromptForm>) -> Result<Response, ApiError> {
+    let name = form.name.trim();
+    if name.is_empty() {
+        return Ok((StatusCode::BAD_REQUEST, "The prompt needs a name").into_response());
+    }
+    // browsers send newlines in a textarea as \r\n
+    set_prompt(name, &form.prompt.replace("\r\n", "\n"))?;
+    Ok(Redirect::to("/ui/prompts").into_response())
+}
+
+async fn login_form() -> Html<String> {
+    Html(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Log in - anna</title></head>\
+         <body><form method=\"post\"><input type=\"password\" name=\"token\" placeholder=\"token\">\
+         <button>Log in</button></form></body></html>"
+            .to_string(),
+    )
+}
+
+#[derive(Deserialize)]
+struct LoginForm {
+    token: String,
+}
+
+/// The cookie that keeps someone logged in, which is only sent back over HTTPS if that's how
+/// they're connecting
+fn login_cookie(token: &str, https: bool) -> String {
+    let secure = if https { "; Secure" } else { "" };
+    format!("{TOKEN_COOKIE}={token}; Path=/ui; HttpOnly; SameSite=Strict{secure}")
+}
+
+async fn login(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Form(form): Form<LoginForm>,
+) -> Response {
+    if !tokens_match(&form.token, &state.token) {
+        return (StatusCode::UNAUTHORIZED, "Wrong token").into_response();
+    }
+    // the API itself is plain HTTP, so HTTPS means a proxy in front of it
+    let forwarded_https = headers
+        .get("x-forwarded-proto")
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|proto| proto.eq_ignore_ascii_case("https"));
+    let cookie = login_cookie(&form.token, state.https || forwarded_https);
+    ([(header::SET_COOKIE, cookie)], Redirect::to("/ui")).into_response()
+}
+
+#[test]
+fn test_escape() {
+    assert_eq!(
+        escape("<achin> a & b \"c\""),
+        "&lt;achin&gt; a &amp; b &quot;c&quot;"
+    );
+    assert_eq!(
+        channel_link("##em32"),
+        "<a href=\"/ui/channels/%23%23em32\">##em32</a>"
+    );
+    assert_eq!(
+        login_cookie("hunter2", true),
+        "anna_token=hunter2; Path=/ui; HttpOnly; SameSite=Strict; Secure"
+    );
+    assert!(!login_cookie("hunter2", false).contains("Secure"));
+}
@@ -0,0 +1,177 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::{openai::megabytes, progress::Progress};
+
+/// How long a whole transfer is allowed to take
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// A file that someone offered to send us with DCC SEND
+#[derive(Debug, PartialEq)]
+pub struct DccOffer {
+    pub filename: String,
+    pub addr: SocketAddr,
+    /// Not every client says how big the file is
+    pub size: Option<u64>,
+}
+
+impl DccOffer {
+    /// Parses a CTCP message like "\x01DCC SEND song.ogg 3232235777 5000 1234\x01"
+    ///
+    /// Filenames with spaces in them are quoted.  The address is either an IPv4 address packed
+    /// into a number, or an IPv6 address written out as usual.
+    pub fn parse(msg: &str) -> Option<Self> {
+        let rest = msg
+            .strip_prefix('\x01')?
+            .strip_suffix('\x01')?
+            .strip_prefix("DCC SEND ")?;
+        let (filename, rest) = match rest.strip_prefix('"') {
+            Some(quoted) => quoted.split_once('"')?,
+            None => rest.split_once(' ')?,
+        };
+        let mut words = rest.split_whitespace();
+        let ip = words.next()?;
+        let ip = match ip.parse::<u32>() {
+            Ok(packed) => IpAddr::V4(Ipv4Addr::from(packed)),
+            Err(_) => ip.parse().ok()?,
+        };
+        let port = words.next()?.parse().ok()?;
+        let size = words.next().and_then(|s| s.parse().ok());
+        Some(Self {
+            filename: filename.to_string(),
+            addr: SocketAddr::new(ip, port),
+            size,
+        })
+    }
+
+    /// Connects to the sender and downloads the file, refusing anything bigger than `max_bytes`
+    pub async fn receive(
+        &self,
+        max_bytes: u64,
+        progress: &mut Progress,
+    ) -> anyhow::Result<Vec<u8>> {
+        if self.addr.port() == 0 {
+            bail!("Passive DCC isn't supported, so we need to connect to you");
+        }
+        if !is_public(self.addr.ip()) {
+            bail!("Not connecting to {}", self.addr.ip());
+        }
+        if self.size.is_some_and(|size| size > max_bytes) {
+            bail!("{} is too big", self.filename);
+        }
+        tokio::time::timeout(TRANSFER_TIMEOUT, self.transfer(max_bytes, progress))
+            .await
+            .context("The transfer took too long")?
+    }
+
+    async fn transfer(&self, max_bytes: u64, progress: &mut Progress) -> anyhow::Result<Vec<u8>> {
+        let mut stream =
+            tokio::time::timeout(Duration::from_secs(10), TcpStream::connect(self.addr))
+                .await
+                .context("Timed out connecting")??;
+        let mut data = Vec::new();
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let n = stream.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            data.extend_from_slice(&buf[..n]);
+            if data.len() as u64 > max_bytes {
+                bail!("{} is too big", self.filename);
+            }
+            // senders wait to hear how much has arrived so far, as a 32-bit count
+            stream.write_all(&(data.len() as u32).to_be_bytes()).await?;
+            progress.update(format!("received {}…", megabytes(data.len())));
+            if self.size.is_some_and(|size| data.len() as u64 >= size) {
+                break;
+            }
+        }
+        if let Some(size) = self.size.filter(|&size| (data.len() as u64) < size) {
+            bail!("Only got {} of {size} bytes", data.len());
+        }
+        Ok(data)
+    }
+}
+
+/// Whether an address is somewhere out on the internet, and not our own network
+pub fn is_public(ip: IpAddr) -> bool {
+    // an IPv4 address written as IPv6 (like ::ffff:127.0.0.1) is checked as what it really is
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                // "this network", carrier-grade NAT, IETF protocol assignments, benchmarking,
+                // and the reserved block
+                || a == 0
+                || (a == 100 && b & 0xc0 == 64)
+                || (a == 192 && b == 0 && c == 0)
+                || (a == 198 && b & 0xfe == 18)
+                || a >= 240)
+        }
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                // unique local and link-local addresses
+                || first & 0xfe00 == 0xfc00
+                || first & 0xffc0 == 0xfe80)
+        }
+    }
+}
+
+#[test]
+fn test_parse_dcc_offer() {
+    assert_eq!(
+        DccOffer::parse("\x01DCC SEND song.ogg 3232235777 5000 1234\x01"),
+        Some(DccOffer {
+            filename: "song.ogg".to_string(),
+            addr: "192.168.1.1:5000".parse().unwrap(),
+            size: Some(1234),
+        })
+    );
+    assert_eq!(
+        DccOffer::parse("\x01DCC SEND \"voice note.m4a\" 2001:db8::1 6000\x01"),
+        Some(DccOffer {
+            filename: "voice note.m4a".to_string(),
+            addr: "[2001:db8::1]:6000".parse().unwrap(),
+            size: None,
+        })
+    );
+    assert_eq!(
+        DccOffer::parse("\x01DCC CHAT chat 3232235777 5000\x01"),
+        None
+    );
+    assert_eq!(DccOffer::parse("DCC SEND song.ogg 3232235777 5000"), None);
+
+    assert!(!is_public("192.168.1.1".parse().unwrap()));
+    assert!(!is_public("::1".parse().unwrap()));
+    for ip in [
+        "::ffff:127.0.0.1",
+        "::ffff:10.0.0.1",
+        "100.64.0.1",
+        "100.127.255.254",
+        "0.0.0.0",
+        "0.1.2.3",
+        "240.0.0.1",
+    ] {
+        assert!(!is_public(ip.parse().unwrap()), "{ip} is public");
+    }
+    assert!(is_public("100.128.0.1".parse().unwrap()));
+    assert!(is_public("::ffff:203.0.113.7".parse().unwrap()));
+    assert!(is_public("203.0.113.7".parse().unwrap()));
+}
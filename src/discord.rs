@@ -0,0 +1,479 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+use futures::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+use crate::{
+    config::DiscordConfig,
+    frontend::{fit_message_in_chunks, Frontend, IncomingMessage, Protocol},
+};
+
+const API: &str = "https://discord.com/api/v10";
+const GATEWAY: &str = "wss://gateway.discord.gg/?v=10&encoding=json";
+
+/// Discord won't take a message longer than this
+const MESSAGE_CHARS: usize = 2000;
+
+/// Most messages a reply is split into before the rest is uploaded instead
+const MAX_MESSAGES: usize = 3;
+
+/// GUILDS (for threads), GUILD_MESSAGES and MESSAGE_CONTENT
+const INTENTS: u64 = (1 << 0) | (1 << 9) | (1 << 15);
+
+/// How long to wait before reconnecting after the gateway drops us
+const RETRY_SECS: u64 = 10;
+
+/// Channels are named like this, so they look like IRC channels to the rest of the bot
+const CHANNEL_PREFIX: &str = "#discord-";
+
+/// Sends to Discord, through a queue so messages go out in order like they do on IRC
+#[derive(Clone)]
+pub struct DiscordSender {
+    queue: mpsc::UnboundedSender<(String, String)>,
+}
+
+impl DiscordSender {
+    fn enqueue(&self, target: &str, body: String) -> anyhow::Result<()> {
+        self.queue
+            .send((target.to_string(), body))
+            .map_err(|_| anyhow::anyhow!("The Discord connection is closed"))
+    }
+}
+
+impl Frontend for DiscordSender {
+    fn protocol(&self) -> Protocol {
+        Protocol::Discord
+    }
+    fn send_message(&self, target: &str, message: &str) -> anyhow::Result<()> {
+        self.enqueue(target, message.to_string())
+    }
+    fn send_notice(&self, target: &str, message: &str) -> anyhow::Result<()> {
+        self.enqueue(target, message.to_string())
+    }
+    fn send_action(&self, target: &str, action: &str) -> anyhow::Result<()> {
+        self.enqueue(target, format!("*{action}*"))
+    }
+    fn join(&self, _channel: &str) -> anyhow::Result<()> {
+        bail!("The bot is added to Discord servers with an invite link")
+    }
+    fn part(&self, _channel: &str) -> anyhow::Result<()> {
+        bail!("The bot is removed from Discord servers by their admins")
+    }
+    fn fit_message(&self, msg: &str) -> (Vec<String>, usize) {
+        fit_message_in_chunks(msg, MESSAGE_CHARS, MAX_MESSAGES)
+    }
+}
+
+struct Discord {
+    http: reqwest::Client,
+    token: String,
+    /// What to replace a mention of the bot with, so it looks like it was addressed on IRC
+    bot_name: String,
+    /// The bot's own user id, once the gateway has told us
+    user_id: Mutex<Option<String>>,
+    /// The channel each thread was started in
+    threads: Mutex<HashMap<String, String>>,
+}
+
+/// Whether a channel is on Discord
+pub fn is_discord_target(target: &str) -> bool {
+    target.starts_with(CHANNEL_PREFIX)
+}
+
+/// The id of the channel (or thread) a target names
+fn channel_id(target: &str) -> Option<&str> {
+    let name = target.strip_prefix(CHANNEL_PREFIX)?;
+    // threads are named after their parent channel, like #discord-<channel>.<thread>
+    name.rsplit('.').next()
+}
+
+/// Connects to the Discord gateway
+///
+/// Returns what sends to Discord, and the messages people send in channels the bot can see.
+/// `bot_name` is what mentions of the bot are turned into.
+pub async fn connect(
+    config: DiscordConfig,
+    bot_name: &str,
+) -> anyhow::Result<(DiscordSender, mpsc::UnboundedReceiver<IncomingMessage>)> {
+    let Some(token) = config.token.filter(|t| !t.is_empty()) else {
+        bail!("There's no Discord token");
+    };
+    let discord = Arc::new(Discord {
+        http: crate::http::client_builder()?
+            .timeout(Duration::from_secs(30))
+            .build()?,
+        token,
+        bot_name: bot_name.to_string(),
+        user_id: Mutex::new(None),
+        threads: Mutex::new(HashMap::new()),
+    });
+
+    let (queue, mut outgoing) = mpsc::unbounded_channel::<(String, String)>();
+    let (incoming, received) = mpsc::unbounded_channel();
+    {
+        let discord = discord.clone();
+        tokio::spawn(async move {
+            while let Some((target, body)) = outgoing.recv().await {
+                if let Err(e) = discord.send(&target, &body).await {
+                    println!("Failed to send to Discord: {e:#}");
+                }
+            }
+        });
+    }
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = discord.run_gateway(&incoming).await {
+                println!("Discord gateway disconnected: {e:#}");
+            }
+            if incoming.is_closed() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_secs(RETRY_SECS)).await;
+        }
+    });
+    Ok((DiscordSender { queue }, received))
+}
+
+impl Discord {
+    fn request(&self, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        self.http
+            .request(method, format!("{API}{path}"))
+            .header("Authorization", format!("Bot {}", self.token))
+    }
+
+    async fn send(&self, target: &str, body: &str) -> anyhow::Result<()> {
+        let Some(channel) = channel_id(target) else {
+            bail!("Can't send to {target}, since only channels are supported");
+        };
+        // pings in replies would be too easy to trick the model into, so nobody gets pinged
+        let payload = json!({"content": body, "allowed_mentions": {"parse": []}});
+        for _ in 0..3 {
+            let resp = self
+                .request(
+                    reqwest::Method::POST,
+                    &format!("/channels/{channel}/messages"),
+                )
+                .json(&payload)
+                .send()
+                .await?;
+            if resp.status() == reqwest::StatusCode::TOO_MANY_REQUESTS {
+                let wait: Value = resp.json().await?;
+                let secs = wait["retry_after"].as_f64().unwrap_or(1.0);
+                tokio::time::sleep(Duration::from_secs_f64(secs)).await;
+                continue;
+            }
+            resp.error_for_status()?;
+            return Ok(());
+        }
+        bail!("Still rate limited after retrying")
+    }
+
+    /// Registers the slash commands, which stand in for the `!` commands
+    async fn register_commands(&self, application_id: &str) -> anyhow::Result<()> {
+        let command = |name: &str, description: &str, option: &str, about: &str| {
+            json!({
+                "name": name,
+                "description": description,
+                "type": 1,
+                "options": [{"type": 3, "name": option, "description": about, "required": true}],
+            })
+        };
+        let commands = json!([
+            command("chat", "Ask the bot something", "prompt", "What to ask"),
+            command("img", "Generate an image", "prompt", "What to draw"),
+            command("tts", "Read something out loud", "text", "What to read"),
+        ]);
+        self.request(
+            reqwest::Method::PUT,
+            &format!("/applications/{application_id}/commands"),
+        )
+        .json(&commands)
+        .send()
+        .await?
+        .error_for_status()?;
+        Ok(())
+    }
+
+    /// The name the rest of the bot knows a channel by
+    fn target_name(&self, channel_id: &str) -> String {
+        let threads = self.threads.lock().expect("threads lock is poisoned");
+        match threads.get(channel_id) {
+            Some(parent) => format!("{CHANNEL_PREFIX}{parent}.{channel_id}"),
+            None => format!("{CHANNEL_PREFIX}{channel_id}"),
+        }
+    }
+
+    fn remember_thread(&self, thread: &Value) {
+        if let (Some(id), Some(parent)) = (thread["id"].as_str(), thread["parent_id"].as_str()) {
+            self.threads
+                .lock()
+                .expect("threads lock is poisoned")
+                .insert(id.to_string(), parent.to_string());
+        }
+    }
+
+    /// Stays connected to the gateway, passing messages along until it disconnects
+    async fn run_gateway(
+        &self,
+        incoming: &mpsc::UnboundedSender<IncomingMessage>,
+    ) -> anyhow::Result<()> {
+        let (ws, _) = tokio_tungstenite::connect_async(GATEWAY).await?;
+        let (mut write, mut read) = ws.split();
+
+        let hello: Value = match read.next().await.context("No hello from the gateway")?? {
+            WsMessage::Text(text) => serde_json::from_str(&text)?,
+            other => bail!("Unexpected hello from the gateway: {other:?}"),
+        };
+        let interval = hello
+            .pointer("/d/heartbeat_interval")
+            .and_then(|i| i.as_u64())
+            .context("No heartbeat interval in hello")?;
+        let identify = json!({
+            "op": 2,
+            "d": {
+                "token": self.token,
+                "intents": INTENTS,
+                "properties": {"os": "linux", "browser": "anna", "device": "anna"},
+            },
+        });
+        write.send(WsMessage::Text(identify.to_string())).await?;
+
+        let mut heartbeat = tokio::time::interval(Duration::from_millis(interval));
+        let mut seq: Option<u64> = None;
+        loop {
+            tokio::select! {
+                _ = heartbeat.tick() => {
+                    let beat = json!({"op": 1, "d": seq});
+                    write.send(WsMessage::Text(beat.to_string())).await?;
+                }
+                msg = read.next() => {
+                    let text = match msg.context("The gateway closed the connection")?? {
+                        WsMessage::Text(text) => text,
+                        WsMessage::Close(frame) => bail!("The gateway closed: {frame:?}"),
+                        _ => continue,
+                    };
+                    let event: Value = serde_json::from_str(&text)?;
+                    if let Some(s) = event["s"].as_u64() {
+                        seq = Some(s);
+                    }
+                    match event["op"].as_u64() {
+                        Some(0) => {
+                            let name = event["t"].as_str().unwrap_or_default();
+                            for message in self.dispatch(name, &event["d"]).await {
+                                if incoming.send(message).is_err() {
+                                    return Ok(());
+                                }
+                            }
+                        }
+                        Some(1) => {
+                            let beat = json!({"op": 1, "d": seq});
+                            write.send(WsMessage::Text(beat.to_string())).await?;
+                        }
+                        // reconnect, or invalid session
+                        Some(7) | Some(9) => bail!("The gateway asked us to reconnect"),
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+
+    /// Handles an event from the gateway, returning the messages it contains
+    async fn dispatch(&self, name: &str, data: &Value) -> Vec<IncomingMessage> {
+        match name {
+            "READY" => {
+                if let Some(user_id) = data.pointer("/user/id").and_then(|id| id.as_str()) {
+                    *self.user_id.lock().expect("user id lock is poisoned") =
+                        Some(user_id.to_string());
+                }
+                if let Some(application_id) =
+                    data.pointer("/application/id").and_then(|id| id.as_str())
+                {
+                    if let Err(e) = self.register_commands(application_id).await {
+                        println!("Failed to register Discord commands: {e:#}");
+                    }
+                }
+                Vec::new()
+            }
+            "GUILD_CREATE" => {
+                for thread in data["threads"].as_array().into_iter().flatten() {
+                    self.remember_thread(thread);
+                }
+                Vec::new()
+            }
+            "THREAD_CREATE" | "THREAD_UPDATE" => {
+                self.remember_thread(data);
+                Vec::new()
+            }
+            "MESSAGE_CREATE" => {
+                let user_id = self
+                    .user_id
+                    .lock()
+                    .expect("user id lock is poisoned")
+                    .clone();
+                let Some(channel) = data["channel_id"].as_str() else {
+                    return Vec::new();
+                };
+                let target = self.target_name(channel);
+                parse_message(&target, user_id.as_deref(), &self.bot_name, data)
+                    .into_iter()
+                    .collect()
+            }
+            "INTERACTION_CREATE" => match self.interaction(data).await {
+                Ok(message) => message.into_iter().collect(),
+                Err(e) => {
+                    println!("Failed to handle a Discord command: {e:#}");
+                    Vec::new()
+                }
+            },
+            _ => Vec::new(),
+        }
+    }
+
+    /// Turns a slash command into the `!` command it stands for, after letting Discord know
+    /// it's been received
+    async fn interaction(&self, data: &Value) -> anyhow::Result<Option<IncomingMessage>> {
+        // only slash commands
+        if data["type"] != 2 {
+            return Ok(None);
+        }
+        let user = data
+            .pointer("/member/user")
+            .or_else(|| data.get("user"))
+            .context("No user in interaction")?;
+        let (Some(id), Some(token), Some(channel)) = (
+            data["id"].as_str(),
+            data["token"].as_str(),
+            data["channel_id"].as_str(),
+        ) else {
+            bail!("Missing id, token or channel in interaction");
+        };
+        let Some(line) = slash_command(&data["data"]) else {
+            return Ok(None);
+        };
+        // the id is what tells people apart, so without one there's no telling who asked
+        let user_id = user["id"].as_str().context("No user id in interaction")?;
+        let nick = user["username"].as_str().unwrap_or("someone");
+        // the reply comes later as a normal message, so this just shows what was asked (without
+        // pinging anyone the prompt happens to mention)
+        let echo = json!({
+            "type": 4,
+            "data": {"content": format!("{nick}: {line}"), "allowed_mentions": {"parse": []}},
+        });
+        self.request(
+            reqwest::Method::POST,
+            &format!("/interactions/{id}/{token}/callback"),
+        )
+        .json(&echo)
+        .send()
+        .await?
+        .error_for_status()?;
+        Ok(Some(IncomingMessage {
+            target: self.target_name(channel),
+            nick: nick.to_string(),
+            user_id: format!("discord:{user_id}"),
+            text: line,
+        }))
+    }
+}
+
+/// The `!` command a slash command stands for, like `!chat what's up?` for `/chat`
+fn slash_command(data: &Value) -> Option<String> {
+    let name = data["name"].as_str()?;
+    if !matches!(name, "chat" | "img" | "tts") {
+        return None;
+    }
+    let arg = data["options"]
+        .as_array()?
+        .first()?
+        .get("value")?
+        .as_str()?;
+    Some(format!("!{name} {arg}"))
+}
+
+/// Turns a message event into a message, if it's from a person in a server
+fn parse_message(
+    target: &str,
+    own_user_id: Option<&str>,
+    bot_name: &str,
+    data: &Value,
+) -> Option<IncomingMessage> {
+    let author = &data["author"];
+    // other bots (and the bot itself) aren't answered, and neither are DMs
+    if author["bot"].as_bool().unwrap_or(false) || data.get("guild_id").is_none() {
+        return None;
+    }
+    let mut text = data["content"].as_str().unwrap_or_default().to_string();
+    if let Some(own) = own_user_id {
+        for mention in [format!("<@{own}>"), format!("<@!{own}>")] {
+            if let Some(rest) = text.strip_prefix(&mention) {
+                text = format!("{bot_name}:{rest}");
+            }
+        }
+    }
+    // attached images are passed as links, which get picked up for vision like any other link
+    for attachment in data["attachments"].as_array().into_iter().flatten() {
+        let is_image = attachment["content_type"]
+            .as_str()
+            .is_some_and(|t| t.starts_with("image/"));
+        if let (true, Some(url)) = (is_image, attachment["url"].as_str()) {
+            text.push(' ');
+            text.push_str(url);
+        }
+    }
+    let text = text.trim().to_string();
+    if text.is_empty() {
+        return None;
+    }
+    Some(IncomingMessage {
+        target: target.to_string(),
+        // anyone can pick any username, so it's only for addressing them; consent and
+        // preferences go by the id
+        nick: author["username"].as_str()?.to_string(),
+        user_id: format!("discord:{}", author["id"].as_str()?),
+        text,
+    })
+}
+
+#[test]
+fn test_parse_message() {
+    let data = json!({
+        "channel_id": "456",
+        "guild_id": "1",
+        "content": "<@99> what's in this picture?",
+        "author": {"id": "7", "username": "achin"},
+        "attachments": [
+            {"url": "https://cdn.discordapp.com/a/cat.png", "content_type": "image/png"},
+            {"url": "https://cdn.discordapp.com/a/notes.txt", "content_type": "text/plain"},
+        ],
+    });
+    let message = parse_message("#discord-123.456", Some("99"), "Charbot9000", &data).unwrap();
+    assert_eq!(message.target, "#discord-123.456");
+    assert_eq!(message.nick, "achin");
+    assert_eq!(message.user_id, "discord:7");
+    assert_eq!(
+        message.text,
+        "Charbot9000: what's in this picture? https://cdn.discordapp.com/a/cat.png"
+    );
+
+    let bot = json!({
+        "guild_id": "1",
+        "content": "hi",
+        "author": {"id": "99", "username": "Charbot9000", "bot": true},
+    });
+    assert!(parse_message("#discord-456", Some("99"), "Charbot9000", &bot).is_none());
+
+    assert_eq!(channel_id("#discord-123.456"), Some("456"));
+    assert_eq!(channel_id("#discord-123"), Some("123"));
+    assert_eq!(channel_id("##em32"), None);
+
+    let command = json!({"name": "chat", "options": [{"name": "prompt", "value": "hi there"}]});
+    assert_eq!(slash_command(&command).unwrap(), "!chat hi there");
+}
@@ -0,0 +1,221 @@
+use std::{io::Read, net::IpAddr, time::Duration};
+
+use anyhow::{bail, Context};
+
+use crate::{dcc, get_prompt, summarize_long_text};
+
+/// Largest document that will be downloaded
+const MAX_DOCUMENT_BYTES: usize = 20 * 1024 * 1024;
+
+/// Most text a .docx is allowed to unzip to, since a small zip can hold a lot
+const MAX_DOCX_XML_BYTES: u64 = 50 * 1024 * 1024;
+
+/// Most files a .docx can have in it
+const MAX_DOCX_ENTRIES: usize = 10_000;
+
+const DOCX_CONTENT_TYPE: &str =
+    "application/vnd.openxmlformats-officedocument.wordprocessingml.document";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DocumentKind {
+    Pdf,
+    Docx,
+    Text,
+}
+
+impl DocumentKind {
+    /// Works out what kind of document this is, from its content type or (failing that) the URL
+    fn detect(content_type: &str, url: &str) -> Option<Self> {
+        let path = url
+            .split(['?', '#'])
+            .next()
+            .unwrap_or(url)
+            .to_ascii_lowercase();
+        if content_type.starts_with("application/pdf") || path.ends_with(".pdf") {
+            Some(Self::Pdf)
+        } else if content_type.starts_with(DOCX_CONTENT_TYPE) || path.ends_with(".docx") {
+            Some(Self::Docx)
+        } else if content_type.starts_with("text/plain") || path.ends_with(".txt") {
+            Some(Self::Text)
+        } else {
+            None
+        }
+    }
+}
+
+/// Pulls the text out of the main part of a .docx file, one line per paragraph
+fn docx_xml_to_text(xml: &str) -> String {
+    let mut text = String::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find('<') {
+        text.push_str(&rest[..start]);
+        let Some(end) = rest[start..].find('>') else {
+            break;
+        };
+        let tag = &rest[start..start + end + 1];
+        if tag == "</w:p>" || tag.starts_with("<w:br") {
+            text.push('\n');
+        } else if tag.starts_with("<w:tab") {
+            text.push('\t');
+        }
+        rest = &rest[start + end + 1..];
+    }
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+fn docx_to_text(data: &[u8]) -> anyhow::Result<String> {
+    let mut archive = zip::ZipArchive::new(std::io::Cursor::new(data))?;
+    if archive.len() > MAX_DOCX_ENTRIES {
+        bail!("That Word document has too many parts");
+    }
+    let mut xml = String::new();
+    archive
+        .by_name("word/document.xml")
+        .context("Not a Word document")?
+        .take(MAX_DOCX_XML_BYTES + 1)
+        .read_to_string(&mut xml)?;
+    if xml.len() as u64 > MAX_DOCX_XML_BYTES {
+        bail!("That Word document is too large");
+    }
+    Ok(docx_xml_to_text(&xml))
+}
+
+/// Whether a link can be followed to a document, which it can't if it's to the bot's own network
+/// (or isn't a web link at all)
+async fn check_url(url: &url::Url) -> anyhow::Result<()> {
+    if !matches!(url.scheme(), "http" | "https") {
+        bail!("I can only read documents from http(s) links");
+    }
+    let addrs: Vec<IpAddr> = match url.host().context("That link doesn't have a host")? {
+        url::Host::Ipv4(ip) => vec![ip.into()],
+        url::Host::Ipv6(ip) => vec![ip.into()],
+        url::Host::Domain(host) => {
+            let port = url.port_or_known_default().unwrap_or(443);
+            tokio::net::lookup_host((host, port))
+                .await?
+                .map(|addr| addr.ip())
+                .collect()
+        }
+    };
+    if addrs.is_empty() || !addrs.into_iter().all(dcc::is_public) {
+        bail!("I can only read documents from public hosts");
+    }
+    Ok(())
+}
+
+/// Downloads a document and extracts its text
+pub async fn fetch_text(url: &str) -> anyhow::Result<String> {
+    check_url(&url::Url::parse(url)?).await?;
+    let client = crate::http::client_builder()?
+        .connect_timeout(Duration::from_secs(2))
+        .timeout(Duration::from_secs(60))
+        .user_agent("anna/1.0.0")
+        // hosts can't be looked up from here, so redirects to a named host are only checked
+        // for their scheme
+        .redirect(reqwest::redirect::Policy::custom(|attempt| {
+            let allowed = matches!(attempt.url().scheme(), "http" | "https")
+                && match attempt.url().host() {
+                    Some(url::Host::Ipv4(ip)) => dcc::is_public(ip.into()),
+                    Some(url::Host::Ipv6(ip)) => dcc::is_public(ip.into()),
+                    Some(url::Host::Domain(host)) => !host.eq_ignore_ascii_case("localhost"),
+                    None => false,
+                };
+            if !allowed {
+                attempt.error("Redirected somewhere that isn't a public web link")
+            } else if attempt.previous().len() >= 10 {
+                attempt.error("Too many redirects")
+            } else {
+                attempt.follow()
+            }
+        }))
+        .build()?;
+    let resp = client.get(url).send().await?.error_for_status()?;
+    let content_type = resp
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|ct| ct.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    let Some(kind) = DocumentKind::detect(&content_type, url) else {
+        bail!("I can only read PDF, Word (.docx) and plain text documents");
+    };
+    if matches!(resp.content_length(), Some(len) if len as usize > MAX_DOCUMENT_BYTES) {
+        bail!("Document is too large");
+    }
+    let data = resp.bytes().await?;
+    if data.len() > MAX_DOCUMENT_BYTES {
+        bail!("Document is too large");
+    }
+
+    // extracting text from a big PDF can take a while, so keep it off the async threads
+    let text = tokio::task::spawn_blocking(move || match kind {
+        DocumentKind::Pdf => {
+            pdf_extract::extract_text_from_mem(&data).context("Failed to read PDF")
+        }
+        DocumentKind::Docx => docx_to_text(&data),
+        DocumentKind::Text => Ok(String::from_utf8_lossy(&data).into_owned()),
+    })
+    .await??;
+
+    if text.trim().is_empty() {
+        bail!("Couldn't find any text in that document (is it a scan?)");
+    }
+    Ok(text)
+}
+
+/// Answers a question about a document, or summarizes it if there's no question
+pub async fn ask(url: &str, question: Option<&str>) -> anyhow::Result<String> {
+    let text = fetch_text(url).await?;
+    let instruction = match question {
+        Some(question) => format!(
+            "Using only the document below, answer this question briefly: {question}\n\
+             If the document doesn't say, then say so."
+        ),
+        None => get_prompt("doc").unwrap_or_else(|_| {
+            "Summarize the document below in a few sentences.  Keep it brief.".to_string()
+        }),
+    };
+    summarize_long_text(&text, &instruction).await
+}
+
+#[test]
+fn test_document_kind() {
+    assert_eq!(
+        DocumentKind::detect("application/pdf", "https://example.com/x"),
+        Some(DocumentKind::Pdf)
+    );
+    assert_eq!(
+        DocumentKind::detect("application/octet-stream", "https://example.com/x.PDF?dl=1"),
+        Some(DocumentKind::Pdf)
+    );
+    assert_eq!(
+        DocumentKind::detect(DOCX_CONTENT_TYPE, "https://example.com/x"),
+        Some(DocumentKind::Docx)
+    );
+    assert_eq!(
+        DocumentKind::detect("text/html", "https://example.com/"),
+        None
+    );
+}
+
+#[test]
+fn test_docx_xml_to_text() {
+    let xml = r#"<?xml version="1.0"?><w:document><w:body><w:p><w:r><w:t>Fish &amp; chips</w:t></w:r></w:p><w:p><w:r><w:t xml:space="preserve">cost </w:t></w:r><w:r><w:t>&lt;$5</w:t></w:r></w:p></w:body></w:document>"#;
+    assert_eq!(docx_xml_to_text(xml), "Fish & chips\ncost <$5\n");
+}
+
+#[tokio::test]
+async fn test_check_url() {
+    let check = |url: &str| {
+        let url = url::Url::parse(url).unwrap();
+        async move { check_url(&url).await }
+    };
+    assert!(check("file:///etc/passwd").await.is_err());
+    assert!(check("http://127.0.0.1/report.pdf").await.is_err());
+    assert!(check("https://[::ffff:10.0.0.1]/report.pdf").await.is_err());
+    assert!(check("https://93.184.215.14/report.pdf").await.is_ok());
+}
@@ -0,0 +1,203 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use anyhow::Context;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{openai, ChatMessageThing};
+
+pub const EMBEDDING_MODEL: &str = "text-embedding-3-small";
+
+/// How many texts are sent in a single embeddings request
+const BATCH_SIZE: usize = 100;
+
+/// How similar a question has to be to an old message before we say it was discussed already
+const PAST_DISCUSSION_THRESHOLD: f32 = 0.88;
+
+/// Messages this close in time to an old message are shown along with it
+const SEGMENT_MINUTES: i64 = 15;
+
+type Store = Arc<Vec<EmbeddedMessage>>;
+
+/// Embedded histories that have been loaded, along with when their file was last changed
+static STORES: Mutex<Option<HashMap<String, (SystemTime, Store)>>> = Mutex::new(None);
+
+/// A message from the channel history, along with its embedding
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmbeddedMessage {
+    pub date: DateTime<Utc>,
+    pub text: String,
+    pub embedding: Vec<f32>,
+}
+
+/// Computes embeddings for every message in a channel history that has some text
+pub async fn embed_history<'a>(
+    messages: impl IntoIterator<Item = &'a ChatMessageThing>,
+) -> anyhow::Result<Vec<EmbeddedMessage>> {
+    let texts: Vec<(DateTime<Utc>, String)> = messages
+        .into_iter()
+        .filter_map(|cmt| Some((cmt.date, cmt.get_as_irc_format()?.to_string())))
+        .filter(|(_, text)| !text.trim().is_empty())
+        .collect();
+
+    let mut embedded = Vec::with_capacity(texts.len());
+    for batch in texts.chunks(BATCH_SIZE) {
+        let embeddings =
+            openai::get_embeddings(batch.iter().map(|(_, text)| text.clone()).collect()).await?;
+        for ((date, text), embedding) in batch.iter().zip(embeddings) {
+            embedded.push(EmbeddedMessage {
+                date: *date,
+                text: text.clone(),
+                embedding,
+            });
+        }
+    }
+    Ok(embedded)
+}
+
+/// Gets the embedding for a single bit of text
+pub async fn embed(text: &str) -> anyhow::Result<Vec<f32>> {
+    openai::get_embeddings(vec![text.to_string()])
+        .await?
+        .pop()
+        .context("No embedding returned")
+}
+
+/// Where `anna embed-history` saves the embeddings for a channel
+pub fn store_path(channel: &str) -> String {
+    format!("{channel}.embeddings.json")
+}
+
+/// Gets the embedded history for a channel, only reading the file again when it's changed
+///
+/// Returns None if the channel's history was never embedded.
+pub fn load_store(channel: &str) -> anyhow::Result<Option<Store>> {
+    let path = store_path(channel);
+    let Ok(metadata) = std::fs::metadata(&path) else {
+        return Ok(None);
+    };
+    let modified = metadata.modified()?;
+    let mut stores = STORES.lock().expect("embedding stores lock is poisoned");
+    let stores = stores.get_or_insert_with(HashMap::new);
+    if let Some((loaded, store)) = stores.get(&path) {
+        if *loaded == modified {
+            return Ok(Some(store.clone()));
+        }
+    }
+    let store = Arc::new(load(&path)?);
+    stores.insert(path, (modified, store.clone()));
+    Ok(Some(store))
+}
+
+/// An earlier bit of conversation that resembles something new
+#[derive(Debug)]
+pub struct PastDiscussion<'a> {
+    /// The message that's most like the new one
+    pub matched: &'a EmbeddedMessage,
+    /// The messages around it, including itself
+    pub segment: &'a [EmbeddedMessage],
+}
+
+/// Looks for an old message (from before `before`) that's strongly similar to a new one
+///
+/// `store` is expected to be in order by date, like [embed_history] makes it.
+pub fn find_past_discussion<'a>(
+    store: &'a [EmbeddedMessage],
+    embedding: &[f32],
+    before: DateTime<Utc>,
+) -> Option<PastDiscussion<'a>> {
+    let (idx, _) = store
+        .iter()
+        .enumerate()
+        .filter(|(_, m)| m.date < before)
+        .map(|(idx, m)| (idx, cosine_similarity(&m.embedding, embedding)))
+        .filter(|(_, similarity)| *similarity >= PAST_DISCUSSION_THRESHOLD)
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))?;
+    let matched = &store[idx];
+    let window = Duration::minutes(SEGMENT_MINUTES);
+    let near =
+        |m: &EmbeddedMessage| (matched.date - window..=matched.date + window).contains(&m.date);
+    let start = store[..idx]
+        .iter()
+        .rposition(|m| !near(m))
+        .map_or(0, |i| i + 1);
+    let end = store[idx..]
+        .iter()
+        .position(|m| !near(m))
+        .map_or(store.len(), |i| idx + i);
+    Some(PastDiscussion {
+        matched,
+        segment: &store[start..end],
+    })
+}
+
+pub fn save(path: impl AsRef<Path>, embedded: &[EmbeddedMessage]) -> anyhow::Result<()> {
+    let output = File::create(path)?;
+    serde_json::to_writer(output, embedded)?;
+    Ok(())
+}
+
+pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Vec<EmbeddedMessage>> {
+    let input = File::open(path)?;
+    Ok(serde_json::from_reader(input)?)
+}
+
+/// Cosine similarity of two embeddings, between -1 and 1
+///
+/// Returns 0 if either of them is all zeros (or if they're different lengths)
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() {
+        return 0.0;
+    }
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot / (norm_a * norm_b)
+}
+
+#[test]
+fn test_find_past_discussion() {
+    let start = Utc::now() - Duration::days(30);
+    let message = |minutes: i64, text: &str, embedding: Vec<f32>| EmbeddedMessage {
+        date: start + Duration::minutes(minutes),
+        text: text.to_string(),
+        embedding,
+    };
+    let store = vec![
+        message(0, "unrelated", vec![0.0, 1.0]),
+        message(60, "how do I render the nether?", vec![0.0, 1.0]),
+        message(61, "with --dimension", vec![1.0, 0.0]),
+        message(70, "thanks", vec![0.0, 1.0]),
+        message(200, "later on", vec![0.0, 1.0]),
+    ];
+
+    let past = find_past_discussion(&store, &[0.99, 0.01], Utc::now()).unwrap();
+    assert_eq!(past.matched.text, "with --dimension");
+    let segment: Vec<_> = past.segment.iter().map(|m| m.text.as_str()).collect();
+    assert_eq!(
+        segment,
+        ["how do I render the nether?", "with --dimension", "thanks"]
+    );
+
+    assert!(find_past_discussion(&store, &[0.7, 0.7], Utc::now()).is_none());
+    // too recent to count
+    assert!(find_past_discussion(&store, &[0.99, 0.01], start).is_none());
+}
+
+#[test]
+fn test_cosine_similarity() {
+    assert!((cosine_similarity(&[1.0, 0.0], &[2.0, 0.0]) - 1.0).abs() < 1e-6);
+    assert!(cosine_similarity(&[1.0, 0.0], &[0.0, 3.0]).abs() < 1e-6);
+    assert!((cosine_similarity(&[1.0, 1.0], &[-1.0, -1.0]) + 1.0).abs() < 1e-6);
+    assert_eq!(cosine_similarity(&[0.0, 0.0], &[1.0, 1.0]), 0.0);
+    assert_eq!(cosine_similarity(&[1.0], &[1.0, 1.0]), 0.0);
+}
@@ -0,0 +1,107 @@
+use std::sync::OnceLock;
+
+use regex::Regex;
+
+/// Added to every offline reply, so nobody mistakes it for the bot's usual self
+pub const RESUME_MARKER: &str = "(offline mode, normal service resumes once the backend is back)";
+
+/// Commands that can't do anything without the chat model
+///
+/// Images, speech and transcription go to their own APIs, which the chat backend being down
+/// doesn't say anything about.
+const NEEDS_MODEL: &[&str] = &["!imagine", "!describe", "!yt-summary", "!doc"];
+
+/// How to answer a question while the model is unavailable
+#[derive(Debug, Clone, PartialEq)]
+pub enum Fallback {
+    /// Something numbat can work out
+    Calc(String),
+    /// A question about the weather somewhere
+    Weather(String),
+    /// Nothing we can answer, so here's something to say instead
+    Canned(&'static str),
+}
+
+/// Whether a command needs the chat model
+pub fn needs_model(msg: &str) -> bool {
+    let command = msg.split_whitespace().next().unwrap_or_default();
+    NEEDS_MODEL.contains(&command)
+}
+
+fn weather_regex() -> &'static Regex {
+    static REGEX: OnceLock<Regex> = OnceLock::new();
+    REGEX.get_or_init(|| {
+        Regex::new(r"(?i)\bweather\b.*?\b(?:in|for|at)\s+(?P<location>[^?!.]+)")
+            .expect("the weather regex is valid")
+    })
+}
+
+fn calc_regex() -> &'static Regex {
+    static REGEX: OnceLock<Regex> = OnceLock::new();
+    REGEX.get_or_init(|| {
+        Regex::new(r"^[\w\s.,+\-*/^()%°>]*\d[\w\s.,+\-*/^()%°>]*$")
+            .expect("the calc regex is valid")
+    })
+}
+
+/// Works out what can be said in reply to a question, without the model
+pub fn respond(question: &str) -> Fallback {
+    let question = question.trim();
+    if let Some(caps) = weather_regex().captures(question) {
+        return Fallback::Weather(caps["location"].trim().to_string());
+    }
+
+    let lower = question.to_lowercase();
+    let expr = ["what is ", "what's ", "calculate ", "compute ", "convert "]
+        .iter()
+        .find_map(|prefix| lower.strip_prefix(prefix))
+        .unwrap_or(&lower)
+        .trim_end_matches(['?', '!', '.', ' ']);
+    let has_operator = expr.contains(['+', '-', '*', '/', '^', '%']) || expr.contains(" to ");
+    if has_operator && calc_regex().is_match(expr) {
+        return Fallback::Calc(expr.to_string());
+    }
+
+    let first = lower
+        .split(|c: char| !c.is_alphanumeric())
+        .next()
+        .unwrap_or_default();
+    match first {
+        "hi" | "hello" | "hey" | "yo" => Fallback::Canned("Hello!  I can't chat much right now"),
+        "thanks" | "thank" | "ty" => Fallback::Canned("You're welcome!"),
+        _ => Fallback::Canned("I can't answer that right now, but !calc and !weather still work"),
+    }
+}
+
+#[test]
+fn test_respond() {
+    assert_eq!(
+        respond("what's the weather like in Springfield, IL?"),
+        Fallback::Weather("Springfield, IL".to_string())
+    );
+    assert_eq!(
+        respond("What is 3 * (4 + 5)?"),
+        Fallback::Calc("3 * (4 + 5)".to_string())
+    );
+    assert_eq!(
+        respond("convert 5 km to miles"),
+        Fallback::Calc("5 km to miles".to_string())
+    );
+    assert_eq!(
+        respond("hi there"),
+        Fallback::Canned("Hello!  I can't chat much right now")
+    );
+    assert!(matches!(
+        respond("write me a poem about the sea"),
+        Fallback::Canned(_)
+    ));
+    assert!(matches!(
+        respond("what's a good-looking hat?"),
+        Fallback::Canned(_)
+    ));
+
+    assert!(needs_model("!describe https://example.com/cat.png"));
+    assert!(!needs_model("!img a cat"));
+    assert!(!needs_model("!transcribe https://example.com/talk.ogg"));
+    assert!(!needs_model("!calc 1+1"));
+}
@@ -0,0 +1,101 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::embeddings::{cosine_similarity, embed};
+
+/// How similar a message has to be to a question before it gets the canned answer
+pub const MATCH_THRESHOLD: f32 = 0.85;
+
+/// Minimum time between two answers to the same question, so a discussion about it isn't
+/// interrupted over and over
+const COOLDOWN_MINUTES: i64 = 30;
+
+/// A question that comes up often enough to have a canonical answer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FaqEntry {
+    pub question: String,
+    pub answer: String,
+    /// Embedding of the question
+    pub embedding: Vec<f32>,
+    #[serde(default)]
+    pub last_answered: Option<DateTime<Utc>>,
+}
+
+impl FaqEntry {
+    pub async fn new(question: &str, answer: &str) -> anyhow::Result<Self> {
+        let embedding = embed(question).await?;
+        Ok(Self {
+            question: question.to_string(),
+            answer: answer.to_string(),
+            embedding,
+            last_answered: None,
+        })
+    }
+
+    fn is_cooling_down(&self, now: DateTime<Utc>) -> bool {
+        matches!(self.last_answered, Some(last) if now - last < Duration::minutes(COOLDOWN_MINUTES))
+    }
+}
+
+/// Splits "<question> :: <answer>"
+pub fn parse_entry(s: &str) -> Option<(&str, &str)> {
+    let (question, answer) = s.split_once("::")?;
+    let (question, answer) = (question.trim(), answer.trim());
+    (!question.is_empty() && !answer.is_empty()).then_some((question, answer))
+}
+
+/// Finds the entry whose question is closest to a message, if it's close enough and hasn't been
+/// answered recently, and starts its cooldown
+pub fn answer<'a>(
+    entries: &'a mut [FaqEntry],
+    embedding: &[f32],
+    now: DateTime<Utc>,
+) -> Option<&'a str> {
+    let entry = entries
+        .iter_mut()
+        .map(|entry| (cosine_similarity(&entry.embedding, embedding), entry))
+        .filter(|(similarity, _)| *similarity >= MATCH_THRESHOLD)
+        .max_by(|(a, _), (b, _)| a.total_cmp(b))
+        .map(|(_, entry)| entry)
+        .filter(|entry| !entry.is_cooling_down(now))?;
+    entry.last_answered = Some(now);
+    Some(&entry.answer)
+}
+
+#[test]
+fn test_faq_answer() {
+    assert_eq!(
+        parse_entry("how do I render the nether? :: Use --dimension nether"),
+        Some(("how do I render the nether?", "Use --dimension nether"))
+    );
+    assert_eq!(parse_entry("no answer here ::  "), None);
+    assert_eq!(parse_entry("no separator"), None);
+
+    let entry = |question: &str, embedding: Vec<f32>| FaqEntry {
+        question: question.to_string(),
+        answer: format!("answer to {question}"),
+        embedding,
+        last_answered: None,
+    };
+    let mut entries = vec![
+        entry("nether", vec![1.0, 0.0]),
+        entry("end", vec![0.6, 0.8]),
+    ];
+    let now = Utc::now();
+
+    assert_eq!(answer(&mut entries, &[0.0, 1.0], now), None);
+    assert_eq!(
+        answer(&mut entries, &[0.95, 0.05], now),
+        Some("answer to nether")
+    );
+    // the closest question has just been answered, so don't fall back to a worse match
+    assert_eq!(answer(&mut entries, &[0.95, 0.05], now), None);
+    assert_eq!(
+        answer(
+            &mut entries,
+            &[0.95, 0.05],
+            now + Duration::minutes(COOLDOWN_MINUTES)
+        ),
+        Some("answer to nether")
+    );
+}
@@ -0,0 +1,135 @@
+use std::collections::BTreeSet;
+
+use serde::{Deserialize, Serialize};
+
+/// Something the bot does in a channel that the channel can turn off
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Feature {
+    /// Speaking up in a conversation without being asked
+    Interject,
+    /// Remembering messages from people who opted in, even when they aren't talking to the bot
+    Capture,
+    /// Announcing the titles of links that are posted
+    UrlTitles,
+    /// Drawing a picture of what the channel has been talking about
+    ImageDay,
+    /// The calculator and code runner (`!calc` and `!run`)
+    Tools,
+    /// Trivia games (`!trivia`)
+    Trivia,
+}
+
+impl Feature {
+    pub const ALL: &'static [Feature] = &[
+        Feature::Interject,
+        Feature::Capture,
+        Feature::UrlTitles,
+        Feature::ImageDay,
+        Feature::Tools,
+        Feature::Trivia,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Feature::Interject => "interject",
+            Feature::Capture => "capture",
+            Feature::UrlTitles => "urltitles",
+            Feature::ImageDay => "imageday",
+            Feature::Tools => "tools",
+            Feature::Trivia => "trivia",
+        }
+    }
+}
+
+impl std::str::FromStr for Feature {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        Feature::ALL
+            .iter()
+            .copied()
+            .find(|f| f.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| {
+                let names: Vec<_> = Feature::ALL.iter().map(|f| f.name()).collect();
+                anyhow::anyhow!("Unknown feature {s:?} (try {})", names.join(", "))
+            })
+    }
+}
+
+/// Which features are turned off in a channel
+///
+/// Everything is on until someone turns it off.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FeatureSwitches {
+    disabled: BTreeSet<Feature>,
+}
+
+impl FeatureSwitches {
+    pub fn is_enabled(&self, feature: Feature) -> bool {
+        !self.disabled.contains(&feature)
+    }
+
+    /// Handles "enable <feature>" or "disable <feature>"
+    pub fn update(&mut self, cmd: &str) -> anyhow::Result<()> {
+        let mut words = cmd.split_ascii_whitespace();
+        let enable = match words.next() {
+            Some("enable" | "on") => true,
+            Some("disable" | "off") => false,
+            _ => anyhow::bail!("Usage: !feature [enable|disable <feature>]"),
+        };
+        let feature: Feature = words
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("Which feature?"))?
+            .parse()?;
+        if enable {
+            self.disabled.remove(&feature);
+        } else {
+            self.disabled.insert(feature);
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for FeatureSwitches {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let states: Vec<String> = Feature::ALL
+            .iter()
+            .map(|&feature| {
+                let state = if self.is_enabled(feature) {
+                    "on"
+                } else {
+                    "off"
+                };
+                format!("{}={state}", feature.name())
+            })
+            .collect();
+        write!(f, "{}", states.join(" "))
+    }
+}
+
+#[test]
+fn test_feature_switches() {
+    let mut switches = FeatureSwitches::default();
+    assert!(switches.is_enabled(Feature::Interject));
+
+    switches.update("disable interject").unwrap();
+    switches.update("disable URLTitles").unwrap();
+    assert!(!switches.is_enabled(Feature::Interject));
+    assert!(!switches.is_enabled(Feature::UrlTitles));
+    assert_eq!(
+        switches.to_string(),
+        "interject=off capture=on urltitles=off imageday=on tools=on trivia=on"
+    );
+
+    switches.update("enable interject").unwrap();
+    assert!(switches.is_enabled(Feature::Interject));
+    assert!(switches.update("disable teleport").is_err());
+    assert!(switches.update("toggle tools").is_err());
+    assert!(switches.update("disable").is_err());
+
+    // only the disabled features are saved
+    let json = serde_json::to_string(&switches).unwrap();
+    assert_eq!(json, r#"{"disabled":["urltitles"]}"#);
+}
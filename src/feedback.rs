@@ -0,0 +1,165 @@
+use std::{
+    collections::VecDeque,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::Path,
+    sync::Mutex,
+};
+
+use async_openai::types::ChatCompletionRequestMessage;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::ChatMessageThing;
+
+/// Every vote is also appended here, since the conversation itself is trimmed after a while
+const FEEDBACK_PATH: &str = "feedback.jsonl";
+
+static FEEDBACK_LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Vote {
+    Good,
+    Bad,
+}
+
+/// Someone's opinion of one of the bot's replies, from `!good` or `!bad`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Feedback {
+    pub nick: String,
+    pub date: DateTime<Utc>,
+    pub vote: Vote,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// A vote, along with the exchange it was about
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FeedbackRecord {
+    pub channel: String,
+    /// The last thing someone said before the reply
+    pub question: Option<String>,
+    pub reply: String,
+    /// The model that wrote the reply
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(flatten)]
+    pub feedback: Feedback,
+}
+
+/// Tags the bot's most recent reply with some feedback
+///
+/// A second vote from the same nick replaces their first one.  Returns None if there's no reply
+/// to vote on.
+pub fn tag_last_reply(
+    channel: &str,
+    messages: &mut VecDeque<ChatMessageThing>,
+    feedback: Feedback,
+) -> Option<FeedbackRecord> {
+    let idx = messages.iter().rposition(|cmt| {
+        matches!(cmt.msg, ChatCompletionRequestMessage::Assistant(_))
+            && cmt.get_as_irc_format().is_some()
+    })?;
+    let question = messages
+        .range(..idx)
+        .rev()
+        .find(|cmt| matches!(cmt.msg, ChatCompletionRequestMessage::User(_)))
+        .and_then(|cmt| cmt.get_as_irc_format())
+        .map(|s| s.to_string());
+
+    let reply = &mut messages[idx];
+    reply
+        .feedback
+        .retain(|f| !f.nick.eq_ignore_ascii_case(&feedback.nick));
+    reply.feedback.push(feedback.clone());
+    Some(FeedbackRecord {
+        channel: channel.to_string(),
+        question,
+        reply: reply.get_as_irc_format().unwrap_or_default().to_string(),
+        model: reply.model.clone(),
+        feedback,
+    })
+}
+
+/// Adds a vote to the feedback log
+pub fn append(record: &FeedbackRecord) -> anyhow::Result<()> {
+    let _lock = FEEDBACK_LOCK.lock().expect("feedback lock is poisoned");
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(FEEDBACK_PATH)?;
+    writeln!(file, "{}", serde_json::to_string(record)?)?;
+    Ok(())
+}
+
+/// Reads back every vote in the feedback log, optionally only those from one channel
+pub fn load(channel: Option<&str>) -> anyhow::Result<Vec<FeedbackRecord>> {
+    let _lock = FEEDBACK_LOCK.lock().expect("feedback lock is poisoned");
+    if !Path::new(FEEDBACK_PATH).exists() {
+        return Ok(Vec::new());
+    }
+    let mut records = Vec::new();
+    for line in BufReader::new(File::open(FEEDBACK_PATH)?).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: FeedbackRecord = serde_json::from_str(&line)?;
+        if channel.is_some_and(|c| !record.channel.eq_ignore_ascii_case(c)) {
+            continue;
+        }
+        records.push(record);
+    }
+    Ok(records)
+}
+
+#[test]
+fn test_tag_last_reply() {
+    use async_openai::types::{
+        ChatCompletionRequestAssistantMessage, ChatCompletionRequestUserMessage, Role,
+    };
+
+    let user = |text: &str| {
+        ChatMessageThing::new_now(ChatCompletionRequestMessage::User(
+            ChatCompletionRequestUserMessage {
+                content: text.to_string().into(),
+                role: Role::User,
+                name: None,
+            },
+        ))
+    };
+    #[allow(deprecated)]
+    let assistant = |text: &str| {
+        ChatMessageThing::new_now(ChatCompletionRequestMessage::Assistant(
+            ChatCompletionRequestAssistantMessage {
+                content: Some(text.to_string()),
+                role: Role::Assistant,
+                name: None,
+                tool_calls: None,
+                function_call: None,
+            },
+        ))
+    };
+    let vote = |nick: &str, vote| Feedback {
+        nick: nick.to_string(),
+        date: Utc::now(),
+        vote,
+        reason: None,
+    };
+
+    let mut messages = VecDeque::from([user("<achin> hello")]);
+    assert!(tag_last_reply("#test", &mut messages, vote("achin", Vote::Good)).is_none());
+
+    messages.push_back(assistant("hi there"));
+    messages.push_back(user("<agrif> unrelated"));
+    let record = tag_last_reply("#test", &mut messages, vote("achin", Vote::Good)).unwrap();
+    assert_eq!(record.question.as_deref(), Some("<achin> hello"));
+    assert_eq!(record.reply, "hi there");
+
+    // voting again replaces the earlier vote
+    tag_last_reply("#test", &mut messages, vote("Achin", Vote::Bad)).unwrap();
+    tag_last_reply("#test", &mut messages, vote("agrif", Vote::Good)).unwrap();
+    let votes: Vec<Vote> = messages[1].feedback.iter().map(|f| f.vote).collect();
+    assert_eq!(votes, [Vote::Bad, Vote::Good]);
+}
@@ -0,0 +1,106 @@
+use std::sync::OnceLock;
+
+use regex::Regex;
+
+/// Color codes, with their optional foreground and background (`\x03` takes mIRC color numbers,
+/// `\x04` takes hex colors)
+fn color_regex() -> &'static Regex {
+    static REGEX: OnceLock<Regex> = OnceLock::new();
+    REGEX.get_or_init(|| {
+        Regex::new(r"\x03(?:\d{1,2}(?:,\d{1,2})?)?|\x04(?:[0-9a-fA-F]{6}(?:,[0-9a-fA-F]{6})?)?")
+            .expect("the color regex is valid")
+    })
+}
+
+/// The markdown for IRC's bold, italic and monospace codes, which are worth keeping
+fn emphasis(c: char) -> Option<&'static str> {
+    match c {
+        '\x02' => Some("**"),
+        '\x1d' => Some("*"),
+        '\x11' => Some("`"),
+        _ => None,
+    }
+}
+
+/// Removes all formatting and control codes from a line
+pub fn strip(text: &str) -> String {
+    color_regex()
+        .replace_all(text, "")
+        .chars()
+        .filter(|c| !c.is_control() || *c == '\t' || *c == '\n')
+        .collect()
+}
+
+/// Removes formatting and control codes from a line, except that bold, italic and monospace are
+/// turned into markdown
+///
+/// Colors, underline, reverse and the like only cost tokens and confuse the model.
+pub fn normalize(text: &str) -> String {
+    let text = color_regex().replace_all(text, "");
+    let mut out = String::with_capacity(text.len());
+    let mut open: Vec<&str> = Vec::new();
+    for c in text.chars() {
+        if let Some(marker) = emphasis(c) {
+            match open.iter().position(|o| *o == marker) {
+                Some(idx) => {
+                    // anything opened inside it gets closed first, so the markdown nests properly
+                    for inner in open.drain(idx..).rev() {
+                        close(&mut out, inner);
+                    }
+                }
+                None => {
+                    out.push_str(marker);
+                    open.push(marker);
+                }
+            }
+        } else if c == '\x0f' {
+            for marker in open.drain(..).rev() {
+                close(&mut out, marker);
+            }
+        } else if !c.is_control() || c == '\t' || c == '\n' {
+            out.push(c);
+        }
+    }
+    for marker in open.drain(..).rev() {
+        close(&mut out, marker);
+    }
+    out
+}
+
+/// Closes some emphasis, or takes it back out if nothing was emphasized
+fn close(out: &mut String, marker: &str) {
+    match out.strip_suffix(marker) {
+        Some(empty) => out.truncate(empty.len()),
+        None => out.push_str(marker),
+    }
+}
+
+#[test]
+fn test_strip() {
+    assert_eq!(strip("\x0304,01red\x0f and \x02bold\x02"), "red and bold");
+    assert_eq!(strip("\x04ff0000hex\x04 \x16rev\x1f\x07"), "hex rev");
+}
+
+#[test]
+fn test_normalize() {
+    assert_eq!(normalize("plain text"), "plain text");
+    assert_eq!(normalize("a \x02bold\x02 word"), "a **bold** word");
+    assert_eq!(
+        normalize("\x1ditalic\x1d and \x11code\x11"),
+        "*italic* and `code`"
+    );
+    assert_eq!(
+        normalize("\x0304,01red\x03 \x1funderlined\x1f"),
+        "red underlined"
+    );
+    // left open, or closed by a reset
+    assert_eq!(normalize("\x02bold to the end"), "**bold to the end**");
+    assert_eq!(normalize("\x02\x1dboth\x0f done"), "***both*** done");
+    assert_eq!(
+        normalize("\x02\x1dboth\x02 italic\x1d"),
+        "***both*** italic"
+    );
+    // empty emphasis goes away entirely
+    assert_eq!(normalize("x\x02\x02y\x1d"), "xy");
+    assert_eq!(normalize("beep\x07\x01"), "beep");
+}
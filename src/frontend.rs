@@ -0,0 +1,224 @@
+use std::sync::Arc;
+
+use irc::proto::{Command, Message, Prefix};
+
+/// Longest line sent to IRC, since the server cuts off anything past 512 bytes with the prefix
+const IRC_LINE_CHARS: usize = 400;
+
+/// A chat network the bot can be on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Irc,
+    Matrix,
+    Discord,
+}
+
+/// What the bot needs from a chat network to talk on it
+///
+/// Targets are channels (or rooms) and nicks, the same way IRC names them.
+pub trait Frontend: Send + Sync + 'static {
+    fn protocol(&self) -> Protocol;
+    fn send_message(&self, target: &str, message: &str) -> anyhow::Result<()>;
+    fn send_notice(&self, target: &str, message: &str) -> anyhow::Result<()>;
+    fn send_action(&self, target: &str, action: &str) -> anyhow::Result<()>;
+    fn join(&self, channel: &str) -> anyhow::Result<()>;
+    fn part(&self, channel: &str) -> anyhow::Result<()>;
+    /// Splits a reply into the messages to send, and how many lines were left over
+    fn fit_message(&self, msg: &str) -> (Vec<String>, usize);
+}
+
+impl Frontend for irc::client::Sender {
+    fn protocol(&self) -> Protocol {
+        Protocol::Irc
+    }
+    fn send_message(&self, target: &str, message: &str) -> anyhow::Result<()> {
+        Ok(self.send_privmsg(target, message)?)
+    }
+    fn send_notice(&self, target: &str, message: &str) -> anyhow::Result<()> {
+        Ok(irc::client::Sender::send_notice(self, target, message)?)
+    }
+    fn send_action(&self, target: &str, action: &str) -> anyhow::Result<()> {
+        Ok(irc::client::Sender::send_action(self, target, action)?)
+    }
+    fn join(&self, channel: &str) -> anyhow::Result<()> {
+        Ok(self.send_join(channel)?)
+    }
+    fn part(&self, channel: &str) -> anyhow::Result<()> {
+        Ok(self.send_part(channel)?)
+    }
+    fn fit_message(&self, msg: &str) -> (Vec<String>, usize) {
+        fit_message_for_irc(msg)
+    }
+}
+
+/// Sends to whichever network a message came from
+///
+/// The methods are named after the `irc` crate's, so code that replies doesn't need to care
+/// which network it's replying on.
+#[derive(Clone)]
+pub struct ChatSender(Arc<dyn Frontend>);
+
+impl ChatSender {
+    pub fn new(frontend: impl Frontend) -> Self {
+        Self(Arc::new(frontend))
+    }
+    pub fn protocol(&self) -> Protocol {
+        self.0.protocol()
+    }
+    pub fn send_privmsg(&self, target: impl ToString, msg: impl ToString) -> anyhow::Result<()> {
+        self.0.send_message(&target.to_string(), &msg.to_string())
+    }
+    pub fn send_notice(&self, target: impl ToString, msg: impl ToString) -> anyhow::Result<()> {
+        self.0.send_notice(&target.to_string(), &msg.to_string())
+    }
+    pub fn send_action(&self, target: impl ToString, msg: impl ToString) -> anyhow::Result<()> {
+        self.0.send_action(&target.to_string(), &msg.to_string())
+    }
+    pub fn send_join(&self, channel: impl ToString) -> anyhow::Result<()> {
+        self.0.join(&channel.to_string())
+    }
+    pub fn send_part(&self, channel: impl ToString) -> anyhow::Result<()> {
+        self.0.part(&channel.to_string())
+    }
+    pub fn fit_message(&self, msg: &str) -> (Vec<String>, usize) {
+        self.0.fit_message(msg)
+    }
+}
+
+/// A message from a network other than IRC
+pub struct IncomingMessage {
+    /// The channel (or room) it was sent to
+    pub target: String,
+    pub nick: String,
+    /// The sender's full id on their network, like `@achin:matrix.org`
+    pub user_id: String,
+    pub text: String,
+}
+
+impl IncomingMessage {
+    /// Dresses the message up as an IRC PRIVMSG, so it goes through the same handling
+    ///
+    /// The user and host can't be mistaken for an IRC user's, since `user_id` is a full id
+    /// and the host is the name of the network.
+    pub fn into_irc(self, protocol: Protocol) -> Message {
+        let host = match protocol {
+            Protocol::Irc => "irc",
+            Protocol::Matrix => "matrix",
+            Protocol::Discord => "discord",
+        };
+        Message {
+            tags: None,
+            prefix: Some(Prefix::Nickname(self.nick, self.user_id, host.to_string())),
+            command: Command::PRIVMSG(self.target, self.text),
+        }
+    }
+}
+
+/// Splits a message into the lines that are short enough to send to IRC directly
+///
+/// Returns the lines to send, and how many lines were left over.
+pub fn fit_message_for_irc(msg: &str) -> (Vec<String>, usize) {
+    let mut lines = split_long_message_for_irc(msg);
+    let mut length = 0;
+    let fits = lines
+        .iter()
+        .take_while(|line| {
+            length += 1 + (line.trim().len() as f32 / 150.0).floor() as i32;
+            length < 8
+        })
+        .count();
+    let omitted = lines.split_off(fits).len();
+    (lines, omitted)
+}
+
+pub fn split_long_message_for_irc(msg: &str) -> Vec<String> {
+    msg.lines()
+        .filter(|l| !l.trim().is_empty())
+        .flat_map(|l| textwrap::wrap(l, IRC_LINE_CHARS))
+        .map(|c| {
+            c.chars()
+                .filter(|c| !c.is_ascii_control() || c.is_ascii_whitespace())
+                .collect()
+        })
+        .collect()
+}
+
+/// Packs a reply into at most `max_messages` messages of up to `message_chars` each, for networks
+/// that take messages with newlines in them
+///
+/// Returns the messages to send, and how many lines were left over.
+pub fn fit_message_in_chunks(
+    msg: &str,
+    message_chars: usize,
+    max_messages: usize,
+) -> (Vec<String>, usize) {
+    let msg: String = msg
+        .chars()
+        .filter(|c| !c.is_control() || *c == '\n' || *c == '\t')
+        .collect();
+    let lines: Vec<String> = msg
+        .lines()
+        .flat_map(|line| {
+            if line.chars().count() > message_chars {
+                textwrap::wrap(line, message_chars)
+                    .into_iter()
+                    .map(|l| l.into_owned())
+                    .collect()
+            } else {
+                vec![line.to_string()]
+            }
+        })
+        .collect();
+    let mut messages = Vec::new();
+    let mut current = String::new();
+    for (idx, line) in lines.iter().enumerate() {
+        if !current.is_empty() && current.chars().count() + 1 + line.chars().count() > message_chars
+        {
+            messages.push(std::mem::take(&mut current).trim().to_string());
+            if messages.len() == max_messages {
+                let omitted = lines[idx..]
+                    .iter()
+                    .filter(|line| !line.trim().is_empty())
+                    .count();
+                return (messages, omitted);
+            }
+        }
+        if !current.is_empty() {
+            current.push('\n');
+        }
+        current.push_str(line);
+    }
+    if !current.trim().is_empty() {
+        messages.push(current.trim().to_string());
+    }
+    (messages, 0)
+}
+
+#[test]
+fn test_fit_message_in_chunks() {
+    let (messages, omitted) = fit_message_in_chunks("one\n\ntwo\u{3}\nthree", 2000, 1);
+    assert_eq!(messages, ["one\n\ntwo\nthree"]);
+    assert_eq!(omitted, 0);
+
+    let line = "x".repeat(1000);
+    let msg = vec![line.as_str(); 10].join("\n");
+    let (messages, omitted) = fit_message_in_chunks(&msg, 8000, 1);
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0].lines().count(), 7);
+    assert_eq!(omitted, 3);
+
+    let (messages, omitted) = fit_message_in_chunks(&msg, 2000, 3);
+    assert_eq!(messages.len(), 3);
+    assert_eq!(omitted, 7);
+
+    let (messages, _) = fit_message_in_chunks(&"y".repeat(2500), 2000, 3);
+    assert_eq!(messages.len(), 2);
+}
+
+#[test]
+fn test_line_split() {
+    let long_line = "Charbot9000: Interesting idea, @agrif! Here's a story about how Nut runs for president with Coco as his running mate:\n\nAfter his heroic deeds in the village battle, Nut became a beloved figure among the people. His unwavering sense of justice and courage inspired many, and soon, he found himself being encouraged to run for president. At first, Nut was hesitant. He had never considered a life in politics before, and he wasn't sure if he was cut out for it. But with the support of his friends and loved ones, Nut eventually decided to throw his hat into the ring. To help him on his campaign, Nut turned to his old friend Coco. Although Coco was still just a coconut, Nut knew that his intelligence and charm would be a valuable asset on the campaign trail. So, Nut named Coco as his running mate and the two began their journey to the White House. Together, Nut and Coco traveled across the country, meeting with voters and spreading their message of hope and unity. Nut's bold vision for a better world, combined with Coco's quick wit and infectious personality, made them a popular duo among the people. Despite facing tough opposition from other candidates, Nut and Coco never lost sight of their values. They ran a clean, honest campaign and focused on the issues that mattered most to the people. And in the end, their hard work paid off - Nut and Coco won the election in a landslide. As Nut was sworn in as the new president of the United States, he knew that he had a lot of work to do. But with Coco by his side, he was confident that they could make a real difference in the world. And as they looked out at the sea of cheering supporters before them, Nut and Coco knew that anything was possible with a little courage and a lot of heart.";
+    for line in split_long_message_for_irc(long_line) {
+        println!("==> {line}");
+    }
+}
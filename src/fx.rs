@@ -0,0 +1,208 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use anyhow::{bail, Context};
+use chrono::{DateTime, Duration, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::openai::OpenAiService;
+
+/// The European Central Bank's reference rates, which only change once a working day
+const RATES_URL: &str = "https://api.frankfurter.app/latest";
+
+/// How long a currency's rates are reused for
+const CACHE_HOURS: i64 = 24;
+
+pub const USAGE: &str = "Usage: !fx [amount] <currency> in <currency>, like !fx 100 EUR in USD";
+
+/// Rates fetched for each base currency, and when they were fetched
+static RATES: Mutex<Option<HashMap<String, (DateTime<Utc>, Rates)>>> = Mutex::new(None);
+
+/// What one unit of a currency is worth in others
+#[derive(Deserialize, Debug, Clone)]
+struct Rates {
+    base: String,
+    /// The day the rates are from, like "2024-03-01"
+    date: String,
+    rates: HashMap<String, f64>,
+}
+
+/// A conversion that was asked for, and what the model's currency tool is given
+#[derive(JsonSchema, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FxInput {
+    pub amount: f64,
+    /// A three letter currency code, like "EUR"
+    pub from: String,
+    /// A three letter currency code, like "USD"
+    pub to: String,
+}
+
+impl FxInput {
+    /// Parses something like "100 EUR in USD", "5.50 gbp to jpy" or "EUR USD"
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        let words: Vec<&str> = s
+            .split_whitespace()
+            .filter(|w| !["in", "to", "into"].contains(&w.to_lowercase().as_str()))
+            .collect();
+        let (amount, currencies) = match words.split_first() {
+            Some((first, rest)) => match first.replace(',', "").parse::<f64>() {
+                Ok(amount) => (amount, rest),
+                Err(_) => (1.0, words.as_slice()),
+            },
+            None => bail!(USAGE),
+        };
+        let [from, to] = currencies else {
+            bail!(USAGE);
+        };
+        let is_code = |c: &str| c.len() == 3 && c.chars().all(|c| c.is_ascii_alphabetic());
+        if !is_code(from) || !is_code(to) || !amount.is_finite() {
+            bail!(USAGE);
+        }
+        Ok(Self {
+            amount,
+            from: from.to_uppercase(),
+            to: to.to_uppercase(),
+        })
+    }
+}
+
+/// A finished conversion
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Conversion {
+    pub amount: f64,
+    pub from: String,
+    pub to: String,
+    pub rate: f64,
+    pub result: f64,
+    /// The day the rate is from
+    pub as_of: String,
+}
+
+impl std::fmt::Display for Conversion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} = {} {} (1 {} = {:.4} {}, as of {})",
+            format_amount(self.amount),
+            self.from,
+            format_amount(self.result),
+            self.to,
+            self.from,
+            self.rate,
+            self.to,
+            self.as_of
+        )
+    }
+}
+
+/// Two decimal places, unless that would round it away
+fn format_amount(amount: f64) -> String {
+    if amount != 0.0 && amount.abs() < 0.01 {
+        format!("{amount:.6}")
+    } else {
+        format!("{amount:.2}")
+    }
+}
+
+fn convert(input: &FxInput, rates: &Rates) -> anyhow::Result<Conversion> {
+    let rate = if input.to == rates.base {
+        1.0
+    } else {
+        *rates
+            .rates
+            .get(&input.to)
+            .with_context(|| format!("I don't have a rate for {}", input.to))?
+    };
+    Ok(Conversion {
+        amount: input.amount,
+        from: input.from.clone(),
+        to: input.to.clone(),
+        rate,
+        result: input.amount * rate,
+        as_of: rates.date.clone(),
+    })
+}
+
+/// Gets the rates for a currency, reusing ones fetched in the last `CACHE_HOURS`
+async fn fetch_rates(base: &str) -> anyhow::Result<Rates> {
+    let now = Utc::now();
+    let cached = RATES
+        .lock()
+        .expect("rates lock is poisoned")
+        .as_ref()
+        .and_then(|rates| rates.get(base))
+        .filter(|(fetched, _)| now - *fetched < Duration::hours(CACHE_HOURS))
+        .map(|(_, rates)| rates.clone());
+    if let Some(rates) = cached {
+        return Ok(rates);
+    }
+
+    let mut url = url::Url::parse(RATES_URL)?;
+    url.query_pairs_mut().append_pair("from", base);
+    let resp = OpenAiService::shared()
+        .downloads()
+        .get(url)
+        .timeout(std::time::Duration::from_secs(10))
+        .send()
+        .await?;
+    if resp.status() == reqwest::StatusCode::NOT_FOUND {
+        bail!("I don't have rates for {base}");
+    }
+    let rates = resp.error_for_status()?.json::<Rates>().await?;
+    RATES
+        .lock()
+        .expect("rates lock is poisoned")
+        .get_or_insert_with(HashMap::new)
+        .insert(base.to_string(), (now, rates.clone()));
+    Ok(rates)
+}
+
+/// Converts between currencies at the latest reference rate
+pub async fn convert_currency(input: &FxInput) -> anyhow::Result<Conversion> {
+    let rates = fetch_rates(&input.from).await?;
+    convert(input, &rates)
+}
+
+/// A conversion as compact JSON, for answering the model's currency tool
+pub async fn fx_for_tool(input: &FxInput) -> anyhow::Result<String> {
+    Ok(serde_json::to_string(&convert_currency(input).await?)?)
+}
+
+#[test]
+fn test_parse_fx() {
+    let input = |amount, from: &str, to: &str| FxInput {
+        amount,
+        from: from.to_string(),
+        to: to.to_string(),
+    };
+    assert_eq!(
+        FxInput::parse("100 EUR in USD").unwrap(),
+        input(100.0, "EUR", "USD")
+    );
+    assert_eq!(
+        FxInput::parse("1,250.50 gbp to jpy").unwrap(),
+        input(1250.5, "GBP", "JPY")
+    );
+    assert_eq!(FxInput::parse("eur usd").unwrap(), input(1.0, "EUR", "USD"));
+    assert!(FxInput::parse("").is_err());
+    assert!(FxInput::parse("100 euros in dollars").is_err());
+    assert!(FxInput::parse("100 EUR").is_err());
+}
+
+#[test]
+fn test_convert() {
+    let rates = Rates {
+        base: "EUR".to_string(),
+        date: "2024-03-01".to_string(),
+        rates: HashMap::from([("USD".to_string(), 1.0823), ("JPY".to_string(), 162.4)]),
+    };
+    let conversion = convert(&FxInput::parse("100 EUR in USD").unwrap(), &rates).unwrap();
+    assert_eq!(
+        conversion.to_string(),
+        "100.00 EUR = 108.23 USD (1 EUR = 1.0823 USD, as of 2024-03-01)"
+    );
+    let same = convert(&FxInput::parse("5 EUR in EUR").unwrap(), &rates).unwrap();
+    assert_eq!(same.result, 5.0);
+    assert!(convert(&FxInput::parse("5 EUR in XYZ").unwrap(), &rates).is_err());
+    assert_eq!(format_amount(0.001234), "0.001234");
+}
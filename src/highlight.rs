@@ -0,0 +1,137 @@
+use std::sync::OnceLock;
+
+use syntect::{
+    highlighting::{Theme, ThemeSet},
+    html::highlighted_html_for_string,
+    parsing::SyntaxSet,
+};
+
+use crate::images::escape_html;
+
+/// How much of a reply has to be code for it to be uploaded as a highlighted page
+const CODE_FRACTION: f32 = 0.5;
+
+const THEME: &str = "InspiredGitHub";
+
+fn syntaxes() -> &'static SyntaxSet {
+    static SYNTAXES: OnceLock<SyntaxSet> = OnceLock::new();
+    SYNTAXES.get_or_init(SyntaxSet::load_defaults_newlines)
+}
+
+fn theme() -> &'static Theme {
+    static THEME_SET: OnceLock<ThemeSet> = OnceLock::new();
+    &THEME_SET.get_or_init(ThemeSet::load_defaults).themes[THEME]
+}
+
+/// A piece of a markdown reply
+#[derive(Debug, PartialEq)]
+enum Block<'a> {
+    Text(&'a str),
+    /// A fenced code block, and the language it's marked as
+    Code {
+        lang: &'a str,
+        code: String,
+    },
+}
+
+/// Splits a reply into text and fenced code blocks
+///
+/// A code block that isn't closed runs to the end of the reply.
+fn blocks(reply: &str) -> Vec<Block<'_>> {
+    let mut blocks = Vec::new();
+    let mut rest = reply;
+    while let Some(start) = rest.find("```") {
+        if !rest[..start].trim().is_empty() {
+            blocks.push(Block::Text(rest[..start].trim()));
+        }
+        let after = &rest[start + 3..];
+        let (lang, body) = after.split_once('\n').unwrap_or((after, ""));
+        let (code, next) = match body.find("```") {
+            Some(end) => (&body[..end], &body[end + 3..]),
+            None => (body, ""),
+        };
+        blocks.push(Block::Code {
+            lang: lang.trim(),
+            code: code.trim_end().to_string() + "\n",
+        });
+        rest = next;
+    }
+    if !rest.trim().is_empty() {
+        blocks.push(Block::Text(rest.trim()));
+    }
+    blocks
+}
+
+/// Whether most of a reply is code, so it reads better as a highlighted page than as text
+pub fn is_code_heavy(reply: &str) -> bool {
+    let total = reply.trim().len();
+    let code: usize = blocks(reply)
+        .iter()
+        .map(|block| match block {
+            Block::Code { code, .. } => code.trim().len(),
+            Block::Text(_) => 0,
+        })
+        .sum();
+    total > 0 && code as f32 >= total as f32 * CODE_FRACTION
+}
+
+/// A page with a reply on it, with its code blocks highlighted, for uploading with
+/// `upload_content`
+pub fn reply_html(reply: &str) -> String {
+    let body: String = blocks(reply)
+        .into_iter()
+        .map(|block| match block {
+            Block::Text(text) => format!("<div class=\"text\">{}</div>\n", escape_html(text)),
+            Block::Code { lang, code } => {
+                let syntax = syntaxes()
+                    .find_syntax_by_token(lang)
+                    .unwrap_or_else(|| syntaxes().find_syntax_plain_text());
+                highlighted_html_for_string(&code, syntaxes(), syntax, theme())
+                    .unwrap_or_else(|_| format!("<pre>{}</pre>", escape_html(&code)))
+            }
+        })
+        .collect();
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+         <style>body {{ max-width: 60em; margin: 1em auto; font-family: sans-serif; }} \
+         .text {{ white-space: pre-wrap; margin: 1em 0; }} \
+         pre {{ padding: 0.5em; overflow-x: auto; }}</style>\n</head>\n<body>\n{body}</body>\n\
+         </html>\n"
+    )
+}
+
+#[test]
+fn test_blocks() {
+    let reply = "Try this:\n```rust\nfn main() {}\n```\nThen run it.";
+    assert_eq!(
+        blocks(reply),
+        [
+            Block::Text("Try this:"),
+            Block::Code {
+                lang: "rust",
+                code: "fn main() {}\n".to_string()
+            },
+            Block::Text("Then run it."),
+        ]
+    );
+    assert_eq!(
+        blocks("```\nunclosed"),
+        [Block::Code {
+            lang: "",
+            code: "unclosed\n".to_string()
+        }]
+    );
+}
+
+#[test]
+fn test_reply_html() {
+    let code = "fn main() {\n    println!(\"<hello>\");\n}";
+    let reply = format!("Here:\n```rust\n{code}\n```");
+    assert!(is_code_heavy(&reply));
+    assert!(!is_code_heavy("Just some words, and `one` bit of code."));
+
+    let html = reply_html(&reply);
+    assert!(html.contains("<div class=\"text\">Here:</div>"));
+    assert!(html.contains("<pre style="));
+    assert!(html.contains("&lt;hello&gt;"));
+}
@@ -0,0 +1,418 @@
+use std::{fs::File, path::Path};
+
+use anyhow::{bail, Context};
+use async_openai::types::{
+    ChatCompletionMessageToolCall, ChatCompletionRequestAssistantMessage,
+    ChatCompletionRequestFunctionMessage, ChatCompletionRequestMessage,
+    ChatCompletionRequestMessageContentPart, ChatCompletionRequestMessageContentPartImage,
+    ChatCompletionRequestMessageContentPartText, ChatCompletionRequestSystemMessage,
+    ChatCompletionRequestToolMessage, ChatCompletionRequestUserMessage,
+    ChatCompletionRequestUserMessageContent, ChatCompletionToolType, FunctionCall, Role,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// Version of the saved history format, written into every `{channel}.json`
+///
+/// Files without a version were written before there was one, and stored async_openai's types
+/// directly.  They're upgraded when they're loaded.
+pub const VERSION: u64 = 1;
+
+/// A chat message, as it's saved on disk
+///
+/// These are our own types rather than async_openai's, so that upgrading that crate can't change
+/// (or break) the saved history.  Changes here need a new `VERSION`, and an upgrade for the
+/// old one.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "role", rename_all = "lowercase")]
+pub enum StoredMessage {
+    System {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
+        content: String,
+    },
+    User {
+        /// The nick of whoever sent it
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
+        content: StoredContent,
+    },
+    Assistant {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        content: Option<String>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        tool_calls: Vec<StoredToolCall>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        function_call: Option<StoredToolCall>,
+    },
+    Tool {
+        tool_call_id: String,
+        content: String,
+    },
+    Function {
+        name: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        content: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum StoredContent {
+    Text(String),
+    Parts(Vec<StoredPart>),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum StoredPart {
+    Text { text: String },
+    Image { url: String },
+}
+
+/// A call the model made to a tool (or to a function, in the older API)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StoredToolCall {
+    /// Function calls don't have an ID
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub id: String,
+    pub name: String,
+    pub arguments: String,
+}
+
+impl From<&FunctionCall> for StoredToolCall {
+    fn from(call: &FunctionCall) -> Self {
+        Self {
+            id: String::new(),
+            name: call.name.clone(),
+            arguments: call.arguments.clone(),
+        }
+    }
+}
+
+impl From<StoredToolCall> for FunctionCall {
+    fn from(call: StoredToolCall) -> Self {
+        Self {
+            name: call.name,
+            arguments: call.arguments,
+        }
+    }
+}
+
+impl From<&ChatCompletionRequestMessage> for StoredMessage {
+    #[allow(deprecated)]
+    fn from(msg: &ChatCompletionRequestMessage) -> Self {
+        match msg {
+            ChatCompletionRequestMessage::System(m) => StoredMessage::System {
+                name: m.name.clone(),
+                content: m.content.clone(),
+            },
+            ChatCompletionRequestMessage::User(m) => StoredMessage::User {
+                name: m.name.clone(),
+                content: match &m.content {
+                    ChatCompletionRequestUserMessageContent::Text(text) => {
+                        StoredContent::Text(text.clone())
+                    }
+                    ChatCompletionRequestUserMessageContent::Array(parts) => StoredContent::Parts(
+                        parts
+                            .iter()
+                            .map(|part| match part {
+                                ChatCompletionRequestMessageContentPart::Text(t) => {
+                                    StoredPart::Text {
+                                        text: t.text.clone(),
+                                    }
+                                }
+                                ChatCompletionRequestMessageContentPart::Image(i) => {
+                                    StoredPart::Image {
+                                        url: i.image_url.url.clone(),
+                                    }
+                                }
+                            })
+                            .collect(),
+                    ),
+                },
+            },
+            ChatCompletionRequestMessage::Assistant(m) => StoredMessage::Assistant {
+                content: m.content.clone(),
+                tool_calls: m
+                    .tool_calls
+                    .iter()
+                    .flatten()
+                    .map(|call| StoredToolCall {
+                        id: call.id.clone(),
+                        ..StoredToolCall::from(&call.function)
+                    })
+                    .collect(),
+                function_call: m.function_call.as_ref().map(|call| call.into()),
+            },
+            ChatCompletionRequestMessage::Tool(m) => StoredMessage::Tool {
+                tool_call_id: m.tool_call_id.clone(),
+                content: m.content.clone(),
+            },
+            ChatCompletionRequestMessage::Function(m) => StoredMessage::Function {
+                name: m.name.clone(),
+                content: m.content.clone(),
+            },
+        }
+    }
+}
+
+impl From<StoredMessage> for ChatCompletionRequestMessage {
+    #[allow(deprecated)]
+    fn from(msg: StoredMessage) -> Self {
+        match msg {
+            StoredMessage::System { name, content } => {
+                ChatCompletionRequestMessage::System(ChatCompletionRequestSystemMessage {
+                    content,
+                    role: Role::System,
+                    name,
+                })
+            }
+            StoredMessage::User { name, content } => {
+                let content = match content {
+                    StoredContent::Text(text) => {
+                        ChatCompletionRequestUserMessageContent::Text(text)
+                    }
+                    StoredContent::Parts(parts) => ChatCompletionRequestUserMessageContent::Array(
+                        parts
+                            .into_iter()
+                            .map(|part| match part {
+                                StoredPart::Text { text } => {
+                                    ChatCompletionRequestMessageContentPartText::from(text).into()
+                                }
+                                StoredPart::Image { url } => {
+                                    ChatCompletionRequestMessageContentPartImage {
+                                        r#type: "image_url".into(),
+                                        image_url: url.as_str().into(),
+                                    }
+                                    .into()
+                                }
+                            })
+                            .collect(),
+                    ),
+                };
+                ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
+                    content,
+                    role: Role::User,
+                    name,
+                })
+            }
+            StoredMessage::Assistant {
+                content,
+                tool_calls,
+                function_call,
+            } => ChatCompletionRequestMessage::Assistant(ChatCompletionRequestAssistantMessage {
+                content,
+                role: Role::Assistant,
+                name: None,
+                tool_calls: (!tool_calls.is_empty()).then(|| {
+                    tool_calls
+                        .into_iter()
+                        .map(|call| ChatCompletionMessageToolCall {
+                            id: call.id.clone(),
+                            r#type: ChatCompletionToolType::Function,
+                            function: call.into(),
+                        })
+                        .collect()
+                }),
+                function_call: function_call.map(|call| call.into()),
+            }),
+            StoredMessage::Tool {
+                tool_call_id,
+                content,
+            } => ChatCompletionRequestMessage::Tool(ChatCompletionRequestToolMessage {
+                role: Role::Tool,
+                content,
+                tool_call_id,
+            }),
+            StoredMessage::Function { name, content } => {
+                ChatCompletionRequestMessage::Function(ChatCompletionRequestFunctionMessage {
+                    role: Role::Function,
+                    content,
+                    name,
+                })
+            }
+        }
+    }
+}
+
+/// For `#[serde(with = "...")]` on a `ChatCompletionRequestMessage`, so it's saved as a
+/// `StoredMessage`
+pub mod api_message {
+    use super::*;
+
+    pub fn serialize<S: serde::Serializer>(
+        msg: &ChatCompletionRequestMessage,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        StoredMessage::from(msg).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<ChatCompletionRequestMessage, D::Error> {
+        StoredMessage::deserialize(deserializer).map(Into::into)
+    }
+}
+
+/// Reads a message the way it was saved before there was a version, when it was whatever
+/// async_openai's types serialized to
+///
+/// Those types are untagged, so deserializing them directly turns everything into a system
+/// message.  The role says what each one really was.
+fn upgrade_unversioned_message(msg: serde_json::Value) -> anyhow::Result<StoredMessage> {
+    let role = msg.get("role").and_then(|r| r.as_str()).unwrap_or("system");
+    let msg = match role {
+        "user" => ChatCompletionRequestMessage::User(serde_json::from_value(msg)?),
+        "assistant" => ChatCompletionRequestMessage::Assistant(serde_json::from_value(msg)?),
+        "tool" => ChatCompletionRequestMessage::Tool(serde_json::from_value(msg)?),
+        "function" => ChatCompletionRequestMessage::Function(serde_json::from_value(msg)?),
+        _ => ChatCompletionRequestMessage::System(serde_json::from_value(msg)?),
+    };
+    Ok((&msg).into())
+}
+
+/// Brings saved state up to the current version, in place
+///
+/// Messages are found under `messages`, and under each of the `saved_contexts`.
+fn upgrade(state: &mut serde_json::Value) -> anyhow::Result<()> {
+    let version = state.get("version").and_then(|v| v.as_u64()).unwrap_or(0);
+    if version > VERSION {
+        bail!("This was saved by a newer version of the bot (history version {version})");
+    }
+    if version == 0 {
+        let mut lists: Vec<&mut serde_json::Value> = Vec::new();
+        if let Some(messages) = state.get_mut("messages") {
+            lists.push(messages);
+        }
+        if let Some(saved) = state
+            .get_mut("saved_contexts")
+            .and_then(|s| s.as_object_mut())
+        {
+            lists.extend(saved.values_mut());
+        }
+        for list in lists {
+            for cmt in list.as_array_mut().into_iter().flatten() {
+                if let Some(msg) = cmt.get_mut("msg") {
+                    *msg = serde_json::to_value(upgrade_unversioned_message(msg.take())?)?;
+                }
+            }
+        }
+    }
+    state["version"] = VERSION.into();
+    Ok(())
+}
+
+/// Loads saved channel state, upgrading it from older versions if needed
+pub fn load<T: DeserializeOwned>(path: impl AsRef<Path>) -> anyhow::Result<T> {
+    let path = path.as_ref();
+    let input = File::open(path)?;
+    let mut state: serde_json::Value = serde_json::from_reader(input)?;
+    upgrade(&mut state).with_context(|| format!("Failed to upgrade {}", path.display()))?;
+    Ok(serde_json::from_value(state)?)
+}
+
+/// Saves channel state, along with the version it's saved in
+pub fn save<T: Serialize>(path: impl AsRef<Path>, state: &T) -> anyhow::Result<()> {
+    let mut state = serde_json::to_value(state)?;
+    state["version"] = VERSION.into();
+    let output = File::create(path)?;
+    serde_json::to_writer_pretty(output, &state)?;
+    Ok(())
+}
+
+#[test]
+fn test_upgrade_history() {
+    // how a user message and a reply were saved before there was a version
+    let mut state = serde_json::json!({
+        "messages": [
+            {
+                "date": "2024-03-01T12:00:00Z",
+                "msg": {"role": "user", "content": "<achin> hello", "name": "achin"}
+            },
+            {
+                "date": "2024-03-01T12:00:05Z",
+                "msg": {"role": "assistant", "content": "Hi!"},
+                "model": "gpt-4o"
+            }
+        ],
+        "saved_contexts": {
+            "old": [
+                {
+                    "date": "2024-03-01T11:00:00Z",
+                    "msg": {"role": "system", "content": "Be nice"}
+                }
+            ]
+        }
+    });
+    upgrade(&mut state).unwrap();
+    assert_eq!(state["version"], VERSION);
+
+    let msg = |value: &serde_json::Value| -> StoredMessage {
+        serde_json::from_value(value.clone()).unwrap()
+    };
+    assert_eq!(
+        msg(&state["messages"][0]["msg"]),
+        StoredMessage::User {
+            name: Some("achin".to_string()),
+            content: StoredContent::Text("<achin> hello".to_string()),
+        }
+    );
+    assert!(matches!(
+        msg(&state["messages"][1]["msg"]),
+        StoredMessage::Assistant { content: Some(c), .. } if c == "Hi!"
+    ));
+    assert_eq!(
+        msg(&state["saved_contexts"]["old"][0]["msg"]),
+        StoredMessage::System {
+            name: None,
+            content: "Be nice".to_string()
+        }
+    );
+
+    // upgrading again changes nothing
+    let upgraded = state.clone();
+    upgrade(&mut state).unwrap();
+    assert_eq!(state, upgraded);
+
+    state["version"] = (VERSION + 1).into();
+    assert!(upgrade(&mut state).is_err());
+}
+
+#[test]
+fn test_stored_message_round_trip() {
+    let messages = [
+        StoredMessage::User {
+            name: Some("achin".to_string()),
+            content: StoredContent::Parts(vec![
+                StoredPart::Text {
+                    text: "what's this?".to_string(),
+                },
+                StoredPart::Image {
+                    url: "https://example.com/cat.png".to_string(),
+                },
+            ]),
+        },
+        StoredMessage::Assistant {
+            content: None,
+            tool_calls: vec![StoredToolCall {
+                id: "call_1".to_string(),
+                name: "weather".to_string(),
+                arguments: r#"{"city": "Paris"}"#.to_string(),
+            }],
+            function_call: None,
+        },
+        StoredMessage::Tool {
+            tool_call_id: "call_1".to_string(),
+            content: "Sunny".to_string(),
+        },
+    ];
+    for stored in messages {
+        let api: ChatCompletionRequestMessage = stored.clone().into();
+        assert_eq!(StoredMessage::from(&api), stored);
+        let json = serde_json::to_string(&stored).unwrap();
+        assert_eq!(
+            serde_json::from_str::<StoredMessage>(&json).unwrap(),
+            stored
+        );
+    }
+}
@@ -0,0 +1,171 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use anyhow::Context;
+
+use crate::config::{get_config, NetworkConfig};
+
+/// The network settings from config.json
+pub fn network() -> NetworkConfig {
+    get_config()
+        .map(|config| config.network)
+        .unwrap_or_default()
+}
+
+/// Looks up a host's addresses, giving up after `timeout`
+async fn lookup(host: &str, port: u16, timeout: Duration) -> anyhow::Result<Vec<SocketAddr>> {
+    let addrs = tokio::time::timeout(timeout, tokio::net::lookup_host((host, port)))
+        .await
+        .with_context(|| format!("Looking up {host} took too long"))??;
+    Ok(addrs.collect())
+}
+
+/// Looks up hosts the usual way, but with a time limit
+struct TimeoutResolver {
+    timeout: Duration,
+}
+
+impl reqwest::dns::Resolve for TimeoutResolver {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        let timeout = self.timeout;
+        Box::pin(async move {
+            // the port is filled in by whoever connects
+            let addrs: Result<reqwest::dns::Addrs, Box<dyn std::error::Error + Send + Sync>> =
+                match lookup(name.as_str(), 0, timeout).await {
+                    Ok(addrs) => Ok(Box::new(addrs.into_iter())),
+                    Err(e) => Err(e.into()),
+                };
+            addrs
+        })
+    }
+}
+
+/// Applies the proxy, extra root certificates and DNS timeout from the config to a client
+fn configure(
+    mut builder: reqwest::ClientBuilder,
+    network: &NetworkConfig,
+) -> anyhow::Result<reqwest::ClientBuilder> {
+    if let Some(proxy) = &network.proxy {
+        let mut proxy = reqwest::Proxy::all(proxy).context("The proxy isn't a valid URL")?;
+        if let Some(no_proxy) = &network.no_proxy {
+            proxy = proxy.no_proxy(reqwest::NoProxy::from_string(no_proxy));
+        }
+        builder = builder.proxy(proxy);
+    }
+    for path in &network.root_certificates {
+        let pem = std::fs::read(path).with_context(|| format!("Couldn't read {path}"))?;
+        let cert = reqwest::Certificate::from_pem(&pem)
+            .with_context(|| format!("{path} isn't a PEM certificate"))?;
+        builder = builder.add_root_certificate(cert);
+    }
+    let resolver = TimeoutResolver {
+        timeout: network.dns_timeout(),
+    };
+    Ok(builder.dns_resolver(Arc::new(resolver)))
+}
+
+/// A builder for an HTTP client that goes through the configured proxy, and trusts the configured
+/// root certificates
+///
+/// Every reqwest client the bot makes should start from this.  Network settings that can't be
+/// used are an error, rather than being left out, so nothing quietly goes around the proxy.
+pub fn client_builder() -> anyhow::Result<reqwest::ClientBuilder> {
+    configure(reqwest::Client::builder(), &network())
+        .context("The network settings in config.json can't be used")
+}
+
+/// Makes sure a client can be built with the network settings, so that a bad proxy or
+/// certificate stops the bot from starting instead of failing every request later
+pub fn check() -> anyhow::Result<()> {
+    client_builder()?.build()?;
+    Ok(())
+}
+
+/// How long to give one address family before also trying the next
+pub const FAMILY_STAGGER: Duration = Duration::from_millis(250);
+
+/// Whether a request failed while connecting, so it might work at another address
+///
+/// Connect timeouts count, but a server that's connected to and then too slow to answer doesn't.
+pub fn is_connection_problem(e: &reqwest::Error) -> bool {
+    e.is_connect()
+}
+
+/// Splits addresses up by family, IPv4 first, since a host that can't be reached at all usually
+/// has an IPv6 address that doesn't work
+fn by_family(addrs: Vec<SocketAddr>) -> Vec<Vec<SocketAddr>> {
+    let (v4, v6): (Vec<_>, Vec<_>) = addrs.into_iter().partition(SocketAddr::is_ipv4);
+    [v4, v6]
+        .into_iter()
+        .filter(|addrs| !addrs.is_empty())
+        .collect()
+}
+
+/// Clients that each only connect to one family of the addresses a link's host has, to try again
+/// with after a request couldn't connect
+///
+/// Each gets the whole connect timeout to itself, rather than sharing it with addresses that never
+/// answer.  Links to an IP address have nothing to fall back to, and neither does anything that
+/// goes through a proxy, since it's the proxy that connects.
+pub async fn fallback_clients(
+    url: &str,
+    timeout: Duration,
+) -> anyhow::Result<Vec<reqwest::Client>> {
+    let url = url::Url::parse(url)?;
+    let Some(url::Host::Domain(host)) = url.host() else {
+        return Ok(Vec::new());
+    };
+    let port = url.port_or_known_default().unwrap_or(443);
+    let network = network();
+    if network.proxy.is_some() {
+        return Ok(Vec::new());
+    }
+    let addrs = lookup(host, port, network.dns_timeout()).await?;
+    by_family(addrs)
+        .into_iter()
+        .map(|addrs| {
+            Ok(client_builder()?
+                .resolve_to_addrs(host, &addrs)
+                .connect_timeout(network.connect_timeout())
+                .timeout(timeout)
+                .user_agent("anna/1.0.0")
+                .build()?)
+        })
+        .collect()
+}
+
+#[test]
+fn test_by_family() {
+    let addrs: Vec<SocketAddr> = ["[2001:db8::1]:443", "192.0.2.1:443", "[2001:db8::2]:443"]
+        .iter()
+        .map(|addr| addr.parse().unwrap())
+        .collect();
+    let families = by_family(addrs.clone());
+    assert_eq!(families, [vec![addrs[1]], vec![addrs[0], addrs[2]]]);
+    assert_eq!(by_family(vec![addrs[0]]), [vec![addrs[0]]]);
+    assert!(by_family(Vec::new()).is_empty());
+}
+
+#[test]
+fn test_configure() {
+    let network = NetworkConfig {
+        proxy: Some("http://proxy.example.com:3128".to_string()),
+        no_proxy: Some("localhost,.internal".to_string()),
+        ..Default::default()
+    };
+    assert!(configure(reqwest::Client::builder(), &network).is_ok());
+
+    let network = NetworkConfig {
+        proxy: Some("not a url".to_string()),
+        ..Default::default()
+    };
+    assert!(configure(reqwest::Client::builder(), &network).is_err());
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("ca.pem");
+    std::fs::write(&path, "not a certificate").unwrap();
+    let network = NetworkConfig {
+        root_certificates: vec![path.display().to_string()],
+        ..Default::default()
+    };
+    assert!(configure(reqwest::Client::builder(), &network).is_err());
+}
@@ -0,0 +1,155 @@
+use std::io::Cursor;
+
+use anyhow::{bail, Context};
+use image::{imageops::FilterType, DynamicImage, ImageFormat};
+use serde::{Deserialize, Serialize};
+
+use crate::upload_content;
+
+/// Images larger than this aren't archived
+const MAX_ARCHIVE_BYTES: usize = 20 * 1024 * 1024;
+/// Thumbnails fit in a square this many pixels wide
+const THUMBNAIL_SIZE: u32 = 256;
+/// Images sent on their own to the vision model are scaled down to fit in a square this big
+const MAX_VISION_SIZE: u32 = 2048;
+
+/// An image that was rehosted when it entered the history
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArchivedImage {
+    /// Where the image was originally posted
+    pub original: String,
+    /// The rehosted copy
+    pub url: String,
+    #[serde(default)]
+    pub thumbnail: Option<String>,
+}
+
+/// Scales an image down to fit in a small square, returning it as a JPEG
+pub fn make_thumbnail(data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let img = image::load_from_memory(data)?;
+    let thumb = DynamicImage::ImageRgb8(img.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE).to_rgb8());
+
+    let mut output = Vec::new();
+    thumb.write_to(&mut Cursor::new(&mut output), ImageFormat::Jpeg)?;
+    Ok(output)
+}
+
+/// Downloads an image and rehosts it (and a thumbnail) with `upload_content`
+///
+/// Image hosts expire links, so the rehosted URL is what gets stored in the history.
+pub async fn archive_image(client: &reqwest::Client, url: &str) -> anyhow::Result<ArchivedImage> {
+    let resp = client.get(url).send().await?.error_for_status()?;
+    let content_type = resp
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|ct| ct.to_str().ok())
+        .filter(|ct| ct.starts_with("image/"))
+        .context("That's not an image")?
+        .to_string();
+    if matches!(resp.content_length(), Some(len) if len as usize > MAX_ARCHIVE_BYTES) {
+        bail!("Image is too large to archive");
+    }
+    let data = resp.bytes().await?;
+    if data.len() > MAX_ARCHIVE_BYTES {
+        bail!("Image is too large to archive");
+    }
+
+    let thumbnail = match make_thumbnail(&data) {
+        Ok(thumb) => upload_content(thumb, "image/jpeg").await.ok(),
+        Err(e) => {
+            println!("Failed to make thumbnail for {url}: {e}");
+            None
+        }
+    };
+
+    let rehosted = upload_content(data.to_vec(), &content_type)
+        .await
+        .context("Failed to rehost image")?;
+
+    Ok(ArchivedImage {
+        original: url.to_string(),
+        url: rehosted,
+        thumbnail,
+    })
+}
+
+/// Checks that a URL points to a reasonably sized image, and scales it down if needed
+///
+/// Returns the URL to give to the vision model, which is a rehosted copy if the image was scaled.
+pub async fn prepare_for_vision(client: &reqwest::Client, url: &str) -> anyhow::Result<String> {
+    let resp = client.get(url).send().await?.error_for_status()?;
+    let is_image = resp
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|ct| ct.to_str().ok())
+        .is_some_and(|ct| ct.starts_with("image/"));
+    if !is_image {
+        bail!("That doesn't look like an image");
+    }
+    if matches!(resp.content_length(), Some(len) if len as usize > MAX_ARCHIVE_BYTES) {
+        bail!("Image is too large");
+    }
+    let data = resp.bytes().await?;
+    if data.len() > MAX_ARCHIVE_BYTES {
+        bail!("Image is too large");
+    }
+
+    let img = image::load_from_memory(&data).context("Failed to decode image")?;
+    if img.width() <= MAX_VISION_SIZE && img.height() <= MAX_VISION_SIZE {
+        return Ok(url.to_string());
+    }
+
+    let scaled = DynamicImage::ImageRgb8(
+        img.resize(MAX_VISION_SIZE, MAX_VISION_SIZE, FilterType::Triangle)
+            .to_rgb8(),
+    );
+    let mut output = Vec::new();
+    scaled.write_to(&mut Cursor::new(&mut output), ImageFormat::Jpeg)?;
+    upload_content(output, "image/jpeg").await
+}
+
+/// Escapes text for including in HTML
+pub fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// A page showing several generated images side by side, for uploading with `upload_content`
+pub fn gallery_html(prompt: &str, urls: &[String]) -> String {
+    let prompt = escape_html(prompt);
+    let images: String = urls
+        .iter()
+        .map(|url| {
+            let url = escape_html(url);
+            format!("<a href=\"{url}\"><img src=\"{url}\" alt=\"{prompt}\"></a>\n")
+        })
+        .collect();
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{prompt}</title>\n\
+         <style>img {{ width: 45%; margin: 1%; }}</style>\n</head>\n<body>\n<p>{prompt}</p>\n\
+         {images}</body>\n</html>\n"
+    )
+}
+
+#[test]
+fn test_thumbnail() -> anyhow::Result<()> {
+    let img = DynamicImage::new_rgba8(1024, 512);
+    let mut png = Vec::new();
+    img.write_to(&mut Cursor::new(&mut png), ImageFormat::Png)?;
+
+    let thumb = image::load_from_memory(&make_thumbnail(&png)?)?;
+    assert_eq!(thumb.width(), THUMBNAIL_SIZE);
+    assert_eq!(thumb.height(), THUMBNAIL_SIZE / 2);
+
+    Ok(())
+}
+
+#[test]
+fn test_gallery_html() {
+    let urls = ["https://example.com/a.png".to_string()];
+    let html = gallery_html("cats & <dogs>", &urls);
+    assert!(html.contains("<title>cats &amp; &lt;dogs&gt;</title>"));
+    assert!(html.contains("<img src=\"https://example.com/a.png\""));
+}
@@ -0,0 +1,109 @@
+use std::collections::HashMap;
+
+/// How many of a channel's latest messages the jargon is taken from
+pub const RECENT_MESSAGES: usize = 50;
+
+/// Longest prompt that's made, since Whisper only looks at the last 224 tokens of one anyway
+const MAX_PROMPT_CHARS: usize = 600;
+
+/// Whether a word looks like a name or a bit of jargon, which Whisper is likely to misspell
+fn is_jargon(word: &str, in_code: bool, starts_sentence: bool) -> bool {
+    if word.chars().count() < 2 || !word.chars().any(char::is_alphabetic) || word.contains('/') {
+        return false;
+    }
+    if in_code {
+        return true;
+    }
+    let mut chars = word.chars();
+    let first = chars.next().unwrap_or_default();
+    let rest = chars.as_str();
+    // CamelCase and acronyms
+    let inner_caps = rest.chars().any(char::is_uppercase);
+    let digits = word.chars().any(|c| c.is_ascii_digit());
+    // things like serde_json, std::fs and main.rs
+    let code_like = word.contains(['_', '.']) || word.contains("::");
+    // a capital that isn't there because it starts a sentence
+    let proper = first.is_uppercase() && !starts_sentence && !word.contains('\'');
+    inner_caps || digits || code_like || proper
+}
+
+/// The names and jargon in some chat lines, the most used (then the most recent) first
+///
+/// Lines can be in the `<nick> text` form they're kept in, and the nicks are left out.
+pub fn terms<'a>(lines: impl IntoIterator<Item = &'a str>) -> Vec<String> {
+    // how often each term was used, and when it was last used
+    let mut seen: HashMap<&str, (usize, usize)> = HashMap::new();
+    let mut position = 0;
+    for line in lines {
+        let text = line
+            .strip_prefix('<')
+            .and_then(|line| line.split_once("> "))
+            .map_or(line, |(_, text)| text);
+        let mut starts_sentence = true;
+        for token in text.split_whitespace() {
+            position += 1;
+            let in_code = token.starts_with('`') && token.ends_with('`') && token.len() > 2;
+            let word = token.trim_matches(|c: char| !c.is_alphanumeric() && c != '_');
+            if !token.starts_with("http") && is_jargon(word, in_code, starts_sentence) {
+                let entry = seen.entry(word).or_default();
+                entry.0 += 1;
+                entry.1 = position;
+            }
+            starts_sentence = token.ends_with(['.', '!', '?', ':']);
+        }
+    }
+    let mut terms: Vec<(&str, (usize, usize))> = seen.into_iter().collect();
+    terms.sort_by(|(_, a), (_, b)| b.cmp(a));
+    terms
+        .into_iter()
+        .map(|(term, _)| term.to_string())
+        .collect()
+}
+
+/// A prompt for Whisper that spells out the names and jargon used in some chat lines, so it
+/// recognizes them in a recording of the same discussion
+pub fn whisper_prompt<'a>(lines: impl IntoIterator<Item = &'a str>) -> Option<String> {
+    let mut prompt = String::new();
+    for term in terms(lines) {
+        if prompt.len() + term.len() + 2 > MAX_PROMPT_CHARS {
+            break;
+        }
+        if !prompt.is_empty() {
+            prompt.push_str(", ");
+        }
+        prompt.push_str(&term);
+    }
+    (!prompt.is_empty()).then_some(prompt)
+}
+
+#[test]
+fn test_jargon_terms() {
+    let lines = [
+        "<achin> Has anyone tried the new Overviewer build?",
+        "<agrif> It panics in serde_json when the config has a trailing comma.",
+        "<achin> Which version? I'm on v0.19 and `tokio` is fine. See https://Example.com/X",
+        "<agrif> The one with WebGL in it, and the Overviewer docs say so.",
+    ];
+    let terms = terms(lines);
+    // mentioned twice, so it's first
+    assert_eq!(terms[0], "Overviewer");
+    for term in ["serde_json", "v0.19", "tokio", "WebGL"] {
+        assert!(terms.iter().any(|t| t == term), "{term} is missing");
+    }
+    // sentence starts, nicks and links aren't jargon
+    for word in [
+        "Has",
+        "Which",
+        "I'm",
+        "The",
+        "achin",
+        "agrif",
+        "Example.com",
+    ] {
+        assert!(!terms.iter().any(|t| t.contains(word)), "{word} is there");
+    }
+
+    let prompt = whisper_prompt(lines).unwrap();
+    assert!(prompt.starts_with("Overviewer, "));
+    assert_eq!(whisper_prompt(["<achin> hello there"]), None);
+}
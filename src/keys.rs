@@ -0,0 +1,315 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+};
+
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::config::get_config;
+
+/// Name used for the key from `secrets.rs`, when no pool is configured
+const DEFAULT_KEY_NAME: &str = "default";
+
+/// Keys that were rate limited this recently are avoided, if there's another one to use
+const THROTTLE_COOLDOWN_SECS: i64 = 60;
+
+static USAGE: Mutex<Option<HashMap<String, KeyUsage>>> = Mutex::new(None);
+/// Where round robin got to
+static CURSOR: Mutex<usize> = Mutex::new(0);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyStrategy {
+    /// Take turns, with keys that have a higher weight taking more turns
+    #[default]
+    RoundRobin,
+    /// Use whichever key has gone longest without being rate limited
+    LeastRecentlyThrottled,
+}
+
+/// Several OpenAI keys to spread requests across, so one key's rate limit doesn't stall the bot
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct KeyPoolConfig {
+    pub strategy: KeyStrategy,
+    /// If this is empty, the key from `secrets.rs` is used for everything
+    pub keys: Vec<ApiKeyConfig>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiKeyConfig {
+    /// What to call the key in `!usage`, so the key itself is never shown
+    pub name: String,
+    pub key: String,
+    /// How many turns this key gets in round robin, relative to the others
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+    /// Requests this key can make in a minute before it's skipped
+    #[serde(default)]
+    pub requests_per_minute: Option<u32>,
+    /// Estimated dollars this key can spend in a day (UTC) before it's skipped
+    #[serde(default)]
+    pub daily_budget: Option<f64>,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+/// What a key has been used for since startup
+#[derive(Debug, Clone, Default)]
+struct KeyUsage {
+    requests: u64,
+    errors: u64,
+    throttled: u64,
+    last_throttled: Option<DateTime<Utc>>,
+    /// When recent requests were made, for the per-minute limit
+    recent: VecDeque<DateTime<Utc>>,
+    /// Estimated spending on `spent_day`
+    spent_today: f64,
+    spent_day: Option<NaiveDate>,
+    spent_total: f64,
+}
+
+impl KeyUsage {
+    fn requests_in_last_minute(&self, now: DateTime<Utc>) -> usize {
+        self.recent
+            .iter()
+            .filter(|&&t| now - t < Duration::minutes(1))
+            .count()
+    }
+    fn spent_on(&self, day: NaiveDate) -> f64 {
+        if self.spent_day == Some(day) {
+            self.spent_today
+        } else {
+            0.0
+        }
+    }
+    fn recently_throttled(&self, now: DateTime<Utc>) -> bool {
+        self.last_throttled
+            .is_some_and(|t| now - t < Duration::seconds(THROTTLE_COOLDOWN_SECS))
+    }
+}
+
+/// A key that was picked for a request
+#[derive(Debug, Clone)]
+pub struct PickedKey {
+    pub name: String,
+    pub key: String,
+}
+
+impl PickedKey {
+    /// Notes how a request with this key went, and roughly what it cost
+    pub fn record<T, E: std::fmt::Display>(&self, result: &Result<T, E>, cost: Option<f64>) {
+        let mut usage = USAGE.lock().expect("key usage lock is poisoned");
+        let usage = usage
+            .get_or_insert_with(HashMap::new)
+            .entry(self.name.clone())
+            .or_default();
+        record_at(usage, result, cost, Utc::now());
+    }
+}
+
+fn record_at<T, E: std::fmt::Display>(
+    usage: &mut KeyUsage,
+    result: &Result<T, E>,
+    cost: Option<f64>,
+    now: DateTime<Utc>,
+) {
+    usage.requests += 1;
+    usage.recent.push_back(now);
+    while usage
+        .recent
+        .front()
+        .is_some_and(|&t| now - t >= Duration::minutes(1))
+    {
+        usage.recent.pop_front();
+    }
+    if let Some(cost) = cost {
+        let today = now.date_naive();
+        if usage.spent_day != Some(today) {
+            usage.spent_day = Some(today);
+            usage.spent_today = 0.0;
+        }
+        usage.spent_today += cost;
+        usage.spent_total += cost;
+    }
+    if let Err(e) = result {
+        usage.errors += 1;
+        if e.to_string().to_lowercase().contains("rate limit") {
+            usage.throttled += 1;
+            usage.last_throttled = Some(now);
+        }
+    }
+}
+
+/// Picks the key to use for the next request
+pub fn pick() -> PickedKey {
+    let pool = get_config().unwrap_or_default().key_pool;
+    if pool.keys.is_empty() {
+        return PickedKey {
+            name: DEFAULT_KEY_NAME.to_string(),
+            key: crate::secrets::OPENAPI_KEY.to_string(),
+        };
+    }
+    let usage = USAGE
+        .lock()
+        .expect("key usage lock is poisoned")
+        .clone()
+        .unwrap_or_default();
+    let mut cursor = CURSOR.lock().expect("key cursor lock is poisoned");
+    let picked = choose(&pool, &usage, &mut cursor, Utc::now());
+    let key = &pool.keys[picked];
+    PickedKey {
+        name: key.name.clone(),
+        key: key.key.clone(),
+    }
+}
+
+/// Returns the index of the key to use
+///
+/// Keys that are over their limits, or were just rate limited, are skipped unless every key is.
+fn choose(
+    pool: &KeyPoolConfig,
+    usage: &HashMap<String, KeyUsage>,
+    cursor: &mut usize,
+    now: DateTime<Utc>,
+) -> usize {
+    let empty = KeyUsage::default();
+    let usage_of = |key: &ApiKeyConfig| usage.get(&key.name).unwrap_or(&empty);
+    let available = |key: &ApiKeyConfig| {
+        let usage = usage_of(key);
+        key.weight > 0
+            && !usage.recently_throttled(now)
+            && key
+                .requests_per_minute
+                .is_none_or(|limit| usage.requests_in_last_minute(now) < limit as usize)
+            && key
+                .daily_budget
+                .is_none_or(|budget| usage.spent_on(now.date_naive()) < budget)
+    };
+    let least_recently_throttled = |candidates: &mut dyn Iterator<Item = usize>| {
+        candidates
+            .min_by_key(|&idx| {
+                let usage = usage_of(&pool.keys[idx]);
+                (usage.last_throttled, usage.requests_in_last_minute(now))
+            })
+            .unwrap_or(0)
+    };
+
+    let candidates: Vec<usize> = (0..pool.keys.len())
+        .filter(|&idx| available(&pool.keys[idx]))
+        .collect();
+    if candidates.is_empty() {
+        // everything is busy, so use whichever key has had the longest to recover
+        return least_recently_throttled(&mut (0..pool.keys.len()));
+    }
+    match pool.strategy {
+        KeyStrategy::LeastRecentlyThrottled => {
+            least_recently_throttled(&mut candidates.iter().copied())
+        }
+        KeyStrategy::RoundRobin => {
+            // each key takes as many turns in a row as its weight
+            let turns: Vec<usize> = candidates
+                .iter()
+                .flat_map(|&idx| std::iter::repeat(idx).take(pool.keys[idx].weight as usize))
+                .collect();
+            let picked = turns[*cursor % turns.len()];
+            *cursor = cursor.wrapping_add(1);
+            picked
+        }
+    }
+}
+
+/// The keys that should never show up in logs
+pub fn all_keys() -> Vec<String> {
+    let pool = get_config().unwrap_or_default().key_pool;
+    pool.keys.into_iter().map(|key| key.key).collect()
+}
+
+/// One line per key, for `!usage`
+pub fn usage_report() -> String {
+    let usage = USAGE
+        .lock()
+        .expect("key usage lock is poisoned")
+        .clone()
+        .unwrap_or_default();
+    if usage.is_empty() {
+        return "No requests since startup".to_string();
+    }
+    let now = Utc::now();
+    let mut names: Vec<_> = usage.keys().collect();
+    names.sort();
+    names
+        .into_iter()
+        .map(|name| {
+            let usage = &usage[name];
+            let mut line = format!(
+                "{name}: {} requests ({} in the last minute), ${:.2} today, ${:.2} total",
+                usage.requests,
+                usage.requests_in_last_minute(now),
+                usage.spent_on(now.date_naive()),
+                usage.spent_total
+            );
+            if usage.errors > 0 {
+                line.push_str(&format!(", {} errors", usage.errors));
+            }
+            if usage.throttled > 0 {
+                line.push_str(&format!(", rate limited {} times", usage.throttled));
+            }
+            line
+        })
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+#[test]
+fn test_choose_key() {
+    let key = |name: &str, weight| ApiKeyConfig {
+        name: name.to_string(),
+        key: format!("sk-{name}"),
+        weight,
+        requests_per_minute: None,
+        daily_budget: None,
+    };
+    let mut pool = KeyPoolConfig {
+        strategy: KeyStrategy::RoundRobin,
+        keys: vec![key("a", 2), key("b", 1)],
+    };
+    let now: DateTime<Utc> = "2024-03-01T12:00:00Z".parse().unwrap();
+    let mut usage = HashMap::new();
+    let mut cursor = 0;
+    let picks: Vec<_> = (0..6)
+        .map(|_| choose(&pool, &usage, &mut cursor, now))
+        .collect();
+    assert_eq!(picks, [0, 0, 1, 0, 0, 1]);
+
+    // a rate limited key is skipped for a while
+    let mut throttled = KeyUsage::default();
+    record_at(
+        &mut throttled,
+        &Err::<(), _>("Rate limit reached for gpt-4o"),
+        None,
+        now,
+    );
+    assert_eq!(throttled.throttled, 1);
+    usage.insert("a".to_string(), throttled);
+    assert_eq!(choose(&pool, &usage, &mut cursor, now), 1);
+    assert_eq!(choose(&pool, &usage, &mut cursor, now), 1);
+    let later = now + Duration::minutes(2);
+    let picks: Vec<_> = (0..3)
+        .map(|_| choose(&pool, &usage, &mut cursor, later))
+        .collect();
+    assert!(picks.contains(&0));
+
+    // and so is one that's over budget
+    pool.keys[1].daily_budget = Some(1.0);
+    let mut spent = KeyUsage::default();
+    record_at(&mut spent, &Ok::<(), &str>(()), Some(1.5), now);
+    usage.insert("b".to_string(), spent);
+    pool.strategy = KeyStrategy::LeastRecentlyThrottled;
+    assert_eq!(choose(&pool, &usage, &mut cursor, later), 0);
+    // unless everything is unavailable
+    assert_eq!(choose(&pool, &usage, &mut cursor, now), 1);
+}
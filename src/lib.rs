@@ -1,74 +1,202 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     fs::File,
     path::Path,
-    sync::{Arc, Mutex},
+    sync::{Arc, Mutex, OnceLock},
 };
 
 use anyhow::Context;
 use async_openai::types::{
     ChatCompletionRequestAssistantMessage, ChatCompletionRequestMessage,
-    ChatCompletionRequestMessageContentPart, ChatCompletionRequestSystemMessage,
-    ChatCompletionRequestUserMessage, ChatCompletionRequestUserMessageContent, Role,
+    ChatCompletionRequestMessageContentPart, ChatCompletionRequestMessageContentPartText,
+    ChatCompletionRequestSystemMessage, ChatCompletionRequestUserMessage,
+    ChatCompletionRequestUserMessageContent,
 };
 use chrono::{DateTime, Utc};
+use config::{get_config, UploadMethod, UploaderConfig};
 // use numbat::markup::Formatter;
+use images::ArchivedImage;
+use retention::Retention;
 use serde::{Deserialize, Serialize};
 use wasmtime::{
     component::ResourceAny,
     Store,
 };
 
-// pub mod plugins;
-
+pub mod addressing;
+pub mod api;
+pub mod archive;
+pub mod audit;
+pub mod autoclear;
+pub mod breaker;
+pub mod chattiness;
+pub mod config;
+pub mod convert;
+mod dashboard;
+pub mod dcc;
+pub mod discord;
+pub mod documents;
+pub mod embeddings;
+pub mod fallback;
+pub mod faq;
+pub mod features;
+pub mod feedback;
+pub mod formatting;
+pub mod frontend;
+pub mod fx;
+pub mod highlight;
+pub mod history;
+pub mod http;
+pub mod images;
+pub mod jargon;
+pub mod keys;
+pub mod listen;
+pub mod localtime;
+pub mod loops;
+pub mod matrix;
 pub mod openai;
+pub mod paste;
+pub mod plugins;
+pub mod poll;
+pub mod prefs;
+pub mod profiles;
+pub mod progress;
+pub mod relay;
+pub mod retention;
+pub mod sandbox;
 mod secrets;
+pub mod selftest;
+pub mod stats;
+pub mod threads;
+pub mod tools;
+pub mod transcript;
+pub mod triggers;
+pub mod trivia;
+pub mod uploads;
+mod webhooks;
 pub mod wttr;
+pub mod youtube;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatMessageThing {
     /// When this message was generated
     pub date: DateTime<Utc>,
+    #[serde(with = "history::api_message")]
     pub msg: ChatCompletionRequestMessage,
+    /// Images in this message that were rehosted, so they outlive the original links
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub archived_images: Vec<ArchivedImage>,
+    /// Votes on this message, if it's one of our replies
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub feedback: Vec<feedback::Feedback>,
+    /// The model that wrote this message, if it's one of our replies
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
 }
 
 impl ChatMessageThing {
     pub fn new_now(msg: ChatCompletionRequestMessage) -> Self {
+        Self::new_at(msg, Utc::now())
+    }
+    pub fn new_at(msg: ChatCompletionRequestMessage, date: DateTime<Utc>) -> Self {
         Self {
-            date: Utc::now(),
+            date,
             msg,
+            archived_images: Vec::new(),
+            feedback: Vec::new(),
+            model: None,
         }
     }
-    pub fn reconstitute(self) -> Self {
-        let msg = match self.msg {
-            ChatCompletionRequestMessage::System(ChatCompletionRequestSystemMessage {
+    /// Folds `next` into this message if they're both lines from the same nick, no more than
+    /// `window` apart
+    ///
+    /// Gives `next` back if it can't be merged.
+    pub fn merge(
+        &mut self,
+        next: ChatMessageThing,
+        window: chrono::Duration,
+    ) -> Result<(), ChatMessageThing> {
+        let (
+            ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
                 content,
-                role,
-                name,
-            }) => {
-                if role == Role::User {
-                    ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
-                        content: ChatCompletionRequestUserMessageContent::Text(content),
-                        role,
-                        name,
-                    })
-                } else {
-                    ChatCompletionRequestMessage::System(ChatCompletionRequestSystemMessage {
-                        content,
-                        role,
-                        name,
-                    })
-                }
+                name: Some(name),
+                ..
+            }),
+            ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
+                content: next_content,
+                name: Some(next_name),
+                ..
+            }),
+        ) = (&mut self.msg, &next.msg)
+        else {
+            return Err(next);
+        };
+        if name != next_name || next.date < self.date || next.date - self.date > window {
+            return Err(next);
+        }
+
+        // the nick is already at the start of this message, so it's only needed once
+        let prefix = format!("<{name}> ");
+        let strip = |text: &str| text.strip_prefix(&prefix).unwrap_or(text).to_string();
+        let mut next_parts = match next_content {
+            ChatCompletionRequestUserMessageContent::Text(text) => {
+                vec![ChatCompletionRequestMessageContentPartText::from(strip(text)).into()]
             }
-            other => other,
+            ChatCompletionRequestUserMessageContent::Array(parts) => parts.clone(),
         };
-        ChatMessageThing {
-            date: self.date,
-            msg,
+        let next_text = match next_parts.first() {
+            Some(ChatCompletionRequestMessageContentPart::Text(part)) => {
+                let text = strip(&part.text);
+                next_parts.remove(0);
+                Some(text)
+            }
+            _ => None,
+        };
+
+        match content {
+            ChatCompletionRequestUserMessageContent::Text(text) if next_parts.is_empty() => {
+                if let Some(next_text) = next_text {
+                    text.push('\n');
+                    text.push_str(&next_text);
+                }
+            }
+            _ => {
+                let mut parts = match std::mem::replace(
+                    content,
+                    ChatCompletionRequestUserMessageContent::Array(Vec::new()),
+                ) {
+                    ChatCompletionRequestUserMessageContent::Text(text) => {
+                        vec![ChatCompletionRequestMessageContentPartText::from(text).into()]
+                    }
+                    ChatCompletionRequestUserMessageContent::Array(parts) => parts,
+                };
+                if let Some(next_text) = next_text {
+                    match parts.first_mut() {
+                        Some(ChatCompletionRequestMessageContentPart::Text(part)) => {
+                            part.text.push('\n');
+                            part.text.push_str(&next_text);
+                        }
+                        _ => parts.insert(
+                            0,
+                            ChatCompletionRequestMessageContentPartText::from(next_text).into(),
+                        ),
+                    }
+                }
+                parts.extend(next_parts);
+                *content = ChatCompletionRequestUserMessageContent::Array(parts);
+            }
         }
+        self.date = next.date;
+        self.archived_images.extend(next.archived_images);
+        Ok(())
     }
-    pub fn get_for_api(&self, now: DateTime<Utc>) -> ChatCompletionRequestMessage {
-        if now - self.date < chrono::Duration::hours(1) {
+    /// This message as it should be sent to the API, without any images that are too old
+    pub fn get_for_api(
+        &self,
+        retention: &Retention,
+        now: DateTime<Utc>,
+    ) -> ChatCompletionRequestMessage {
+        if retention.sends_images(self.date, now) {
             return self.msg.clone();
         }
         match &self.msg {
@@ -93,6 +221,84 @@ impl ChatMessageThing {
             _ => self.msg.clone(),
         }
     }
+    /// How many images are attached to this message
+    pub fn image_count(&self) -> usize {
+        match &self.msg {
+            ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
+                content: ChatCompletionRequestUserMessageContent::Array(arr),
+                ..
+            }) => arr
+                .iter()
+                .filter(|part| matches!(part, ChatCompletionRequestMessageContentPart::Image(..)))
+                .count(),
+            _ => 0,
+        }
+    }
+    /// Links to the images attached to this message
+    pub fn image_urls(&self) -> Vec<String> {
+        match &self.msg {
+            ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
+                content: ChatCompletionRequestUserMessageContent::Array(arr),
+                ..
+            }) => arr
+                .iter()
+                .filter_map(|part| match part {
+                    ChatCompletionRequestMessageContentPart::Image(image) => {
+                        Some(image.image_url.url.clone())
+                    }
+                    _ => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+    /// Points this message's links to an image at the copy it was rehosted to
+    ///
+    /// Returns whether the image was in this message.
+    pub fn rehost_image(&mut self, archived: &ArchivedImage) -> bool {
+        let ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
+            content: ChatCompletionRequestUserMessageContent::Array(arr),
+            ..
+        }) = &mut self.msg
+        else {
+            return false;
+        };
+        let mut found = false;
+        for part in arr {
+            if let ChatCompletionRequestMessageContentPart::Image(image) = part {
+                if image.image_url.url == archived.original {
+                    image.image_url.url = archived.url.clone();
+                    found = true;
+                }
+            }
+        }
+        if found {
+            self.archived_images.push(archived.clone());
+        }
+        found
+    }
+    /// A rough guess at how many tokens this message uses when it's sent to the API
+    ///
+    /// This assumes about 4 characters per token, and that any images get sent (see `get_for_api`)
+    /// at a typical size.
+    pub fn estimate_tokens(&self, retention: &Retention, now: DateTime<Utc>) -> usize {
+        let text = self.get_as_irc_format().unwrap_or_default();
+        let images = if retention.sends_images(self.date, now) {
+            self.image_count()
+        } else {
+            0
+        };
+        4 + text.len().div_ceil(4) + images * openai::IMAGE_TOKEN_ESTIMATE
+    }
+    /// The nick of the user who sent this message, if it came from a user
+    pub fn get_sender(&self) -> Option<&str> {
+        match &self.msg {
+            ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
+                name, ..
+            }) => name.as_deref(),
+            _ => None,
+        }
+    }
     pub fn get_as_irc_format(&self) -> Option<&str> {
         match &self.msg {
             ChatCompletionRequestMessage::System(ChatCompletionRequestSystemMessage {
@@ -125,27 +331,149 @@ impl ChatMessageThing {
     }
 }
 
-/// Upload some content to up.em32.site and return a URL
-///
-///
-pub async fn upload_content(data: Vec<u8>, content_type: &str) -> anyhow::Result<String> {
-    let client = reqwest::Client::builder().build()?;
+#[test]
+fn test_merge_lines() {
+    let start = Utc::now();
+    let line = |nick: &str, text: &str, secs| {
+        ChatMessageThing::new_at(
+            ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
+                content: ChatCompletionRequestUserMessageContent::Text(format!("<{nick}> {text}")),
+                role: async_openai::types::Role::User,
+                name: Some(nick.to_string()),
+            }),
+            start + chrono::Duration::seconds(secs),
+        )
+    };
+    let window = chrono::Duration::seconds(20);
+
+    let mut first = line("achin", "so I was thinking", 0);
+    first
+        .merge(line("achin", "about lunch", 5), window)
+        .unwrap();
+    // the window is from the latest line, so a thought can keep going
+    first.merge(line("achin", "tacos?", 24), window).unwrap();
+    assert_eq!(
+        first.get_as_irc_format(),
+        Some("<achin> so I was thinking\nabout lunch\ntacos?")
+    );
+    assert_eq!(first.date, start + chrono::Duration::seconds(24));
+
+    assert!(first.merge(line("em32", "sure", 25), window).is_err());
+    assert!(first.merge(line("achin", "hello?", 60), window).is_err());
+
+    // an image in the next line turns the merged message into parts
+    let mut image = line("achin", "look", 30);
+    image.msg = ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
+        content: ChatCompletionRequestUserMessageContent::Array(vec![
+            ChatCompletionRequestMessageContentPartText::from("<achin> look").into(),
+            async_openai::types::ChatCompletionRequestMessageContentPartImage {
+                r#type: "image_url".into(),
+                image_url: "https://example.com/taco.png".into(),
+            }
+            .into(),
+        ]),
+        role: async_openai::types::Role::User,
+        name: Some("achin".to_string()),
+    });
+    first.merge(image, window).unwrap();
+    assert_eq!(
+        first.get_as_irc_format(),
+        Some("<achin> so I was thinking\nabout lunch\ntacos?\nlook")
+    );
+    let ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
+        content: ChatCompletionRequestUserMessageContent::Array(parts),
+        ..
+    }) = &first.msg
+    else {
+        panic!("expected the parts of a user message");
+    };
+    assert_eq!(parts.len(), 2);
+}
 
-    let upload_resp = client
-        .put("https://up.em32.site")
+async fn upload_to(
+    client: &reqwest::Client,
+    uploader: &UploaderConfig,
+    data: Vec<u8>,
+    content_type: &str,
+) -> anyhow::Result<uploads::Upload> {
+    let mut request = match uploader.method {
+        UploadMethod::Put => client.put(&uploader.url),
+        UploadMethod::Post => client.post(&uploader.url),
+    };
+    let expiry_hours = get_config().ok().and_then(|c| c.upload_expiry_hours);
+    let expires_header = uploader.expires_header.as_ref().zip(expiry_hours);
+    if let Some((header, hours)) = expires_header {
+        request = request.header(header, hours.to_string());
+    }
+    let upload_resp = request
         .header("Content-Type", content_type)
         .body(data)
         .send()
         .await
-        .context("Failed to upload text")?;
-
+        .context("Failed to upload text")?
+        .error_for_status()?;
+
+    let token = uploader
+        .token_header
+        .as_ref()
+        .and_then(|header| upload_resp.headers().get(header))
+        .and_then(|token| token.to_str().ok())
+        .map(|token| token.to_string());
     let url = upload_resp.text().await?;
+    let url = url.trim();
     if url.starts_with("https://") {
-        return Ok(url);
+        let date = Utc::now();
+        return Ok(uploads::Upload {
+            url: url.to_string(),
+            date,
+            content_type: content_type.to_string(),
+            uploader: uploader.url.clone(),
+            expires: expires_header.map(|(_, hours)| date + chrono::Duration::hours(hours.into())),
+            token,
+        });
     }
     anyhow::bail!("Unexpected error uploading")
 }
 
+/// up.em32.site, followed by the `upload_fallbacks` from the config
+pub fn uploaders() -> Vec<UploaderConfig> {
+    let primary = UploaderConfig {
+        url: "https://up.em32.site".to_string(),
+        method: UploadMethod::Put,
+        expires_header: None,
+        deletable: false,
+        token_header: None,
+    };
+    let fallbacks = get_config().map(|c| c.upload_fallbacks).unwrap_or_default();
+    std::iter::once(primary).chain(fallbacks).collect()
+}
+
+/// Upload some content to up.em32.site and return a URL
+///
+/// If that fails, the `upload_fallbacks` from the config are tried in order.  Every upload is
+/// recorded in the ledger, so old ones can be purged later.
+pub async fn upload_content(data: Vec<u8>, content_type: &str) -> anyhow::Result<String> {
+    let client = crate::http::client_builder()?
+        .timeout(std::time::Duration::from_secs(30))
+        .build()?;
+
+    let mut error = None;
+    for uploader in uploaders() {
+        match upload_to(&client, &uploader, data.clone(), content_type).await {
+            Ok(upload) => {
+                let url = upload.url.clone();
+                uploads::record(upload);
+                return Ok(url);
+            }
+            Err(e) => {
+                println!("Failed to upload to {}: {e}", uploader.url);
+                error = Some(e);
+            }
+        }
+    }
+    Err(error.expect("there's always at least one uploader"))
+}
+
 #[tokio::test]
 async fn test_upload() {
     let data = "hello world";
@@ -162,6 +490,19 @@ pub fn get_prompt(key: &str) -> anyhow::Result<String> {
     Ok(prompts.remove(key).context("Prompt not found")?)
 }
 
+/// Replaces one prompt in prompts.json, or adds it if it's new
+pub fn set_prompt(key: &str, prompt: &str) -> anyhow::Result<()> {
+    let mut prompts: BTreeMap<String, String> = match File::open("prompts.json") {
+        Ok(file) => serde_json::from_reader(file).context("prompts.json is invalid")?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+        Err(e) => return Err(e.into()),
+    };
+    prompts.insert(key.to_string(), prompt.to_string());
+    let output = File::create("prompts.json")?;
+    serde_json::to_writer_pretty(output, &prompts)?;
+    Ok(())
+}
+
 pub async fn generate_interjection(
     channel_messages: &[ChatMessageThing],
 ) -> anyhow::Result<Option<String>> {
@@ -257,12 +598,128 @@ pub async fn generate_image_prompt(
             if m.contains("no image") {
                 return Ok(None);
             }
-            return Ok(Some(openai::get_image(m.trim_matches('"')).await?));
+            let image = openai::get_image(m.trim_matches('"'), None).await?;
+            return Ok(Some(image.links()));
         }
     }
     Ok(None)
 }
 
+/// Expands a rough idea into a detailed prompt for DALL-E
+pub async fn enhance_image_prompt(idea: &str) -> anyhow::Result<String> {
+    let instruction = get_prompt("imagine").unwrap_or_else(|_| {
+        "Expand the idea below into a detailed prompt for an image generator.  Describe the \
+         subject, setting, style, lighting and composition in a few sentences.  Reply with only \
+         the prompt."
+            .to_string()
+    });
+
+    let completion_messages = vec![
+        ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
+            content: ChatCompletionRequestUserMessageContent::Text(instruction),
+            role: async_openai::types::Role::User,
+            name: None,
+        }),
+        ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
+            content: ChatCompletionRequestUserMessageContent::Text(idea.to_string()),
+            role: async_openai::types::Role::User,
+            name: None,
+        }),
+    ];
+
+    let resp = openai::get_chat(completion_messages, Some("gpt-4o"), Some(0.8)).await?;
+    resp.get(0)
+        .and_then(|m| m.content.as_deref())
+        .map(|prompt| prompt.trim().trim_matches('"').to_string())
+        .context("No prompt in response")
+}
+
+/// Summarizes a conversation, for reading by a human
+pub async fn generate_summary(channel_messages: &[ChatMessageThing]) -> anyhow::Result<String> {
+    let mut all_msg = String::new();
+    for msg in channel_messages
+        .iter()
+        .filter_map(|msg| msg.get_as_irc_format())
+    {
+        all_msg.push_str(msg);
+        all_msg.push('\n');
+    }
+    if all_msg.is_empty() {
+        anyhow::bail!("There's nothing to summarize");
+    }
+
+    let instruction = get_prompt("summarize").unwrap_or_else(|_| {
+        "Summarize the IRC conversation {AB}. Mention who said what, and keep it brief.".to_string()
+    });
+
+    let completion_messages = vec![
+        ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
+            content: ChatCompletionRequestUserMessageContent::Text(
+                instruction.replace("{AB}", "below"),
+            ),
+            role: async_openai::types::Role::User,
+            name: None,
+        }),
+        ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
+            content: ChatCompletionRequestUserMessageContent::Text(all_msg),
+            role: async_openai::types::Role::User,
+            name: None,
+        }),
+        ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
+            content: ChatCompletionRequestUserMessageContent::Text(
+                instruction.replace("{AB}", "above"),
+            ),
+            role: async_openai::types::Role::User,
+            name: None,
+        }),
+    ];
+
+    let resp = openai::get_chat(completion_messages, Some("gpt-4o"), Some(0.3)).await?;
+    resp.get(0)
+        .and_then(|m| m.content.clone())
+        .context("No summary in response")
+}
+
+/// How much text is summarized in a single request
+const SUMMARY_CHUNK_CHARS: usize = 48_000;
+/// Longer text than this (in chunks) is refused, rather than running up a large bill
+const SUMMARY_MAX_CHUNKS: usize = 20;
+
+/// Summarizes a long piece of text, following the given instruction
+///
+/// Text that's too long for one request is split into chunks, each chunk is summarized on its
+/// own, and then the instruction is applied to the combined summaries.
+pub async fn summarize_long_text(text: &str, instruction: &str) -> anyhow::Result<String> {
+    let chunks = openai::split_for_tts(text, SUMMARY_CHUNK_CHARS);
+    if chunks.is_empty() {
+        anyhow::bail!("There's nothing to summarize");
+    }
+    if chunks.len() > SUMMARY_MAX_CHUNKS {
+        anyhow::bail!("That's too long to summarize ({} characters)", text.len());
+    }
+
+    let text = if chunks.len() == 1 {
+        text.to_string()
+    } else {
+        let mut partial = Vec::new();
+        for (idx, chunk) in chunks.iter().enumerate() {
+            let prompt = format!(
+                "This is part {} of {} of a longer text.  Summarize this part in detail, \
+                 keeping any important facts, names and numbers, and anything relevant to \
+                 this request: {instruction}\n\n{chunk}",
+                idx + 1,
+                chunks.len()
+            );
+            let (summary, _) = openai::get_completion(&prompt, 1024).await?;
+            partial.push(summary);
+        }
+        partial.join("\n\n")
+    };
+
+    let (summary, _) = openai::get_completion(&format!("{instruction}\n\n{text}"), 1024).await?;
+    Ok(summary)
+}
+
 // struct IRCFormatter;
 
 // impl numbat::markup::Formatter for IRCFormatter {
@@ -320,7 +777,7 @@ pub async fn generate_image_prompt(
 wasmtime::component::bindgen!({
     path: "world.wit",
     world: "example",
-    async: false
+    async: true
 });
 
 struct MyState {
@@ -350,6 +807,41 @@ impl MyState {
     }
 }
 
+/// The engine shared by all numbat components
+///
+/// Epoch interruption is used to make long evaluations yield back to the tokio runtime
+/// every tick, so they don't stall the IRC event loop.
+fn numbat_engine() -> &'static wasmtime::Engine {
+    static ENGINE: OnceLock<wasmtime::Engine> = OnceLock::new();
+    ENGINE.get_or_init(|| {
+        let mut config = wasmtime::Config::default();
+        config.wasm_component_model(true);
+        config.async_support(true);
+        config.epoch_interruption(true);
+        let engine = wasmtime::Engine::new(&config).expect("Failed to create wasmtime engine");
+
+        let ticker = engine.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            ticker.increment_epoch();
+        });
+
+        engine
+    })
+}
+
+/// An error reported by numbat itself (like a syntax error), as opposed to the component crashing
+#[derive(Debug)]
+pub struct NumbatError(pub String);
+
+impl std::fmt::Display for NumbatError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for NumbatError {}
+
 pub struct NumbatComponent {
     store: Store<MyState>,
     inst: Example,
@@ -357,26 +849,28 @@ pub struct NumbatComponent {
 }
 
 impl NumbatComponent {
-    pub fn new(path: impl AsRef<Path>) -> anyhow::Result<Self> {
-        let mut config = wasmtime::Config::default();
-        config.wasm_component_model(true);
-        // config.async_support(true);
-
-        let engine = wasmtime::Engine::new(&config)?;
-        let mut linker = wasmtime::component::Linker::new(&engine);
+    pub async fn new(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let engine = numbat_engine();
+        let mut linker = wasmtime::component::Linker::new(engine);
 
         let wasi_view = MyState::new();
 
-        let mut store = wasmtime::Store::new(&engine, wasi_view);
+        let mut store = wasmtime::Store::new(engine, wasi_view);
+        store.epoch_deadline_async_yield_and_update(1);
 
-        let component = wasmtime::component::Component::from_file(&engine, path)?;
+        // compiling the component is slow and synchronous, so keep it off the async runtime
+        let path = path.as_ref().to_path_buf();
+        let component = tokio::task::spawn_blocking(move || {
+            wasmtime::component::Component::from_file(engine, path)
+        })
+        .await??;
 
-        wasmtime_wasi::add_to_linker_sync(&mut linker)?;
+        wasmtime_wasi::add_to_linker_async(&mut linker)?;
 
-        let (inst, _) = Example::instantiate(&mut store, &component, &linker)?;
+        let (inst, _) = Example::instantiate_async(&mut store, &component, &linker).await?;
 
         let x = inst.component_numbat_component_numbat();
-        let y = x.ctx().call_constructor(&mut store)?;
+        let y = x.ctx().call_constructor(&mut store).await?;
 
         Ok(Self {
             store,
@@ -385,27 +879,32 @@ impl NumbatComponent {
         })
     }
 
-    pub fn eval(&mut self, input: &str) -> anyhow::Result<String> {
+    /// Evaluates some numbat code
+    ///
+    /// Errors from numbat are returned as a [`NumbatError`].  Any other error means the
+    /// component trapped, and this session shouldn't be used again.
+    pub async fn eval(&mut self, input: &str) -> anyhow::Result<String> {
         let guest = self.inst.component_numbat_component_numbat();
 
         let output = guest
             .ctx()
-            .call_eval(&mut self.store, self.inner_ctx, input)?
-            .map_err(|s| anyhow::anyhow!(s))?;
+            .call_eval(&mut self.store, self.inner_ctx, input)
+            .await?
+            .map_err(NumbatError)?;
 
         Ok(output)
     }
 }
 
-#[test]
-fn test_wasmtime() -> anyhow::Result<()> {
-    let mut comp = NumbatComponent::new("numbat_component.wasm")?;
-    let x = comp.eval("let x = 1")?;
+#[tokio::test]
+async fn test_wasmtime() -> anyhow::Result<()> {
+    let mut comp = NumbatComponent::new("numbat_component.wasm").await?;
+    let x = comp.eval("let x = 1").await?;
     dbg!(x);
-    let y = comp.eval("x * 2")?;
+    let y = comp.eval("x * 2").await?;
     dbg!(y);
 
-    let z = comp.eval("panic");
+    let z = comp.eval("panic").await;
     dbg!(z);
 
     // let mut comp = NumbatComponent::new("numbat_component.wasm")?;
@@ -414,3 +913,32 @@ fn test_wasmtime() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_rehost_image() {
+    let mut cmt = ChatMessageThing::new_now(ChatCompletionRequestMessage::User(
+        ChatCompletionRequestUserMessage {
+            content: ChatCompletionRequestUserMessageContent::Array(vec![
+                ChatCompletionRequestMessageContentPartText::from("<achin> look").into(),
+                async_openai::types::ChatCompletionRequestMessageContentPartImage {
+                    r#type: "image_url".into(),
+                    image_url: "https://example.com/taco.png".into(),
+                }
+                .into(),
+            ]),
+            role: async_openai::types::Role::User,
+            name: Some("achin".to_string()),
+        },
+    ));
+    assert_eq!(cmt.image_urls(), ["https://example.com/taco.png"]);
+    let archived = ArchivedImage {
+        original: "https://example.com/taco.png".to_string(),
+        url: "https://uploads.example.com/1.png".to_string(),
+        thumbnail: None,
+    };
+    assert!(cmt.rehost_image(&archived));
+    assert_eq!(cmt.image_urls(), ["https://uploads.example.com/1.png"]);
+    assert_eq!(cmt.archived_images.len(), 1);
+    // it's only rehosted once
+    assert!(!cmt.rehost_image(&archived));
+}
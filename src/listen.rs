@@ -0,0 +1,250 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::{bail, Context};
+use chrono::{DateTime, Duration, Utc};
+use tokio::process::Command;
+
+use crate::{
+    get_prompt,
+    openai::{self, megabytes, TimedTranscript, TranscriptSegment},
+    progress::Progress,
+    summarize_long_text, upload_content,
+};
+
+/// Length of each piece that audio too big to transcribe in one go is cut into
+///
+/// Ten minutes of even fairly high quality audio is well under whisper's limit.
+const CHUNK_SECS: u32 = 10 * 60;
+
+/// Biggest recording `!listen` will download, even though it can be cut into pieces
+const MAX_RECORDING_BYTES: u64 = 200 * 1024 * 1024;
+
+/// Most pieces a recording is cut into, which makes two hours the longest one that's transcribed
+const MAX_CHUNKS: usize = 12;
+
+/// How long someone has to wait after starting `!listen` before starting it again
+const COOLDOWN_MINUTES: i64 = 15;
+
+/// When each nick (lowercased) last started `!listen`
+static LAST_LISTEN: Mutex<Option<HashMap<String, DateTime<Utc>>>> = Mutex::new(None);
+
+/// What `!listen` found out about a recording
+pub struct Listened {
+    pub summary: String,
+    /// Where the full transcript was uploaded
+    pub transcript_url: String,
+}
+
+/// Starts `nick`'s cooldown, unless they're still in the last one
+pub fn start_cooldown(nick: &str, now: DateTime<Utc>) -> anyhow::Result<()> {
+    let mut last = LAST_LISTEN.lock().expect("last listen lock is poisoned");
+    let last = last.get_or_insert_with(HashMap::new);
+    let cooldown = Duration::minutes(COOLDOWN_MINUTES);
+    last.retain(|_, at| now - *at < cooldown);
+    if let Some(at) = last.get(&nick.to_lowercase()) {
+        bail!(
+            "You can listen to something else in {} minutes",
+            (*at + cooldown - now).num_minutes() + 1
+        );
+    }
+    last.insert(nick.to_lowercase(), now);
+    Ok(())
+}
+
+/// Downloads a recording, transcribes it, and summarizes what was said
+pub async fn listen(url: &str, progress: &mut Progress) -> anyhow::Result<Listened> {
+    let (filename, audio) = openai::download_audio(url, MAX_RECORDING_BYTES, progress).await?;
+    let segments = transcribe_long(&filename, audio, progress).await?;
+    if segments.iter().all(|s| s.text.is_empty()) {
+        bail!("Nobody seems to say anything in that");
+    }
+    let transcript = format_transcript(&segments);
+
+    let transcript_url = upload_content(transcript.clone().into_bytes(), "text/plain").await?;
+
+    let instruction = get_prompt("listen").unwrap_or_else(|_| {
+        "Below is a transcript of a recording, with a timestamp at the start of each line.  \
+         Summarize it in a few sentences, then list the key points, each starting with the \
+         timestamp of where it's discussed, like [12:34].  Keep it brief."
+            .to_string()
+    });
+    let summary = progress
+        .wait_for(
+            "summarizing the transcript…",
+            summarize_long_text(&transcript, &instruction),
+        )
+        .await?;
+    Ok(Listened {
+        summary,
+        transcript_url,
+    })
+}
+
+/// Transcribes a recording of any length, cutting it into pieces if it's too big for one request
+async fn transcribe_long(
+    filename: &str,
+    audio: bytes::Bytes,
+    progress: &mut Progress,
+) -> anyhow::Result<Vec<TranscriptSegment>> {
+    let max_bytes = openai::transcription_max_bytes();
+    if audio.len() as u64 <= max_bytes {
+        let notice = format!("transcribing {} of audio…", megabytes(audio.len()));
+        let transcript = progress
+            .wait_for(&notice, openai::transcribe_timed(filename, audio, None))
+            .await?;
+        return Ok(join_transcripts(vec![transcript]));
+    }
+
+    let (_dir, chunks) = split_audio(filename, &audio).await?;
+    if chunks.len() > MAX_CHUNKS {
+        bail!(
+            "That's too long to listen to (the most is {} minutes)",
+            MAX_CHUNKS as u32 * CHUNK_SECS / 60
+        );
+    }
+    let mut transcripts = Vec::new();
+    for (idx, chunk) in chunks.iter().enumerate() {
+        let chunk_name = chunk
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or(filename);
+        let data = tokio::fs::read(chunk).await?;
+        if data.len() as u64 > max_bytes {
+            bail!(
+                "Even cut into pieces, that's too much audio ({} for {} minutes)",
+                megabytes(data.len()),
+                CHUNK_SECS / 60
+            );
+        }
+        let notice = format!("transcribing part {} of {}…", idx + 1, chunks.len());
+        let transcript = progress
+            .wait_for(
+                &notice,
+                openai::transcribe_timed(chunk_name, data.into(), None),
+            )
+            .await?;
+        transcripts.push(transcript);
+    }
+    Ok(join_transcripts(transcripts))
+}
+
+/// Cuts audio into `CHUNK_SECS` pieces with ffmpeg, returning the pieces in order
+///
+/// The pieces are in the returned temporary directory, which is deleted when it's dropped.
+async fn split_audio(
+    filename: &str,
+    audio: &[u8],
+) -> anyhow::Result<(tempfile::TempDir, Vec<PathBuf>)> {
+    let dir = tempfile::tempdir()?;
+    let ext = Path::new(filename)
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or("ogg");
+    let input = dir.path().join(format!("input.{ext}"));
+    tokio::fs::write(&input, audio).await?;
+
+    let output = Command::new("ffmpeg")
+        .args(["-nostdin", "-loglevel", "error", "-i"])
+        .arg(&input)
+        .args(["-f", "segment", "-segment_time", &CHUNK_SECS.to_string()])
+        .args(["-c", "copy"])
+        .arg(dir.path().join(format!("chunk%04d.{ext}")))
+        .kill_on_drop(true)
+        .output()
+        .await
+        .context("Failed to run ffmpeg")?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        bail!(
+            "ffmpeg failed: {}",
+            stderr.lines().last().unwrap_or("unknown error")
+        );
+    }
+
+    let mut paths: Vec<PathBuf> = std::fs::read_dir(dir.path())?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| {
+            p.file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| n.starts_with("chunk"))
+        })
+        .collect();
+    // the names are numbered in order
+    paths.sort();
+    if paths.is_empty() {
+        bail!("ffmpeg didn't produce any audio");
+    }
+    Ok((dir, paths))
+}
+
+/// Puts the transcripts of consecutive pieces of audio together, shifting each piece's
+/// timestamps to where it starts in the whole recording
+fn join_transcripts(transcripts: Vec<TimedTranscript>) -> Vec<TranscriptSegment> {
+    let mut offset = 0.0;
+    let mut joined = Vec::new();
+    for transcript in transcripts {
+        joined.extend(transcript.segments.into_iter().map(|s| TranscriptSegment {
+            start: s.start + offset,
+            text: s.text,
+        }));
+        offset += transcript.duration;
+    }
+    joined
+}
+
+/// "12:34", or "1:02:03" for anything over an hour
+fn format_timestamp(secs: f32) -> String {
+    let secs = secs.max(0.0) as u64;
+    let (hours, mins, secs) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if hours > 0 {
+        format!("{hours}:{mins:02}:{secs:02}")
+    } else {
+        format!("{mins:02}:{secs:02}")
+    }
+}
+
+/// One line per segment, each starting with its timestamp
+fn format_transcript(segments: &[TranscriptSegment]) -> String {
+    segments
+        .iter()
+        .filter(|s| !s.text.is_empty())
+        .map(|s| format!("[{}] {}\n", format_timestamp(s.start), s.text))
+        .collect()
+}
+
+#[test]
+fn test_join_transcripts() {
+    let segment = |start, text: &str| TranscriptSegment {
+        start,
+        text: text.to_string(),
+    };
+    let joined = join_transcripts(vec![
+        TimedTranscript {
+            duration: 3590.0,
+            segments: vec![segment(0.0, "Hello"), segment(61.5, "and welcome")],
+        },
+        TimedTranscript {
+            duration: 300.0,
+            segments: vec![segment(10.0, "to the show"), segment(12.0, "")],
+        },
+    ]);
+    assert_eq!(joined[2], segment(3600.0, "to the show"));
+    assert_eq!(
+        format_transcript(&joined),
+        "[00:00] Hello\n[01:01] and welcome\n[1:00:00] to the show\n"
+    );
+}
+
+#[test]
+fn test_start_cooldown() {
+    let now = Utc::now();
+    assert!(start_cooldown("listener", now).is_ok());
+    assert!(start_cooldown("Listener", now + Duration::minutes(1)).is_err());
+    assert!(start_cooldown("someone-else", now).is_ok());
+    assert!(start_cooldown("listener", now + Duration::minutes(COOLDOWN_MINUTES)).is_ok());
+}
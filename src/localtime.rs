@@ -0,0 +1,125 @@
+use anyhow::{bail, Context};
+use chrono::{DateTime, Utc};
+use chrono_tz::{Tz, TZ_VARIANTS};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::wttr::{self, or_list, PlaceLookup, WeatherInput};
+
+/// What the model's time tool is given
+#[derive(JsonSchema, Serialize, Deserialize, Debug)]
+pub struct TimeInput {
+    /// A place, like "Tokyo" or "Springfield, IL", or a timezone, like "Europe/Paris" or "UTC"
+    pub location: String,
+}
+
+/// The time somewhere
+#[derive(Debug)]
+pub struct LocalTime {
+    /// Where it is, as the geocoder put it (or the timezone, if that's what was asked about)
+    pub place: String,
+    pub time: DateTime<Tz>,
+}
+
+impl LocalTime {
+    /// The time as compact JSON, for answering the model's time tool
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "place": self.place,
+            "timezone": self.time.timezone().name(),
+            "local_time": self.time.format("%Y-%m-%d %H:%M (%A)").to_string(),
+            "utc_offset": self.time.format("%:z").to_string(),
+        })
+    }
+}
+
+impl std::fmt::Display for LocalTime {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: {} ({}, UTC{})",
+            self.place,
+            self.time.format("%H:%M on %A, %-d %B"),
+            self.time.timezone().name(),
+            self.time.format("%:z")
+        )
+    }
+}
+
+/// Finds a timezone by its name, like "Europe/Paris" or "utc", or by its city, like "new york"
+fn timezone_named(name: &str) -> Option<Tz> {
+    let name = name.trim().replace(' ', "_");
+    if let Ok(tz) = name.parse() {
+        return Some(tz);
+    }
+    TZ_VARIANTS.iter().copied().find(|tz| {
+        tz.name().eq_ignore_ascii_case(&name)
+            || tz
+                .name()
+                .rsplit('/')
+                .next()
+                .is_some_and(|city| city.eq_ignore_ascii_case(&name))
+    })
+}
+
+/// The time in a place or timezone, which can be written the same ways as for !weather
+pub async fn lookup(location: &str, now: DateTime<Utc>) -> anyhow::Result<LocalTime> {
+    if let Some(tz) = timezone_named(location) {
+        return Ok(LocalTime {
+            place: tz.name().replace('_', " "),
+            time: now.with_timezone(&tz),
+        });
+    }
+    match wttr::locate(&WeatherInput::parse(location)).await? {
+        PlaceLookup::Found {
+            name,
+            timezone: Some(timezone),
+        } => {
+            let tz: Tz = timezone
+                .parse()
+                .ok()
+                .with_context(|| format!("{name} is in {timezone}, which I don't know"))?;
+            Ok(LocalTime {
+                place: name,
+                time: now.with_timezone(&tz),
+            })
+        }
+        PlaceLookup::Found { name, .. } => bail!("I don't know what timezone {name} is in"),
+        PlaceLookup::Ambiguous(places) => bail!(
+            "Did you mean {}?  (add a state or --country= to pick one)",
+            or_list(&places)
+        ),
+        PlaceLookup::NotFound => bail!("I couldn't find {:?}", location.trim()),
+    }
+}
+
+/// The time somewhere as compact JSON, for answering the model's time tool
+pub async fn time_for_tool(input: &TimeInput) -> anyhow::Result<String> {
+    let time = lookup(&input.location, Utc::now()).await?;
+    Ok(time.to_json().to_string())
+}
+
+#[test]
+fn test_timezone_named() {
+    assert_eq!(timezone_named("Europe/Paris"), Some(Tz::Europe__Paris));
+    assert_eq!(timezone_named("utc"), Some(Tz::UTC));
+    assert_eq!(timezone_named("tokyo"), Some(Tz::Asia__Tokyo));
+    assert_eq!(timezone_named(" New York "), Some(Tz::America__New_York));
+    assert_eq!(timezone_named("Springfield"), None);
+}
+
+#[test]
+fn test_local_time() {
+    let now: DateTime<Utc> = "2024-03-01T12:30:00Z".parse().unwrap();
+    let tz = timezone_named("Tokyo").unwrap();
+    let time = LocalTime {
+        place: "Tokyo, Japan".to_string(),
+        time: now.with_timezone(&tz),
+    };
+    assert_eq!(
+        time.to_string(),
+        "Tokyo, Japan: 21:30 on Friday, 1 March (Asia/Tokyo, UTC+09:00)"
+    );
+    assert_eq!(time.to_json()["local_time"], "2024-03-01 21:30 (Friday)");
+    assert_eq!(time.to_json()["utc_offset"], "+09:00");
+}
@@ -0,0 +1,218 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+};
+
+use chrono::{DateTime, Duration, Utc};
+
+/// A peer that answers the bot this quickly is probably not a person typing
+const FAST_ANSWER_SECS: i64 = 10;
+
+/// Quick answers in a row (within `WINDOW_SECS`) before a peer is taken to be a bot
+const FAST_EXCHANGES: usize = 5;
+
+const WINDOW_SECS: i64 = 120;
+
+/// Times the same thing can be said to the bot (within `REPEAT_MINUTES`) before it looks like a
+/// loop
+const REPEATS: usize = 3;
+
+const REPEAT_MINUTES: i64 = 10;
+
+/// How long a suspected bot is ignored for
+pub const MUTE_MINUTES: i64 = 30;
+
+/// How a conversation with someone has been going
+#[derive(Debug, Default)]
+struct Peer {
+    /// When the bot last answered them
+    last_reply: Option<DateTime<Utc>>,
+    /// When they quickly answered the bot's answers
+    fast_answers: VecDeque<DateTime<Utc>>,
+    /// What they've recently said to the bot
+    said: VecDeque<(DateTime<Utc>, String)>,
+    muted_until: Option<DateTime<Utc>>,
+}
+
+/// Everyone who's talked to the bot lately, keyed by lowercased nick
+static PEERS: Mutex<Option<HashMap<String, Peer>>> = Mutex::new(None);
+
+/// Why someone looks like a bot the bot is stuck talking to
+#[derive(Debug, Clone, PartialEq)]
+pub enum Suspicion {
+    /// Answered the bot this many times in a row, each within a few seconds
+    Alternating(usize),
+    /// Said the same thing this many times
+    Repeating(usize),
+}
+
+impl std::fmt::Display for Suspicion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Suspicion::Alternating(n) => write!(
+                f,
+                "answered me {n} times in a row within {FAST_ANSWER_SECS} seconds"
+            ),
+            Suspicion::Repeating(n) => write!(
+                f,
+                "said the same thing {n} times in {REPEAT_MINUTES} minutes"
+            ),
+        }
+    }
+}
+
+/// Lowercased, with the whitespace evened out, so trivially different repeats still match
+fn normalize(msg: &str) -> String {
+    msg.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+impl Peer {
+    fn is_muted(&self, now: DateTime<Utc>) -> bool {
+        self.muted_until.is_some_and(|until| now < until)
+    }
+
+    /// Whether there's nothing left worth remembering about them
+    fn is_stale(&self, now: DateTime<Utc>) -> bool {
+        let last_seen = [
+            self.last_reply,
+            self.fast_answers.back().copied(),
+            self.said.back().map(|(at, _)| *at),
+        ]
+        .into_iter()
+        .flatten()
+        .max();
+        !self.is_muted(now)
+            && last_seen.map_or(true, |at| now - at > Duration::minutes(REPEAT_MINUTES))
+    }
+
+    fn incoming(&mut self, msg: &str, now: DateTime<Utc>) -> Option<Suspicion> {
+        match self.last_reply {
+            Some(reply) if now - reply <= Duration::seconds(FAST_ANSWER_SECS) => {
+                self.fast_answers.push_back(now)
+            }
+            // a slow answer means there's probably someone typing after all
+            _ => self.fast_answers.clear(),
+        }
+        let window = now - Duration::seconds(WINDOW_SECS);
+        self.fast_answers.retain(|at| *at >= window);
+
+        let msg = normalize(msg);
+        let window = now - Duration::minutes(REPEAT_MINUTES);
+        self.said.retain(|(at, _)| *at >= window);
+        self.said.push_back((now, msg.clone()));
+        let repeats = self.said.iter().filter(|(_, said)| *said == msg).count();
+
+        let suspicion = if self.fast_answers.len() >= FAST_EXCHANGES {
+            Suspicion::Alternating(self.fast_answers.len())
+        } else if repeats >= REPEATS {
+            Suspicion::Repeating(repeats)
+        } else {
+            return None;
+        };
+        *self = Peer {
+            muted_until: Some(now + Duration::minutes(MUTE_MINUTES)),
+            ..Peer::default()
+        };
+        Some(suspicion)
+    }
+}
+
+fn with_peer<T>(nick: &str, now: DateTime<Utc>, f: impl FnOnce(&mut Peer) -> T) -> T {
+    let mut peers = PEERS.lock().expect("peers lock is poisoned");
+    let peers = peers.get_or_insert_with(HashMap::new);
+    peers.retain(|_, peer| !peer.is_stale(now));
+    f(peers.entry(nick.to_lowercase()).or_default())
+}
+
+/// Whether someone could be a bot, going by their nick, their host (Libera gives bots cloaks
+/// with `/bot/` in them), or whether they were passed along by a relay
+pub fn looks_like_bot(nick: &str, host: Option<&str>, relayed: bool) -> bool {
+    relayed
+        || nick.to_lowercase().ends_with("bot")
+        || host.is_some_and(|host| host.split('/').any(|part| part == "bot"))
+}
+
+/// Whether someone is being ignored as a suspected bot
+pub fn is_muted(nick: &str, now: DateTime<Utc>) -> bool {
+    with_peer(nick, now, |peer| peer.is_muted(now))
+}
+
+/// Records something said to the bot
+///
+/// When this makes `nick` look like a bot the bot is caught in a loop with, they're muted for
+/// `MUTE_MINUTES`, and the reason is returned.
+pub fn record_incoming(nick: &str, msg: &str, now: DateTime<Utc>) -> Option<Suspicion> {
+    with_peer(nick, now, |peer| peer.incoming(msg, now))
+}
+
+/// Records that the bot answered someone
+pub fn record_reply(nick: &str, now: DateTime<Utc>) {
+    with_peer(nick, now, |peer| peer.last_reply = Some(now))
+}
+
+#[test]
+fn test_alternating_loop() {
+    let mut now: DateTime<Utc> = "2024-03-01T12:00:00Z".parse().unwrap();
+    let mut peer = Peer::default();
+    for i in 0..FAST_EXCHANGES {
+        peer.last_reply = Some(now);
+        now += Duration::seconds(3);
+        let suspicion = peer.incoming(&format!("interesting point number {i}"), now);
+        if i + 1 < FAST_EXCHANGES {
+            assert_eq!(suspicion, None);
+        } else {
+            assert_eq!(suspicion, Some(Suspicion::Alternating(FAST_EXCHANGES)));
+        }
+    }
+    assert!(peer.is_muted(now));
+    assert!(!peer.is_muted(now + Duration::minutes(MUTE_MINUTES)));
+
+    // someone who takes their time to answer is left alone
+    let mut peer = Peer::default();
+    for i in 0..FAST_EXCHANGES * 2 {
+        peer.last_reply = Some(now);
+        now += Duration::seconds(FAST_ANSWER_SECS * 3);
+        assert_eq!(peer.incoming(&format!("question {i}"), now), None);
+    }
+}
+
+#[test]
+fn test_repeating_loop() {
+    let mut now: DateTime<Utc> = "2024-03-01T12:00:00Z".parse().unwrap();
+    let mut peer = Peer::default();
+    assert_eq!(peer.incoming("I don't understand", now), None);
+    now += Duration::minutes(2);
+    assert_eq!(peer.incoming("I  DON'T understand ", now), None);
+    now += Duration::minutes(2);
+    assert_eq!(
+        peer.incoming("i don't understand", now),
+        Some(Suspicion::Repeating(REPEATS))
+    );
+    assert!(peer.is_muted(now));
+
+    assert!(!peer.is_stale(now));
+    assert!(peer.is_stale(now + Duration::minutes(MUTE_MINUTES)));
+
+    // repeats spread out enough don't count
+    let mut peer = Peer::default();
+    for _ in 0..REPEATS * 2 {
+        now += Duration::minutes(REPEAT_MINUTES + 1);
+        assert_eq!(peer.incoming("ping", now), None);
+    }
+}
+
+#[test]
+fn test_looks_like_bot() {
+    assert!(looks_like_bot("GizmoBot", None, false));
+    assert!(looks_like_bot(
+        "helper",
+        Some("user/someone/bot/helper"),
+        false
+    ));
+    assert!(looks_like_bot("achin", None, true));
+    assert!(!looks_like_bot("achin", Some("overviewer/achin"), false));
+    assert!(!looks_like_bot("abbott", Some("user/bottle"), false));
+}
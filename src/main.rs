@@ -1,18 +1,52 @@
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::{BTreeMap, HashMap, VecDeque},
     fs::File,
-    path::Path,
+    path::{Path, PathBuf},
     sync::{
-        atomic::{AtomicU32, Ordering},
+        atomic::{AtomicBool, AtomicU32, Ordering},
         Arc, Mutex,
     },
     time::Duration,
 };
 
 use anna::{
-    generate_image_prompt, generate_interjection,
-    openai::{self, get_tts},
-    upload_content, ChatMessageThing, NumbatComponent,
+    addressing,
+    api::{self, BotControl},
+    archive,
+    autoclear::AutoClear,
+    breaker::BackendUnavailable,
+    chattiness::Chattiness,
+    config::Permission,
+    convert::{self, AutoConvert},
+    dcc::DccOffer,
+    discord, documents, embeddings,
+    fallback::{self, Fallback},
+    faq::{self, FaqEntry},
+    features::{Feature, FeatureSwitches},
+    feedback::{self, Feedback, Vote},
+    formatting,
+    frontend::{ChatSender, IncomingMessage, Protocol},
+    fx::{self, FxInput},
+    generate_image_prompt, generate_interjection, highlight,
+    images::{self, archive_image, prepare_for_vision},
+    jargon, localtime, loops, matrix,
+    openai::{self, get_tts_reply},
+    paste::{self, Paste},
+    plugins::PluginManager,
+    poll::{self, Poll},
+    prefs::{get_user_prefs, is_valid_lang, update_user_prefs},
+    profiles,
+    progress::Progress,
+    relay,
+    retention::{parse_age, Clock, Retention, SystemClock},
+    sandbox, selftest,
+    stats::{ChannelStats, ContextInfo},
+    threads::Threads,
+    transcript,
+    triggers::InterjectionTrigger,
+    trivia, upload_content, uploads,
+    wttr::{WeatherLookup, WeatherOutputForChat},
+    youtube, ChatMessageThing, NumbatComponent, NumbatError,
 };
 use anyhow::{bail, Context};
 use async_openai::types::{
@@ -26,8 +60,9 @@ use async_openai::types::{
     ChatCompletionRequestMessageContentPartText,
 };
 use chrono::{DateTime, Utc};
+use clap::{Parser, Subcommand};
 use futures::prelude::*;
-use irc::client::prelude::*;
+use irc::client::{data::AccessLevel, prelude::*};
 // use numbat::{markup::Markup, module_importer::BuiltinModuleImporter, InterpreterSettings};
 use serde::{Deserialize, Serialize};
 
@@ -40,10 +75,37 @@ const OPT_IN_ALL_CAPTURE: &[&str] = &[
     "GizmoBot",
     "ion",
 ];
+
+/// Whether someone has opted in to having everything they say kept
+///
+/// Only IRC nicks are protected by services.  Anyone on Matrix or Discord can call themselves
+/// `achin`, so nobody there is taken for someone on the list.
+fn opted_in(protocol: Protocol, nick: &str) -> bool {
+    protocol == Protocol::Irc && OPT_IN_ALL_CAPTURE.contains(&nick)
+}
+
+/// What someone's preferences are kept under: their nick on IRC, and their full id (like
+/// `@achin:matrix.org`) elsewhere, so they can't change an IRC user's by taking their name
+fn prefs_key<'a>(protocol: Protocol, nick: &'a str, user_id: &'a str) -> &'a str {
+    if protocol == Protocol::Irc {
+        nick
+    } else {
+        user_id
+    }
+}
+
 const BOTNAME: &str = "Charbot9000";
 const BOTNAME_PREFIX1: &str = "Charbot9000:";
 const BOTNAME_PREFIX2: &str = "Charbot9000,";
 const BOTS_TO_IGNORE: &[&str] = &["EmceeOverviewer", "box-bot", "GizmoBot"];
+const ONLY_OPS: &str = "Only channel ops can change that";
+/// Most matches `!grep` lists in the channel, before it uploads them instead
+const GREP_LIMIT: usize = 5;
+/// Most matches `!grep` finds
+const GREP_MAX_MATCHES: usize = 500;
+/// Longest checking what a link is can take, including trying its addresses again
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+const TOOLS_OFF: &str = "The calculator and code runner are turned off here (see !feature)";
 
 /// An atomic F32
 ///
@@ -74,6 +136,10 @@ impl AtomicF32 {
 }
 
 static TEMPERATURE: AtomicF32 = AtomicF32::init();
+/// When set, chat requests are put together and shown, but never sent
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+/// The chat model to use, if not the default
+static MODEL: Mutex<Option<String>> = Mutex::new(None);
 
 // #[derive(Debug)]
 // pub enum IRCSender {
@@ -122,14 +188,7 @@ static TEMPERATURE: AtomicF32 = AtomicF32::init();
 // }
 
 pub fn trim_botname(msg: &str) -> &str {
-    let msg = msg.trim_start();
-    if let Some(x) = msg.strip_prefix(&format!("{BOTNAME}:")) {
-        x.trim()
-    } else if let Some(x) = msg.strip_prefix(&format!("<{BOTNAME}>")) {
-        x.trim()
-    } else {
-        msg.trim()
-    }
+    addressing::strip_self_address(msg, BOTNAME)
 }
 
 fn reponse_msg_to_request_msg(msg: ChatCompletionResponseMessage) -> ChatCompletionRequestMessage {
@@ -184,20 +243,76 @@ pub struct ChannelState {
     last_interjection_attempt: DateTime<Utc>,
     /// A possible interjection for this channel
     interjection: Option<String>,
+    /// Patterns that make the bot consider interjecting
+    #[serde(default)]
+    triggers: Vec<InterjectionTrigger>,
+    /// Questions that get a canned answer when someone asks them
+    #[serde(default)]
+    faqs: Vec<FaqEntry>,
+    /// Limits on unsolicited messages
+    #[serde(default)]
+    chattiness: Chattiness,
+    /// When to clear the conversation on our own
+    #[serde(default)]
+    auto_clear: AutoClear,
+    /// Which features the channel has turned off
+    #[serde(default)]
+    features: FeatureSwitches,
+    /// Whether quantities people mention get converted to other units
+    #[serde(default)]
+    auto_convert: AutoConvert,
+    /// Snapshots of the conversation, saved with `!ctx save`
+    #[serde(default)]
+    saved_contexts: BTreeMap<String, Vec<ChatMessageThing>>,
+    /// Who saved each snapshot (lowercased), since only they or an op can replace or delete it
+    #[serde(default)]
+    context_owners: BTreeMap<String, String>,
+    /// Who's in the channel, as of the last message, for the system prompt's `{users_present}`
+    #[serde(skip)]
+    users_present: Vec<String>,
+    /// The channel's topic, as of the last time it was sent or changed
+    #[serde(skip)]
+    topic: Option<String>,
+    /// Side conversations, started with `!thread start`
+    #[serde(default)]
+    threads: Threads,
+    /// Most tokens the conversation is allowed to take up, set with `!budget`
+    #[serde(default)]
+    token_budget: Option<usize>,
 
     /// A numbat context
     ///
-    /// It's wrapped in a mutex so we can make it unwindsafe
+    /// This is created the first time it's needed.  It's wrapped in an async mutex so that
+    /// evaluation can happen in a separate task, without blocking the main loop.
     #[serde(skip, default = "make_new_numbat_context")]
-    numbat_context: Arc<Mutex<Option<NumbatComponent>>>,
+    numbat_context: Arc<tokio::sync::Mutex<Option<NumbatComponent>>>,
+}
+
+fn make_new_numbat_context() -> Arc<tokio::sync::Mutex<Option<NumbatComponent>>> {
+    Arc::new(tokio::sync::Mutex::new(None))
 }
 
-fn make_new_numbat_context() -> Arc<Mutex<Option<NumbatComponent>>> {
-    Arc::new(Mutex::new(
-        NumbatComponent::new("numbat_component.wasm")
-            .map_err(|e| println!("Failed to create NumbatComponent: {e}"))
-            .ok(),
-    ))
+/// Evaluates a numbat expression, creating the component first if needed
+///
+/// If the component crashes, the session is dropped so the next evaluation starts a fresh one.
+async fn eval_numbat(
+    ctx: &tokio::sync::Mutex<Option<NumbatComponent>>,
+    expr: &str,
+) -> anyhow::Result<String> {
+    let mut ctx = ctx.lock().await;
+    if ctx.is_none() {
+        *ctx = Some(NumbatComponent::new("numbat_component.wasm").await?);
+    }
+    let Some(session) = ctx.as_mut() else {
+        bail!("No Numbat context")
+    };
+    match session.eval(expr).await {
+        Err(e) if !e.is::<NumbatError>() => {
+            *ctx = None;
+            Err(e.context("Numbat crashed, so this channel's session was reset"))
+        }
+        result => result,
+    }
 }
 
 impl std::fmt::Debug for ChannelState {
@@ -207,6 +322,18 @@ impl std::fmt::Debug for ChannelState {
             .field("last_bot_message", &self.last_bot_message)
             .field("last_interjection_attempt", &self.last_interjection_attempt)
             .field("interjection", &self.interjection)
+            .field("triggers", &self.triggers)
+            .field("faqs", &self.faqs.len())
+            .field("chattiness", &self.chattiness)
+            .field("auto_clear", &self.auto_clear)
+            .field("features", &self.features)
+            .field("auto_convert", &self.auto_convert)
+            .field("saved_contexts", &self.saved_contexts.keys())
+            .field("context_owners", &self.context_owners)
+            .field("users_present", &self.users_present.len())
+            .field("topic", &self.topic)
+            .field("threads", &self.threads.to_string())
+            .field("token_budget", &self.token_budget)
             .finish_non_exhaustive()
     }
 }
@@ -218,50 +345,137 @@ impl Default for ChannelState {
             last_bot_message: Utc::now(),
             last_interjection_attempt: Utc::now(),
             interjection: Default::default(),
+            triggers: Default::default(),
+            faqs: Default::default(),
+            chattiness: Default::default(),
+            auto_clear: Default::default(),
+            features: Default::default(),
+            auto_convert: Default::default(),
+            saved_contexts: Default::default(),
+            context_owners: Default::default(),
+            users_present: Default::default(),
+            topic: Default::default(),
+            threads: Default::default(),
+            token_budget: Default::default(),
             numbat_context: make_new_numbat_context(),
         }
     }
 }
 
+/// Most snapshots a channel can have saved with `!ctx save`
+const MAX_SAVED_CONTEXTS: usize = 10;
+
+/// Most messages kept in one snapshot; anything older is left out
+const MAX_SAVED_MESSAGES: usize = 200;
+
 impl ChannelState {
-    fn reconstitute(self) -> Self {
-        // when deserilizing, all messages will be read as SystemMessages, and we need to convert them back to the correct type, based on the "role"
-        let messages = self
-            .messages
-            .into_iter()
-            .map(|cmt| cmt.reconstitute())
-            .collect();
-        Self {
-            messages,
-            last_bot_message: self.last_bot_message,
-            last_interjection_attempt: self.last_interjection_attempt,
-            interjection: self.interjection,
-            numbat_context: make_new_numbat_context(),
+    /// Checks that `nick` may replace or delete the named snapshot
+    fn may_change_context(&self, name: &str, nick: &str, is_admin: bool) -> anyhow::Result<()> {
+        match self.context_owners.get(name) {
+            Some(owner) if !is_admin && *owner != nick.to_lowercase() => {
+                bail!("{name:?} was saved by {owner}, so only they or an op can change it")
+            }
+            _ => Ok(()),
         }
     }
-    fn trim_message_for_age_and_contextsize(&mut self) {
-        // remove any message older than 24 hours
-        let now = Utc::now();
-        while let Some(ChatMessageThing { date, .. }) = self.messages.front() {
-            if now.signed_duration_since(*date).num_hours() > 48 {
-                self.messages.pop_front();
-            } else {
-                break;
-            }
+    /// Snapshots the current conversation under the given name, replacing any older snapshot
+    ///
+    /// Only the newest [`MAX_SAVED_MESSAGES`] are kept.  Returns how many messages were saved.
+    fn save_context(&mut self, name: &str, nick: &str, is_admin: bool) -> anyhow::Result<usize> {
+        if self.saved_contexts.contains_key(name) {
+            self.may_change_context(name, nick, is_admin)?;
+        } else if self.saved_contexts.len() >= MAX_SAVED_CONTEXTS {
+            bail!("There are already {MAX_SAVED_CONTEXTS} saved contexts; delete one first");
+        }
+        let skip = self.messages.len().saturating_sub(MAX_SAVED_MESSAGES);
+        let saved: Vec<_> = self.messages.iter().skip(skip).cloned().collect();
+        let count = saved.len();
+        self.saved_contexts.insert(name.to_string(), saved);
+        self.context_owners
+            .insert(name.to_string(), nick.to_lowercase());
+        Ok(count)
+    }
+    /// Deletes a saved snapshot, returning false if there's no snapshot with that name
+    fn delete_context(&mut self, name: &str, nick: &str, is_admin: bool) -> anyhow::Result<bool> {
+        if !self.saved_contexts.contains_key(name) {
+            return Ok(false);
+        }
+        self.may_change_context(name, nick, is_admin)?;
+        self.saved_contexts.remove(name);
+        self.context_owners.remove(name);
+        Ok(true)
+    }
+    /// Replaces the current conversation with a saved snapshot
+    ///
+    /// The restored messages are shifted forward in time so that the newest one is from `now`,
+    /// otherwise an old snapshot would just get trimmed away again.  Returns false if there's no
+    /// snapshot with that name.
+    fn load_context(&mut self, name: &str, now: DateTime<Utc>) -> bool {
+        let Some(saved) = self.saved_contexts.get(name) else {
+            return false;
+        };
+        let shift = saved
+            .last()
+            .map(|cmt| now - cmt.date)
+            .unwrap_or_else(chrono::Duration::zero);
+        self.messages = saved
+            .iter()
+            .cloned()
+            .map(|mut cmt| {
+                cmt.date += shift;
+                cmt
+            })
+            .collect();
+        true
+    }
+    fn trim_message_for_age_and_contextsize(&mut self, retention: &Retention, now: DateTime<Utc>) {
+        retention.trim(&mut self.messages, now);
+        for thread in self.threads.iter_mut() {
+            retention.trim(&mut thread.messages, now);
+        }
+        if let Some(budget) = self.token_budget {
+            retention.trim_to_budget(&mut self.messages, budget, now);
         }
-
-        // todo make sure we're below a certain context size (as measured in tokens)
     }
     pub fn save(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
-        let output = File::create(path)?;
-        serde_json::to_writer_pretty(output, self)?;
-        Ok(())
+        anna::history::save(path, self)
     }
     pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
-        let input = File::open(path)?;
-        let state: Self = serde_json::from_reader(input)?;
-        Ok(state.reconstitute())
+        anna::history::load(path)
+    }
+}
+
+/// Asks for a link's Content-Type, with a HEAD request if the server answers those
+async fn probe_content_type(client: &reqwest::Client, url: &str) -> anyhow::Result<String> {
+    // First, try a head request
+    if let Ok(resp) = client.head(url).send().await {
+        // extract the Content-Type header if the response was successful
+        if dbg!(resp.status()).is_success() {
+            if let Some(ct) = resp.headers().get(reqwest::header::CONTENT_TYPE) {
+                return Ok(ct.to_str()?.to_owned());
+            }
+        }
+        println!("Retrying with GET request");
+
+        // if the resp is a 404, then don't try a GET request
+        if resp.status() == reqwest::StatusCode::NOT_FOUND {
+            bail!("404");
+        }
     }
+
+    // if the head request failed, try a GET request
+    let resp = client.get(url).send().await?;
+
+    let ct = resp
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|ct| ct.to_str().ok().map(|s| s.to_owned()))
+        .context("Failed to get content type")?;
+
+    // let body = resp.text().await?;
+    // println!("Got body: {body}");
+
+    Ok(ct)
 }
 
 /// Contains a list of all relevant messages for a given IRC channel
@@ -269,12 +483,15 @@ impl ChannelState {
 pub struct MessageMap {
     inner: Arc<Mutex<HashMap<String, ChannelState>>>,
     client: reqwest::Client,
+    clock: Arc<dyn Clock>,
+    retention: Retention,
 }
 
 impl Default for MessageMap {
     fn default() -> Self {
-        let client = reqwest::Client::builder()
-            .connect_timeout(Duration::from_secs(2))
+        let client = anna::http::client_builder()
+            .expect("the network settings were checked at startup")
+            .connect_timeout(anna::http::network().connect_timeout())
             .timeout(Duration::from_secs(10))
             .user_agent("anna/1.0.0")
             .build()
@@ -282,53 +499,132 @@ impl Default for MessageMap {
         Self {
             inner: Default::default(),
             client,
+            clock: Arc::new(SystemClock),
+            retention: Retention::default(),
         }
     }
 }
 
 impl MessageMap {
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+    pub fn with_retention(mut self, retention: Retention) -> Self {
+        self.retention = retention;
+        self
+    }
+    pub fn retention(&self) -> Retention {
+        self.retention
+    }
+    pub fn now(&self) -> DateTime<Utc> {
+        self.clock.now()
+    }
     pub fn with_channel<T>(&self, channel: &str, f: impl FnOnce(&mut ChannelState) -> T) -> T {
         let mut inner = self.inner.lock().expect("inner lock is poisoned");
         let chan = inner.entry(channel.to_string()).or_default();
         f(chan)
     }
 
+    pub fn has_channel(&self, channel: &str) -> bool {
+        let inner = self.inner.lock().expect("inner lock is poisoned");
+        inner.contains_key(channel)
+    }
+
+    /// Like `with_channel`, but for looking only, so a channel with no state doesn't get any
+    pub fn get_channel<T>(&self, channel: &str, f: impl FnOnce(&ChannelState) -> T) -> Option<T> {
+        let inner = self.inner.lock().expect("inner lock is poisoned");
+        inner.get(channel).map(f)
+    }
+
+    /// Runs something on one of a channel's conversations: its own, or one of its threads
+    ///
+    /// A thread that has since ended falls back to the channel's own conversation.
+    fn with_messages<T>(
+        &self,
+        channel: &str,
+        thread: Option<&str>,
+        f: impl FnOnce(&mut VecDeque<ChatMessageThing>) -> T,
+    ) -> T {
+        let now = self.now();
+        self.with_channel(channel, |chan| {
+            match thread.and_then(|name| chan.threads.get_mut(name)) {
+                Some(thread) => {
+                    thread.last_active = now;
+                    f(&mut thread.messages)
+                }
+                None => f(&mut chan.messages),
+            }
+        })
+    }
+
+    /// Whether anyone but `nick` has said something in a channel since `since`
+    fn others_spoke_since(&self, channel: &str, nick: &str, since: DateTime<Utc>) -> bool {
+        self.with_channel(channel, |chan| {
+            chan.messages
+                .iter()
+                .rev()
+                .take_while(|msg| msg.date > since)
+                .any(|msg| match &msg.msg {
+                    ChatCompletionRequestMessage::User(user) => user
+                        .name
+                        .as_deref()
+                        .is_some_and(|name| !name.eq_ignore_ascii_case(nick)),
+                    _ => false,
+                })
+        })
+    }
+
+    fn feature_enabled(&self, channel: &str, feature: Feature) -> bool {
+        self.with_channel(channel, |chan| chan.features.is_enabled(feature))
+    }
+
     fn save_interjection(&self, channel: &str, interjection: Option<String>) {
         self.with_channel(channel, |chan| {
             chan.interjection = interjection;
             chan.last_interjection_attempt = Utc::now();
         });
     }
+    /// What a link is, going by its Content-Type
+    ///
+    /// If the host can't be connected to, its address families are tried on their own (raced,
+    /// a little apart) before giving up, since a broken IPv6 address can use up the whole connect
+    /// timeout.  All of that has to fit in `PROBE_TIMEOUT`.
     pub async fn get_content_type(&self, url: &str) -> anyhow::Result<String> {
-        // First, try a head request
-        if let Ok(resp) = self.client.head(url).send().await {
-            // extract the Content-Type header if the response was successful
-            if dbg!(resp.status()).is_success() {
-                if let Some(ct) = resp.headers().get(reqwest::header::CONTENT_TYPE) {
-                    return Ok(ct.to_str()?.to_owned());
-                }
+        tokio::time::timeout(PROBE_TIMEOUT, self.get_content_type_inner(url))
+            .await
+            .with_context(|| format!("Checking what {url} is took too long"))?
+    }
+    async fn get_content_type_inner(&self, url: &str) -> anyhow::Result<String> {
+        let e = match probe_content_type(&self.client, url).await {
+            Err(e)
+                if e.downcast_ref::<reqwest::Error>()
+                    .is_some_and(anna::http::is_connection_problem) =>
+            {
+                e
             }
-            println!("Retrying with GET request");
-
-            // if the resp is a 404, then don't try a GET request
-            if resp.status() == reqwest::StatusCode::NOT_FOUND {
-                bail!("404");
+            result => return result,
+        };
+        let clients = match anna::http::fallback_clients(url, PROBE_TIMEOUT).await {
+            Ok(clients) if !clients.is_empty() => clients,
+            Ok(_) => return Err(e),
+            Err(lookup) => {
+                println!("Couldn't look up {url} to try it again: {lookup}");
+                return Err(e);
             }
+        };
+        println!("Couldn't connect to {url} ({e}), trying one address family at a time");
+        let attempts = clients.into_iter().enumerate().map(|(i, client)| {
+            Box::pin(async move {
+                tokio::time::sleep(anna::http::FAMILY_STAGGER * i as u32).await;
+                probe_content_type(&client, url).await
+            })
+        });
+        match future::select_ok(attempts).await {
+            Ok((ct, _)) => Ok(ct),
+            // what went wrong the first time says the most about why
+            Err(_) => Err(e),
         }
-
-        // if the head request failed, try a GET request
-        let resp = self.client.get(url).send().await?;
-
-        let ct = resp
-            .headers()
-            .get(reqwest::header::CONTENT_TYPE)
-            .and_then(|ct| ct.to_str().ok().map(|s| s.to_owned()))
-            .context("Failed to get content type")?;
-
-        // let body = resp.text().await?;
-        // println!("Got body: {body}");
-
-        Ok(ct)
     }
     pub async fn extract_image_urls(&self, sender: &str, message: &str) -> Vec<ChatMessageThing> {
         let mut m = Vec::new();
@@ -346,7 +642,7 @@ impl MessageMap {
                 role: async_openai::types::Role::User,
                 name: Some(sender.to_string()),
             });
-            m.push(ChatMessageThing::new_now(msg));
+            m.push(ChatMessageThing::new_at(msg, self.now()));
         } else {
             let mut content: Vec<ChatCompletionRequestMessageContentPart> =
                 vec![ChatCompletionRequestMessageContentPartText::from(format!(
@@ -355,6 +651,10 @@ impl MessageMap {
                 .into()];
             for url in urls {
                 dbg!(&url);
+                if paste::raw_url(url).is_some() {
+                    // pastes are fetched separately (see `spawn_paste_fetches`)
+                    continue;
+                }
                 if let Some(ct) = self.get_content_type(url).await.ok() {
                     dbg!(&ct);
                     if ct.starts_with("image/") {
@@ -373,7 +673,7 @@ impl MessageMap {
                 role: async_openai::types::Role::User,
                 name: Some(sender.to_string()),
             });
-            m.push(ChatMessageThing::new_now(msg));
+            m.push(ChatMessageThing::new_at(msg, self.now()));
         }
 
         m
@@ -394,37 +694,207 @@ impl MessageMap {
                 (now - chan.last_interjection_attempt).num_minutes()
             );
 
-            now - chan.last_bot_message > chrono::Duration::hours(36)
+            chan.features.is_enabled(Feature::Interject)
+                && now - chan.last_bot_message > chrono::Duration::hours(36)
                 && now - chan.last_interjection_attempt > chrono::Duration::minutes(30)
                 && num_messages_past_hour >= 30
+                && chan.chattiness.may_speak(now).is_ok()
+        })
+    }
+    /// Checks a channel message against the channel's interjection triggers
+    ///
+    /// Returns the pattern of the trigger that fired, if any
+    fn check_triggers(&self, channel: &str, msg: &str) -> Option<String> {
+        self.with_channel(channel, |chan| {
+            if !chan.features.is_enabled(Feature::Interject) {
+                return None;
+            }
+            let now = Utc::now();
+            chan.triggers
+                .iter_mut()
+                .find_map(|t| t.fire(msg, now).then(|| t.pattern.clone()))
         })
     }
-    pub async fn insert_usermsg(&mut self, channel: &str, sender: &str, message: &str) {
+    /// Clears the conversation for a channel, on behalf of its auto-clear settings
+    ///
+    /// If summarizing is turned on, the old conversation is replaced by a summary of it, once
+    /// the summary's been written.
+    pub fn auto_clear_context(&self, channel: &str) {
+        let (messages, summarize) = self.with_channel(channel, |chan| {
+            (
+                std::mem::take(&mut chan.messages),
+                chan.auto_clear.summarize,
+            )
+        });
+        if !summarize || messages.is_empty() {
+            return;
+        }
+        let messages: Vec<_> = messages.into();
+        let message_map = self.clone();
+        let channel = channel.to_string();
+        tokio::spawn(async move {
+            match anna::generate_summary(&messages).await {
+                Ok(summary) => message_map.with_channel(&channel, |chan| {
+                    chan.messages.push_front(ChatMessageThing::new_now(
+                        ChatCompletionRequestMessage::System(ChatCompletionRequestSystemMessage {
+                            content: format!("Summary of the earlier conversation: {summary}"),
+                            role: async_openai::types::Role::System,
+                            name: None,
+                        }),
+                    ))
+                }),
+                Err(e) => println!("Failed to summarize the old context for {channel}: {e}"),
+            }
+        });
+    }
+    pub async fn insert_usermsg(
+        &mut self,
+        channel: &str,
+        thread: Option<&str>,
+        sender: &str,
+        message: &str,
+    ) {
+        // threads expire on their own, rather than being cleared
+        let stale = thread.is_none()
+            && self.with_channel(channel, |chan| {
+                let last_activity = chan.messages.back().map(|cmt| cmt.date);
+                chan.auto_clear.is_stale(last_activity, self.now())
+            });
+        if stale {
+            println!("Context for {channel} has gone stale, clearing it");
+            self.auto_clear_context(channel);
+        }
+
+        // colors and the like would only waste tokens
+        let message = formatting::normalize(message);
         // look for things that look like URLs in the message
-        let urls = self.extract_image_urls(sender, message).await;
+        let urls = self.extract_image_urls(sender, &message).await;
+        let images = urls.iter().flat_map(|cmt| cmt.image_urls()).collect();
 
+        let merge_window = anna::config::get_config()
+            .map(|config| config.merge_window())
+            .unwrap_or_else(|_| chrono::Duration::seconds(20));
+        self.with_messages(channel, thread, |messages| {
+            for cmt in urls {
+                let unmerged = match messages.back_mut() {
+                    Some(last) => last.merge(cmt, merge_window).err(),
+                    None => Some(cmt),
+                };
+                messages.extend(unmerged);
+            }
+        });
         self.with_channel(channel, |chan| {
-            chan.messages.extend(urls);
-
-            chan.trim_message_for_age_and_contextsize();
+            chan.trim_message_for_age_and_contextsize(&self.retention, self.now());
 
             // write out list of message to a file
             // if let Ok(output) = File::create(format!("{channel}.json")) {
             //     let _ = serde_json::to_writer_pretty(output, &chan.messages);
             // }
         });
+        self.spawn_archiving(channel, thread, images);
     }
-    pub fn insert_selfmsg(&mut self, channel: &str, messages: &[ChatCompletionResponseMessage]) {
-        self.with_channel(channel, |chan| {
-            chan.last_bot_message = Utc::now();
+    /// Rehosts images that entered the history, so they're still around when the original links
+    /// expire, and points the history at the copies once they're up
+    fn spawn_archiving(&self, channel: &str, thread: Option<&str>, images: Vec<String>) {
+        if images.is_empty() {
+            return;
+        }
+        let message_map = self.clone();
+        let (channel, thread) = (channel.to_string(), thread.map(|t| t.to_string()));
+        tokio::spawn(async move {
+            for url in images {
+                let archived = match archive_image(&message_map.client, &url).await {
+                    Ok(archived) => archived,
+                    Err(e) => {
+                        println!("Failed to archive {url}: {e}");
+                        continue;
+                    }
+                };
+                message_map.with_messages(&channel, thread.as_deref(), |messages| {
+                    // it's most likely still one of the latest messages
+                    messages
+                        .iter_mut()
+                        .rev()
+                        .any(|cmt| cmt.rehost_image(&archived))
+                });
+            }
+        });
+    }
+    /// Fetches the pastes linked in a message in the background, and adds their text to the
+    /// history once it's in, so "what's wrong with this?" can be answered
+    fn spawn_paste_fetches(&self, channel: &str, sender: &str, message: &str) {
+        let links: Vec<(String, String)> = message
+            .split_ascii_whitespace()
+            .filter_map(|url| Some((url.to_string(), paste::raw_url(url)?)))
+            .collect();
+        if links.is_empty() {
+            return;
+        }
+        let message_map = self.clone();
+        let (channel, sender) = (channel.to_string(), sender.to_string());
+        tokio::spawn(async move {
+            for (url, raw) in links {
+                let text = match paste::fetch(&message_map.client, &raw).await {
+                    Ok(text) => text,
+                    Err(e) => {
+                        println!("Failed to fetch the paste at {url}: {e}");
+                        continue;
+                    }
+                };
+                let msg = ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
+                    content: ChatCompletionRequestUserMessageContent::Text(format!(
+                        "<{sender}> {}",
+                        paste::for_context(&url, &text)
+                    )),
+                    role: async_openai::types::Role::User,
+                    name: Some(sender.clone()),
+                });
+                let now = message_map.now();
+                message_map.with_channel(&channel, |chan| {
+                    chan.messages.push_back(ChatMessageThing::new_at(msg, now))
+                });
+            }
+        });
+    }
+    /// The bot's replies are archived wherever the channel's traffic is
+    fn archive_selfmsg(&self, channel: &str, message: &str) {
+        if channel.starts_with('#') && self.feature_enabled(channel, Feature::Capture) {
+            archive::record(channel, BOTNAME, message);
+        }
+    }
+    pub fn insert_selfmsg(
+        &mut self,
+        channel: &str,
+        thread: Option<&str>,
+        messages: &[ChatCompletionResponseMessage],
+        model: Option<&str>,
+    ) {
+        let now = self.now();
+        // tool calls (and what came back from them) were only for working out the reply
+        let messages: Vec<_> = messages
+            .iter()
+            .filter(|msg| {
+                msg.tool_calls
+                    .as_ref()
+                    .map_or(true, |calls| calls.is_empty())
+            })
+            .collect();
+        for msg in &messages {
+            if let Some(content) = &msg.content {
+                self.archive_selfmsg(channel, content);
+            }
+        }
+        self.with_messages(channel, thread, |conversation| {
             for msg in messages {
-                chan.messages
-                    .push_back(ChatMessageThing::new_now(reponse_msg_to_request_msg(
-                        msg.to_owned(),
-                    )));
+                let mut cmt =
+                    ChatMessageThing::new_at(reponse_msg_to_request_msg((*msg).clone()), now);
+                cmt.model = model.map(|m| m.to_string());
+                conversation.push_back(cmt);
             }
-
-            chan.trim_message_for_age_and_contextsize();
+        });
+        self.with_channel(channel, |chan| {
+            chan.last_bot_message = now;
+            chan.trim_message_for_age_and_contextsize(&self.retention, now);
 
             // write out list of message to a file
             // if let Ok(output) = File::create(format!("{channel}.json")) {
@@ -433,11 +903,13 @@ impl MessageMap {
         });
     }
     pub fn insert_selfmsg_str(&self, channel: &str, message: &str) {
+        self.archive_selfmsg(channel, message);
+        let now = self.now();
         self.with_channel(channel, |chan| {
-            chan.last_bot_message = Utc::now();
+            chan.last_bot_message = now;
             #[allow(deprecated)]
             chan.messages.push_back(ChatMessageThing {
-                date: Utc::now(),
+                date: now,
                 msg: ChatCompletionRequestMessage::Assistant(
                     ChatCompletionRequestAssistantMessage {
                         content: Some(message.to_string()),
@@ -447,6 +919,9 @@ impl MessageMap {
                         function_call: None,
                     },
                 ),
+                archived_images: Vec::new(),
+                feedback: Vec::new(),
+                model: None,
             })
         })
     }
@@ -459,22 +934,48 @@ impl MessageMap {
     pub fn get_chat_messages(
         &self,
         channel: &str,
+        thread: Option<&str>,
         all_context: bool,
+        limit: Option<HistoryLimit>,
+        with_images: bool,
     ) -> Vec<ChatCompletionRequestMessage> {
         let inner = self.inner.lock().expect("inner lock is poisoned");
         let mut v = Vec::new();
 
         // When converting into a list to sent to the API, don't send images older than
-        // an hour, in order to keep context size down and speed up processing
-        let now = Utc::now();
-        if let Some(list) = inner.get(channel) {
+        // an hour, in order to keep context size down and speed up processing (unless
+        // they were explicitly asked for)
+        let now = self.now();
+        let for_api = |cmt: &ChatMessageThing| {
+            if with_images {
+                cmt.msg.clone()
+            } else {
+                cmt.get_for_api(&self.retention, now)
+            }
+        };
+        let messages =
+            inner.get(channel).map(
+                |chan| match thread.and_then(|name| chan.threads.get(name)) {
+                    Some(thread) => &thread.messages,
+                    None => &chan.messages,
+                },
+            );
+        if let Some(messages) = messages {
             if all_context {
-                v.extend(list.messages.iter().map(|cmt| cmt.get_for_api(now)));
+                let skip = match limit {
+                    None => 0,
+                    Some(HistoryLimit::Messages(count)) => messages.len().saturating_sub(count),
+                    Some(HistoryLimit::Age(age)) => messages
+                        .iter()
+                        .take_while(|cmt| now - cmt.date > age)
+                        .count(),
+                };
+                v.extend(messages.iter().skip(skip).map(for_api));
                 // for msg in list {
                 //     v.push(msg.clone());
                 // }
-            } else if let Some(cmt) = list.messages.back() {
-                v.push(cmt.get_for_api(now));
+            } else if let Some(cmt) = messages.back() {
+                v.push(for_api(cmt));
             }
         }
 
@@ -483,13 +984,19 @@ impl MessageMap {
     pub fn save_all(&self) -> anyhow::Result<()> {
         let inner = self.inner.lock().expect("inner lock is poisoned");
         for (channel, state) in inner.iter() {
-            state.save(format!("{channel}.json"))?;
+            match state_path(channel) {
+                Ok(path) => state.save(path)?,
+                Err(e) => {
+                    println!("Not saving state: {e}");
+                    continue;
+                }
+            }
             println!("Saved state for {channel}");
         }
         Ok(())
     }
     pub fn load(&mut self, channel: &str, force: bool) -> anyhow::Result<()> {
-        let state = ChannelState::load(format!("{channel}.json"))?;
+        let state = ChannelState::load(state_path(channel)?)?;
         let mut inner = self.inner.lock().unwrap();
         if force || !inner.contains_key(channel) {
             inner.insert(channel.to_string(), state);
@@ -500,6 +1007,17 @@ impl MessageMap {
     }
 }
 
+/// Where a channel's state is saved
+///
+/// Channel names also come from Matrix, Discord and the HTTP API, so one that would put the file
+/// somewhere other than the working directory is refused.
+fn state_path(channel: &str) -> anyhow::Result<String> {
+    if channel.is_empty() || channel.contains(['/', '\\', '\0']) {
+        bail!("{channel:?} isn't a channel name that state can be saved under");
+    }
+    Ok(format!("{channel}.json"))
+}
+
 fn boolify(s: Option<&str>) -> Option<bool> {
     s.and_then(|s| match s {
         "y" | "yes" | "true" | "on" => Some(true),
@@ -508,52 +1026,124 @@ fn boolify(s: Option<&str>) -> Option<bool> {
     })
 }
 
-fn get_chat_instruction(line: &str) -> Option<ChatInstruction> {
-    // defaults
-    let mut inst = ChatInstruction {
-        msg: line.trim(),
-        temp: TEMPERATURE.load(),
-        context: true,
-        save: true,
-        pastebin: false,
-        tts: false,
-    };
+/// Options understood by `!chat`, along with a short description of each, for the usage message
+const CHAT_FLAGS: &[(&str, &str)] = &[
+    ("context=yes|no", "send earlier messages too"),
+    ("history=30m|10msgs|none", "only send recent messages"),
+    ("save=yes|no", "remember this exchange"),
+    ("paste", "reply with a link"),
+    ("temp=N", "temperature, 0 to 2"),
+    ("tts", "reply with audio"),
+    ("with-images", "include images older than an hour"),
+    ("dry", "show the request instead of sending it"),
+    ("maxtokens=N", "limit the length of the reply"),
+    ("brief", "ask for a short reply"),
+    ("verbose", "ask for a detailed reply"),
+    (
+        "lang=LANG",
+        "reply in another language (set a default with !lang)",
+    ),
+    ("tools", "say which tools were used"),
+    ("cost", "say what the request cost"),
+    ("model=NAME", "use another model"),
+    (
+        "effort=low|medium|high",
+        "how hard a reasoning model thinks",
+    ),
+];
 
-    if let Some(data) = line.trim().strip_prefix("!chat") {
-        if data.is_empty() {
-            return Some(ChatInstruction::default(""));
-        }
-        // multiple parsing options, because why not
-        if data.starts_with(['/', ':']) {
-            let mut split = data[1..].splitn(2, ' ');
-            let cmds = split.next().unwrap();
+fn chat_usage() -> String {
+    let flags: Vec<String> = CHAT_FLAGS
+        .iter()
+        .map(|(flag, desc)| format!("--{flag} ({desc})"))
+        .collect();
+    format!(
+        "Usage: !chat [--option ...] <message>, or {BOTNAME}: <message>.  Options: {}",
+        flags.join(", ")
+    )
+}
 
-            for cmd in cmds.split([':', ',', '/']) {
-                inst.update(cmd);
-            }
+/// Why a `!chat` line couldn't be understood
+#[derive(Debug, Clone, PartialEq)]
+enum ChatParseError {
+    UnknownOption(String),
+    InvalidValue { option: String, value: String },
+    UnterminatedQuote,
+}
 
-            if let Some(rest) = split.next() {
-                inst.msg = rest.trim();
-            } else {
-                inst.msg = ""
+impl std::fmt::Display for ChatParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChatParseError::UnknownOption(option) => {
+                write!(f, "Unknown option --{option} (try !chat for a list)")
             }
-        } else {
-            // maybe we have !chat --foo=bar --baz syntax
-            let mut skipped_words = 0;
-            for (idx, cmd) in data.split_ascii_whitespace().enumerate() {
-                if let Some(cmd) = cmd.strip_prefix("--") {
-                    inst.update(cmd);
-                } else {
-                    skipped_words = idx;
+            ChatParseError::InvalidValue { option, value } => {
+                write!(f, "Invalid value {value:?} for --{option}")
+            }
+            ChatParseError::UnterminatedQuote => write!(f, "Unterminated quote in the options"),
+        }
+    }
+}
+
+/// Splits the `--option` words off the start of a `!chat` line
+///
+/// Values can be quoted to include spaces (`--foo="bar baz"`), and a bare `--` ends the options.
+/// Returns the options, with quotes removed, and the rest of the line (the message).
+fn split_chat_options(data: &str) -> Result<(Vec<String>, &str), ChatParseError> {
+    let mut options = Vec::new();
+    let mut rest = data.trim_start();
+    while let Some(after) = rest.strip_prefix("--") {
+        if after.is_empty() || after.starts_with(char::is_whitespace) {
+            rest = after.trim_start();
+            break;
+        }
+        let mut option = String::new();
+        let mut quote = None;
+        let mut end = after.len();
+        for (idx, c) in after.char_indices() {
+            match quote {
+                None if c.is_whitespace() => {
+                    end = idx;
                     break;
                 }
+                None if c == '"' || c == '\'' => quote = Some(c),
+                Some(q) if c == q => quote = None,
+                _ => option.push(c),
             }
-            inst.msg = data
-                .trim()
-                .splitn(skipped_words + 1, ' ')
-                .last()
-                .unwrap()
-                .trim();
+        }
+        if quote.is_some() {
+            return Err(ChatParseError::UnterminatedQuote);
+        }
+        options.push(option);
+        rest = after[end..].trim_start();
+    }
+    Ok((options, rest))
+}
+
+/// Parses a line that asks the bot something
+///
+/// Returns `None` if the line isn't meant for the bot at all.
+fn get_chat_instruction(line: &str) -> Option<Result<ChatInstruction, ChatParseError>> {
+    let mut inst = ChatInstruction::default(line.trim());
+
+    if let Some(data) = line.trim().strip_prefix("!chat") {
+        // multiple parsing options, because why not
+        let parsed = if let Some(data) = data.strip_prefix(['/', ':']) {
+            let (cmds, rest) = data.split_once(' ').unwrap_or((data, ""));
+            cmds.split([':', ',', '/'])
+                .filter(|cmd| !cmd.is_empty())
+                .try_for_each(|cmd| inst.update(cmd))
+                .map(|()| rest.trim())
+        } else {
+            // maybe we have !chat --foo=bar --baz syntax
+            split_chat_options(data).and_then(|(options, rest)| {
+                options.iter().try_for_each(|cmd| inst.update(cmd))?;
+                Ok(rest.trim())
+            })
+        };
+        match parsed {
+            Ok(msg) => inst.msg = msg,
+            Err(e) => return Some(Err(e)),
         }
     } else if let Some(data) = line
         .strip_prefix(BOTNAME_PREFIX1)
@@ -563,21 +1153,76 @@ fn get_chat_instruction(line: &str) -> Option<ChatInstruction> {
     } else {
         return None;
     }
-    Some(inst)
+    Some(Ok(inst))
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone)]
 struct ChatInstruction<'a> {
     msg: &'a str,
     temp: f32,
     /// Whether or not to send previous messages as context
     context: bool,
+    /// How much of the previous messages to send, if not all of them
+    history: Option<HistoryLimit>,
     /// Whether or not to save this message and its reply as context
     save: bool,
     /// Whether or not to send only a pastebin link
     pastebin: bool,
     /// Whether to send the reply as audio
     tts: bool,
+    /// Whether to include images older than an hour, which are normally left out
+    with_images: bool,
+    /// Show the request that would be sent, instead of sending it
+    dry: bool,
+    /// Limit on the length of the reply, in tokens
+    max_tokens: Option<u16>,
+    length: ReplyLength,
+    /// Language to reply in
+    lang: Option<String>,
+    /// Announce any tools the model called, before the answer
+    show_tools: bool,
+    /// Say how many tokens the request took, and what that cost
+    cost: bool,
+    /// The model to use, instead of the bot's current one
+    model: Option<String>,
+    /// How much reasoning models should think, one of `openai::REASONING_EFFORTS`
+    reasoning_effort: Option<String>,
+    /// The thread the asker is talking in, which is where the conversation comes from and goes
+    thread: Option<String>,
+    /// Something long that was pasted along with the question, which goes into the context (or
+    /// a summary of it does)
+    paste: Option<Paste>,
+}
+
+/// How much of a channel's context goes along with a request, as asked for with `--history`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum HistoryLimit {
+    /// Only messages from this long ago or newer
+    Age(chrono::Duration),
+    /// Only this many of the most recent messages
+    Messages(usize),
+}
+
+impl std::str::FromStr for HistoryLimit {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        if let Some(count) = s.strip_suffix("msgs").or_else(|| s.strip_suffix("msg")) {
+            return match count.parse() {
+                Ok(count) if count > 0 => Ok(HistoryLimit::Messages(count)),
+                _ => Err(()),
+            };
+        }
+        parse_age(s).map(HistoryLimit::Age).ok_or(())
+    }
+}
+
+/// How long a reply should be, as asked for with `--brief` or `--verbose`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum ReplyLength {
+    Normal,
+    Brief,
+    Verbose,
 }
 
 impl<'a> ChatInstruction<'a> {
@@ -586,60 +1231,288 @@ impl<'a> ChatInstruction<'a> {
             msg: s,
             temp: TEMPERATURE.load(),
             context: true,
+            history: None,
             save: true,
             pastebin: false,
             tts: false,
+            with_images: false,
+            dry: DRY_RUN.load(Ordering::SeqCst),
+            max_tokens: None,
+            length: ReplyLength::Normal,
+            lang: None,
+            show_tools: false,
+            cost: false,
+            model: None,
+            reasoning_effort: None,
+            thread: None,
+            paste: None,
+        }
+    }
+    /// Extra instructions for the system prompt, to go along with these options
+    fn directives(&self) -> Vec<String> {
+        let mut directives = Vec::new();
+        match self.length {
+            ReplyLength::Normal => (),
+            ReplyLength::Brief => directives
+                .push("Keep your reply as short as possible, ideally a single line.".to_string()),
+            ReplyLength::Verbose => {
+                directives.push("Give a thorough, detailed reply, even if it's long.".to_string())
+            }
         }
+        if let Some(lang) = &self.lang {
+            directives.push(format!("Reply in this language: {lang}"));
+        }
+        directives
     }
     /// Updates this object
     ///
-    /// cmd is somse sting of the form "key" or "key=value"
-    pub fn update(&mut self, cmd: &str) {
-        let mut s = cmd.splitn(2, '=');
-        let param = s.next().unwrap();
+    /// cmd is somse sting of the form "key" or "key=value".  For yes/no options, just "key"
+    /// means yes.
+    fn update(&mut self, cmd: &str) -> Result<(), ChatParseError> {
+        let (param, value) = match cmd.split_once('=') {
+            Some((param, value)) => (param, Some(value)),
+            None => (cmd, None),
+        };
+        let invalid = || ChatParseError::InvalidValue {
+            option: param.to_string(),
+            value: value.unwrap_or_default().to_string(),
+        };
+        let flag = || match value {
+            None => Ok(true),
+            Some(_) => boolify(value).ok_or_else(invalid),
+        };
         match param {
-            "context" => {
-                if let Some(val) = boolify(s.next()) {
-                    self.context = val
+            "context" => self.context = flag()?,
+            "history" => match value {
+                // the same as --context=no
+                Some("none") => self.context = false,
+                Some("all") => {
+                    self.context = true;
+                    self.history = None;
                 }
-            }
-            "save" => {
-                if let Some(val) = boolify(s.next()) {
-                    self.save = val
+                Some(limit) => {
+                    self.context = true;
+                    self.history = Some(limit.parse().map_err(|_| invalid())?);
                 }
+                None => return Err(invalid()),
+            },
+            "save" => self.save = flag()?,
+            "paste" | "pastebin" => self.pastebin = flag()?,
+            "temp" => {
+                let temp = value
+                    .and_then(|s| s.parse::<f32>().ok())
+                    .filter(|t| t.is_finite())
+                    .ok_or_else(invalid)?;
+                self.temp = temp.clamp(0.0, 2.0);
             }
-            "paste" | "pastebin" => {
-                self.pastebin = boolify(s.next()).unwrap_or(true);
+            "tts" => self.tts = flag()?,
+            "with-images" | "images" => self.with_images = flag()?,
+            "dry" | "dry-run" => self.dry = flag()?,
+            "maxtokens" | "max-tokens" => {
+                let max = value
+                    .and_then(|s| s.parse::<u16>().ok())
+                    .filter(|max| *max > 0)
+                    .ok_or_else(invalid)?;
+                self.max_tokens = Some(max);
             }
-            "temp" => {
-                if let Some(val) = s.next().and_then(|s| s.parse::<f32>().ok()) {
-                    self.temp = val.clamp(0.0, 2.0)
+            "brief" => {
+                if flag()? {
+                    self.length = ReplyLength::Brief
                 }
             }
-            "tts" => {
-                self.tts = boolify(s.next()).unwrap_or(true);
+            "verbose" => {
+                if flag()? {
+                    self.length = ReplyLength::Verbose
+                }
             }
-            _ => (),
+            "tools" | "show-tools" => self.show_tools = flag()?,
+            "cost" => self.cost = flag()?,
+            "lang" => {
+                let lang = value.filter(|l| is_valid_lang(l)).ok_or_else(invalid)?;
+                self.lang = Some(lang.to_string());
+            }
+            "model" => {
+                let model = value
+                    .filter(|m| !m.is_empty() && !m.contains(char::is_whitespace))
+                    .ok_or_else(invalid)?;
+                self.model = Some(model.to_string());
+            }
+            "effort" | "reasoning-effort" => {
+                let effort = value
+                    .filter(|e| openai::REASONING_EFFORTS.contains(e))
+                    .ok_or_else(invalid)?;
+                self.reasoning_effort = Some(effort.to_string());
+            }
+            _ => return Err(ChatParseError::UnknownOption(param.to_string())),
         }
+        Ok(())
     }
 }
 
+/// Describes the tools the model called along the way to its answer, like
+/// "🔧 weather(Paris), calc(3 kg in lb)"
+///
+/// Returns `None` if no tools were called.
+fn describe_tool_calls(messages: &[ChatCompletionResponseMessage]) -> Option<String> {
+    #![allow(deprecated)]
+    let describe = |name: &str, arguments: &str| {
+        let args = match serde_json::from_str::<serde_json::Value>(arguments) {
+            Ok(serde_json::Value::Object(map)) => map
+                .values()
+                .map(|v| match v {
+                    serde_json::Value::String(s) => s.clone(),
+                    other => other.to_string(),
+                })
+                .collect::<Vec<_>>()
+                .join(", "),
+            _ => arguments.chars().take(40).collect(),
+        };
+        format!("{name}({args})")
+    };
+    let calls: Vec<String> = messages
+        .iter()
+        .flat_map(|msg| {
+            let tools = msg
+                .tool_calls
+                .iter()
+                .flatten()
+                .map(|call| describe(&call.function.name, &call.function.arguments));
+            let function = msg
+                .function_call
+                .iter()
+                .map(|call| describe(&call.name, &call.arguments));
+            tools.chain(function).collect::<Vec<_>>()
+        })
+        .collect();
+    (!calls.is_empty()).then(|| format!("🔧 {}", calls.join(", ")))
+}
+
 // Takes all owned parameters because we'll spawn an async closure in here
 fn spawn_chat_completion_inner<'a>(
-    for_chat: Vec<ChatCompletionRequestMessage>,
+    mut for_chat: Vec<ChatCompletionRequestMessage>,
     inst: ChatInstruction<'a>,
     resp_target: String,
     target: String,
-    sender: Sender,
+    sender: ChatSender,
     source_nick: String,
     mut message_map: MessageMap,
 ) {
+    let channel_config = anna::config::get_config()
+        .ok()
+        .and_then(|config| config.channels.get(&target).cloned());
+    let options = openai::ChatOptions {
+        model: inst
+            .model
+            .clone()
+            .or_else(|| MODEL.lock().expect("model lock is poisoned").clone()),
+        temp: Some(inst.temp),
+        max_tokens: inst.max_tokens,
+        reasoning_effort: inst.reasoning_effort.clone(),
+        directives: inst
+            .directives()
+            .into_iter()
+            .chain(profiles::directives_for(inst.msg))
+            .collect(),
+        user: Some(source_nick.clone()),
+        prompt_vars: openai::PromptVars {
+            channel: target.starts_with('#').then(|| target.clone()),
+            botname: Some(BOTNAME.to_string()),
+            users_present: if target.starts_with('#') {
+                message_map.with_channel(&target, |chan| chan.users_present.clone())
+            } else {
+                Vec::new()
+            },
+            description: channel_config.as_ref().and_then(|c| c.description.clone()),
+            topic: channel_config
+                .is_some_and(|c| c.topic_in_prompt)
+                .then(|| message_map.with_channel(&target, |chan| chan.topic.clone()))
+                .flatten(),
+        },
+        tools: message_map.feature_enabled(&target, Feature::Tools),
+    };
+    if inst.dry {
+        tokio::spawn(async move {
+            let reply = match openai::dry_run_chat(for_chat, &options) {
+                Ok(dry) => {
+                    println!("Dry run request:\n{}", dry.payload);
+                    let cost = dry
+                        .estimated_cost
+                        .map(|c| format!(", ~${c:.4}"))
+                        .unwrap_or_default();
+                    let cost = match &dry.note {
+                        Some(note) => format!("{cost} ({note})"),
+                        None => cost,
+                    };
+                    match upload_content(dry.payload.into_bytes(), "application/json").await {
+                        Ok(url) => format!(
+                            "Dry run: ~{} input tokens{cost}, request at {url}",
+                            dry.estimated_tokens
+                        ),
+                        Err(e) => format!(
+                            "Dry run: ~{} input tokens{cost} (failed to upload the request: {e})",
+                            dry.estimated_tokens
+                        ),
+                    }
+                }
+                Err(e) => format!("Dry run failed: {e}"),
+            };
+            let _ = sender.send_privmsg(&resp_target, format!("{source_nick}: {reply}"));
+        });
+        return;
+    }
+    let asked_at = message_map.now();
+    // the task outlives the message it's answering
+    let question = inst.msg.to_string();
     tokio::spawn(async move {
-        match openai::get_chat(for_chat, None, Some(inst.temp)).await {
-            Ok(resp) => {
-                dbg!(&resp);
+        if let Some(paste) = &inst.paste {
+            match add_paste(&mut for_chat, paste, &question, &source_nick, &message_map).await {
+                Ok(note) if inst.save => {
+                    let thread = inst.thread.as_deref();
+                    remember_paste(&message_map, &target, thread, &source_nick, note);
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    let _ = sender.send_privmsg(
+                        &resp_target,
+                        format!("{source_nick}: I couldn't read what you pasted ({e})"),
+                    );
+                }
+            }
+        }
+        // with --tts the reply is spoken while it's being written, rather than afterwards
+        let (result, spoken) = if inst.tts && !inst.pastebin {
+            match openai::get_spoken_chat(for_chat, &options).await {
+                Ok(spoken) => (Ok(spoken.reply), Some(spoken.audio)),
+                Err(e) => (Err(e), None),
+            }
+        } else {
+            (
+                openai::get_chat_with_options(for_chat, &options).await,
+                None,
+            )
+        };
+        match result {
+            Ok(openai::ChatReply {
+                messages: resp,
+                model,
+                note,
+                usage,
+            }) => {
+                if let Some(note) = note {
+                    let _ = sender.send_privmsg(&resp_target, format!("({note})"));
+                }
                 if inst.save {
-                    message_map.insert_selfmsg(&target, &resp);
+                    message_map.insert_selfmsg(
+                        &target,
+                        inst.thread.as_deref(),
+                        &resp,
+                        Some(&model),
+                    );
+                }
+                if inst.show_tools {
+                    if let Some(tools) = describe_tool_calls(&resp) {
+                        let _ = sender.send_privmsg(&resp_target, tools);
+                    }
                 }
                 // we need to save all messages, but only the last one will be sent back to IRC
                 match resp.last() {
@@ -647,30 +1520,35 @@ fn spawn_chat_completion_inner<'a>(
                         content: Some(resp_content),
                         ..
                     }) => {
+                        let mode = anna::config::get_config()
+                            .unwrap_or_default()
+                            .reply_addressing;
+                        let busy = message_map.others_spoke_since(&target, &source_nick, asked_at);
+                        let address = |reply: &str| {
+                            addressing::address(reply, BOTNAME, &source_nick, mode, busy)
+                        };
                         if inst.pastebin {
-                            match upload_content(
-                                resp_content.as_bytes().to_vec(),
-                                "text/plain; charset=utf-8",
-                            )
-                            .await
-                            {
+                            match upload_reply(resp_content).await {
                                 Ok(url) => {
-                                    let _ = sender.send_privmsg(
-                                        &resp_target,
-                                        format!("{source_nick}: {url}",),
-                                    );
+                                    let _ = sender.send_privmsg(&resp_target, address(&url));
                                 }
                                 Err(e) => {
-                                    dbg!(e);
+                                    println!("Failed to upload a reply: {e}");
+                                    send_truncated_message(
+                                        &sender,
+                                        &resp_target,
+                                        &address(resp_content),
+                                    );
                                 }
                             }
                         } else if inst.tts {
-                            match get_tts(&resp_content).await {
+                            let audio = match spoken {
+                                Some(audio) => audio,
+                                None => get_tts_reply(resp_content).await,
+                            };
+                            match audio {
                                 Ok(url) => {
-                                    let _ = sender.send_privmsg(
-                                        &resp_target,
-                                        format!("{source_nick}: {url}"),
-                                    );
+                                    let _ = sender.send_privmsg(&resp_target, address(&url));
                                 }
                                 Err(e) => {
                                     dbg!(e);
@@ -680,13 +1558,37 @@ fn spawn_chat_completion_inner<'a>(
                             send_possibly_long_message(
                                 sender,
                                 &resp_target,
-                                trim_botname(resp_content),
+                                &address(resp_content),
                             )
                             .await;
                         }
                     }
                     _ => {}
                 }
+                loops::record_reply(&source_nick, message_map.now());
+                if inst.cost {
+                    let mut cost = openai::describe_usage(&model, usage.as_ref());
+                    if inst.tts && !inst.pastebin {
+                        let spoken = resp.last().and_then(|m| m.content.as_deref());
+                        let tts = openai::tts_cost(spoken.unwrap_or_default());
+                        cost.push_str(&format!(", plus ~${tts:.4} for the audio"));
+                    }
+                    let _ = sender.send_privmsg(&resp_target, format!("({cost})"));
+                }
+            }
+            Err(e) if e.is::<BackendUnavailable>() => {
+                // the channel hears about it once, not once for every request
+                let announce = e
+                    .downcast_ref::<BackendUnavailable>()
+                    .is_some_and(|unavailable| unavailable.announce);
+                if announce {
+                    let _ = sender.send_privmsg(&resp_target, e.to_string());
+                }
+                let reply = offline_reply(&message_map, &target, &question).await;
+                let _ = sender.send_privmsg(
+                    &resp_target,
+                    format!("{source_nick}: {reply} {}", fallback::RESUME_MARKER),
+                );
             }
             Err(e) => {
                 println!("Error getting chat from openai:");
@@ -700,16 +1602,85 @@ fn spawn_chat_completion_inner<'a>(
     });
 }
 
-fn spawn_chat_completion<'a>(
-    for_chat: Vec<ChatCompletionRequestMessage>,
-    inst: ChatInstruction<'a>,
-    resp_target: impl ToString,
-    target: impl ToString,
-    sender: Sender,
-    source_nick: impl ToString,
-    message_map: MessageMap,
+/// Puts what was pasted along with a question into the request, just before the question
+///
+/// Long pastes are summarized with the question in mind.  Returns the message the paste (or its
+/// summary) went in, for `remember_paste`.
+async fn add_paste(
+    for_chat: &mut Vec<ChatCompletionRequestMessage>,
+    paste: &Paste,
+    question: &str,
+    nick: &str,
+    message_map: &MessageMap,
+) -> anyhow::Result<ChatCompletionRequestMessage> {
+    // fetched just the once, whether it's summarized or not
+    let text = paste::text(&message_map.client, paste).await?;
+    let digest = paste::digest(paste, &text, nick, question).await?;
+    // it's something the user said, so it's attributed to them like the rest of what they say
+    let note = ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
+        content: ChatCompletionRequestUserMessageContent::Text(format!("<{nick}> {digest}")),
+        role: async_openai::types::Role::User,
+        name: Some(nick.to_string()),
+    });
+    // in both the history and the request, the paste goes just before the question about it
+    let question = for_chat.len().saturating_sub(1);
+    for_chat.insert(question, note.clone());
+    Ok(note)
+}
+
+/// Keeps a paste added with `add_paste` in the context, just before the question about it
+fn remember_paste(
+    message_map: &MessageMap,
+    target: &str,
+    thread: Option<&str>,
+    nick: &str,
+    note: ChatCompletionRequestMessage,
 ) {
-    spawn_chat_completion_inner(
+    let now = message_map.now();
+    message_map.with_messages(target, thread, |m| {
+        let question = m.iter().rposition(|cmt| {
+            matches!(&cmt.msg, ChatCompletionRequestMessage::User(user)
+                if user.name.as_deref() == Some(nick))
+        });
+        let cmt = ChatMessageThing::new_at(note, now);
+        match question {
+            Some(i) => m.insert(i, cmt),
+            None => m.push_back(cmt),
+        }
+    });
+}
+
+/// Answers what can be answered without the model, for when the backend is down
+async fn offline_reply(message_map: &MessageMap, channel: &str, question: &str) -> String {
+    match fallback::respond(question) {
+        Fallback::Calc(expr) => {
+            let ctx = message_map.with_channel(channel, |chan| chan.numbat_context.clone());
+            match eval_numbat(&ctx, &expr).await {
+                Ok(result) => result,
+                Err(e) => format!("Error: {e}"),
+            }
+        }
+        Fallback::Weather(location) => {
+            let input = anna::wttr::WeatherInput::parse(&location);
+            match anna::wttr::get_weather(&input).await {
+                Ok(weather) => weather.to_string(),
+                Err(e) => format!("Error: {e}"),
+            }
+        }
+        Fallback::Canned(reply) => reply.to_string(),
+    }
+}
+
+fn spawn_chat_completion<'a>(
+    for_chat: Vec<ChatCompletionRequestMessage>,
+    inst: ChatInstruction<'a>,
+    resp_target: impl ToString,
+    target: impl ToString,
+    sender: ChatSender,
+    source_nick: impl ToString,
+    message_map: MessageMap,
+) {
+    spawn_chat_completion_inner(
         for_chat,
         inst,
         resp_target.to_string(),
@@ -720,32 +1691,508 @@ fn spawn_chat_completion<'a>(
     );
 }
 
+/// Runs a trivia game: asks each question, waits for someone to get it, and announces the
+/// winner at the end
+fn spawn_trivia(sender: ChatSender, channel: String, id: u64) {
+    tokio::spawn(async move {
+        loop {
+            let question = match trivia::next_question(&channel, id).await {
+                Ok(Some(question)) => question,
+                Ok(None) => break,
+                Err(e) => {
+                    let _ = sender
+                        .send_privmsg(&channel, format!("I couldn't come up with a question: {e}"));
+                    break;
+                }
+            };
+            let _ = sender.send_privmsg(&channel, question);
+            let deadline =
+                tokio::time::Instant::now() + std::time::Duration::from_secs(trivia::ANSWER_SECS);
+            while trivia::is_waiting(&channel, id) && tokio::time::Instant::now() < deadline {
+                tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+            }
+            if let Some(answer) = trivia::expire(&channel, id) {
+                let _ = sender.send_privmsg(&channel, answer);
+            }
+            // a breather between questions, which also keeps the bot from flooding
+            tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+        }
+        if let Some(scores) = trivia::finish(&channel, Some(id), Utc::now()) {
+            let _ = sender.send_privmsg(&channel, scores);
+        }
+    });
+}
+
+/// Handles the owner-only `!trigger` admin commands, returning the reply to send
+///
+/// `!trigger add <channel> <cooldown minutes> <regex>`, `!trigger list <channel>`,
+/// `!trigger del <channel> <index>`
+fn trigger_command(message_map: &MessageMap, args: &str) -> String {
+    let usage = "Usage: !trigger add <channel> <cooldown minutes> <regex> | list <channel> | del <channel> <index>";
+    let mut split = args.trim().splitn(4, ' ');
+    match (split.next(), split.next(), split.next(), split.next()) {
+        (Some("add"), Some(channel), Some(cooldown), Some(pattern)) => {
+            let Ok(cooldown) = cooldown.parse::<i64>() else {
+                return usage.to_string();
+            };
+            match InterjectionTrigger::new(pattern.trim(), cooldown) {
+                Ok(trigger) => {
+                    message_map.with_channel(channel, |chan| chan.triggers.push(trigger));
+                    format!("Added trigger for {channel}")
+                }
+                Err(e) => format!("Invalid pattern: {e}"),
+            }
+        }
+        (Some("list"), Some(channel), None, None) => message_map.with_channel(channel, |chan| {
+            if chan.triggers.is_empty() {
+                return format!("No triggers for {channel}");
+            }
+            chan.triggers
+                .iter()
+                .enumerate()
+                .map(|(idx, t)| format!("{idx}: /{}/ ({}m)", t.pattern, t.cooldown_minutes))
+                .collect::<Vec<_>>()
+                .join(", ")
+        }),
+        (Some("del"), Some(channel), Some(idx), None) => {
+            let Ok(idx) = idx.parse::<usize>() else {
+                return usage.to_string();
+            };
+            message_map.with_channel(channel, |chan| {
+                if idx < chan.triggers.len() {
+                    let removed = chan.triggers.remove(idx);
+                    format!("Removed trigger /{}/", removed.pattern)
+                } else {
+                    format!("No trigger {idx} for {channel}")
+                }
+            })
+        }
+        _ => usage.to_string(),
+    }
+}
+
+/// Handles the owner-only `!faq` commands for a channel, returning the reply to send
+///
+/// `!faq add <question> :: <answer>`, `!faq list`, `!faq del <index>`
+async fn faq_command(message_map: &MessageMap, channel: &str, args: &str) -> String {
+    let usage = "Usage: !faq add <question> :: <answer> | list | del <index>";
+    let (cmd, rest) = args.trim().split_once(' ').unwrap_or((args.trim(), ""));
+    match cmd {
+        "add" => {
+            let Some((question, answer)) = faq::parse_entry(rest) else {
+                return usage.to_string();
+            };
+            match FaqEntry::new(question, answer).await {
+                Ok(entry) => {
+                    message_map.with_channel(channel, |chan| chan.faqs.push(entry));
+                    format!("Added FAQ for {channel}")
+                }
+                Err(e) => format!("Error: {e}"),
+            }
+        }
+        "list" => message_map.with_channel(channel, |chan| {
+            if chan.faqs.is_empty() {
+                return format!("No FAQs for {channel}");
+            }
+            chan.faqs
+                .iter()
+                .enumerate()
+                .map(|(idx, entry)| format!("{idx}: {}", entry.question))
+                .collect::<Vec<_>>()
+                .join(", ")
+        }),
+        "del" => {
+            let Ok(idx) = rest.trim().parse::<usize>() else {
+                return usage.to_string();
+            };
+            message_map.with_channel(channel, |chan| {
+                if idx < chan.faqs.len() {
+                    let removed = chan.faqs.remove(idx);
+                    format!("Removed FAQ {:?}", removed.question)
+                } else {
+                    format!("No FAQ {idx} for {channel}")
+                }
+            })
+        }
+        _ => usage.to_string(),
+    }
+}
+
+/// Handles `!ctx`, for saving and restoring named snapshots of a channel's conversation
+///
+/// A snapshot can only be replaced or deleted by whoever saved it, or by an op.
+fn ctx_command(
+    message_map: &MessageMap,
+    channel: &str,
+    nick: &str,
+    is_admin: bool,
+    args: &str,
+) -> String {
+    let usage = "Usage: !ctx save <name> | load <name> | del <name> | list";
+    let now = message_map.now();
+    let mut split = args.split_ascii_whitespace();
+    message_map.with_channel(channel, |chan| {
+        let reply = match (split.next(), split.next(), split.next()) {
+            (Some("save"), Some(name), None) => chan
+                .save_context(name, nick, is_admin)
+                .map(|count| format!("Saved {count} messages as {name:?}")),
+            (Some("load"), Some(name), None) => Ok(if chan.load_context(name, now) {
+                format!("Restored {} messages from {name:?}", chan.messages.len())
+            } else {
+                format!("No saved context named {name:?}")
+            }),
+            (Some("del"), Some(name), None) => {
+                chan.delete_context(name, nick, is_admin).map(|deleted| {
+                    if deleted {
+                        format!("Deleted saved context {name:?}")
+                    } else {
+                        format!("No saved context named {name:?}")
+                    }
+                })
+            }
+            (Some("list"), None, None) => {
+                if chan.saved_contexts.is_empty() {
+                    return "No saved contexts".to_string();
+                }
+                Ok(chan
+                    .saved_contexts
+                    .iter()
+                    .map(|(name, messages)| format!("{name} ({} messages)", messages.len()))
+                    .collect::<Vec<_>>()
+                    .join(", "))
+            }
+            _ => Ok(usage.to_string()),
+        };
+        reply.unwrap_or_else(|e| format!("{nick}: {e}"))
+    })
+}
+
+/// Handles `!thread`, returning the reply to send
+///
+/// `!thread start <name>`, `!thread use <name>`, `!thread leave`, `!thread end`, `!thread list`
+fn thread_command(message_map: &MessageMap, channel: &str, nick: &str, args: &str) -> String {
+    let usage = "Usage: !thread start <name> | use <name> | leave | end | list";
+    let now = message_map.now();
+    let mut split = args.split_ascii_whitespace();
+    message_map.with_channel(channel, |chan| {
+        let threads = &mut chan.threads;
+        let reply = match (split.next(), split.next(), split.next()) {
+            (Some("start"), Some(name), None) => threads
+                .start(nick, name, now)
+                .map(|()| format!("{nick}: Started thread {name}, with a conversation of its own")),
+            (Some("use"), Some(name), None) => threads
+                .join(nick, name, now)
+                .map(|()| format!("{nick}: You're in thread {name} now")),
+            (Some("leave"), None, None) => Ok(match threads.leave(nick) {
+                Some(name) => format!("{nick}: Left thread {name}"),
+                None => format!("{nick}: You're not in a thread"),
+            }),
+            (Some("end"), None, None) => Ok(match threads.end(nick) {
+                Some(name) => format!("Ended thread {name}"),
+                None => format!("{nick}: You're not in a thread"),
+            }),
+            (Some("list"), None, None) => {
+                threads.expire(now);
+                Ok(threads.to_string())
+            }
+            _ => Ok(usage.to_string()),
+        };
+        reply.unwrap_or_else(|e| format!("{nick}: {e}"))
+    })
+}
+
+/// Handles the owner-only `!plugin` admin commands, returning the reply to send
+async fn plugin_command(plugins: &mut PluginManager, cmd: &str) -> String {
+    let mut split = cmd.split_ascii_whitespace();
+    match (split.next(), split.next()) {
+        (Some("list"), _) => {
+            let list = plugins.list();
+            if list.is_empty() {
+                return "No plugins found".to_string();
+            }
+            list.iter()
+                .map(|(name, status)| format!("{name} ({status})"))
+                .collect::<Vec<_>>()
+                .join(", ")
+        }
+        (Some("reload"), Some(name)) => match plugins.reload(name).await {
+            Ok(()) => format!("Reloaded plugin {name}"),
+            Err(e) => format!("Failed to reload plugin {name}: {e}"),
+        },
+        (Some("disable"), Some(name)) => {
+            if plugins.disable(name) {
+                format!("Disabled plugin {name}")
+            } else {
+                format!("Plugin {name} wasn't loaded, but it won't be loaded again until reloaded")
+            }
+        }
+        _ => "Usage: !plugin list | reload <name> | disable <name>".to_string(),
+    }
+}
+
+#[derive(Parser)]
+#[command(about = "An IRC bot, and some tools for working with its saved state")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<CliCommand>,
+}
+
+#[derive(Subcommand)]
+enum CliCommand {
+    /// Connect to IRC and run the bot (this is the default)
+    Run {
+        /// Show chat requests instead of sending them to the API
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Summarize a saved channel history file
+    Summarize { path: PathBuf },
+    /// Transcribe a local audio file
+    Transcribe {
+        file: PathBuf,
+        /// Text to guide the transcription (names, spellings, etc)
+        #[arg(long)]
+        prompt: Option<String>,
+    },
+    /// Compute embeddings for a channel's saved history, and save them to {channel}.embeddings.json
+    EmbedHistory { channel: String },
+    /// Check that config.json and prompts.json can be loaded
+    CheckConfig,
+    /// Check that everything the bot depends on works: the API key and models, the paste
+    /// services, the numbat component and plugins
+    Check,
+    /// Delete old uploads from the paste services that allow it, and forget the expired ones
+    PurgeUploads {
+        /// How old an upload has to be, like 30d or 12h
+        #[arg(long, default_value = "30d")]
+        older_than: String,
+    },
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    match cli.command.unwrap_or(CliCommand::Run { dry_run: false }) {
+        CliCommand::Run { dry_run } => {
+            DRY_RUN.store(dry_run, Ordering::SeqCst);
+            run_bot().await
+        }
+        CliCommand::Summarize { path } => {
+            let state = ChannelState::load(&path)
+                .with_context(|| format!("Failed to load {}", path.display()))?;
+            let messages: Vec<_> = state.messages.into_iter().collect();
+            println!("{}", anna::generate_summary(&messages).await?);
+            Ok(())
+        }
+        CliCommand::Transcribe { file, prompt } => {
+            let text = openai::transcribe_file(&file, prompt, &mut Progress::silent()).await?;
+            println!("{text}");
+            Ok(())
+        }
+        CliCommand::EmbedHistory { channel } => {
+            let state = ChannelState::load(format!("{channel}.json"))
+                .with_context(|| format!("Failed to load state for {channel}"))?;
+            let embedded = embeddings::embed_history(&state.messages).await?;
+            let path = embeddings::store_path(&channel);
+            embeddings::save(&path, &embedded)?;
+            println!("Saved {} embeddings to {path}", embedded.len());
+            Ok(())
+        }
+        CliCommand::CheckConfig => check_config(),
+        CliCommand::Check => {
+            let mut plugins = PluginManager::new("plugins")?;
+            plugins.load_all().await;
+            let checks = selftest::run(plugins.list()).await;
+            for check in &checks {
+                println!("{check}");
+            }
+            println!("{}", selftest::summary(&checks));
+            if checks.iter().any(|check| check.result.is_err()) {
+                bail!("Some checks failed");
+            }
+            Ok(())
+        }
+        CliCommand::PurgeUploads { older_than } => {
+            let older_than = parse_age(&older_than)
+                .with_context(|| format!("Can't tell how long {older_than} is (try 30d)"))?;
+            println!("{}", uploads::purge(older_than).await?);
+            Ok(())
+        }
+    }
+}
+
+/// Makes sure the files the bot reads at runtime can be parsed
+fn check_config() -> anyhow::Result<()> {
+    let config = anna::config::get_config().context("config.json is invalid")?;
+    println!("config.json: ok ({:?} backend)", config.backend.kind);
+    anna::http::check()?;
+    for name in config.plugins.keys() {
+        println!("  policy for plugin {name}");
+    }
+
+    let file = File::open("prompts.json").context("Failed to open prompts.json")?;
+    let prompts: HashMap<String, String> =
+        serde_json::from_reader(file).context("prompts.json is invalid")?;
+    let mut keys: Vec<_> = prompts.keys().map(|k| k.as_str()).collect();
+    keys.sort();
+    println!("prompts.json: ok ({})", keys.join(", "));
+    Ok(())
+}
+
+/// Lets the HTTP API operate the bot
+struct ApiControl {
+    sender: ChatSender,
+    matrix_sender: Option<ChatSender>,
+    discord_sender: Option<ChatSender>,
+    message_map: MessageMap,
+}
+
+impl ApiControl {
+    fn sender_for(&self, target: &str) -> &ChatSender {
+        match (&self.matrix_sender, &self.discord_sender) {
+            (Some(matrix), _) if matrix::is_matrix_target(target) => matrix,
+            (_, Some(discord)) if discord::is_discord_target(target) => discord,
+            _ => &self.sender,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl BotControl for ApiControl {
+    fn send(&self, target: &str, message: &str) -> anyhow::Result<()> {
+        self.sender_for(target).send_privmsg(target, message)
+    }
+    fn join(&self, channel: &str) -> anyhow::Result<()> {
+        self.sender_for(channel).send_join(channel)
+    }
+    fn part(&self, channel: &str) -> anyhow::Result<()> {
+        self.sender_for(channel).send_part(channel)
+    }
+    async fn summary(&self, channel: &str) -> anyhow::Result<Option<String>> {
+        let Some(messages) = self.message_map.get_channel(channel, |chan| {
+            chan.messages.iter().cloned().collect::<Vec<_>>()
+        }) else {
+            return Ok(None);
+        };
+        Ok(Some(anna::generate_summary(&messages).await?))
+    }
+    fn channels(&self) -> Vec<String> {
+        let inner = self
+            .message_map
+            .inner
+            .lock()
+            .expect("inner lock is poisoned");
+        inner.keys().cloned().collect()
+    }
+    fn history(&self, channel: &str) -> Option<Vec<ChatMessageThing>> {
+        self.message_map
+            .get_channel(channel, |chan| chan.messages.iter().cloned().collect())
+    }
+    fn settings(&self, channel: &str) -> Option<Vec<(&'static str, String)>> {
+        self.message_map.get_channel(channel, |chan| {
+            vec![
+                ("chattiness", chan.chattiness.to_string()),
+                ("autoclear", chan.auto_clear.to_string()),
+                ("features", chan.features.to_string()),
+                ("convert", chan.auto_convert.to_string()),
+                (
+                    "token budget",
+                    chan.token_budget
+                        .map_or_else(|| "none".to_string(), |b| b.to_string()),
+                ),
+                ("triggers", chan.triggers.len().to_string()),
+                ("faqs", chan.faqs.len().to_string()),
+                (
+                    "saved contexts",
+                    chan.saved_contexts
+                        .keys()
+                        .cloned()
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                ),
+            ]
+        })
+    }
+}
+
+async fn run_bot() -> anyhow::Result<()> {
     let config = Config {
         owners: vec!["achin".into()],
         nickname: Some(BOTNAME.into()),
         channels: vec!["##em32".into(), "#overviewer".into()],
         server: Some("irc.libera.chat".into()),
         use_tls: Some(true),
+        // outgoing messages are queued and sent at most 15 per 8 seconds
+        burst_window_length: Some(8),
+        max_messages_in_burst: Some(15),
         ..Default::default()
     };
 
     TEMPERATURE.store(1.0);
+    anna::http::check()?;
 
     let mut client = Client::from_config(config).await?;
 
     // keeps a list of the past 50 messages in a chat room
     let mut message_map = MessageMap::default();
 
+    // wasm plugins, loaded from the plugins directory
+    let plugins = Arc::new(tokio::sync::Mutex::new(PluginManager::new("plugins")?));
+    plugins.lock().await.load_all().await;
+
     let mut stream = client.stream()?;
-    let sender = client.sender();
+    let irc_sender = ChatSender::new(client.sender());
     client.identify()?;
 
+    // messages from networks other than IRC, along with what sends the replies back
+    let (network_tx, mut network_messages) = tokio::sync::mpsc::unbounded_channel();
+    let mut matrix_sender = None;
+    let mut discord_sender = None;
+    if let Ok(config) = anna::config::get_config() {
+        if config.matrix.access_token.is_some() {
+            let rooms = config.matrix.rooms.clone();
+            match matrix::connect(config.matrix).await {
+                Ok((sender, messages)) => {
+                    let sender = ChatSender::new(sender);
+                    forward_messages(messages, sender.clone(), &message_map, network_tx.clone());
+                    matrix_sender = Some(sender);
+                    for room in &rooms {
+                        if let Err(e) = message_map.load(room, false) {
+                            println!("Failed to load state for room {room}: {e}");
+                        }
+                    }
+                }
+                Err(e) => println!("Failed to connect to Matrix: {e:#}"),
+            }
+        }
+        if config.discord.token.is_some() {
+            match discord::connect(config.discord, BOTNAME).await {
+                Ok((sender, messages)) => {
+                    let sender = ChatSender::new(sender);
+                    forward_messages(messages, sender.clone(), &message_map, network_tx.clone());
+                    discord_sender = Some(sender);
+                }
+                Err(e) => println!("Failed to connect to Discord: {e:#}"),
+            }
+        }
+        let control = ApiControl {
+            sender: irc_sender.clone(),
+            matrix_sender,
+            discord_sender,
+            message_map: message_map.clone(),
+        };
+        api::spawn(config.api, Arc::new(control));
+    }
+
     // Channel and message
 
     loop {
-        let message: Message = stream.select_next_some().await?;
+        // messages from other networks are handled just like IRC ones, and the replies go back
+        // to wherever the message came from
+        let (message, sender): (Message, ChatSender) = tokio::select! {
+            message = stream.select_next_some() => (message?, irc_sender.clone()),
+            Some(from_network) = network_messages.recv() => from_network,
+        };
         // dbg!(&message);
         match message.command {
             Command::PING(..) | Command::PONG(..) => continue,
@@ -763,6 +2210,25 @@ async fn main() -> anyhow::Result<()> {
                 println!("Loaded state for {channel}");
             }
         }
+        // the topic is sent when joining, and again whenever someone changes it
+        let new_topic = match &message.command {
+            Command::TOPIC(channel, Some(topic)) => Some((channel.as_str(), topic.as_str())),
+            Command::Response(Response::RPL_TOPIC, args) if args.len() >= 3 => {
+                Some((args[1].as_str(), args[2].as_str()))
+            }
+            _ => None,
+        };
+        if let Some((channel, topic)) = new_topic {
+            message_map.with_channel(channel, |chan| {
+                chan.topic = (!topic.trim().is_empty()).then(|| topic.trim().to_string())
+            });
+        }
+        if let Command::TOPIC(channel, Some(_)) = &message.command {
+            if message_map.with_channel(channel, |chan| chan.auto_clear.on_topic_change) {
+                println!("Topic changed in {channel}, clearing context");
+                message_map.auto_clear_context(channel);
+            }
+        }
         if let Command::PRIVMSG(target, msg) = &message.command {
             let from_achin_operator = match &message.prefix {
                 Some(Prefix::Nickname(nick, user, host)) => {
@@ -773,10 +2239,60 @@ async fn main() -> anyhow::Result<()> {
             let Some(source_nick) = message.source_nickname() else {
                 continue;
             };
+            // lines passed along by a bridge are attributed to (and need the consent of) whoever
+            // actually said them, rather than the bridge
+            let source_host = match &message.prefix {
+                Some(Prefix::Nickname(_, _, host)) => Some(host.as_str()),
+                _ => None,
+            };
+            let relays = anna::config::get_config()
+                .map(|config| config.relays)
+                .unwrap_or_default();
+            let relayed = relay::unwrap(&relays, source_nick, source_host, msg);
+            let (source_nick, msg) = match &relayed {
+                Some(relayed) => (relayed.nick.as_str(), relayed.text.as_str()),
+                None => (source_nick, msg.as_str()),
+            };
             if BOTS_TO_IGNORE.contains(&source_nick) {
                 // to prevent annoying bot loops, never listen to other robots
                 continue;
             }
+            let opted_in = opted_in(sender.protocol(), source_nick);
+            let source_user = match &message.prefix {
+                Some(Prefix::Nickname(_, user, _)) => user.as_str(),
+                _ => source_nick,
+            };
+            let prefs_key = prefs_key(sender.protocol(), source_nick, source_user);
+            // only bots get caught in loops, and the owner is never taken for one
+            let loop_checked = !from_achin_operator
+                && loops::looks_like_bot(source_nick, source_host, relayed.is_some());
+            if loop_checked && loops::is_muted(source_nick, message_map.now()) {
+                // a robot that isn't on the list yet, by the look of it
+                continue;
+            }
+            // channel ops can manage their own channel, but bot-wide commands stay owner-only.
+            // A relayed nick could be anyone's, so it's never taken as an op's.
+            let may_admin_channel = || {
+                from_achin_operator
+                    || (relayed.is_none()
+                        && target.starts_with('#')
+                        && is_chanop(&client, target, source_nick))
+            };
+
+            if let Some(users) = client.list_users(target) {
+                let nicks = users.iter().map(|u| u.get_nickname().to_string()).collect();
+                message_map.with_channel(target, |chan| chan.users_present = nicks);
+            }
+
+            {
+                // plugins see every message, and decide for themselves what to do with it
+                let plugins = plugins.clone();
+                let line = msg.to_string();
+                let channel = target.starts_with('#').then(|| target.to_string());
+                tokio::spawn(async move {
+                    plugins.lock().await.handle(&line, channel.as_deref()).await;
+                });
+            }
 
             if let Some(resp_target) = message.response_target() {
                 if from_achin_operator {
@@ -791,6 +2307,109 @@ async fn main() -> anyhow::Result<()> {
                         sender.send_part(to_part.trim())?;
                         continue;
                     }
+                    if let Some(cmd) = msg.strip_prefix("!plugin ") {
+                        let reply = plugin_command(&mut *plugins.lock().await, cmd).await;
+                        sender.send_privmsg(resp_target, reply)?;
+                        continue;
+                    }
+                    if msg.trim() == "!selftest" {
+                        let plugin_status = plugins.lock().await.list();
+                        let sender = sender.clone();
+                        let resp_target = resp_target.to_string();
+                        tokio::spawn(async move {
+                            let checks = selftest::run(plugin_status).await;
+                            let _ = sender.send_privmsg(&resp_target, selftest::summary(&checks));
+                            for check in checks.iter().filter(|check| check.result.is_err()) {
+                                let _ = sender.send_privmsg(&resp_target, check.to_string());
+                            }
+                        });
+                        continue;
+                    }
+                    if msg.trim() == "!usage" {
+                        sender.send_privmsg(resp_target, anna::keys::usage_report())?;
+                        continue;
+                    }
+                    if msg.trim() == "!lastreq" {
+                        let Some(entry) = anna::audit::last_entry() else {
+                            sender.send_privmsg(resp_target, "No requests since startup")?;
+                            continue;
+                        };
+                        let sender = sender.clone();
+                        let resp_target = resp_target.to_string();
+                        tokio::spawn(async move {
+                            match upload_content(entry.into_bytes(), "application/json").await {
+                                Ok(url) => sender.send_privmsg(resp_target, url),
+                                Err(e) => sender.send_privmsg(resp_target, format!("Error: {e}")),
+                            }
+                        });
+                        continue;
+                    }
+                    if let Some(channel) = msg.strip_prefix("!feedback") {
+                        let channel = Some(channel.trim()).filter(|c| !c.is_empty());
+                        let records = match feedback::load(channel) {
+                            Ok(records) if records.is_empty() => {
+                                sender.send_privmsg(resp_target, "No feedback yet")?;
+                                continue;
+                            }
+                            Ok(records) => records,
+                            Err(e) => {
+                                sender.send_privmsg(resp_target, format!("Error: {e}"))?;
+                                continue;
+                            }
+                        };
+                        let good = records
+                            .iter()
+                            .filter(|r| r.feedback.vote == Vote::Good)
+                            .count();
+                        let summary = format!("{good} good, {} bad", records.len() - good);
+                        let export = serde_json::to_string_pretty(&records)?;
+                        let sender = sender.clone();
+                        let resp_target = resp_target.to_string();
+                        tokio::spawn(async move {
+                            match upload_content(export.into_bytes(), "application/json").await {
+                                Ok(url) => {
+                                    sender.send_privmsg(resp_target, format!("{summary}: {url}"))
+                                }
+                                Err(e) => sender.send_privmsg(resp_target, format!("Error: {e}")),
+                            }
+                        });
+                        continue;
+                    }
+                    let (say, is_action) = match msg.strip_prefix("!say ") {
+                        Some(args) => (Some(args), false),
+                        None => (msg.strip_prefix("!act "), true),
+                    };
+                    if let Some(args) = say {
+                        let Some((to, text)) = args.trim().split_once(' ') else {
+                            sender
+                                .send_privmsg(resp_target, "Usage: !say|!act <target> <message>")?;
+                            continue;
+                        };
+                        let joined = client.list_channels().unwrap_or_default();
+                        if to.starts_with('#') && !joined.iter().any(|c| c.eq_ignore_ascii_case(to))
+                        {
+                            sender.send_privmsg(resp_target, format!("I'm not in {to}"))?;
+                            continue;
+                        }
+                        // these go through the same throttled queue as everything else we send
+                        if is_action {
+                            sender.send_action(to, text.trim())?;
+                        } else {
+                            sender.send_privmsg(to, text.trim())?;
+                        }
+                        continue;
+                    }
+                }
+
+                if fallback::needs_model(msg) && !openai::backend_available() {
+                    sender.send_privmsg(
+                        resp_target,
+                        format!(
+                            "{source_nick}: That needs the AI backend, which is down right now {}",
+                            fallback::RESUME_MARKER
+                        ),
+                    )?;
+                    continue;
                 }
 
                 if from_achin_operator && target == BOTNAME {
@@ -818,11 +2437,16 @@ async fn main() -> anyhow::Result<()> {
                         }
                     } else if let Some(channel) = msg.strip_prefix("!sendinterjection ") {
                         let channel = channel.trim();
-                        let x = message_map.with_channel(channel, |c| c.interjection.clone());
-                        if let Some(interjection) = x {
+                        let (x, may_speak) = message_map.with_channel(channel, |c| {
+                            (c.interjection.clone(), c.chattiness.may_speak(Utc::now()))
+                        });
+                        if let Err(reason) = may_speak {
+                            sender.send_privmsg(resp_target, format!("Not sending: {reason}"))?;
+                        } else if let Some(interjection) = x {
                             sender.send_privmsg(channel, &interjection)?;
                             message_map.insert_selfmsg_str(channel, &interjection);
                             message_map.save_interjection(channel, None);
+                            message_map.with_channel(channel, |c| c.chattiness.record(Utc::now()));
                         } else {
                             println!("no interjection for {channel}");
                         }
@@ -842,6 +2466,9 @@ async fn main() -> anyhow::Result<()> {
                                     .send_privmsg(resp_target, format!("Error in imggen: {e}"))?;
                             }
                         }
+                    } else if let Some(args) = msg.strip_prefix("!trigger ") {
+                        let reply = trigger_command(&message_map, args);
+                        sender.send_privmsg(resp_target, reply)?;
                     } else if let Some(_) = msg.strip_prefix("!save") {
                         message_map.save_all()?;
                     } else if let Some(channel) = msg.strip_prefix("!load") {
@@ -869,6 +2496,42 @@ async fn main() -> anyhow::Result<()> {
                         )?;
                     }
                     continue;
+                } else if let Some(model) = msg.strip_prefix("!set model") {
+                    let model = model.trim();
+                    if model.is_empty() {
+                        let current = MODEL.lock().expect("model lock is poisoned").clone();
+                        sender.send_privmsg(
+                            resp_target,
+                            format!(
+                                "Current model is {}",
+                                current.as_deref().unwrap_or("gpt-4o")
+                            ),
+                        )?;
+                        continue;
+                    }
+                    if !from_achin_operator {
+                        sender.send_privmsg(resp_target, "Only the owner can change the model")?;
+                        continue;
+                    }
+                    let config = anna::config::get_config().unwrap_or_default();
+                    if !config.models.allows(Permission::Owner, model) {
+                        sender.send_privmsg(
+                            resp_target,
+                            format!("{model} isn't in the models allowed in config.json"),
+                        )?;
+                        continue;
+                    }
+                    match openai::resolve_model_name(config.backend.kind, model) {
+                        Ok(resolved) => {
+                            *MODEL.lock().expect("model lock is poisoned") = Some(resolved.clone());
+                            sender.send_privmsg(resp_target, format!("Model is now {resolved}"))?;
+                        }
+                        Err(e) => {
+                            sender
+                                .send_privmsg(resp_target, format!("Can't use that model: {e}"))?;
+                        }
+                    }
+                    continue;
                 } else if msg.starts_with("!get_temp") {
                     sender.send_privmsg(
                         resp_target,
@@ -877,10 +2540,17 @@ async fn main() -> anyhow::Result<()> {
                     continue;
                 } else if let Some(msg) = msg.strip_prefix("!tts ") {
                     let sender = sender.clone();
-                    let msg = msg.to_string();
+                    let (cost, msg) = match msg.trim_start().strip_prefix("--cost ") {
+                        Some(msg) => (true, msg.to_string()),
+                        None => (false, msg.to_string()),
+                    };
                     let resp_target = resp_target.to_string();
                     tokio::spawn(async move {
-                        match get_tts(&msg).await {
+                        match get_tts_reply(&msg).await {
+                            Ok(url) if cost => {
+                                let cost = openai::tts_cost(&msg);
+                                sender.send_privmsg(resp_target, format!("{url} (~${cost:.4})"))
+                            }
                             Ok(url) => sender.send_privmsg(resp_target, url),
                             Err(e) => sender.send_privmsg(resp_target, format!("Error: {e}")),
                         }
@@ -906,20 +2576,16 @@ async fn main() -> anyhow::Result<()> {
                             }
                         });
                     }
-                } else if let Some(msg) = msg.strip_prefix("!transcribe ") {
-                    let sender = sender.clone();
-                    let resp_target = resp_target.to_string();
-                    let mut split = msg.splitn(2, ' ');
-                    let url = split.next().unwrap_or("");
-                    let prompt = split.next();
+                } else if let Some(url) = msg.strip_prefix("!ocr ") {
+                    let url = url.trim();
                     if url.starts_with("https://") {
+                        let sender = sender.clone();
+                        let resp_target = resp_target.to_string();
                         let url = url.to_string();
-                        let prompt = prompt.map(|s| s.to_string());
                         tokio::spawn(async move {
-                            match openai::get_transcription(&url, prompt).await {
-                                Ok(translated) => {
-                                    send_possibly_long_message(sender, &resp_target, &translated)
-                                        .await;
+                            match openai::get_ocr(&url).await {
+                                Ok(text) => {
+                                    send_possibly_long_message(sender, &resp_target, &text).await;
                                 }
                                 Err(e) => {
                                     let _ = sender.send_privmsg(resp_target, format!("Error: {e}"));
@@ -927,29 +2593,322 @@ async fn main() -> anyhow::Result<()> {
                             }
                         });
                     }
-                } else if let Some(inst) = get_chat_instruction(msg) {
-                    dbg!(&inst);
-                    if inst.save && !inst.msg.trim().is_empty() {
-                        message_map
-                            .insert_usermsg(target, source_nick, inst.msg.trim())
-                            .await;
-                    }
-
-                    // get a list of all known messages for the given channel (or only the last message if inst.context = false)
-                    let mut for_chat = message_map.get_chat_messages(target, inst.context);
-                    if !inst.save {
-                        // our message wasn't inserted into the message map, so we have to explictly append it to what we send to openai
-                        for_chat.extend(
-                            message_map
-                                .extract_image_urls(source_nick, inst.msg)
-                                .await
-                                .into_iter()
-                                .map(|cmt| cmt.msg),
-                        );
+                } else if let Some(args) = msg.strip_prefix("!doc ") {
+                    let (url, question) = match args.trim().split_once(' ') {
+                        Some((url, question)) => (url, Some(question.trim().to_string())),
+                        None => (args.trim(), None),
+                    };
+                    let url = url.to_string();
+                    let sender = sender.clone();
+                    let resp_target = resp_target.to_string();
+                    tokio::spawn(async move {
+                        match documents::ask(&url, question.as_deref()).await {
+                            Ok(answer) => {
+                                send_possibly_long_message(sender, &resp_target, &answer).await;
+                            }
+                            Err(e) => {
+                                let _ = sender.send_privmsg(resp_target, format!("Error: {e}"));
+                            }
+                        }
+                    });
+                } else if let Some(args) = msg
+                    .strip_prefix("!run")
+                    .filter(|a| a.is_empty() || a.starts_with(' '))
+                {
+                    if !message_map.feature_enabled(resp_target, Feature::Tools) {
+                        sender.send_privmsg(resp_target, TOOLS_OFF)?;
+                        continue;
                     }
-                    dbg!(&for_chat);
-                    spawn_chat_completion(
-                        for_chat,
+                    let Some((lang, code)) = args.trim().split_once(char::is_whitespace) else {
+                        sender.send_privmsg(
+                            resp_target,
+                            format!(
+                                "Usage: !run <lang> <code>  (languages: {})",
+                                sandbox::languages().join(", ")
+                            ),
+                        )?;
+                        continue;
+                    };
+                    let (lang, code) = (lang.to_string(), code.trim().to_string());
+                    let sender = sender.clone();
+                    let resp_target = resp_target.to_string();
+                    let source_nick = source_nick.to_string();
+                    tokio::spawn(async move {
+                        match sandbox::run(&lang, &code).await {
+                            Ok(output) => {
+                                send_possibly_long_message(
+                                    sender,
+                                    &resp_target,
+                                    &format!("{source_nick}: {output}"),
+                                )
+                                .await;
+                            }
+                            Err(e) => {
+                                let _ = sender.send_privmsg(resp_target, format!("Error: {e}"));
+                            }
+                        }
+                    });
+                } else if let Some(location) = msg.strip_prefix("!weather ") {
+                    reply_with_weather(&sender, resp_target, location, |w| w.to_string());
+                } else if let Some(location) = msg.strip_prefix("!sun ") {
+                    reply_with_weather(&sender, resp_target, location, WeatherOutputForChat::sun);
+                } else if let Some(location) = msg
+                    .strip_prefix("!moon")
+                    .filter(|rest| rest.is_empty() || rest.starts_with(' '))
+                {
+                    // the phase is the same everywhere, so the location is optional
+                    reply_with_weather(&sender, resp_target, location, WeatherOutputForChat::moon);
+                } else if let Some(args) = msg
+                    .strip_prefix("!fx")
+                    .filter(|rest| rest.is_empty() || rest.starts_with(' '))
+                {
+                    let input = match FxInput::parse(args) {
+                        Ok(input) => input,
+                        Err(e) => {
+                            sender.send_privmsg(resp_target, e.to_string())?;
+                            continue;
+                        }
+                    };
+                    let sender = sender.clone();
+                    let resp_target = resp_target.to_string();
+                    tokio::spawn(async move {
+                        let reply = match fx::convert_currency(&input).await {
+                            Ok(conversion) => conversion.to_string(),
+                            Err(e) => format!("Error: {e}"),
+                        };
+                        let _ = sender.send_privmsg(resp_target, reply);
+                    });
+                } else if let Some(location) = msg
+                    .strip_prefix("!time")
+                    .filter(|rest| rest.is_empty() || rest.starts_with(' '))
+                {
+                    let location = match location.trim() {
+                        "" => "UTC".to_string(),
+                        location => location.to_string(),
+                    };
+                    let sender = sender.clone();
+                    let resp_target = resp_target.to_string();
+                    let now = message_map.now();
+                    tokio::spawn(async move {
+                        let reply = match localtime::lookup(&location, now).await {
+                            Ok(time) => time.to_string(),
+                            Err(e) => format!("Error: {e}"),
+                        };
+                        let _ = sender.send_privmsg(resp_target, reply);
+                    });
+                } else if let Some(url) = msg.strip_prefix("!yt-summary ") {
+                    let Some(id) = youtube::find_video(url.trim()) else {
+                        sender
+                            .send_privmsg(resp_target, "That doesn't look like a YouTube link")?;
+                        continue;
+                    };
+                    let sender = sender.clone();
+                    let resp_target = resp_target.to_string();
+                    tokio::spawn(async move {
+                        match youtube::summarize(&id).await {
+                            Ok(summary) => {
+                                send_possibly_long_message(sender, &resp_target, &summary).await;
+                            }
+                            Err(e) => {
+                                let _ = sender.send_privmsg(resp_target, format!("Error: {e}"));
+                            }
+                        }
+                    });
+                } else if let Some(args) = msg.strip_prefix("!describe ") {
+                    // only saved to the channel's history if asked for
+                    let (save, args) = match args.trim().strip_prefix("--save ") {
+                        Some(rest) => (true, rest.trim()),
+                        None => (false, args.trim()),
+                    };
+                    let mut split = args.splitn(2, ' ');
+                    let url = split.next().unwrap_or("").to_string();
+                    let question = split.next().map(|q| q.trim()).unwrap_or("");
+                    if url.starts_with("https://") {
+                        if save {
+                            message_map
+                                .insert_usermsg(target, None, source_nick, msg)
+                                .await;
+                        }
+                        let instruction = if question.is_empty() {
+                            "Describe this image.".to_string()
+                        } else {
+                            question.to_string()
+                        };
+                        let sender = sender.clone();
+                        let resp_target = resp_target.to_string();
+                        let target = target.to_string();
+                        let message_map = message_map.clone();
+                        tokio::spawn(async move {
+                            let result = match prepare_for_vision(&message_map.client, &url).await {
+                                Ok(url) => openai::get_vision(&url, &instruction).await,
+                                Err(e) => Err(e),
+                            };
+                            match result {
+                                Ok(description) => {
+                                    if save {
+                                        message_map.insert_selfmsg_str(&target, &description);
+                                    }
+                                    send_possibly_long_message(
+                                        sender,
+                                        &resp_target,
+                                        trim_botname(&description),
+                                    )
+                                    .await;
+                                }
+                                Err(e) => {
+                                    let _ = sender.send_privmsg(resp_target, format!("Error: {e}"));
+                                }
+                            }
+                        });
+                    }
+                } else if let Some(msg) = msg.strip_prefix("!transcribe ") {
+                    let mut split = msg.splitn(2, ' ');
+                    let url = split.next().unwrap_or("");
+                    // without a prompt, the names being talked about are the best guess at what
+                    // it'll have in it
+                    let prompt = split
+                        .next()
+                        .map(|s| s.to_string())
+                        .or_else(|| jargon_prompt(&message_map, target));
+                    if url.starts_with("https://") {
+                        let source = AudioSource::Url(url.to_string());
+                        spawn_transcription(&sender, resp_target, source, prompt);
+                    } else if url.starts_with('/') && from_achin_operator && target == BOTNAME {
+                        // only the owner gets to read files off the bot's disk
+                        let source = AudioSource::File(url.into());
+                        spawn_transcription(&sender, resp_target, source, prompt);
+                    }
+                } else if let Some(url) = msg.strip_prefix("!listen ") {
+                    let url = url.trim();
+                    if url.starts_with("https://") {
+                        // it's a lot of transcribing, so nobody gets to queue up several at once
+                        match anna::listen::start_cooldown(source_nick, message_map.now()) {
+                            Err(e) if !from_achin_operator => {
+                                sender.send_privmsg(resp_target, format!("{source_nick}: {e}"))?;
+                                continue;
+                            }
+                            _ => {}
+                        }
+                        spawn_listen(&sender, resp_target, url.to_string());
+                    }
+                } else if let Some(offer) = DccOffer::parse(msg) {
+                    // accepting means connecting wherever the offer says, so only for people we
+                    // know
+                    if from_achin_operator || opted_in {
+                        // someone sent us a file, so it's presumably something to transcribe
+                        spawn_transcription(&sender, resp_target, AudioSource::Dcc(offer), None);
+                    } else {
+                        println!("Ignoring a DCC offer from {source_nick}");
+                    }
+                } else if let Some(inst) = get_chat_instruction(msg) {
+                    let mut inst = match inst {
+                        Ok(inst) => inst,
+                        Err(e) => {
+                            sender.send_privmsg(resp_target, format!("{source_nick}: {e}"))?;
+                            continue;
+                        }
+                    };
+                    dbg!(&inst);
+                    if inst.msg.trim().is_empty() {
+                        // nothing to ask, so don't bother the API
+                        sender.send_privmsg(resp_target, chat_usage())?;
+                        continue;
+                    }
+                    let now = message_map.now();
+                    let suspicion = loop_checked
+                        .then(|| loops::record_incoming(source_nick, inst.msg, now))
+                        .flatten();
+                    if let Some(suspicion) = suspicion {
+                        println!("Muting {source_nick}, who looks like a bot: {suspicion}");
+                        // the owner is on IRC, whichever network the loop is on
+                        irc_sender.send_privmsg(
+                            "achin",
+                            format!(
+                                "I'm ignoring {source_nick} in {target} for {} minutes, since \
+                                 they look like a bot I'm stuck talking to (they {suspicion})",
+                                loops::MUTE_MINUTES
+                            ),
+                        )?;
+                        continue;
+                    }
+                    if let Some((question, pasted)) = paste::find(inst.msg) {
+                        inst.msg = question;
+                        inst.paste = Some(pasted);
+                    }
+                    if let Some(model) = &inst.model {
+                        let permission = if from_achin_operator {
+                            Permission::Owner
+                        } else {
+                            Permission::Everyone
+                        };
+                        let models = anna::config::get_config().unwrap_or_default().models;
+                        if !models.allows(permission, model) {
+                            sender.send_privmsg(
+                                resp_target,
+                                format!(
+                                    "{source_nick}: You can't use {model} (try one of: {})",
+                                    models.everyone.join(", ")
+                                ),
+                            )?;
+                            continue;
+                        }
+                    }
+                    if inst.dry && !DRY_RUN.load(Ordering::SeqCst) && !from_achin_operator {
+                        sender.send_privmsg(resp_target, "Only the bot owner can do dry runs")?;
+                        continue;
+                    }
+                    if inst.dry {
+                        // a request that isn't sent shouldn't leave anything behind in the context
+                        inst.save = false;
+                    }
+                    if inst.lang.is_none() {
+                        inst.lang = get_user_prefs(prefs_key).lang;
+                    }
+                    if target.starts_with('#') {
+                        let now = message_map.now();
+                        inst.thread = message_map
+                            .with_channel(target, |chan| chan.threads.current(source_nick, now));
+                    }
+                    if inst.save && !inst.msg.trim().is_empty() {
+                        message_map
+                            .insert_usermsg(
+                                target,
+                                inst.thread.as_deref(),
+                                source_nick,
+                                inst.msg.trim(),
+                            )
+                            .await;
+                    }
+
+                    // get a list of all known messages for the given channel (or only the last message if inst.context = false)
+                    let mut for_chat = message_map.get_chat_messages(
+                        target,
+                        inst.thread.as_deref(),
+                        inst.context,
+                        inst.history,
+                        inst.with_images,
+                    );
+                    if !inst.save {
+                        // our message wasn't inserted into the message map, so we have to explictly append it to what we send to openai
+                        for_chat.extend(
+                            message_map
+                                .extract_image_urls(source_nick, inst.msg)
+                                .await
+                                .into_iter()
+                                .map(|cmt| cmt.msg),
+                        );
+                    }
+                    dbg!(&for_chat);
+                    if target.starts_with('#') && !inst.dry {
+                        mention_past_discussion(
+                            sender.clone(),
+                            resp_target.to_string(),
+                            target.to_string(),
+                            inst.msg.trim().to_string(),
+                        );
+                    }
+                    spawn_chat_completion(
+                        for_chat,
                         inst,
                         resp_target,
                         target,
@@ -959,25 +2918,57 @@ async fn main() -> anyhow::Result<()> {
                     );
 
                     continue;
-                } else if let Some(prompt) = msg.strip_prefix("!img ") {
-                    let cloned_sender = sender.clone();
-                    let resp_target = resp_target.to_string();
-                    let prompt = prompt.to_string();
-                    let source_nick = source_nick.to_string();
+                } else if let Some(args) = msg.strip_prefix("!img ") {
+                    let (options, prompt) = match parse_img_options(args) {
+                        Ok((options, prompt)) => (options, prompt.to_string()),
+                        Err(e) => {
+                            sender.send_privmsg(resp_target, format!("{source_nick}: {e}"))?;
+                            continue;
+                        }
+                    };
+                    let image_reply = ImageReply {
+                        sender: sender.clone(),
+                        resp_target: resp_target.to_string(),
+                        target: target.to_string(),
+                        source_nick: source_nick.to_string(),
+                        message_map: message_map.clone(),
+                    };
+                    tokio::spawn(async move { image_reply.generate(&prompt, options).await });
+
+                    continue;
+                } else if let Some(idea) = msg.strip_prefix("!imagine ") {
+                    let (raw, idea) = match idea.trim().strip_prefix("--raw ") {
+                        Some(idea) => (true, idea.trim().to_string()),
+                        None => (false, idea.trim().to_string()),
+                    };
+                    let image_reply = ImageReply {
+                        sender: sender.clone(),
+                        resp_target: resp_target.to_string(),
+                        target: target.to_string(),
+                        source_nick: source_nick.to_string(),
+                        message_map: message_map.clone(),
+                    };
                     tokio::spawn(async move {
-                        match openai::get_image(&prompt).await {
-                            Ok(url) => {
-                                let _ = cloned_sender.send_privmsg(
-                                    resp_target,
-                                    format!("{}...: {url}", &prompt[..25.min(prompt.len())]),
-                                );
+                        if raw {
+                            return image_reply.generate(&idea, ImgOptions::default()).await;
+                        }
+                        match anna::enhance_image_prompt(&idea).await {
+                            Ok(prompt) => {
+                                send_possibly_long_message(
+                                    image_reply.sender.clone(),
+                                    &image_reply.resp_target,
+                                    &format!("Drawing: {prompt}"),
+                                )
+                                .await;
+                                image_reply.generate(&prompt, ImgOptions::default()).await;
                             }
                             Err(e) => {
-                                println!("Error getting image from openai:");
-                                println!("{e}");
-                                let _ = cloned_sender.send_privmsg(
-                                    &resp_target,
-                                    format!("{source_nick}: Error getting image from openai: {e}"),
+                                let _ = image_reply.sender.send_privmsg(
+                                    &image_reply.resp_target,
+                                    format!(
+                                        "{}: Error expanding the idea: {e}",
+                                        image_reply.source_nick
+                                    ),
                                 );
                             }
                         }
@@ -985,41 +2976,436 @@ async fn main() -> anyhow::Result<()> {
 
                     continue;
                 } else if msg.starts_with("!clearctx") {
+                    // anyone can clear their own conversation with the bot
+                    if target.starts_with('#') && !may_admin_channel() {
+                        sender.send_privmsg(resp_target, ONLY_OPS)?;
+                        continue;
+                    }
                     message_map.clear_chat_message(resp_target);
                     sender.send_privmsg(
                         resp_target,
                         format!("Clearing list of saved context for {resp_target}"),
                     )?;
-                } else if let Some(expr) = msg.strip_prefix("!nb ") {
-                    let result = message_map.with_channel(resp_target, |chan| {
-                        let ctx_clone = chan.numbat_context.clone();
-                        std::panic::catch_unwind(move || {
-                            if let Ok(mut ctx) = ctx_clone.lock() {
-                                if let Some(ctx) = ctx.as_mut() {
-                                    Ok(ctx.eval(expr.trim())?)
-                                } else {
-                                    Ok("No Numbat context".to_string())
+                } else if let Some(args) = msg.strip_prefix("!faq ") {
+                    if may_admin_channel() {
+                        let reply = faq_command(&message_map, resp_target, args).await;
+                        sender.send_privmsg(resp_target, reply)?;
+                    }
+                } else if let Some(args) = msg.strip_prefix("!ctx ") {
+                    let is_admin = may_admin_channel();
+                    let reply = ctx_command(&message_map, resp_target, source_nick, is_admin, args);
+                    sender.send_privmsg(resp_target, reply)?;
+                } else if let Some(args) = msg.strip_prefix("!thread") {
+                    let reply = thread_command(&message_map, resp_target, source_nick, args);
+                    sender.send_privmsg(resp_target, reply)?;
+                } else if let Some(lang) = msg.strip_prefix("!lang") {
+                    let lang = lang.trim();
+                    let reply = if lang.is_empty() {
+                        match get_user_prefs(prefs_key).lang {
+                            Some(lang) => format!("{source_nick}: I'll reply to you in {lang}"),
+                            None => {
+                                format!("{source_nick}: No language set (use !lang <language>)")
+                            }
+                        }
+                    } else if lang == "off" || lang == "none" {
+                        match update_user_prefs(prefs_key, |p| p.lang = None) {
+                            Ok(()) => format!("{source_nick}: Cleared your language"),
+                            Err(e) => format!("Error: {e}"),
+                        }
+                    } else if !is_valid_lang(lang) {
+                        format!("{source_nick}: That doesn't look like a language")
+                    } else {
+                        match update_user_prefs(prefs_key, |p| p.lang = Some(lang.to_string())) {
+                            Ok(()) => format!("{source_nick}: I'll reply to you in {lang}"),
+                            Err(e) => format!("Error: {e}"),
+                        }
+                    };
+                    sender.send_privmsg(resp_target, reply)?;
+                } else if let Some(args) = msg.strip_prefix("!poll ") {
+                    if !target.starts_with('#') {
+                        sender.send_privmsg(resp_target, "Polls only work in channels")?;
+                        continue;
+                    }
+                    let started = Poll::parse(args, Utc::now()).and_then(|p| {
+                        let announcement = p.announcement();
+                        let ends = p.ends;
+                        poll::start(target, p).map(|()| (announcement, ends))
+                    });
+                    let (announcement, ends) = match started {
+                        Ok(started) => started,
+                        Err(e) => {
+                            sender.send_privmsg(resp_target, format!("{source_nick}: {e}"))?;
+                            continue;
+                        }
+                    };
+                    sender.send_privmsg(resp_target, announcement)?;
+                    let sender = sender.clone();
+                    let target = target.to_string();
+                    tokio::spawn(async move {
+                        let wait = (ends - Utc::now()).to_std().unwrap_or_default();
+                        tokio::time::sleep(wait).await;
+                        let Some(poll) = poll::finish(&target) else {
+                            return;
+                        };
+                        let _ = sender.send_privmsg(&target, poll.results());
+                        if poll.snark {
+                            match poll::analyze(&poll).await {
+                                Ok(analysis) => {
+                                    send_possibly_long_message(sender, &target, &analysis).await
                                 }
-                            } else {
-                                anyhow::bail!("Failed to get context mutex lock")
+                                Err(e) => println!("Failed to analyze the poll in {target}: {e}"),
                             }
-                        })
+                        }
                     });
-                    match result {
-                        Ok(Ok(result)) => {
-                            let _ = sender.send_privmsg(resp_target, &result);
+                } else if let Some(args) = msg.strip_prefix("!trivia") {
+                    let (action, topic) = match args.trim().split_once(' ') {
+                        Some((action, topic)) => (action, Some(topic.trim().to_string())),
+                        None => (args.trim(), None),
+                    };
+                    let reply = if !target.starts_with('#') {
+                        "Trivia only works in channels".to_string()
+                    } else if action == "start" {
+                        if !message_map.feature_enabled(target, Feature::Trivia) {
+                            "Trivia is turned off here (see !feature)".to_string()
+                        } else {
+                            match trivia::start(target, source_nick, topic, Utc::now()) {
+                                Ok(id) => {
+                                    spawn_trivia(sender.clone(), target.to_string(), id);
+                                    format!(
+                                        "Trivia time!  {} questions, {} seconds each.  Just say \
+                                         the answer.",
+                                        trivia::ROUNDS,
+                                        trivia::ANSWER_SECS
+                                    )
+                                }
+                                Err(e) => format!("{source_nick}: {e}"),
+                            }
                         }
-                        Ok(Err(e)) => {
-                            let _ = sender.send_privmsg(resp_target, format!("Error: {e}"));
+                    } else if action == "stop" {
+                        if !may_admin_channel() && !trivia::started_by(target, source_nick) {
+                            ONLY_OPS.to_string()
+                        } else {
+                            trivia::finish(target, None, Utc::now())
+                                .unwrap_or_else(|| "There's no game running".to_string())
                         }
-                        Err(p) => {
-                            let _ = sender.send_privmsg(resp_target, format!("Panic: {p:?}"));
-                            // construct a new context because the old one is probably in a bad state
-                            message_map.with_channel(resp_target, |chan| {
-                                chan.numbat_context = make_new_numbat_context();
-                            });
+                    } else {
+                        "Usage: !trivia start [topic] | stop".to_string()
+                    };
+                    sender.send_privmsg(resp_target, reply)?;
+                } else if let Some(choice) = msg.strip_prefix("!vote ") {
+                    match poll::vote(target, source_nick, choice) {
+                        Some(option) => {
+                            sender.send_notice(source_nick, format!("You voted for {option}"))?
+                        }
+                        None if poll::is_running(target) => sender.send_privmsg(
+                            resp_target,
+                            format!("{source_nick}: That's not one of the options"),
+                        )?,
+                        None => sender.send_privmsg(
+                            resp_target,
+                            format!("{source_nick}: There's no poll running here"),
+                        )?,
+                    }
+                } else if let Some(args) = msg.strip_prefix("!profile") {
+                    let reply = match args.split_ascii_whitespace().collect::<Vec<_>>()[..] {
+                        ["show", nick] => match profiles::get(nick) {
+                            Some(profile) => format!("{}: {}", profile.nick, profile.summary),
+                            None => format!("{source_nick}: I don't know anything about {nick}"),
+                        },
+                        ["clear", nick] => {
+                            if !nick.eq_ignore_ascii_case(source_nick) && !from_achin_operator {
+                                format!("{source_nick}: You can only clear your own profile")
+                            } else {
+                                match profiles::clear(nick) {
+                                    Ok(true) => format!("{source_nick}: Forgot about {nick}"),
+                                    Ok(false) => {
+                                        format!("{source_nick}: I don't know anything about {nick}")
+                                    }
+                                    Err(e) => format!("Error: {e}"),
+                                }
+                            }
+                        }
+                        _ => "Usage: !profile show|clear <nick>".to_string(),
+                    };
+                    sender.send_privmsg(resp_target, reply)?;
+                } else if let Some((vote, reason)) = msg
+                    .strip_prefix("!good")
+                    .map(|r| (Vote::Good, r))
+                    .or_else(|| msg.strip_prefix("!bad").map(|r| (Vote::Bad, r)))
+                    .filter(|(_, r)| r.is_empty() || r.starts_with(' '))
+                {
+                    let reason = reason.trim();
+                    let entry = Feedback {
+                        nick: source_nick.to_string(),
+                        date: Utc::now(),
+                        vote,
+                        reason: (!reason.is_empty()).then(|| reason.to_string()),
+                    };
+                    let record = message_map.with_channel(resp_target, |chan| {
+                        feedback::tag_last_reply(resp_target, &mut chan.messages, entry)
+                    });
+                    let reply = match record.map(|r| feedback::append(&r)) {
+                        None => format!("{source_nick}: I haven't said anything to vote on"),
+                        Some(Ok(())) => format!("{source_nick}: Thanks for the feedback"),
+                        Some(Err(e)) => format!("Error: {e}"),
+                    };
+                    sender.send_privmsg(resp_target, reply)?;
+                } else if let Some(args) = msg.strip_prefix("!transcript") {
+                    let hours = match args.trim() {
+                        "" => Some(1),
+                        hours => hours
+                            .parse::<u32>()
+                            .ok()
+                            .filter(|hours| (1..=transcript::MAX_HOURS).contains(hours)),
+                    };
+                    let Some(hours) = hours else {
+                        sender.send_privmsg(
+                            resp_target,
+                            format!(
+                                "Usage: !transcript [hours, up to {}]",
+                                transcript::MAX_HOURS
+                            ),
+                        )?;
+                        continue;
+                    };
+                    let now = message_map.now();
+                    let messages = message_map.with_channel(resp_target, |chan| {
+                        transcript::recent(&chan.messages, hours, now)
+                    });
+                    if messages.is_empty() {
+                        sender.send_privmsg(resp_target, "Nothing's been said in that time")?;
+                        continue;
+                    }
+                    let page = transcript::render(resp_target, &messages, BOTNAME);
+                    let sender = sender.clone();
+                    let resp_target = resp_target.to_string();
+                    tokio::spawn(async move {
+                        let reply =
+                            match upload_content(page.into_bytes(), "text/html; charset=utf-8")
+                                .await
+                            {
+                                Ok(url) => format!("Transcript of the last {hours}h: {url}"),
+                                Err(e) => format!("Failed to upload the transcript: {e}"),
+                            };
+                        let _ = sender.send_privmsg(resp_target, reply);
+                    });
+                } else if msg.trim() == "!ctxinfo" || msg.trim() == "!status" {
+                    let (retention, now) = (message_map.retention(), message_map.now());
+                    let info = message_map.with_channel(resp_target, |chan| ContextInfo {
+                        budget: chan.token_budget,
+                        ..ContextInfo::compute(&chan.messages, &retention, now)
+                    });
+                    sender.send_privmsg(resp_target, info.to_string())?;
+                } else if let Some(args) = msg.strip_prefix("!budget") {
+                    let is_admin = may_admin_channel();
+                    let (retention, now) = (message_map.retention(), message_map.now());
+                    let reply = message_map.with_channel(resp_target, |chan| {
+                        let args = args.trim();
+                        if !args.is_empty() {
+                            if !is_admin {
+                                return ONLY_OPS.to_string();
+                            }
+                            match args {
+                                "off" | "none" => chan.token_budget = None,
+                                _ => match args.trim_end_matches("tokens").trim().parse() {
+                                    Ok(budget) if budget > 0 => chan.token_budget = Some(budget),
+                                    _ => return "Usage: !budget [<tokens>|off]".to_string(),
+                                },
+                            }
+                            chan.trim_message_for_age_and_contextsize(&retention, now);
                         }
+                        match chan.token_budget {
+                            Some(budget) => format!(
+                                "The context is kept under ~{budget} tokens, dropping the \
+                                 oldest messages first"
+                            ),
+                            None => "There's no token budget, only the age limits".to_string(),
+                        }
+                    });
+                    sender.send_privmsg(resp_target, reply)?;
+                } else if let Some(args) = msg
+                    .strip_prefix("!grep ")
+                    .or_else(|| msg.strip_prefix("!logsearch "))
+                {
+                    let sender = sender.clone();
+                    let resp_target = resp_target.to_string();
+                    let search = archive::Search::parse(args, Utc::now());
+                    tokio::spawn(async move {
+                        let found = match search {
+                            Ok(search) => {
+                                archive::search(&resp_target, search, GREP_MAX_MATCHES).await
+                            }
+                            Err(e) => Err(e),
+                        };
+                        let reply = match found {
+                            Ok(lines) if lines.is_empty() => "No matches".to_string(),
+                            Ok(lines) => {
+                                // oldest first, the way they were said
+                                let all = lines
+                                    .iter()
+                                    .rev()
+                                    .map(|line| line.to_string())
+                                    .collect::<Vec<_>>()
+                                    .join("\n");
+                                if lines.len() <= GREP_LIMIT {
+                                    send_possibly_long_message(sender, &resp_target, &all).await;
+                                    return;
+                                }
+                                let upload =
+                                    upload_content(all.into_bytes(), "text/plain; charset=utf-8");
+                                match upload.await {
+                                    Ok(url) => format!("{} matches: {url}", lines.len()),
+                                    Err(e) => format!("Error: {e}"),
+                                }
+                            }
+                            Err(e) => format!("Error: {e}"),
+                        };
+                        let _ = sender.send_privmsg(&resp_target, reply);
+                    });
+                } else if let Some(args) = msg.strip_prefix("!stats") {
+                    let stats = message_map.with_channel(resp_target, |chan| {
+                        ChannelStats::compute(&chan.messages, Utc::now())
+                    });
+                    if args.trim() == "full" {
+                        let sender = sender.clone();
+                        let resp_target = resp_target.to_string();
+                        let report = stats.report();
+                        tokio::spawn(async move {
+                            match upload_content(report.into_bytes(), "text/plain; charset=utf-8")
+                                .await
+                            {
+                                Ok(url) => sender.send_privmsg(resp_target, url),
+                                Err(e) => sender.send_privmsg(resp_target, format!("Error: {e}")),
+                            }
+                        });
+                    } else {
+                        sender.send_privmsg(resp_target, stats.summary())?;
                     }
+                } else if let Some(args) = msg.strip_prefix("!chattiness") {
+                    let is_admin = may_admin_channel();
+                    let reply = message_map.with_channel(resp_target, |chan| {
+                        let now = Utc::now();
+                        let args = args.trim();
+                        if args.is_empty() {
+                            return chan.chattiness.to_string();
+                        }
+                        let mut split = args.split_ascii_whitespace();
+                        match split.next() {
+                            Some("mute") if is_admin => {
+                                let minutes = split
+                                    .next()
+                                    .and_then(|m| m.parse().ok())
+                                    .unwrap_or(chan.chattiness.mute_minutes);
+                                let minutes = chan.chattiness.mute(now, minutes);
+                                format!("Okay, I'll keep quiet for {minutes} minutes")
+                            }
+                            Some("unmute") if is_admin => {
+                                chan.chattiness.muted_until = None;
+                                "Unmuted".to_string()
+                            }
+                            Some(_) if is_admin => {
+                                for cmd in args.split_ascii_whitespace() {
+                                    if let Err(e) = chan.chattiness.update(cmd) {
+                                        return e.to_string();
+                                    }
+                                }
+                                chan.chattiness.to_string()
+                            }
+                            _ => ONLY_OPS.to_string(),
+                        }
+                    });
+                    sender.send_privmsg(resp_target, reply)?;
+                } else if let Some(args) = msg.strip_prefix("!feature") {
+                    let is_admin = may_admin_channel();
+                    let reply = message_map.with_channel(resp_target, |chan| {
+                        let args = args.trim();
+                        if args.is_empty() {
+                            return chan.features.to_string();
+                        }
+                        if !is_admin {
+                            return ONLY_OPS.to_string();
+                        }
+                        match chan.features.update(args) {
+                            Ok(()) => chan.features.to_string(),
+                            Err(e) => e.to_string(),
+                        }
+                    });
+                    sender.send_privmsg(resp_target, reply)?;
+                } else if let Some(args) = msg.strip_prefix("!autoclear") {
+                    let is_admin = may_admin_channel();
+                    let reply = message_map.with_channel(resp_target, |chan| {
+                        let args = args.trim();
+                        if args.is_empty() {
+                            return chan.auto_clear.to_string();
+                        }
+                        if !is_admin {
+                            return ONLY_OPS.to_string();
+                        }
+                        for cmd in args.split_ascii_whitespace() {
+                            if let Err(e) = chan.auto_clear.update(cmd) {
+                                return e.to_string();
+                            }
+                        }
+                        chan.auto_clear.to_string()
+                    });
+                    sender.send_privmsg(resp_target, reply)?;
+                } else if let Some(args) = msg.strip_prefix("!convert") {
+                    let is_admin = may_admin_channel();
+                    let reply = message_map.with_channel(resp_target, |chan| {
+                        let args = args.trim();
+                        if args.is_empty() {
+                            return chan.auto_convert.to_string();
+                        }
+                        if !is_admin {
+                            return ONLY_OPS.to_string();
+                        }
+                        for cmd in args.split_ascii_whitespace() {
+                            if let Err(e) = chan.auto_convert.update(cmd) {
+                                return e.to_string();
+                            }
+                        }
+                        chan.auto_convert.to_string()
+                    });
+                    sender.send_privmsg(resp_target, reply)?;
+                } else if (msg.starts_with("!calc") || msg.starts_with("!nb"))
+                    && !message_map.feature_enabled(resp_target, Feature::Tools)
+                {
+                    sender.send_privmsg(resp_target, TOOLS_OFF)?;
+                } else if msg.trim() == "!calc reset" {
+                    message_map.with_channel(resp_target, |chan| {
+                        chan.numbat_context = make_new_numbat_context();
+                    });
+                    sender.send_privmsg(resp_target, "Numbat session reset")?;
+                } else if let Some(expr) = msg
+                    .strip_prefix("!nb ")
+                    .or_else(|| msg.strip_prefix("!calc "))
+                {
+                    let ctx =
+                        message_map.with_channel(resp_target, |chan| chan.numbat_context.clone());
+                    let expr = expr.trim().to_string();
+                    let sender = sender.clone();
+                    let resp_target = resp_target.to_string();
+                    let message_map = message_map.clone();
+                    tokio::spawn(async move {
+                        // evaluate in its own task, so that a panic can be caught
+                        let result =
+                            tokio::spawn(async move { eval_numbat(&ctx, &expr).await }).await;
+                        match result {
+                            Ok(Ok(result)) => {
+                                let _ = sender.send_privmsg(&resp_target, &result);
+                            }
+                            Ok(Err(e)) => {
+                                let _ = sender.send_privmsg(&resp_target, format!("Error: {e}"));
+                            }
+                            Err(p) => {
+                                let _ = sender.send_privmsg(&resp_target, format!("Panic: {p:?}"));
+                                // construct a new context because the old one is probably in a bad state
+                                message_map.with_channel(&resp_target, |chan| {
+                                    chan.numbat_context = make_new_numbat_context();
+                                });
+                            }
+                        }
+                    });
                 } else if msg.starts_with("!nbclear") {
                     message_map.with_channel(resp_target, |chan| {
                         chan.numbat_context = make_new_numbat_context();
@@ -1032,6 +3418,11 @@ async fn main() -> anyhow::Result<()> {
                     sender.send_privmsg(resp_target, "Reloaded numbat wasm")?;
                 } else if let Some(channel) = msg.strip_prefix("!imggen ") {
                     let channel = channel.trim();
+                    if !message_map.feature_enabled(channel, Feature::ImageDay) {
+                        sender
+                            .send_privmsg(resp_target, format!("imageday is off in {channel}"))?;
+                        continue;
+                    }
                     let messages: Vec<ChatMessageThing> =
                         message_map.with_channel(channel, |c| c.messages.iter().cloned().collect());
                     match generate_image_prompt(&messages).await {
@@ -1048,12 +3439,123 @@ async fn main() -> anyhow::Result<()> {
                 }
             }
             if target.starts_with('#') {
+                if !msg.starts_with('!') {
+                    if let Some(correct) = trivia::guess(target, source_nick, msg) {
+                        sender.send_privmsg(target, correct)?;
+                    }
+                }
+                if !msg.starts_with('!') {
+                    let quantities = convert::find_quantities(msg);
+                    let ctx = message_map.with_channel(target, |chan| {
+                        (!quantities.is_empty() && chan.auto_convert.allow(Utc::now()))
+                            .then(|| chan.numbat_context.clone())
+                    });
+                    if let Some(ctx) = ctx {
+                        let sender = sender.clone();
+                        let target = target.to_string();
+                        tokio::spawn(async move {
+                            let mut converted = Vec::new();
+                            for quantity in &quantities {
+                                match eval_numbat(&ctx, &quantity.expr).await {
+                                    Ok(result) => {
+                                        converted.push(convert::describe(quantity, &result))
+                                    }
+                                    Err(e) => println!("Failed to convert {}: {e}", quantity.text),
+                                }
+                            }
+                            if !converted.is_empty() {
+                                let _ = sender
+                                    .send_privmsg(&target, format!("({})", converted.join(", ")));
+                            }
+                        });
+                    }
+                }
+                // while a poll is running, a plain "b" counts as a vote
+                if !msg.starts_with('!') && poll::is_running(target) {
+                    poll::vote(target, source_nick, msg);
+                }
+                let video = youtube::find_video(msg).filter(|_| {
+                    !msg.starts_with('!') && message_map.feature_enabled(target, Feature::UrlTitles)
+                });
+                if let Some(id) = video {
+                    let sender = sender.clone();
+                    let target = target.to_string();
+                    tokio::spawn(async move {
+                        match youtube::get_info(&id).await {
+                            Ok(info) => {
+                                let _ = sender.send_privmsg(&target, info.announcement());
+                            }
+                            Err(e) => println!("Failed to get info for YouTube video {id}: {e}"),
+                        }
+                    });
+                }
+
                 // only certain users are comfortable with all their messages being used
-                if OPT_IN_ALL_CAPTURE.contains(&source_nick) {
-                    message_map.insert_usermsg(target, source_nick, msg).await;
+                if opted_in && message_map.feature_enabled(target, Feature::Capture) {
+                    message_map
+                        .insert_usermsg(target, None, source_nick, msg)
+                        .await;
+                    message_map.spawn_paste_fetches(target, source_nick, msg);
+                    if !msg.starts_with('!') {
+                        archive::record(target, source_nick, msg);
+                        if let Some(lines) = profiles::observe(source_nick, msg) {
+                            let nick = source_nick.to_string();
+                            tokio::spawn(async move {
+                                if let Err(e) = profiles::update(&nick, &lines).await {
+                                    println!("Failed to update the profile for {nick}: {e}");
+                                }
+                            });
+                        }
+                    }
+                }
+
+                let muted = message_map
+                    .with_channel(target, |chan| chan.chattiness.observe(msg, Utc::now()));
+                if muted {
+                    println!("Negative reaction in {target}, muting unsolicited messages");
                 }
 
-                if message_map.can_interject(target) {
+                // matching a message against the FAQs sends it off to be embedded, which needs the
+                // same consent as keeping it
+                let has_faqs = message_map.with_channel(target, |chan| !chan.faqs.is_empty());
+                let consented = opted_in || msg.contains(BOTNAME);
+                if has_faqs
+                    && consented
+                    && message_map.feature_enabled(target, Feature::Capture)
+                    && !msg.starts_with('!')
+                {
+                    let message_map = message_map.clone();
+                    let sender = sender.clone();
+                    let (target, msg) = (target.to_string(), msg.to_string());
+                    let source_nick = source_nick.to_string();
+                    tokio::spawn(async move {
+                        let embedding = match embeddings::embed(&msg).await {
+                            Ok(embedding) => embedding,
+                            Err(e) => {
+                                println!("Failed to check {target} FAQs: {e}");
+                                return;
+                            }
+                        };
+                        let answer = message_map.with_channel(&target, |chan| {
+                            let now = Utc::now();
+                            chan.chattiness.may_speak(now).ok()?;
+                            let answer = faq::answer(&mut chan.faqs, &embedding, now)?.to_string();
+                            chan.chattiness.record(now);
+                            Some(answer)
+                        });
+                        if let Some(answer) = answer {
+                            let _ =
+                                sender.send_privmsg(&target, format!("{source_nick}: {answer}"));
+                            message_map.insert_selfmsg_str(&target, &answer);
+                        }
+                    });
+                }
+
+                let triggered = message_map.check_triggers(target, msg);
+                if let Some(pattern) = &triggered {
+                    println!("Interjection trigger {pattern:?} fired in {target}");
+                }
+                if triggered.is_some() || message_map.can_interject(target) {
                     let messages: Vec<ChatMessageThing> =
                         message_map.with_channel(target, |c| c.messages.iter().cloned().collect());
                     match generate_interjection(&messages).await {
@@ -1084,47 +3586,697 @@ async fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
-async fn send_possibly_long_message(sender: Sender, resp_target: &str, msg: &str) {
-    let mut length = 0;
-    for line in split_long_message_for_irc(msg).iter() {
-        length += 1 + (line.trim().len() as f32 / 150.0).floor() as i32;
-        if length < 8 {
-            let _ = sender.send_privmsg(resp_target, line.trim());
-        } else {
-            // upload
-            if let Ok(url) =
-                upload_content(msg.as_bytes().to_vec(), "text/plain; charset=utf-8").await
+/// Passes along the messages from another network, with what sends the replies back
+fn forward_messages(
+    mut messages: tokio::sync::mpsc::UnboundedReceiver<IncomingMessage>,
+    sender: ChatSender,
+    message_map: &MessageMap,
+    to: tokio::sync::mpsc::UnboundedSender<(Message, ChatSender)>,
+) {
+    let mut message_map = message_map.clone();
+    tokio::spawn(async move {
+        while let Some(message) = messages.recv().await {
+            // there's no JOIN like on IRC, so a channel's state is loaded when it's first heard from
+            let channel = &message.target;
+            let saved = state_path(channel).is_ok_and(|path| Path::new(&path).exists());
+            if !message_map.has_channel(channel) && saved {
+                match message_map.load(channel, false) {
+                    Ok(()) => println!("Loaded state for {channel}"),
+                    Err(e) => println!("Failed to load state for channel {channel}: {e}"),
+                }
+            }
+            if to
+                .send((message.into_irc(sender.protocol()), sender.clone()))
+                .is_err()
             {
+                break;
+            }
+        }
+    });
+}
+
+/// Everything needed to generate an image and say where it is
+struct ImageReply {
+    sender: ChatSender,
+    resp_target: String,
+    /// Where the image is recorded in the history
+    target: String,
+    source_nick: String,
+    message_map: MessageMap,
+}
+
+impl ImageReply {
+    async fn generate(self, prompt: &str, options: ImgOptions) {
+        let Self {
+            sender,
+            resp_target,
+            target,
+            source_nick,
+            message_map,
+        } = self;
+        // image generation refuses these with an unhelpful error, and still costs an attempt
+        match openai::moderate(prompt).await {
+            Ok(flagged) if !flagged.is_empty() => {
+                let reasons = flagged.join(", ");
                 let _ = sender.send_privmsg(
                     &resp_target,
-                    format!("(there were more lines in the reply, read more at {url})"),
+                    format!("{source_nick}: I can't draw that, it was flagged for {reasons}"),
                 );
-            } else {
-                let _ = sender.send_privmsg(&resp_target, "(there were more lines in the reply, but there was an error uploading the content)");
+                return;
             }
-            break;
+            Ok(_) => {}
+            Err(e) => println!("Failed to moderate an image prompt, trying anyway: {e}"),
+        }
+        let results = futures::future::join_all(
+            (0..options.n).map(|_| openai::get_image(prompt, Some(&source_nick))),
+        )
+        .await;
+        let mut generated = Vec::new();
+        let mut error = None;
+        for result in results {
+            match result {
+                Ok(image) => generated.push(image),
+                Err(e) => error = Some(e),
+            }
+        }
+        let Some(first) = generated.first() else {
+            let e = error.map_or_else(|| "no images".to_string(), |e| e.to_string());
+            println!("Error getting image from openai:");
+            println!("{e}");
+            let _ = sender.send_privmsg(
+                &resp_target,
+                format!("{source_nick}: Error getting image from openai: {e}"),
+            );
+            return;
+        };
+        if let Some(e) = error {
+            println!("Failed to generate some of the images for {prompt:?}: {e}");
+        }
+
+        let urls: Vec<String> = generated.iter().map(|image| image.url.clone()).collect();
+        let links: Vec<String> = generated.iter().map(|image| image.links()).collect();
+        let short: String = prompt.chars().take(25).collect();
+        let mut reply = format!("{short}...: {}", links.join(" "));
+        if urls.len() > 1 {
+            let gallery = images::gallery_html(prompt, &urls);
+            match upload_content(gallery.into_bytes(), "text/html; charset=utf-8").await {
+                Ok(gallery) => reply.push_str(&format!(" (all together: {gallery})")),
+                Err(e) => println!("Failed to upload a gallery: {e}"),
+            }
+        }
+        if options.cost {
+            let cost = openai::IMAGE_PRICE * generated.len() as f64;
+            reply.push_str(&format!(" (${cost:.2})"));
+        }
+        let _ = sender.send_privmsg(&resp_target, reply);
+        for image in &generated {
+            // so that later questions about the image know what was drawn
+            let drawn = image.revised_prompt.as_deref().unwrap_or(prompt);
+            message_map.insert_selfmsg_str(
+                &target,
+                &format!("[Generated an image at {}, showing: {drawn}]", image.url),
+            );
+        }
+        if let (true, Some(revised)) = (options.show_revised, &first.revised_prompt) {
+            send_possibly_long_message(sender, &resp_target, &format!("rendered as: {revised}"))
+                .await;
+        }
+    }
+}
+
+/// Most images that one `!img` can ask for
+const MAX_IMAGES: usize = 4;
+
+/// Options for `!img`, given before the prompt
+#[derive(Debug, PartialEq)]
+struct ImgOptions {
+    /// Also show the prompt that DALL-E actually drew
+    show_revised: bool,
+    /// How many images to generate
+    n: usize,
+    /// Say what the images cost
+    cost: bool,
+}
+
+impl Default for ImgOptions {
+    fn default() -> Self {
+        Self {
+            show_revised: false,
+            n: 1,
+            cost: false,
+        }
+    }
+}
+
+/// Splits `--revised`, `--cost` and `--n=<count>` off the front of an `!img` prompt
+fn parse_img_options(args: &str) -> Result<(ImgOptions, &str), String> {
+    let mut options = ImgOptions::default();
+    let mut rest = args.trim_start();
+    while let Some(option) = rest.strip_prefix("--") {
+        let (word, remaining) = option.split_once(' ').unwrap_or((option, ""));
+        match word.split_once('=') {
+            None if word == "revised" => options.show_revised = true,
+            None if word == "cost" => options.cost = true,
+            Some(("n", n)) => {
+                options.n = n
+                    .parse()
+                    .ok()
+                    .filter(|n| (1..=MAX_IMAGES).contains(n))
+                    .ok_or_else(|| format!("--n should be between 1 and {MAX_IMAGES}"))?;
+            }
+            _ => return Err(format!("Unknown option --{word}")),
+        }
+        rest = remaining.trim_start();
+    }
+    Ok((options, rest.trim_end()))
+}
+
+/// Points out when a question was already talked about in the channel's embedded history,
+/// with a link to that part of the conversation
+fn mention_past_discussion(
+    sender: ChatSender,
+    resp_target: String,
+    channel: String,
+    question: String,
+) {
+    tokio::spawn(async move {
+        let store = match embeddings::load_store(&channel) {
+            Ok(Some(store)) => store,
+            Ok(None) => return,
+            Err(e) => {
+                println!("Failed to load embeddings for {channel}: {e}");
+                return;
+            }
+        };
+        let embedding = match embeddings::embed(&question).await {
+            Ok(embedding) => embedding,
+            Err(e) => {
+                println!("Failed to embed a question in {channel}: {e}");
+                return;
+            }
+        };
+        // anything newer is probably still in the context anyway
+        let before = Utc::now() - chrono::Duration::days(1);
+        let Some(past) = embeddings::find_past_discussion(&store, &embedding, before) else {
+            return;
+        };
+        let snippet: String = past.matched.text.chars().take(120).collect();
+        let mut reply = format!(
+            "We talked about this on {}: \"{snippet}\"",
+            past.matched.date.format("%Y-%m-%d")
+        );
+        let transcript = past
+            .segment
+            .iter()
+            .map(|m| format!("[{}] {}", m.date.format("%Y-%m-%d %H:%M"), m.text))
+            .collect::<Vec<_>>()
+            .join("\n");
+        match upload_content(transcript.into_bytes(), "text/plain; charset=utf-8").await {
+            Ok(url) => reply.push_str(&format!(" ({url})")),
+            Err(e) => println!("Failed to upload an old discussion: {e}"),
         }
+        let _ = sender.send_privmsg(resp_target, reply);
+    });
+}
+
+/// Somewhere to get audio from
+enum AudioSource {
+    Url(String),
+    File(PathBuf),
+    Dcc(DccOffer),
+}
+
+/// A Whisper prompt with the names and jargon that have been used in a channel lately
+fn jargon_prompt(message_map: &MessageMap, channel: &str) -> Option<String> {
+    if !channel.starts_with('#') {
+        return None;
+    }
+    let lines: Vec<String> = message_map.with_channel(channel, |chan| {
+        let recent = chan.messages.iter().rev().take(jargon::RECENT_MESSAGES);
+        recent
+            .rev()
+            .filter_map(|msg| msg.get_as_irc_format())
+            .map(str::to_string)
+            .collect()
+    });
+    let prompt = jargon::whisper_prompt(lines.iter().map(String::as_str));
+    if let Some(prompt) = &prompt {
+        println!("Transcribing with the prompt {prompt:?}");
     }
+    prompt
+}
+
+/// Transcribes some audio, with notices along the way if it's taking a while
+fn spawn_transcription(
+    sender: &ChatSender,
+    resp_target: &str,
+    source: AudioSource,
+    prompt: Option<String>,
+) {
+    let sender = sender.clone();
+    let resp_target = resp_target.to_string();
+    tokio::spawn(async move {
+        let mut progress = Progress::new({
+            let (sender, resp_target) = (sender.clone(), resp_target.clone());
+            move |notice| {
+                let _ = sender.send_privmsg(&resp_target, notice);
+            }
+        });
+        let transcription = match source {
+            AudioSource::Url(url) => openai::get_transcription(&url, prompt, &mut progress).await,
+            AudioSource::File(path) => openai::transcribe_file(&path, prompt, &mut progress).await,
+            AudioSource::Dcc(offer) => {
+                match offer
+                    .receive(openai::transcription_max_bytes(), &mut progress)
+                    .await
+                {
+                    Ok(audio) => {
+                        openai::transcribe_with_progress(
+                            &offer.filename,
+                            audio.into(),
+                            prompt,
+                            &mut progress,
+                        )
+                        .await
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+        };
+        match transcription {
+            Ok(mut text) => {
+                if let Some(note) = progress.elapsed_note() {
+                    text.push_str(&format!(" ({note})"));
+                }
+                send_possibly_long_message(sender, &resp_target, &text).await;
+            }
+            Err(e) => {
+                let _ = sender.send_privmsg(resp_target, format!("Error: {e}"));
+            }
+        }
+    });
 }
 
-fn split_long_message_for_irc(msg: &str) -> Vec<String> {
-    msg.lines()
-        .filter(|l| !l.trim().is_empty())
-        .flat_map(|l| textwrap::wrap(l, 400))
-        .map(|c| {
-            c.chars()
-                .filter(|c| !c.is_ascii_control() || c.is_ascii_whitespace())
-                .collect()
+/// Whether someone has ops (or better) in a channel, going by the channel's names list
+fn is_chanop(client: &Client, channel: &str, nick: &str) -> bool {
+    client.list_users(channel).is_some_and(|users| {
+        users.iter().any(|user| {
+            user.get_nickname() == nick
+                && user.access_levels().iter().any(|level| {
+                    matches!(
+                        level,
+                        AccessLevel::Owner | AccessLevel::Admin | AccessLevel::Oper
+                    )
+                })
         })
-        .collect()
+    })
+}
+
+/// Transcribes a recording and replies with a summary, linking to the full transcript
+fn spawn_listen(sender: &ChatSender, resp_target: &str, url: String) {
+    let sender = sender.clone();
+    let resp_target = resp_target.to_string();
+    tokio::spawn(async move {
+        let mut progress = Progress::new({
+            let (sender, resp_target) = (sender.clone(), resp_target.clone());
+            move |notice| {
+                let _ = sender.send_privmsg(&resp_target, notice);
+            }
+        });
+        match anna::listen::listen(&url, &mut progress).await {
+            Ok(listened) => {
+                let mut text = format!(
+                    "{} (full transcript: {})",
+                    listened.summary, listened.transcript_url
+                );
+                if let Some(note) = progress.elapsed_note() {
+                    text.push_str(&format!(" ({note})"));
+                }
+                send_possibly_long_message(sender, &resp_target, &text).await;
+            }
+            Err(e) => {
+                let _ = sender.send_privmsg(resp_target, format!("Error: {e}"));
+            }
+        }
+    });
+}
+
+/// Looks up the weather somewhere and replies with the part of it that was asked for
+fn reply_with_weather(
+    sender: &ChatSender,
+    resp_target: &str,
+    location: &str,
+    describe: fn(&WeatherOutputForChat) -> String,
+) {
+    let input = anna::wttr::WeatherInput::parse(location);
+    let sender = sender.clone();
+    let resp_target = resp_target.to_string();
+    tokio::spawn(async move {
+        let reply = match anna::wttr::lookup_weather(&input).await {
+            Ok(WeatherLookup::Found(weather)) => describe(&weather),
+            Ok(WeatherLookup::Ambiguous(places)) => format!(
+                "Did you mean {}?  (add a state or --country= to pick one)",
+                anna::wttr::or_list(&places)
+            ),
+            Err(e) => format!("Error: {e}"),
+        };
+        let _ = sender.send_privmsg(resp_target, reply);
+    });
+}
+
+fn truncation_marker(omitted: usize) -> String {
+    let s = if omitted == 1 { "" } else { "s" };
+    format!("[reply truncated: {omitted} more line{s} couldn't be uploaded]")
+}
+
+/// Sends as much of a message as fits, and marks where it was cut off
+///
+/// This is the last resort, for when the full message can't be uploaded anywhere.
+fn send_truncated_message(sender: &ChatSender, resp_target: &str, msg: &str) {
+    let (lines, omitted) = sender.fit_message(msg);
+    for line in lines {
+        let _ = sender.send_privmsg(resp_target, line.trim());
+    }
+    if omitted > 0 {
+        let _ = sender.send_privmsg(resp_target, truncation_marker(omitted));
+    }
+}
+
+/// Uploads a reply, as a page with its code highlighted if it's mostly code
+async fn upload_reply(reply: &str) -> anyhow::Result<String> {
+    if highlight::is_code_heavy(reply) {
+        let page = highlight::reply_html(reply);
+        upload_content(page.into_bytes(), "text/html; charset=utf-8").await
+    } else {
+        upload_content(reply.as_bytes().to_vec(), "text/plain; charset=utf-8").await
+    }
+}
+
+async fn send_possibly_long_message(sender: ChatSender, resp_target: &str, msg: &str) {
+    let (lines, omitted) = sender.fit_message(msg);
+    for line in lines {
+        let _ = sender.send_privmsg(resp_target, line.trim());
+    }
+    if omitted == 0 {
+        return;
+    }
+    match upload_reply(msg).await {
+        Ok(url) => {
+            let _ = sender.send_privmsg(
+                resp_target,
+                format!("(there were more lines in the reply, read more at {url})"),
+            );
+        }
+        Err(e) => {
+            println!("Failed to upload the rest of a reply: {e}");
+            let _ = sender.send_privmsg(resp_target, truncation_marker(omitted));
+        }
+    }
 }
 
 #[test]
-fn test_line_split() {
-    let long_line = "Charbot9000: Interesting idea, @agrif! Here's a story about how Nut runs for president with Coco as his running mate:\n\nAfter his heroic deeds in the village battle, Nut became a beloved figure among the people. His unwavering sense of justice and courage inspired many, and soon, he found himself being encouraged to run for president. At first, Nut was hesitant. He had never considered a life in politics before, and he wasn't sure if he was cut out for it. But with the support of his friends and loved ones, Nut eventually decided to throw his hat into the ring. To help him on his campaign, Nut turned to his old friend Coco. Although Coco was still just a coconut, Nut knew that his intelligence and charm would be a valuable asset on the campaign trail. So, Nut named Coco as his running mate and the two began their journey to the White House. Together, Nut and Coco traveled across the country, meeting with voters and spreading their message of hope and unity. Nut's bold vision for a better world, combined with Coco's quick wit and infectious personality, made them a popular duo among the people. Despite facing tough opposition from other candidates, Nut and Coco never lost sight of their values. They ran a clean, honest campaign and focused on the issues that mattered most to the people. And in the end, their hard work paid off - Nut and Coco won the election in a landslide. As Nut was sworn in as the new president of the United States, he knew that he had a lot of work to do. But with Coco by his side, he was confident that they could make a real difference in the world. And as they looked out at the sea of cheering supporters before them, Nut and Coco knew that anything was possible with a little courage and a lot of heart.";
-    for line in split_long_message_for_irc(long_line) {
-        println!("==> {line}");
+fn test_fit_message_for_irc() {
+    let (lines, omitted) = anna::frontend::fit_message_for_irc("one\n\ntwo\nthree");
+    assert_eq!(lines, ["one", "two", "three"]);
+    assert_eq!(omitted, 0);
+
+    let msg = (1..=20)
+        .map(|n| format!("line {n}"))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let (lines, omitted) = anna::frontend::fit_message_for_irc(&msg);
+    assert_eq!(lines.len(), 7);
+    assert_eq!(omitted, 13);
+    assert_eq!(
+        truncation_marker(omitted),
+        "[reply truncated: 13 more lines couldn't be uploaded]"
+    );
+}
+
+#[test]
+fn test_state_path() {
+    assert_eq!(state_path("##em32").unwrap(), "##em32.json");
+    assert_eq!(
+        state_path("#anna:matrix.org").unwrap(),
+        "#anna:matrix.org.json"
+    );
+    assert!(state_path("../../somewhere").is_err());
+    assert!(state_path("").is_err());
+
+    // looking at a channel doesn't make it one worth saving
+    let map = MessageMap::default();
+    assert!(map.get_channel("#nowhere", |_| ()).is_none());
+    assert!(!map.has_channel("#nowhere"));
+}
+
+#[test]
+fn test_foreign_nicks() {
+    assert!(opted_in(Protocol::Irc, "achin"));
+    assert!(!opted_in(Protocol::Irc, "mallory"));
+    // someone on Matrix who calls themselves achin isn't
+    assert!(!opted_in(Protocol::Matrix, "achin"));
+    assert!(!opted_in(Protocol::Discord, "achin"));
+    assert_eq!(prefs_key(Protocol::Irc, "achin", "~achin"), "achin");
+    assert_eq!(
+        prefs_key(Protocol::Matrix, "achin", "@achin:evil.example"),
+        "@achin:evil.example"
+    );
+    assert_eq!(
+        prefs_key(Protocol::Discord, "achin", "discord:7"),
+        "discord:7"
+    );
+}
+
+#[test]
+fn test_named_contexts() {
+    let mut state = ChannelState::default();
+    let then = Utc::now() - chrono::Duration::days(3);
+    state.messages.push_back(ChatMessageThing {
+        date: then,
+        msg: ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
+            content: "<achin> remember this".to_string().into(),
+            role: async_openai::types::Role::User,
+            name: Some("achin".to_string()),
+        }),
+        archived_images: Vec::new(),
+        feedback: Vec::new(),
+        model: None,
+    });
+    assert_eq!(state.save_context("story", "achin", false).unwrap(), 1);
+    state.messages.clear();
+
+    assert!(!state.load_context("nope", Utc::now()));
+    let now = Utc::now();
+    assert!(state.load_context("story", now));
+    assert_eq!(state.messages.len(), 1);
+    // restored messages are re-dated, so they don't get trimmed for being too old
+    assert_eq!(state.messages[0].date, now);
+    state.trim_message_for_age_and_contextsize(&Retention::default(), now);
+    assert_eq!(state.messages.len(), 1);
+    // the snapshot itself is left alone
+    assert_eq!(state.saved_contexts["story"][0].date, then);
+
+    // only whoever saved it, or an op, can replace or delete it
+    assert!(state.save_context("story", "someone", false).is_err());
+    assert!(state.delete_context("story", "someone", false).is_err());
+    assert!(state.save_context("story", "Achin", false).is_ok());
+    assert!(state.delete_context("story", "someone", true).unwrap());
+    assert!(!state.delete_context("story", "someone", true).unwrap());
+
+    for i in 0..MAX_SAVED_CONTEXTS {
+        state
+            .save_context(&format!("ctx{i}"), "achin", false)
+            .unwrap();
     }
+    assert!(state.save_context("one_too_many", "achin", true).is_err());
+}
+
+#[test]
+fn test_ctx_command_clock() {
+    use anna::retention::ManualClock;
+
+    let then = Utc::now() - chrono::Duration::days(30);
+    let clock = Arc::new(ManualClock::new(then));
+    let map = MessageMap::default().with_clock(clock.clone());
+    let msg = ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
+        content: "<achin> remember this".to_string().into(),
+        role: async_openai::types::Role::User,
+        name: Some("achin".to_string()),
+    });
+    map.with_channel("#test", |chan| {
+        chan.messages
+            .push_back(ChatMessageThing::new_at(msg, clock.now()))
+    });
+    ctx_command(&map, "#test", "achin", false, "save story");
+    clock.advance(chrono::Duration::hours(1));
+    ctx_command(&map, "#test", "achin", false, "load story");
+    // restored messages are dated by the map's clock, not the wall clock
+    let dates = map.with_channel("#test", |chan| {
+        chan.messages.iter().map(|cmt| cmt.date).collect::<Vec<_>>()
+    });
+    assert_eq!(dates, [clock.now()]);
+}
+
+#[test]
+fn test_message_map_retention() {
+    use anna::retention::ManualClock;
+
+    let clock = Arc::new(ManualClock::new(Utc::now()));
+    let mut map = MessageMap::default()
+        .with_clock(clock.clone())
+        .with_retention(Retention::default().with_message_max_age(chrono::Duration::hours(2)));
+    let image = ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
+        content: ChatCompletionRequestUserMessageContent::Array(vec![
+            ChatCompletionRequestMessageContentPartText::from("<achin> look".to_string()).into(),
+            ChatCompletionRequestMessageContentPartImage {
+                r#type: "image_url".into(),
+                image_url: "https://example.com/cat.png".into(),
+            }
+            .into(),
+        ]),
+        role: async_openai::types::Role::User,
+        name: Some("achin".to_string()),
+    });
+    map.with_channel("#test", |chan| {
+        chan.messages
+            .push_back(ChatMessageThing::new_at(image, clock.now()))
+    });
+    let image_count = |msgs: Vec<ChatCompletionRequestMessage>| {
+        msgs.iter()
+            .map(|msg| ChatMessageThing::new_at(msg.clone(), Utc::now()).image_count())
+            .sum::<usize>()
+    };
+
+    clock.advance(chrono::Duration::minutes(59));
+    assert_eq!(
+        image_count(map.get_chat_messages("#test", None, true, None, false)),
+        1
+    );
+    clock.advance(chrono::Duration::minutes(1));
+    // an hour old, so the image is left out unless it's asked for
+    assert_eq!(
+        image_count(map.get_chat_messages("#test", None, true, None, false)),
+        0
+    );
+    assert_eq!(
+        image_count(map.get_chat_messages("#test", None, true, None, true)),
+        1
+    );
+
+    // trimming happens when something new is added
+    clock.advance(chrono::Duration::minutes(60));
+    map.insert_selfmsg("#test", None, &[], None);
+    assert_eq!(
+        map.get_chat_messages("#test", None, true, None, false)
+            .len(),
+        1
+    );
+    clock.advance(chrono::Duration::seconds(1));
+    map.insert_selfmsg("#test", None, &[], None);
+    assert!(map
+        .get_chat_messages("#test", None, true, None, false)
+        .is_empty());
+}
+
+#[test]
+fn test_history_limit() {
+    use anna::retention::ManualClock;
+
+    let clock = Arc::new(ManualClock::new(Utc::now()));
+    let map = MessageMap::default().with_clock(clock.clone());
+    let msg = |text: &str| {
+        ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
+            content: ChatCompletionRequestUserMessageContent::Text(text.to_string()),
+            role: async_openai::types::Role::User,
+            name: Some("achin".to_string()),
+        })
+    };
+    for text in ["<achin> one", "<achin> two", "<achin> three"] {
+        map.with_channel("#test", |chan| {
+            chan.messages
+                .push_back(ChatMessageThing::new_at(msg(text), clock.now()))
+        });
+        clock.advance(chrono::Duration::minutes(20));
+    }
+
+    let count = |limit| {
+        map.get_chat_messages("#test", None, true, limit, false)
+            .len()
+    };
+    assert_eq!(count(None), 3);
+    assert_eq!(count(Some(HistoryLimit::Messages(2))), 2);
+    assert_eq!(count(Some(HistoryLimit::Messages(10))), 3);
+    assert_eq!(
+        count(Some(HistoryLimit::Age(chrono::Duration::minutes(30)))),
+        1
+    );
+    assert_eq!(
+        count(Some(HistoryLimit::Age(chrono::Duration::minutes(40)))),
+        2
+    );
+    assert_eq!(
+        map.get_chat_messages("#test", None, false, None, false)
+            .len(),
+        1
+    );
+}
+
+#[test]
+fn test_thread_conversations() {
+    #![allow(deprecated)]
+    let mut map = MessageMap::default();
+    let now = map.now();
+    map.with_channel("threadtest", |chan| {
+        chan.threads.start("achin", "rust", now)
+    })
+    .unwrap();
+    let reply = |text: &str| ChatCompletionResponseMessage {
+        content: Some(text.to_string()),
+        role: async_openai::types::Role::Assistant,
+        tool_calls: None,
+        function_call: None,
+    };
+    map.insert_selfmsg("threadtest", Some("rust"), &[reply("in the thread")], None);
+    map.insert_selfmsg(
+        "threadtest",
+        Some("ended"),
+        &[reply("in the channel")],
+        None,
+    );
+
+    let thread = map.get_chat_messages("threadtest", Some("rust"), true, None, false);
+    assert_eq!(thread.len(), 1);
+    let channel = map.get_chat_messages("threadtest", None, true, None, false);
+    assert_eq!(channel.len(), 1);
+    assert_ne!(thread, channel);
+}
+
+#[test]
+fn test_describe_tool_calls() {
+    #![allow(deprecated)]
+    let msg = |tool_calls| ChatCompletionResponseMessage {
+        content: None,
+        tool_calls,
+        role: async_openai::types::Role::Assistant,
+        function_call: None,
+    };
+    let call = |name: &str, arguments: &str| async_openai::types::ChatCompletionMessageToolCall {
+        id: "call_1".to_string(),
+        r#type: async_openai::types::ChatCompletionToolType::Function,
+        function: async_openai::types::FunctionCall {
+            name: name.to_string(),
+            arguments: arguments.to_string(),
+        },
+    };
+
+    assert_eq!(describe_tool_calls(&[msg(None)]), None);
+    assert_eq!(
+        describe_tool_calls(&[msg(Some(vec![
+            call("weather", r#"{"city": "Paris"}"#),
+            call("calc", r#"{"input": "3 kg in lb"}"#),
+        ]))]),
+        Some("🔧 weather(Paris), calc(3 kg in lb)".to_string())
+    );
 }
 
 #[test]
@@ -1136,41 +4288,74 @@ fn test_atomic_f32() {
     assert_eq!(x.load(), 1.5);
 }
 
+#[test]
+fn test_img_options() {
+    assert_eq!(
+        parse_img_options("a cat"),
+        Ok((ImgOptions::default(), "a cat"))
+    );
+    assert_eq!(
+        parse_img_options("--n=3 --revised  a cat "),
+        Ok((
+            ImgOptions {
+                show_revised: true,
+                n: 3,
+                cost: false
+            },
+            "a cat"
+        ))
+    );
+    assert!(parse_img_options("--cost a cat").unwrap().0.cost);
+    assert!(parse_img_options("--n=5 a cat").is_err());
+    assert!(parse_img_options("--n=lots a cat").is_err());
+    assert!(parse_img_options("--huge a cat").is_err());
+}
+
 #[test]
 fn test_chat_instruction() {
     let inst = get_chat_instruction("hello world");
     assert!(inst.is_none());
 
-    let inst = get_chat_instruction("!chat hello world").unwrap();
+    let inst = get_chat_instruction("!chat hello world").unwrap().unwrap();
     assert_eq!(inst.msg, "hello world");
 
-    let inst = get_chat_instruction("Charbot9000: hello world").unwrap();
+    let inst = get_chat_instruction("Charbot9000: hello world")
+        .unwrap()
+        .unwrap();
     assert_eq!(inst.msg, "hello world");
-    let inst = get_chat_instruction("Charbot9000, hello world").unwrap();
+    let inst = get_chat_instruction("Charbot9000, hello world")
+        .unwrap()
+        .unwrap();
     assert_eq!(inst.msg, "hello world");
 
-    let inst = get_chat_instruction("!chat:temp=1").unwrap();
+    let inst = get_chat_instruction("!chat:temp=1").unwrap().unwrap();
     assert_eq!(inst.temp, 1.0);
     assert!(inst.context);
     assert!(inst.save);
     assert!(!inst.pastebin);
     assert!(inst.msg.is_empty());
 
-    let inst = get_chat_instruction("!chat:temp=0.5,context=no hello world").unwrap();
+    let inst = get_chat_instruction("!chat:temp=0.5,context=no hello world")
+        .unwrap()
+        .unwrap();
     assert_eq!(inst.temp, 0.5);
     assert!(!inst.context);
     assert!(inst.save);
     assert!(!inst.pastebin);
     assert_eq!(inst.msg, "hello world");
 
-    let inst = get_chat_instruction("!chat/temp=55/save hello world").unwrap();
+    let inst = get_chat_instruction("!chat/temp=55/save hello world")
+        .unwrap()
+        .unwrap();
     assert_eq!(inst.temp, 2.0);
     assert!(inst.context);
     assert!(inst.save);
     assert!(!inst.pastebin);
     assert_eq!(inst.msg, "hello world");
 
-    let inst = get_chat_instruction("!chat --pastebin --save=no --temp=3 hello    world").unwrap();
+    let inst = get_chat_instruction("!chat --pastebin --save=no --temp=3 hello    world")
+        .unwrap()
+        .unwrap();
     assert_eq!(inst.temp, 2.0);
     assert!(inst.context);
     assert!(!inst.save);
@@ -1178,14 +4363,129 @@ fn test_chat_instruction() {
     assert!(!inst.tts);
     assert_eq!(inst.msg, "hello    world");
 
-    let inst = get_chat_instruction("!chat --tts hello").unwrap();
+    let inst = get_chat_instruction("!chat --tts hello").unwrap().unwrap();
     assert!(inst.tts);
 
-    let inst = get_chat_instruction("!chat --tts=yes hello").unwrap();
+    let inst = get_chat_instruction("!chat --tts=yes hello")
+        .unwrap()
+        .unwrap();
     assert!(inst.tts);
 
-    let inst = get_chat_instruction("!chat --tts=false hello").unwrap();
+    let inst = get_chat_instruction("!chat --tts=false hello")
+        .unwrap()
+        .unwrap();
     assert!(!inst.tts);
+    assert!(!inst.with_images);
+
+    let inst = get_chat_instruction("!chat --with-images what was in that screenshot?")
+        .unwrap()
+        .unwrap();
+    assert!(inst.with_images);
+    assert!(!inst.dry);
+    assert_eq!(inst.msg, "what was in that screenshot?");
+
+    let inst = get_chat_instruction("!chat --dry hello").unwrap().unwrap();
+    assert!(inst.dry);
+    assert_eq!(inst.msg, "hello");
+    assert!(!inst.show_tools);
+
+    let inst = get_chat_instruction("!chat --tools weather in Paris?")
+        .unwrap()
+        .unwrap();
+    assert!(inst.show_tools);
+    assert_eq!(inst.model, None);
+
+    let inst = get_chat_instruction("!chat --model=gpt-4o-mini hi")
+        .unwrap()
+        .unwrap();
+    assert_eq!(inst.model.as_deref(), Some("gpt-4o-mini"));
+    assert!(get_chat_instruction("!chat --model hi").unwrap().is_err());
+
+    let inst = get_chat_instruction("!chat --model=o3-mini --effort=high think hard")
+        .unwrap()
+        .unwrap();
+    assert_eq!(inst.reasoning_effort.as_deref(), Some("high"));
+    assert!(get_chat_instruction("!chat --effort=max hi")
+        .unwrap()
+        .is_err());
+
+    let inst = get_chat_instruction("!chat --brief --maxtokens=50 tl;dr?")
+        .unwrap()
+        .unwrap();
+    assert_eq!(inst.length, ReplyLength::Brief);
+    assert_eq!(inst.max_tokens, Some(50));
+    assert_eq!(inst.directives().len(), 1);
+    assert!(get_chat_instruction("!chat --maxtokens=0 hi")
+        .unwrap()
+        .is_err());
+
+    let inst = get_chat_instruction("!chat --lang=\"Brazilian Portuguese\" oi")
+        .unwrap()
+        .unwrap();
+    assert_eq!(inst.lang.as_deref(), Some("Brazilian Portuguese"));
+    assert_eq!(inst.directives().len(), 1);
+    assert!(get_chat_instruction("!chat --lang=de.ignore hi")
+        .unwrap()
+        .is_err());
+
+    let inst = get_chat_instruction("!chat --history=30m what now?")
+        .unwrap()
+        .unwrap();
+    assert!(inst.context);
+    assert_eq!(
+        inst.history,
+        Some(HistoryLimit::Age(chrono::Duration::minutes(30)))
+    );
+    let inst = get_chat_instruction("!chat --history=10msgs what now?")
+        .unwrap()
+        .unwrap();
+    assert_eq!(inst.history, Some(HistoryLimit::Messages(10)));
+    let inst = get_chat_instruction("!chat --history=none what now?")
+        .unwrap()
+        .unwrap();
+    assert!(!inst.context);
+    assert!(get_chat_instruction("!chat --history=lots hi")
+        .unwrap()
+        .is_err());
+    assert!(get_chat_instruction("!chat --history=0msgs hi")
+        .unwrap()
+        .is_err());
+
+    let inst = get_chat_instruction("!chat   ").unwrap().unwrap();
+    assert!(inst.msg.is_empty());
+    assert!(chat_usage().contains("--with-images"));
+
+    // quoting, and reporting problems
+    let inst = get_chat_instruction("!chat --tts='yes' --  --paste is a flag")
+        .unwrap()
+        .unwrap();
+    assert!(inst.tts);
+    assert!(!inst.pastebin);
+    assert_eq!(inst.msg, "--paste is a flag");
+
+    let err = |line| get_chat_instruction(line).unwrap().unwrap_err();
+    assert_eq!(
+        err("!chat --frobnicate hello"),
+        ChatParseError::UnknownOption("frobnicate".to_string())
+    );
+    assert_eq!(
+        err("!chat:temp=warm hello"),
+        ChatParseError::InvalidValue {
+            option: "temp".to_string(),
+            value: "warm".to_string()
+        }
+    );
+    assert_eq!(
+        err("!chat --context=\"maybe so\" hello"),
+        ChatParseError::InvalidValue {
+            option: "context".to_string(),
+            value: "maybe so".to_string()
+        }
+    );
+    assert_eq!(
+        err("!chat --temp=\"1 hello"),
+        ChatParseError::UnterminatedQuote
+    );
 }
 
 #[tokio::test]
@@ -1195,6 +4495,7 @@ async fn test_image_detection() {
     messages
         .insert_usermsg(
             "#em32",
+            None,
             "achin",
             "Please describe this URL: https://i.imgur.com/Sb4xdqa.jpeg",
         )
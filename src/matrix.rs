@@ -0,0 +1,409 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+
+use crate::{
+    config::MatrixConfig,
+    frontend::{fit_message_in_chunks, Frontend, IncomingMessage, Protocol},
+};
+
+/// Longest reply sent as one message, which is well under the homeserver's 64KB event limit but
+/// still a lot to scroll past
+const MESSAGE_CHARS: usize = 8000;
+
+/// How long each sync waits for something to happen
+const SYNC_TIMEOUT_MS: u64 = 30_000;
+
+/// How long to wait before trying again after the homeserver fails
+const RETRY_SECS: u64 = 10;
+
+enum Outgoing {
+    Message {
+        target: String,
+        msgtype: &'static str,
+        body: String,
+    },
+    Join(String),
+    Part(String),
+}
+
+/// Sends to Matrix, through a queue so messages go out in order like they do on IRC
+#[derive(Clone)]
+pub struct MatrixSender {
+    queue: mpsc::UnboundedSender<Outgoing>,
+}
+
+impl MatrixSender {
+    fn enqueue(&self, outgoing: Outgoing) -> anyhow::Result<()> {
+        self.queue
+            .send(outgoing)
+            .map_err(|_| anyhow::anyhow!("The Matrix connection is closed"))
+    }
+    fn message(&self, target: &str, msgtype: &'static str, body: &str) -> anyhow::Result<()> {
+        self.enqueue(Outgoing::Message {
+            target: target.to_string(),
+            msgtype,
+            body: body.to_string(),
+        })
+    }
+}
+
+impl Frontend for MatrixSender {
+    fn protocol(&self) -> Protocol {
+        Protocol::Matrix
+    }
+    fn send_message(&self, target: &str, message: &str) -> anyhow::Result<()> {
+        self.message(target, "m.text", message)
+    }
+    fn send_notice(&self, target: &str, message: &str) -> anyhow::Result<()> {
+        self.message(target, "m.notice", message)
+    }
+    fn send_action(&self, target: &str, action: &str) -> anyhow::Result<()> {
+        self.message(target, "m.emote", action)
+    }
+    fn join(&self, channel: &str) -> anyhow::Result<()> {
+        self.enqueue(Outgoing::Join(channel.to_string()))
+    }
+    fn part(&self, channel: &str) -> anyhow::Result<()> {
+        self.enqueue(Outgoing::Part(channel.to_string()))
+    }
+    fn fit_message(&self, msg: &str) -> (Vec<String>, usize) {
+        // Matrix takes long messages, so a reply goes out as one unless it's really long
+        fit_message_in_chunks(msg, MESSAGE_CHARS, 1)
+    }
+}
+
+/// A logged in Matrix account
+struct Matrix {
+    http: reqwest::Client,
+    homeserver: String,
+    token: String,
+    user_id: String,
+    /// Room ids, keyed by the alias they're known by
+    rooms: Mutex<HashMap<String, String>>,
+    next_txn: AtomicU64,
+}
+
+/// Connects to Matrix and joins the configured rooms
+///
+/// Returns what sends to Matrix, and the messages people send in the rooms.
+pub async fn connect(
+    config: MatrixConfig,
+) -> anyhow::Result<(MatrixSender, mpsc::UnboundedReceiver<IncomingMessage>)> {
+    let Some(token) = config.access_token.filter(|t| !t.is_empty()) else {
+        bail!("There's no Matrix access token");
+    };
+    let matrix = Arc::new(Matrix {
+        http: crate::http::client_builder()?
+            .timeout(Duration::from_millis(SYNC_TIMEOUT_MS) + Duration::from_secs(30))
+            .build()?,
+        homeserver: config.homeserver.trim_end_matches('/').to_string(),
+        token,
+        user_id: config.user_id,
+        rooms: Mutex::new(HashMap::new()),
+        next_txn: AtomicU64::new(0),
+    });
+    for room in &config.rooms {
+        if let Err(e) = matrix.join(room).await {
+            println!("Failed to join {room} on Matrix: {e:#}");
+        }
+    }
+    // only what's said from now on is answered, not whatever happened while we were away
+    let since = matrix.sync(None, 0).await?["next_batch"]
+        .as_str()
+        .context("No next_batch in sync response")?
+        .to_string();
+
+    let (queue, mut outgoing) = mpsc::unbounded_channel();
+    let (incoming, received) = mpsc::unbounded_channel();
+    {
+        let matrix = matrix.clone();
+        tokio::spawn(async move {
+            while let Some(out) = outgoing.recv().await {
+                if let Err(e) = matrix.send(out).await {
+                    println!("Failed to send to Matrix: {e:#}");
+                }
+            }
+        });
+    }
+    tokio::spawn(async move {
+        let mut since = since;
+        loop {
+            let resp = match matrix.sync(Some(&since), SYNC_TIMEOUT_MS).await {
+                Ok(resp) => resp,
+                Err(e) => {
+                    println!("Matrix sync failed: {e:#}");
+                    tokio::time::sleep(Duration::from_secs(RETRY_SECS)).await;
+                    continue;
+                }
+            };
+            for message in matrix.messages(&resp) {
+                if incoming.send(message).is_err() {
+                    // nobody's listening anymore
+                    return;
+                }
+            }
+            if let Some(next) = resp["next_batch"].as_str() {
+                since = next.to_string();
+            }
+        }
+    });
+    Ok((MatrixSender { queue }, received))
+}
+
+/// Whether a channel or nick is on Matrix, where every name includes the homeserver, like
+/// `#anna:matrix.org`
+pub fn is_matrix_target(target: &str) -> bool {
+    target.starts_with(['#', '!', '@']) && target.contains(':')
+}
+
+fn encode(segment: &str) -> String {
+    url::form_urlencoded::byte_serialize(segment.as_bytes()).collect()
+}
+
+impl Matrix {
+    fn url(&self, path: &str) -> String {
+        format!("{}/_matrix/client/v3{path}", self.homeserver)
+    }
+
+    async fn check(resp: reqwest::Response) -> anyhow::Result<Value> {
+        let status = resp.status();
+        let body: Value = resp.json().await?;
+        if !status.is_success() {
+            bail!(
+                "{status}: {}",
+                body["error"].as_str().unwrap_or("no error message")
+            );
+        }
+        Ok(body)
+    }
+
+    async fn sync(&self, since: Option<&str>, timeout_ms: u64) -> anyhow::Result<Value> {
+        let mut query = vec![("timeout", timeout_ms.to_string())];
+        match since {
+            Some(since) => query.push(("since", since.to_string())),
+            // the first sync is only for its next_batch, so it doesn't need any events
+            None => query.push((
+                "filter",
+                json!({"room": {"timeline": {"limit": 0}}}).to_string(),
+            )),
+        }
+        let resp = self
+            .http
+            .get(self.url("/sync"))
+            .bearer_auth(&self.token)
+            .query(&query)
+            .send()
+            .await?;
+        Self::check(resp).await
+    }
+
+    async fn join(&self, room: &str) -> anyhow::Result<String> {
+        let resp = self
+            .http
+            .post(self.url(&format!("/join/{}", encode(room))))
+            .bearer_auth(&self.token)
+            .json(&json!({}))
+            .send()
+            .await?;
+        let room_id = Self::check(resp).await?["room_id"]
+            .as_str()
+            .context("No room_id in join response")?
+            .to_string();
+        self.rooms
+            .lock()
+            .expect("rooms lock is poisoned")
+            .insert(room.to_string(), room_id.clone());
+        Ok(room_id)
+    }
+
+    /// Looks up the id of a room we know by its alias, joining it if we haven't yet
+    async fn room_id(&self, target: &str) -> anyhow::Result<String> {
+        if target.starts_with('!') {
+            return Ok(target.to_string());
+        }
+        if !target.starts_with('#') {
+            bail!("Can't send to {target}, since only rooms are supported");
+        }
+        let known = self
+            .rooms
+            .lock()
+            .expect("rooms lock is poisoned")
+            .get(target)
+            .cloned();
+        match known {
+            Some(room_id) => Ok(room_id),
+            None => self.join(target).await,
+        }
+    }
+
+    /// The alias we know a room by, if it's one of ours
+    fn alias(&self, room_id: &str) -> Option<String> {
+        let rooms = self.rooms.lock().expect("rooms lock is poisoned");
+        rooms
+            .iter()
+            .find(|(_, id)| *id == room_id)
+            .map(|(alias, _)| alias.clone())
+    }
+
+    async fn send(&self, out: Outgoing) -> anyhow::Result<()> {
+        match out {
+            Outgoing::Message {
+                target,
+                msgtype,
+                body,
+            } => {
+                let room_id = self.room_id(&target).await?;
+                // the transaction id only has to be unique for this access token
+                let txn = format!(
+                    "anna-{}-{}",
+                    chrono::Utc::now().timestamp_millis(),
+                    self.next_txn.fetch_add(1, Ordering::SeqCst)
+                );
+                let resp = self
+                    .http
+                    .put(self.url(&format!(
+                        "/rooms/{}/send/m.room.message/{txn}",
+                        encode(&room_id)
+                    )))
+                    .bearer_auth(&self.token)
+                    .json(&json!({"msgtype": msgtype, "body": body}))
+                    .send()
+                    .await?;
+                Self::check(resp).await?;
+            }
+            Outgoing::Join(room) => {
+                self.join(&room).await?;
+            }
+            Outgoing::Part(room) => {
+                let room_id = self.room_id(&room).await?;
+                let resp = self
+                    .http
+                    .post(self.url(&format!("/rooms/{}/leave", encode(&room_id))))
+                    .bearer_auth(&self.token)
+                    .json(&json!({}))
+                    .send()
+                    .await?;
+                Self::check(resp).await?;
+                self.rooms
+                    .lock()
+                    .expect("rooms lock is poisoned")
+                    .remove(&room);
+            }
+        }
+        Ok(())
+    }
+
+    /// The messages people sent in our rooms, from a sync response
+    fn messages(&self, sync: &Value) -> Vec<IncomingMessage> {
+        let Some(joined) = sync.pointer("/rooms/join").and_then(|j| j.as_object()) else {
+            return Vec::new();
+        };
+        let mut messages = Vec::new();
+        for (room_id, room) in joined {
+            let Some(alias) = self.alias(room_id) else {
+                continue;
+            };
+            let events = room
+                .pointer("/timeline/events")
+                .and_then(|e| e.as_array())
+                .into_iter()
+                .flatten();
+            for event in events {
+                if let Some(message) = parse_event(&alias, &self.user_id, event) {
+                    messages.push(message);
+                }
+            }
+        }
+        messages
+    }
+}
+
+/// Turns a room event into a message, if it's something someone (other than us) said
+fn parse_event(room: &str, own_user_id: &str, event: &Value) -> Option<IncomingMessage> {
+    if event["type"] != "m.room.message" {
+        return None;
+    }
+    let user_id = event["sender"].as_str()?;
+    if user_id == own_user_id {
+        return None;
+    }
+    let content = &event["content"];
+    // edits come through as new messages, which would get answered twice
+    if content.pointer("/m.relates_to/rel_type") == Some(&json!("m.replace")) {
+        return None;
+    }
+    // emotes aren't said to anyone, and notices are from bots, so only text gets answered
+    if content["msgtype"] != "m.text" {
+        return None;
+    }
+    let text = strip_reply_fallback(content["body"].as_str()?).to_string();
+    Some(IncomingMessage {
+        target: room.to_string(),
+        // anyone can have this localpart on their own server, so it's only for addressing them;
+        // consent and preferences go by the full id
+        nick: localpart(user_id).to_string(),
+        user_id: user_id.to_string(),
+        text,
+    })
+}
+
+/// The name part of a user id, so `@achin:matrix.org` is `achin`
+fn localpart(user_id: &str) -> &str {
+    let name = user_id.strip_prefix('@').unwrap_or(user_id);
+    name.split_once(':').map_or(name, |(name, _)| name)
+}
+
+/// Replies start with a quote of what they're replying to, which isn't part of what was said
+fn strip_reply_fallback(body: &str) -> &str {
+    if !body.starts_with("> ") {
+        return body;
+    }
+    match body.split_once("\n\n") {
+        Some((_, reply)) => reply,
+        None => body,
+    }
+}
+
+#[test]
+fn test_parse_event() {
+    let event = json!({
+        "type": "m.room.message",
+        "sender": "@achin:matrix.org",
+        "content": {
+            "msgtype": "m.text",
+            "body": "> <@agrif:matrix.org> what's 2+2?\n\nCharbot9000: can you answer that?",
+        },
+    });
+    let message = parse_event("#anna:matrix.org", "@charbot:matrix.org", &event).unwrap();
+    assert_eq!(message.target, "#anna:matrix.org");
+    assert_eq!(message.nick, "achin");
+    assert_eq!(message.user_id, "@achin:matrix.org");
+    assert_eq!(message.text, "Charbot9000: can you answer that?");
+
+    // our own messages come back in the sync too
+    assert!(parse_event("#anna:matrix.org", "@achin:matrix.org", &event).is_none());
+
+    let edit = json!({
+        "type": "m.room.message",
+        "sender": "@achin:matrix.org",
+        "content": {
+            "msgtype": "m.text",
+            "body": "* fixed",
+            "m.relates_to": {"rel_type": "m.replace", "event_id": "$abc"},
+        },
+    });
+    assert!(parse_event("#anna:matrix.org", "@charbot:matrix.org", &edit).is_none());
+
+    assert!(is_matrix_target("#anna:matrix.org"));
+    assert!(!is_matrix_target("##em32"));
+}
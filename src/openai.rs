@@ -1,18 +1,42 @@
-use std::time::Duration;
+use std::{
+    collections::HashMap,
+    fs::File,
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
 
-use crate::{get_prompt, upload_content};
+use crate::{
+    audit, breaker,
+    config::{
+        get_config, BackendConfig, BackendKind, OpenAIAccountConfig, TranscriptionBackend,
+        TtsConfig, TtsLayout,
+    },
+    get_prompt, images,
+    keys::{self, PickedKey},
+    progress::Progress,
+    tools, upload_content,
+};
 use anyhow::{bail, Context};
 use async_openai::{
     config::OpenAIConfig,
+    error::{ApiError, OpenAIError},
     types::{
-        AudioInput, AudioResponseFormat, ChatCompletionRequestMessage,
-        ChatCompletionRequestSystemMessage, ChatCompletionResponseMessage,
-        CreateChatCompletionRequest, CreateImageRequest, CreateTranscriptionRequest,
-        CreateTranslationRequest, Image, ImageQuality,
+        AudioInput, AudioResponseFormat, ChatChoice, ChatCompletionRequestAssistantMessage,
+        ChatCompletionRequestMessage, ChatCompletionRequestMessageContentPart,
+        ChatCompletionRequestMessageContentPartImage, ChatCompletionRequestMessageContentPartText,
+        ChatCompletionRequestSystemMessage, ChatCompletionRequestToolMessage,
+        ChatCompletionRequestUserMessage, ChatCompletionRequestUserMessageContent,
+        ChatCompletionResponseMessage, CompletionUsage, CreateChatCompletionRequest,
+        CreateChatCompletionResponse, CreateImageRequest, CreateModerationRequest,
+        CreateTranscriptionRequest, CreateTranslationRequest, Image, ImageQuality, ModerationInput,
+        SpeechModel, TimestampGranularity, Voice,
     },
 };
-use chrono::Utc;
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
 use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
 
 #[derive(JsonSchema)]
 // Start function definitions
@@ -49,139 +73,1819 @@ struct Evaluate {
 //     };
 //     dbg!(&resp.usage);
 
-//     let resp = resp
-//         .choices
-//         .pop()
-//         .map(|x| x.message)
-//         .context("No completions returned")?;
+//     let resp = resp
+//         .choices
+//         .pop()
+//         .map(|x| x.message)
+//         .context("No completions returned")?;
+
+//     Ok(resp)
+// }
+
+/// Model name aliases for OpenRouter, for vendors whose full model names are a mouthful
+const OPENROUTER_ALIASES: &[(&str, &str)] = &[
+    ("mistral", "mistralai/mistral-large"),
+    ("mixtral", "mistralai/mixtral-8x22b-instruct"),
+    ("llama", "meta-llama/llama-3-70b-instruct"),
+    ("gemini", "google/gemini-pro-1.5"),
+    ("claude", "anthropic/claude-3.5-sonnet"),
+];
+
+/// Translates a model name as typed by a user into the name the configured backend expects
+///
+/// OpenRouter names models as `vendor/model`, so bare OpenAI model names get an `openai/` prefix
+/// there, and the prefix is stripped again when talking to OpenAI directly.
+pub fn resolve_model_name(backend: BackendKind, name: &str) -> anyhow::Result<String> {
+    let name = name.trim();
+    if name.is_empty() {
+        bail!("Empty model name");
+    }
+    match backend {
+        BackendKind::OpenAI => match name.split_once('/') {
+            None => Ok(name.to_string()),
+            Some(("openai", model)) => Ok(model.to_string()),
+            Some(_) => bail!("{name} isn't available when using OpenAI directly"),
+        },
+        BackendKind::OpenRouter => {
+            if let Some((_, full)) = OPENROUTER_ALIASES.iter().find(|(alias, _)| *alias == name) {
+                Ok(full.to_string())
+            } else if name.contains('/') {
+                Ok(name.to_string())
+            } else {
+                Ok(format!("openai/{name}"))
+            }
+        }
+    }
+}
+
+/// How long to wait for an API server to accept a connection
+const API_CONNECT_TIMEOUT_SECS: u64 = 10;
+
+/// Longest an API request can take, which is long because reasoning models can think for minutes
+const API_TIMEOUT_SECS: u64 = 10 * 60;
+
+/// The HTTP clients used to talk to OpenAI-compatible APIs, built once so that connections are
+/// pooled instead of being set up again for every request
+///
+/// Clones share their clients.  Everything in this module uses `OpenAiService::shared()`.
+#[derive(Debug, Clone)]
+pub struct OpenAiService {
+    /// Clients for API requests, keyed by the extra headers they send, since those come from
+    /// config.json and can change while the bot is running
+    api: Arc<Mutex<HashMap<Vec<(String, String)>, reqwest::Client>>>,
+    /// For fetching audio and images to pass along to the API, and rehosting generated images
+    downloads: reqwest::Client,
+}
+
+impl OpenAiService {
+    pub fn new() -> anyhow::Result<Self> {
+        let downloads = crate::http::client_builder()?
+            .connect_timeout(Duration::from_secs(API_CONNECT_TIMEOUT_SECS))
+            .user_agent("anna/1.0.0")
+            .build()?;
+        Ok(Self {
+            api: Default::default(),
+            downloads,
+        })
+    }
+
+    /// The service that the bot and its plugins share
+    pub fn shared() -> &'static OpenAiService {
+        static SERVICE: OnceLock<OpenAiService> = OnceLock::new();
+        SERVICE.get_or_init(|| OpenAiService::new().expect("the HTTP client can be built"))
+    }
+
+    /// For downloads, which should each be given a `timeout` of their own
+    pub fn downloads(&self) -> &reqwest::Client {
+        &self.downloads
+    }
+
+    /// The client that sends these headers with every API request
+    fn api_client(&self, headers: reqwest::header::HeaderMap) -> anyhow::Result<reqwest::Client> {
+        let mut key: Vec<(String, String)> = headers
+            .iter()
+            .map(|(name, value)| {
+                let value = String::from_utf8_lossy(value.as_bytes()).into_owned();
+                (name.to_string(), value)
+            })
+            .collect();
+        key.sort();
+        let mut clients = self.api.lock().expect("client lock is poisoned");
+        if let Some(client) = clients.get(&key) {
+            return Ok(client.clone());
+        }
+        let client = crate::http::client_builder()?
+            .default_headers(headers)
+            .connect_timeout(Duration::from_secs(API_CONNECT_TIMEOUT_SECS))
+            .timeout(Duration::from_secs(API_TIMEOUT_SECS))
+            .build()?;
+        clients.insert(key, client.clone());
+        Ok(client)
+    }
+
+    /// A client for the OpenAI API, using the next key from the pool
+    fn openai_client(&self) -> anyhow::Result<(async_openai::Client<OpenAIConfig>, PickedKey)> {
+        let api_key = keys::pick();
+        let cfg = OpenAIConfig::new().with_api_key(api_key.key.clone());
+
+        let mut headers = reqwest::header::HeaderMap::new();
+        add_account_headers(&get_config()?.openai, &mut headers)?;
+        let http_client = self.api_client(headers)?;
+
+        Ok((
+            async_openai::Client::with_config(cfg).with_http_client(http_client),
+            api_key,
+        ))
+    }
+
+    /// A client for chat completions, according to the configured backend
+    fn chat_client(&self, backend: &BackendConfig) -> anyhow::Result<ChatClient> {
+        let api_base = api_base(backend);
+        // the pool is all OpenAI keys, so it's no use with other backends
+        let pooled_key =
+            (backend.api_key.is_none() && backend.kind == BackendKind::OpenAI).then(keys::pick);
+        let api_key = match (&backend.api_key, &pooled_key) {
+            (Some(key), _) => key.clone(),
+            (None, Some(pooled)) => pooled.key.clone(),
+            (None, None) => crate::secrets::OPENAPI_KEY.to_string(),
+        };
+        let cfg = OpenAIConfig::new()
+            .with_api_base(&api_base)
+            .with_api_key(&api_key);
+
+        let mut headers = reqwest::header::HeaderMap::new();
+        for (name, value) in &backend.headers {
+            headers.insert(
+                reqwest::header::HeaderName::from_bytes(name.as_bytes())?,
+                reqwest::header::HeaderValue::from_str(value)?,
+            );
+        }
+        if backend.kind == BackendKind::OpenAI {
+            add_account_headers(&get_config()?.openai, &mut headers)?;
+        }
+        let http_client = self.api_client(headers)?;
+
+        Ok(ChatClient {
+            client: async_openai::Client::with_config(cfg).with_http_client(http_client.clone()),
+            pooled_key,
+            api_base,
+            http: http_client,
+            api_key,
+        })
+    }
+
+    /// Completes a single prompt, without the bot's system prompt
+    ///
+    /// Returns the reply along with the total number of tokens used, so callers can keep track of
+    /// spending
+    pub async fn completion(&self, prompt: &str, max_tokens: u16) -> anyhow::Result<(String, u32)> {
+        let backend = get_config()?.backend;
+        let model = resolve_model_name(backend.kind, "gpt-4o-mini")?;
+        let client = self.chat_client(&backend)?;
+
+        let mut resp = create_chat(
+            &client,
+            CreateChatCompletionRequest {
+                messages: vec![ChatCompletionRequestMessage::User(
+                    ChatCompletionRequestUserMessage {
+                        content: prompt.into(),
+                        role: async_openai::types::Role::User,
+                        name: None,
+                    },
+                )],
+                model,
+                max_tokens: Some(max_tokens),
+                ..Default::default()
+            },
+            None,
+        )
+        .await?;
+
+        let used = resp.usage.map(|usage| usage.total_tokens).unwrap_or(0);
+        let content = resp
+            .choices
+            .pop()
+            .and_then(|choice| choice.message.content)
+            .context("Missing a response")?;
+
+        Ok((content, used))
+    }
+}
+
+fn openai_client() -> anyhow::Result<(async_openai::Client<OpenAIConfig>, PickedKey)> {
+    OpenAiService::shared().openai_client()
+}
+
+/// Adds the headers that say which organization and project to bill
+fn add_account_headers(
+    account: &OpenAIAccountConfig,
+    headers: &mut reqwest::header::HeaderMap,
+) -> anyhow::Result<()> {
+    let ids = [
+        ("OpenAI-Organization", &account.organization),
+        ("OpenAI-Project", &account.project),
+    ];
+    for (name, id) in ids {
+        if let Some(id) = id {
+            headers.insert(name, reqwest::header::HeaderValue::from_str(id)?);
+        }
+    }
+    Ok(())
+}
+
+/// An opaque ID for an IRC nick, sent as the `user` of a request
+///
+/// OpenAI's usage dashboard can break spending down by this, without the nick itself being sent.
+pub fn billing_user(nick: &str) -> String {
+    // nicks are case-insensitive
+    format!("irc-{:x}", md5::compute(nick.to_lowercase()))
+}
+
+/// A client for chat completions, and the pooled key it uses (if it isn't a configured one)
+struct ChatClient {
+    client: async_openai::Client<OpenAIConfig>,
+    pooled_key: Option<PickedKey>,
+    /// Which backend this is, for its circuit breaker
+    api_base: String,
+    /// For requests that async_openai's types can't express (see `post_chat_body`)
+    http: reqwest::Client,
+    api_key: String,
+}
+
+fn api_base(backend: &BackendConfig) -> String {
+    match (&backend.api_base, backend.kind) {
+        (Some(base), _) => base.clone(),
+        (None, BackendKind::OpenAI) => "https://api.openai.com/v1".to_string(),
+        (None, BackendKind::OpenRouter) => "https://openrouter.ai/api/v1".to_string(),
+    }
+}
+
+/// Whether chat requests are being sent, which they aren't while the backend's circuit breaker
+/// is open
+pub fn backend_available() -> bool {
+    match get_config() {
+        Ok(config) => !breaker::is_open(&api_base(&config.backend)),
+        Err(_) => true,
+    }
+}
+
+fn chat_client(backend: &BackendConfig) -> anyhow::Result<ChatClient> {
+    OpenAiService::shared().chat_client(backend)
+}
+
+/// Lists the models that the chat backend offers
+///
+/// This is also a cheap way to check that the API key works.
+pub async fn list_models() -> anyhow::Result<Vec<String>> {
+    let backend = get_config()?.backend;
+    let client = chat_client(&backend)?;
+    let resp = client.client.models().list().await?;
+    Ok(resp.data.into_iter().map(|model| model.id).collect())
+}
+
+/// Input prices in dollars per million tokens, by model name prefix (more specific names first)
+const INPUT_PRICES: &[(&str, f64)] = &[
+    ("o1-mini", 1.1),
+    ("o1", 15.0),
+    ("o3-mini", 1.1),
+    ("gpt-4o-mini", 0.15),
+    ("gpt-4o", 2.5),
+    ("gpt-4-turbo", 10.0),
+    ("gpt-4", 30.0),
+    ("gpt-3.5-turbo", 0.5),
+];
+
+/// Output prices in dollars per million tokens, by model name prefix (more specific names
+/// first)
+const OUTPUT_PRICES: &[(&str, f64)] = &[
+    ("o1-mini", 4.4),
+    ("o1", 60.0),
+    ("o3-mini", 4.4),
+    ("gpt-4o-mini", 0.6),
+    ("gpt-4o", 10.0),
+    ("gpt-4-turbo", 30.0),
+    ("gpt-4", 60.0),
+    ("gpt-3.5-turbo", 1.5),
+];
+
+/// Context window sizes in tokens, by model name prefix (more specific names first)
+const CONTEXT_WINDOWS: &[(&str, usize)] = &[
+    ("gpt-4o", 128_000),
+    ("gpt-4-turbo", 128_000),
+    ("gpt-4-32k", 32_768),
+    ("gpt-4", 8_192),
+    ("gpt-3.5-turbo", 16_385),
+    ("o1", 128_000),
+    ("o3", 200_000),
+];
+
+/// Similar models with a bigger context window, to switch to when a request doesn't fit
+const LARGER_CONTEXT_MODELS: &[(&str, &str)] =
+    &[("gpt-3.5-turbo", "gpt-4o-mini"), ("gpt-4", "gpt-4o")];
+
+/// Roughly how many tokens an image in a request costs
+pub(crate) const IMAGE_TOKEN_ESTIMATE: usize = 765;
+
+/// What it costs to send a million input tokens to a model, if we know
+pub fn input_price_per_million(model: &str) -> Option<f64> {
+    let model = model.strip_prefix("openai/").unwrap_or(model);
+    INPUT_PRICES
+        .iter()
+        .find(|(prefix, _)| model.starts_with(prefix))
+        .map(|(_, price)| *price)
+}
+
+/// What it costs to get a million output tokens from a model, if we know
+fn output_price_per_million(model: &str) -> Option<f64> {
+    let model = model.strip_prefix("openai/").unwrap_or(model);
+    OUTPUT_PRICES
+        .iter()
+        .find(|(prefix, _)| model.starts_with(prefix))
+        .map(|(_, price)| *price)
+}
+
+/// What a chat request cost in dollars, going by the usage the API reported, if we know
+pub fn chat_cost(model: &str, usage: &CompletionUsage) -> Option<f64> {
+    let input = input_price_per_million(model)? * usage.prompt_tokens as f64;
+    let output = output_price_per_million(model)? * usage.completion_tokens as f64;
+    Some((input + output) / 1_000_000.0)
+}
+
+/// "1234 tokens in, 56 out, ~$0.0041", for `--cost`
+pub fn describe_usage(model: &str, usage: Option<&CompletionUsage>) -> String {
+    let Some(usage) = usage else {
+        return "the API didn't say how many tokens that took".to_string();
+    };
+    let cost = match chat_cost(model, usage) {
+        Some(cost) => format!("~${cost:.4}"),
+        None => format!("no price known for {model}"),
+    };
+    format!(
+        "{} tokens in, {} out, {cost}",
+        usage.prompt_tokens, usage.completion_tokens
+    )
+}
+
+/// How many tokens a model can take, including its reply, if we know
+fn context_window(model: &str) -> Option<usize> {
+    let model = model.strip_prefix("openai/").unwrap_or(model);
+    CONTEXT_WINDOWS
+        .iter()
+        .find(|(prefix, _)| model.starts_with(prefix))
+        .map(|(_, size)| *size)
+}
+
+fn larger_context_model(model: &str) -> Option<String> {
+    let name = model.strip_prefix("openai/").unwrap_or(model);
+    let window = context_window(name)?;
+    let (_, larger) = LARGER_CONTEXT_MODELS
+        .iter()
+        .find(|(prefix, _)| name.starts_with(prefix))?;
+    if context_window(larger)? <= window {
+        return None;
+    }
+    // keep the OpenRouter-style prefix, if there was one
+    Some(model.replace(name, larger))
+}
+
+/// Makes sure a request fits in its model's context window, with room left for the reply
+///
+/// Switching to a model with a bigger window is preferred, since nothing is lost that way.
+/// Otherwise the oldest messages (after the system prompt) are dropped.  Returns a note about
+/// what was changed, if anything, for telling the user.
+fn fit_to_context(req: &mut CreateChatCompletionRequest) -> anyhow::Result<Option<String>> {
+    let Some(window) = context_window(&req.model) else {
+        // nothing to check against, so let the API decide
+        return Ok(None);
+    };
+    let reply = req.max_tokens.unwrap_or(0) as usize;
+    let needed =
+        |messages: &[ChatCompletionRequestMessage]| estimate_request_tokens(messages) + reply;
+    if needed(&req.messages) <= window {
+        return Ok(None);
+    }
+
+    if let Some(larger) = larger_context_model(&req.model) {
+        if context_window(&larger).is_some_and(|w| needed(&req.messages) <= w) {
+            let note = format!("That was too long for {}, so I used {larger}", req.model);
+            req.model = larger;
+            return Ok(Some(note));
+        }
+    }
+
+    let mut dropped = 0;
+    while needed(&req.messages) > window && req.messages.len() > 2 {
+        req.messages.remove(1);
+        dropped += 1;
+    }
+    if needed(&req.messages) > window {
+        bail!(
+            "That's too long for {} (about {} tokens, but it can only take {window})",
+            req.model,
+            needed(&req.messages)
+        );
+    }
+    let s = if dropped == 1 { "" } else { "s" };
+    Ok(Some(format!(
+        "I left out the {dropped} oldest message{s} to fit in {}'s context window",
+        req.model
+    )))
+}
+
+/// A rough guess at the number of input tokens in a request, assuming about 4 characters per token
+pub fn estimate_request_tokens(messages: &[ChatCompletionRequestMessage]) -> usize {
+    messages
+        .iter()
+        .map(|msg| {
+            let (text, images) = match msg {
+                ChatCompletionRequestMessage::System(m) => (m.content.len(), 0),
+                ChatCompletionRequestMessage::User(m) => match &m.content {
+                    ChatCompletionRequestUserMessageContent::Text(t) => (t.len(), 0),
+                    ChatCompletionRequestUserMessageContent::Array(parts) => {
+                        parts
+                            .iter()
+                            .fold((0, 0), |(text, images), part| match part {
+                                ChatCompletionRequestMessageContentPart::Text(t) => {
+                                    (text + t.text.len(), images)
+                                }
+                                ChatCompletionRequestMessageContentPart::Image(_) => {
+                                    (text, images + 1)
+                                }
+                            })
+                    }
+                },
+                ChatCompletionRequestMessage::Assistant(m) => {
+                    (m.content.as_ref().map_or(0, |c| c.len()), 0)
+                }
+                ChatCompletionRequestMessage::Tool(m) => (m.content.len(), 0),
+                ChatCompletionRequestMessage::Function(m) => {
+                    (m.content.as_ref().map_or(0, |c| c.len()), 0)
+                }
+            };
+            4 + text.div_ceil(4) + images * IMAGE_TOKEN_ESTIMATE
+        })
+        .sum()
+}
+
+/// Per-request tweaks to a chat completion
+#[derive(Debug, Clone, Default)]
+pub struct ChatOptions {
+    /// The model to use, if not the default
+    pub model: Option<String>,
+    pub temp: Option<f32>,
+    /// Limit on the length of the reply, if not the model's default
+    pub max_tokens: Option<u16>,
+    /// For reasoning models, one of `REASONING_EFFORTS` (ignored by other models)
+    pub reasoning_effort: Option<String>,
+    /// Extra instructions added to the end of the system prompt
+    pub directives: Vec<String>,
+    /// Nick of whoever asked, so the request is attributed to them (see `billing_user`)
+    pub user: Option<String>,
+    /// What's filled in for the variables in the system prompt
+    pub prompt_vars: PromptVars,
+    /// Whether the model can call the tools in `tools::definitions`
+    pub tools: bool,
+}
+
+/// Values for the variables the system prompt in prompts.json can use: `{date}`, `{channel}`,
+/// `{botname}`, `{users_present}`, `{description}` and `{topic}`
+#[derive(Debug, Clone, Default)]
+pub struct PromptVars {
+    /// The channel the request came from, if it wasn't a private message
+    pub channel: Option<String>,
+    pub botname: Option<String>,
+    /// Nicks of everyone in the channel
+    pub users_present: Vec<String>,
+    /// What the channel is for, from its entry in config.json
+    pub description: Option<String>,
+    /// The channel's current topic, if config.json says to use it
+    pub topic: Option<String>,
+}
+
+/// Most nicks listed in the system prompt, so a huge channel doesn't crowd out the conversation
+const MAX_PROMPT_NICKS: usize = 100;
+
+/// Fills in the variables in a system prompt
+///
+/// Prompts that don't mention `{date}` get it added at the end, like it always was before
+/// prompts could place it themselves, and the same goes for who's present and the channel's
+/// description and topic.  Variables that aren't known are left alone.
+pub fn render_system_prompt(template: &str, vars: &PromptVars, now: DateTime<Utc>) -> String {
+    let date = now.date_naive().to_string();
+    let users_present = vars
+        .users_present
+        .iter()
+        .take(MAX_PROMPT_NICKS)
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .join(", ");
+    let mut prompt = template
+        .replace("{date}", &date)
+        .replace(
+            "{channel}",
+            vars.channel.as_deref().unwrap_or("a private message"),
+        )
+        .replace("{botname}", vars.botname.as_deref().unwrap_or("the bot"))
+        .replace("{users_present}", &users_present)
+        .replace("{description}", vars.description.as_deref().unwrap_or(""))
+        .replace("{topic}", vars.topic.as_deref().unwrap_or(""));
+    if !template.contains("{date}") {
+        prompt.push_str(&format!(". Current date: {date}"));
+    }
+    if !users_present.is_empty() && !template.contains("{users_present}") {
+        prompt.push_str(&format!(
+            "\nPeople in the channel right now (only address these by name): {users_present}"
+        ));
+    }
+    match &vars.description {
+        Some(description) if !template.contains("{description}") => {
+            prompt.push_str(&format!("\nAbout this channel: {description}"));
+        }
+        _ => (),
+    }
+    match &vars.topic {
+        Some(topic) if !template.contains("{topic}") => {
+            prompt.push_str(&format!("\nThe channel's topic is: {topic}"));
+        }
+        _ => (),
+    }
+    prompt
+}
+
+/// What a family of models accepts in a chat request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ModelProfile {
+    /// Whether the temperature can be changed
+    temperature: bool,
+    /// Whether the limit on the reply goes in `max_completion_tokens`, rather than `max_tokens`
+    max_completion_tokens: bool,
+    /// Whether it takes `reasoning_effort`
+    reasoning_effort: bool,
+    /// Whether it takes a system prompt, rather than needing it sent as a user message
+    system_prompt: bool,
+    /// Limit on the reply, unless one is asked for
+    default_max_tokens: u16,
+    /// Whether it can look at images, which otherwise have to be described to it
+    vision: bool,
+}
+
+impl ModelProfile {
+    const STANDARD: ModelProfile = ModelProfile {
+        temperature: true,
+        max_completion_tokens: false,
+        reasoning_effort: false,
+        system_prompt: true,
+        default_max_tokens: 4096,
+        vision: true,
+    };
+
+    const TEXT_ONLY: ModelProfile = ModelProfile {
+        vision: false,
+        ..ModelProfile::STANDARD
+    };
+
+    /// The reasoning models, whose limit has to leave room for the (hidden) reasoning too
+    const REASONING: ModelProfile = ModelProfile {
+        temperature: false,
+        max_completion_tokens: true,
+        reasoning_effort: true,
+        system_prompt: true,
+        default_max_tokens: 25_000,
+        vision: true,
+    };
+
+    const TEXT_ONLY_REASONING: ModelProfile = ModelProfile {
+        vision: false,
+        ..ModelProfile::REASONING
+    };
+
+    /// The first reasoning models, which don't take much of anything
+    const EARLY_REASONING: ModelProfile = ModelProfile {
+        reasoning_effort: false,
+        system_prompt: false,
+        vision: false,
+        ..ModelProfile::REASONING
+    };
+}
+
+/// Model families that don't take a standard request (more specific names first)
+const MODEL_PROFILES: &[(&str, ModelProfile)] = &[
+    ("o1-mini", ModelProfile::EARLY_REASONING),
+    ("o1-preview", ModelProfile::EARLY_REASONING),
+    ("o1", ModelProfile::REASONING),
+    ("o3-mini", ModelProfile::TEXT_ONLY_REASONING),
+    ("o3", ModelProfile::REASONING),
+    ("o4-mini", ModelProfile::REASONING),
+    ("gpt-3.5-turbo", ModelProfile::TEXT_ONLY),
+    ("gpt-4-turbo", ModelProfile::STANDARD),
+    ("gpt-4-vision-preview", ModelProfile::STANDARD),
+    ("gpt-4", ModelProfile::TEXT_ONLY),
+    ("deepseek", ModelProfile::TEXT_ONLY),
+    ("llama-3", ModelProfile::TEXT_ONLY),
+    ("mistral", ModelProfile::TEXT_ONLY),
+    ("mixtral", ModelProfile::TEXT_ONLY),
+];
+
+fn model_profile(model: &str) -> ModelProfile {
+    let name = model.rsplit('/').next().unwrap_or(model);
+    MODEL_PROFILES
+        .iter()
+        .find(|(family, _)| name == *family || name.starts_with(&format!("{family}-")))
+        .map(|(_, profile)| *profile)
+        .unwrap_or(ModelProfile::STANDARD)
+}
+
+/// Whether a model lets the temperature be changed
+fn supports_temperature(model: &str) -> bool {
+    model_profile(model).temperature
+}
+
+/// Whether a model can be sent images
+pub fn supports_vision(model: &str) -> bool {
+    model_profile(model).vision
+}
+
+/// Most image descriptions remembered, after which the least recently used are forgotten
+const MAX_IMAGE_DESCRIPTIONS: usize = 500;
+
+/// How long an image that couldn't be described is left alone before it's tried again
+const DESCRIBE_RETRY: Duration = Duration::from_secs(10 * 60);
+
+/// Descriptions of images for text-only models, by image URL
+///
+/// Images are rehosted before they go into the context, so their URLs don't change, and the same
+/// image is sent along with every request while it's in the context.
+static IMAGE_DESCRIPTIONS: Mutex<Option<ImageDescriptions>> = Mutex::new(None);
+
+struct ImageDescription {
+    /// None if the image couldn't be described
+    description: Option<String>,
+    added: Instant,
+    last_used: Instant,
+}
+
+#[derive(Default)]
+struct ImageDescriptions {
+    entries: HashMap<String, ImageDescription>,
+}
+
+impl ImageDescriptions {
+    /// Whether an image still has to be described: it's new, or it failed a while ago
+    fn needs_describing(&self, url: &str, now: Instant) -> bool {
+        match self.entries.get(url) {
+            Some(entry) => {
+                entry.description.is_none() && now.duration_since(entry.added) >= DESCRIBE_RETRY
+            }
+            None => true,
+        }
+    }
+    fn insert(&mut self, url: String, description: Option<String>, now: Instant) {
+        if !self.entries.contains_key(&url) && self.entries.len() >= MAX_IMAGE_DESCRIPTIONS {
+            let least_recent = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(url, _)| url.clone());
+            if let Some(least_recent) = least_recent {
+                self.entries.remove(&least_recent);
+            }
+        }
+        let entry = ImageDescription {
+            description,
+            added: now,
+            last_used: now,
+        };
+        self.entries.insert(url, entry);
+    }
+    /// The descriptions there are for some images, which count as being used
+    fn lookup(&mut self, urls: &[String], now: Instant) -> HashMap<String, String> {
+        let mut found = HashMap::new();
+        for url in urls {
+            if let Some(entry) = self.entries.get_mut(url) {
+                entry.last_used = now;
+                if let Some(description) = &entry.description {
+                    found.insert(url.clone(), description.clone());
+                }
+            }
+        }
+        found
+    }
+}
+
+/// The URLs of the images in some messages
+fn image_urls(messages: &[ChatCompletionRequestMessage]) -> Vec<String> {
+    messages
+        .iter()
+        .flat_map(|msg| match msg {
+            ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
+                content: ChatCompletionRequestUserMessageContent::Array(parts),
+                ..
+            }) => parts.as_slice(),
+            _ => &[],
+        })
+        .filter_map(|part| match part {
+            ChatCompletionRequestMessageContentPart::Image(image) => {
+                Some(image.image_url.url.clone())
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Swaps each image for its description, or a placeholder if there isn't one
+///
+/// Messages that end up with only text are turned back into plain text messages, which is all
+/// some backends take.
+fn replace_images(
+    messages: &mut [ChatCompletionRequestMessage],
+    descriptions: &HashMap<String, String>,
+) {
+    for msg in messages {
+        let ChatCompletionRequestMessage::User(user) = msg else {
+            continue;
+        };
+        let ChatCompletionRequestUserMessageContent::Array(parts) = &user.content else {
+            continue;
+        };
+        let text: Vec<String> = parts
+            .iter()
+            .map(|part| match part {
+                ChatCompletionRequestMessageContentPart::Text(t) => t.text.clone(),
+                ChatCompletionRequestMessageContentPart::Image(image) => {
+                    match descriptions.get(&image.image_url.url) {
+                        Some(description) => format!("[image: {description}]"),
+                        None => "[an image that couldn't be shown]".to_string(),
+                    }
+                }
+            })
+            .collect();
+        user.content = ChatCompletionRequestUserMessageContent::Text(text.join("\n"));
+    }
+}
+
+/// Replaces the images in a request with descriptions of them, for models that can't see
+///
+/// The descriptions come from the vision model, and are remembered.  Images that can't be
+/// described get a placeholder, rather than failing the whole request.
+async fn describe_images(messages: &mut [ChatCompletionRequestMessage]) {
+    let urls = image_urls(messages);
+    if urls.is_empty() {
+        return;
+    }
+    let instruction = get_prompt("describe_image").unwrap_or_else(|_| {
+        "Describe this image in a few sentences, for someone who can't see it.  Include any \
+         text in it."
+            .to_string()
+    });
+    for url in &urls {
+        let needed = IMAGE_DESCRIPTIONS
+            .lock()
+            .expect("image descriptions lock is poisoned")
+            .get_or_insert_with(Default::default)
+            .needs_describing(url, Instant::now());
+        if !needed {
+            continue;
+        }
+        // failures are remembered too, so a broken image isn't retried with every request
+        let description = match get_vision(url, &instruction).await {
+            Ok(description) => Some(description.trim().to_string()),
+            Err(e) => {
+                println!("Failed to describe {url}: {e}");
+                None
+            }
+        };
+        IMAGE_DESCRIPTIONS
+            .lock()
+            .expect("image descriptions lock is poisoned")
+            .get_or_insert_with(Default::default)
+            .insert(url.clone(), description, Instant::now());
+    }
+    let descriptions = IMAGE_DESCRIPTIONS
+        .lock()
+        .expect("image descriptions lock is poisoned")
+        .get_or_insert_with(Default::default)
+        .lookup(&urls, Instant::now());
+    replace_images(messages, &descriptions);
+}
+
+/// How much thought a reasoning model is asked to put into a reply
+pub const REASONING_EFFORTS: &[&str] = &["low", "medium", "high"];
+
+/// The body that's sent for a request, with the parameters the model wants
+///
+/// async_openai doesn't know about the reasoning models' parameters, so they're put in here.
+fn request_body(
+    req: &CreateChatCompletionRequest,
+    reasoning_effort: Option<&str>,
+) -> anyhow::Result<serde_json::Value> {
+    let profile = model_profile(&req.model);
+    let mut body = serde_json::to_value(req)?;
+    let fields = body
+        .as_object_mut()
+        .context("A request should be an object")?;
+    if !profile.temperature {
+        fields.remove("temperature");
+    }
+    if profile.max_completion_tokens {
+        if let Some(max) = fields.remove("max_tokens") {
+            fields.insert("max_completion_tokens".to_string(), max);
+        }
+    }
+    if let Some(effort) = reasoning_effort.filter(|_| profile.reasoning_effort) {
+        fields.insert("reasoning_effort".to_string(), effort.into());
+    }
+    if !profile.system_prompt {
+        let messages = fields.get_mut("messages").and_then(|m| m.as_array_mut());
+        for msg in messages.into_iter().flatten() {
+            if msg["role"] == "system" {
+                msg["role"] = "user".into();
+            }
+        }
+    }
+    Ok(body)
+}
+
+/// Builds the request that `get_chat` sends, including the system prompt
+fn build_chat_request(
+    backend: &BackendConfig,
+    messages: Vec<ChatCompletionRequestMessage>,
+    options: &ChatOptions,
+) -> anyhow::Result<CreateChatCompletionRequest> {
+    request_with_system_prompt(backend, &get_prompt("system")?, messages, options)
+}
+
+fn request_with_system_prompt(
+    backend: &BackendConfig,
+    prompt: &str,
+    messages: Vec<ChatCompletionRequestMessage>,
+    options: &ChatOptions,
+) -> anyhow::Result<CreateChatCompletionRequest> {
+    let mut system = render_system_prompt(prompt, &options.prompt_vars, Utc::now());
+    for directive in &options.directives {
+        system.push('\n');
+        system.push_str(directive);
+    }
+
+    let mut m = vec![ChatCompletionRequestMessage::System(
+        ChatCompletionRequestSystemMessage {
+            role: async_openai::types::Role::System,
+            content: system,
+            name: None,
+        },
+    )];
+
+    m.extend(messages);
+
+    let model = resolve_model_name(backend.kind, options.model.as_deref().unwrap_or("gpt-4o"))?;
+    let profile = model_profile(&model);
+    let temperature = match options.temp {
+        Some(temp) if !profile.temperature => {
+            println!("{model} doesn't support changing the temperature, ignoring {temp}");
+            None
+        }
+        temp => temp,
+    };
+    Ok(CreateChatCompletionRequest {
+        messages: m,
+        model,
+        max_tokens: Some(options.max_tokens.unwrap_or(profile.default_max_tokens)),
+        temperature,
+        user: options.user.as_deref().map(billing_user),
+        tools: options.tools.then(tools::definitions),
+        ..Default::default()
+    })
+}
+
+/// Whether an error means the backend is having trouble, rather than that the request was bad
+fn is_outage(e: &OpenAIError) -> bool {
+    match e {
+        OpenAIError::ApiError(e) => e.r#type.as_deref() != Some("invalid_request_error"),
+        OpenAIError::InvalidArgument(_) => false,
+        _ => true,
+    }
+}
+
+/// Sends a request body as is, for when it isn't something async_openai can send
+async fn post_chat_body(
+    client: &ChatClient,
+    body: &serde_json::Value,
+) -> Result<CreateChatCompletionResponse, OpenAIError> {
+    #[derive(Deserialize)]
+    struct WrappedError {
+        error: ApiError,
+    }
+
+    let resp = client
+        .http
+        .post(format!("{}/chat/completions", client.api_base))
+        .bearer_auth(&client.api_key)
+        .json(body)
+        .send()
+        .await?;
+    let status = resp.status();
+    let bytes = resp.bytes().await?;
+    if !status.is_success() {
+        return Err(match serde_json::from_slice::<WrappedError>(&bytes) {
+            Ok(wrapped) => OpenAIError::ApiError(wrapped.error),
+            Err(e) => OpenAIError::JSONDeserialize(e),
+        });
+    }
+    serde_json::from_slice(&bytes).map_err(OpenAIError::JSONDeserialize)
+}
+
+/// Sends a chat request, and records it (and the response) in the audit log
+///
+/// Requests aren't sent while the backend's circuit breaker is open.
+async fn create_chat(
+    client: &ChatClient,
+    req: CreateChatCompletionRequest,
+    reasoning_effort: Option<&str>,
+) -> anyhow::Result<CreateChatCompletionResponse> {
+    breaker::admit(&client.api_base, Utc::now())?;
+    let body = request_body(&req, reasoning_effort)?;
+    let resp = if body == serde_json::to_value(&req)? {
+        client.client.chat().create(req.clone()).await
+    } else {
+        post_chat_body(client, &body).await
+    };
+    audit::record("chat", &body, &resp);
+    let failed = resp.as_ref().is_err_and(is_outage);
+    if let Some(unavailable) = breaker::record(&client.api_base, failed, Utc::now()) {
+        if let Err(e) = &resp {
+            println!("Chat request failed: {e}");
+        }
+        return Err(unavailable.into());
+    }
+    if let Some(pooled_key) = &client.pooled_key {
+        let cost = resp.as_ref().ok().and_then(|resp| {
+            let price = input_price_per_million(&req.model)?;
+            let usage = resp.usage.as_ref()?;
+            Some(price * usage.prompt_tokens as f64 / 1_000_000.0)
+        });
+        pooled_key.record(&resp, cost);
+    }
+    Ok(resp?)
+}
+
+/// Sends a chat request with the reply streamed back, collecting it into one response
+async fn collect_stream(
+    client: &ChatClient,
+    req: CreateChatCompletionRequest,
+    on_text: &mut impl FnMut(&str),
+) -> Result<CreateChatCompletionResponse, OpenAIError> {
+    let mut stream = client.client.chat().create_stream(req).await?;
+    let mut text = String::new();
+    let mut finish_reason = None;
+    let mut last = None;
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk?;
+        for choice in &chunk.choices {
+            if let Some(delta) = &choice.delta.content {
+                on_text(delta);
+                text.push_str(delta);
+            }
+            finish_reason = choice.finish_reason.or(finish_reason);
+        }
+        last = Some(chunk);
+    }
+    let last = last.ok_or_else(|| OpenAIError::StreamError("Nothing was streamed".to_string()))?;
+    #[allow(deprecated)]
+    let message = ChatCompletionResponseMessage {
+        content: Some(text),
+        tool_calls: None,
+        role: async_openai::types::Role::Assistant,
+        function_call: None,
+    };
+    Ok(CreateChatCompletionResponse {
+        id: last.id,
+        choices: vec![ChatChoice {
+            index: 0,
+            message,
+            finish_reason,
+            logprobs: None,
+        }],
+        created: last.created,
+        model: last.model,
+        system_fingerprint: last.system_fingerprint,
+        object: "chat.completion".to_string(),
+        // streamed replies don't say how many tokens they used
+        usage: None,
+    })
+}
+
+/// Like `create_chat`, but with the reply streamed back, and each piece of it passed to
+/// `on_text` as it arrives
+///
+/// Requests that async_openai can't send (see `request_body`) aren't streamed, and the whole
+/// reply is passed along at once.
+async fn create_chat_streamed(
+    client: &ChatClient,
+    req: CreateChatCompletionRequest,
+    reasoning_effort: Option<&str>,
+    mut on_text: impl FnMut(&str),
+) -> anyhow::Result<CreateChatCompletionResponse> {
+    let body = request_body(&req, reasoning_effort)?;
+    if body != serde_json::to_value(&req)? {
+        let resp = create_chat(client, req, reasoning_effort).await?;
+        if let Some(text) = resp
+            .choices
+            .first()
+            .and_then(|c| c.message.content.as_deref())
+        {
+            on_text(text);
+        }
+        return Ok(resp);
+    }
+    breaker::admit(&client.api_base, Utc::now())?;
+    let estimated_tokens = estimate_request_tokens(&req.messages);
+    let model = req.model.clone();
+    let resp = collect_stream(client, req, &mut on_text).await;
+    audit::record("chat", &body, &resp);
+    let failed = resp.as_ref().is_err_and(is_outage);
+    if let Some(unavailable) = breaker::record(&client.api_base, failed, Utc::now()) {
+        if let Err(e) = &resp {
+            println!("Chat request failed: {e}");
+        }
+        return Err(unavailable.into());
+    }
+    if let Some(pooled_key) = &client.pooled_key {
+        let cost = input_price_per_million(&model)
+            .map(|price| price * estimated_tokens as f64 / 1_000_000.0);
+        pooled_key.record(&resp, cost);
+    }
+    Ok(resp?)
+}
+
+/// A chat request that was put together but not sent
+#[derive(Debug)]
+pub struct DryRun {
+    /// The request body, exactly as it would have been sent
+    pub payload: String,
+    pub estimated_tokens: usize,
+    /// Estimated cost of the input, in dollars (if the model's price is known)
+    pub estimated_cost: Option<f64>,
+    /// What had to be changed for the request to fit in the model's context window
+    pub note: Option<String>,
+}
+
+/// Assembles the request that `get_chat_with_options` would send, without calling the API
+pub fn dry_run_chat(
+    messages: Vec<ChatCompletionRequestMessage>,
+    options: &ChatOptions,
+) -> anyhow::Result<DryRun> {
+    let backend = get_config()?.backend;
+    let mut req = build_chat_request(&backend, messages, options)?;
+    let note = fit_to_context(&mut req)?;
+    let estimated_tokens = estimate_request_tokens(&req.messages);
+    let body = request_body(&req, options.reasoning_effort.as_deref())?;
+    Ok(DryRun {
+        payload: serde_json::to_string_pretty(&body)?,
+        estimated_tokens,
+        estimated_cost: input_price_per_million(&req.model)
+            .map(|price| price * estimated_tokens as f64 / 1_000_000.0),
+        note,
+    })
+}
+
+/// Get the chat completions for the given chat messages
+///
+/// This can return multiple chat messages if a function was called
+pub async fn get_chat(
+    messages: Vec<ChatCompletionRequestMessage>,
+    model: Option<&str>,
+    temp: Option<f32>,
+) -> anyhow::Result<Vec<ChatCompletionResponseMessage>> {
+    let options = ChatOptions {
+        model: model.map(|m| m.to_string()),
+        temp,
+        ..Default::default()
+    };
+    Ok(get_chat_with_options(messages, &options).await?.messages)
+}
+
+/// The messages from a chat completion, and the model that wrote them
+#[derive(Debug)]
+pub struct ChatReply {
+    pub messages: Vec<ChatCompletionResponseMessage>,
+    /// As reported by the API, so it can be more specific than the model that was asked for
+    pub model: String,
+    /// What had to be changed for the request to fit in the model's context window
+    pub note: Option<String>,
+    /// Tokens used, as the API reported them (it doesn't for streamed replies)
+    pub usage: Option<CompletionUsage>,
+}
+
+/// Like `get_chat`, but with more control over the request
+pub async fn get_chat_with_options(
+    messages: Vec<ChatCompletionRequestMessage>,
+    options: &ChatOptions,
+) -> anyhow::Result<ChatReply> {
+    let _start = std::time::Instant::now();
+    println!(
+        "Sending chat completion request ({} total messages) {:?}",
+        messages.len(),
+        messages.last()
+    );
+
+    let (client, mut req, note) = prepare_chat(messages, options).await?;
+    let mut called = Vec::new();
+    let mut usage: Option<CompletionUsage> = None;
+    for round in 0..=tools::MAX_ROUNDS {
+        if round == tools::MAX_ROUNDS {
+            // that's enough tools, it's time for an answer
+            req.tools = None;
+        }
+        let resp = create_chat(&client, req.clone(), options.reasoning_effort.as_deref()).await?;
+        let mut reply = chat_reply(resp, note.clone())?;
+        usage = match (usage, reply.usage.take()) {
+            (Some(total), Some(more)) => Some(CompletionUsage {
+                prompt_tokens: total.prompt_tokens + more.prompt_tokens,
+                completion_tokens: total.completion_tokens + more.completion_tokens,
+                total_tokens: total.total_tokens + more.total_tokens,
+            }),
+            (total, more) => total.or(more),
+        };
+        let calls = reply
+            .messages
+            .last()
+            .and_then(|msg| msg.tool_calls.clone())
+            .unwrap_or_default();
+        if calls.is_empty() {
+            called.append(&mut reply.messages);
+            reply.messages = called;
+            reply.usage = usage;
+            return Ok(reply);
+        }
+
+        // the model is answered and asked again, with the results of the calls in the request
+        for msg in reply.messages.drain(..) {
+            #[allow(deprecated)]
+            let asked = ChatCompletionRequestAssistantMessage {
+                content: msg.content.clone(),
+                role: msg.role,
+                name: None,
+                tool_calls: msg.tool_calls.clone(),
+                function_call: None,
+            };
+            req.messages
+                .push(ChatCompletionRequestMessage::Assistant(asked));
+            called.push(msg);
+        }
+        for call in calls {
+            println!(
+                "Calling {}({})",
+                call.function.name, call.function.arguments
+            );
+            let output = tools::call(&call.function.name, &call.function.arguments).await;
+            req.messages.push(ChatCompletionRequestMessage::Tool(
+                ChatCompletionRequestToolMessage {
+                    role: async_openai::types::Role::Tool,
+                    content: output,
+                    tool_call_id: call.id,
+                },
+            ));
+        }
+    }
+    bail!("The model kept calling tools instead of answering")
+}
+
+/// The request that `get_chat_with_options` sends, the client to send it with, and what had to
+/// be changed to fit it in the model's context window
+async fn prepare_chat(
+    messages: Vec<ChatCompletionRequestMessage>,
+    options: &ChatOptions,
+) -> anyhow::Result<(ChatClient, CreateChatCompletionRequest, Option<String>)> {
+    let backend = get_config()?.backend;
+    let mut req = build_chat_request(&backend, messages, options)?;
+    if !supports_vision(&req.model) {
+        describe_images(&mut req.messages).await;
+    }
+    let note = fit_to_context(&mut req)?;
+    let client = chat_client(&backend)?;
+    Ok((client, req, note))
+}
+
+fn chat_reply(
+    mut resp: CreateChatCompletionResponse,
+    note: Option<String>,
+) -> anyhow::Result<ChatReply> {
+    if let Some(usage) = &resp.usage {
+        println!("Chat API usage: {:?}", usage);
+    }
+    let resp_msg = resp.choices.pop().context("Missing a response")?.message;
+
+    Ok(ChatReply {
+        messages: vec![resp_msg],
+        model: resp.model,
+        note,
+        usage: resp.usage,
+    })
+}
+
+/// A chat reply, and a link to it spoken aloud
+#[derive(Debug)]
+pub struct SpokenReply {
+    pub reply: ChatReply,
+    /// The audio can fail without the reply failing
+    pub audio: anyhow::Result<String>,
+}
+
+/// Like `get_chat_with_options`, but with the reply spoken too, for `!chat --tts`
+///
+/// The reply is spoken a few sentences at a time while it's still being written, so the audio
+/// is ready soon after the reply is.  Long replies that are to be summarized before they're
+/// spoken, or split into paragraphs (see `TtsConfig`), can't be, so they're spoken with
+/// `get_tts_reply` once they're done.
+pub async fn get_spoken_chat(
+    messages: Vec<ChatCompletionRequestMessage>,
+    options: &ChatOptions,
+) -> anyhow::Result<SpokenReply> {
+    let cfg = get_config()?.tts;
+    if cfg.summarize_long || cfg.layout != TtsLayout::Single {
+        let reply = get_chat_with_options(messages, options).await?;
+        let text = reply.messages.last().and_then(|m| m.content.clone());
+        let audio = get_tts_reply(text.as_deref().unwrap_or_default()).await;
+        return Ok(SpokenReply { reply, audio });
+    }
+
+    println!(
+        "Sending streamed chat completion request ({} total messages) {:?}",
+        messages.len(),
+        messages.last()
+    );
+    let (client, mut req, note) = prepare_chat(messages, options).await?;
+    // the reply is spoken as it comes, so there's no stopping partway to call tools
+    req.tools = None;
+    let (chunks, to_speak) = mpsc::unbounded_channel();
+    let max_chars = (cfg.max_duration_secs * TTS_CHARS_PER_SECOND) as usize;
+    let speaking = tokio::spawn(speak_chunks(to_speak, max_chars));
+    let mut chunker = SentenceChunker::default();
+    let resp = create_chat_streamed(&client, req, options.reasoning_effort.as_deref(), |text| {
+        if let Some(chunk) = chunker.push(text) {
+            let _ = chunks.send(chunk);
+        }
+    })
+    .await;
+    let resp = match resp {
+        Ok(resp) => resp,
+        Err(e) => {
+            speaking.abort();
+            return Err(e);
+        }
+    };
+    if let Some(rest) = chunker.finish() {
+        let _ = chunks.send(rest);
+    }
+    drop(chunks);
+
+    let reply = chat_reply(resp, note)?;
+    let audio = match speaking.await {
+        Ok(audio) => audio,
+        Err(e) => Err(e.into()),
+    };
+    if let (Ok(url), Some(text)) = (
+        &audio,
+        reply.messages.last().and_then(|m| m.content.as_ref()),
+    ) {
+        tts_cache_insert(tts_cache_key(&TTS_VOICE, &TTS_MODEL, text), url);
+    }
+    Ok(SpokenReply { reply, audio })
+}
+
+/// Completes a single prompt, without the bot's system prompt (see `OpenAiService::completion`)
+pub async fn get_completion(prompt: &str, max_tokens: u16) -> anyhow::Result<(String, u32)> {
+    OpenAiService::shared().completion(prompt, max_tokens).await
+}
+
+/// Asks the vision model about a single image, without any channel context
+pub async fn get_vision(image_url: &str, instruction: &str) -> anyhow::Result<String> {
+    let backend = get_config()?.backend;
+    let model = resolve_model_name(backend.kind, "gpt-4o")?;
+    let client = chat_client(&backend)?;
+
+    let content = vec![
+        ChatCompletionRequestMessageContentPartText::from(instruction.to_string()).into(),
+        ChatCompletionRequestMessageContentPartImage {
+            r#type: "image_url".into(),
+            image_url: image_url.into(),
+        }
+        .into(),
+    ];
+
+    let mut resp = create_chat(
+        &client,
+        CreateChatCompletionRequest {
+            messages: vec![ChatCompletionRequestMessage::User(
+                ChatCompletionRequestUserMessage {
+                    content: ChatCompletionRequestUserMessageContent::Array(content),
+                    role: async_openai::types::Role::User,
+                    name: None,
+                },
+            )],
+            model,
+            max_tokens: Some(4096),
+            ..Default::default()
+        },
+        None,
+    )
+    .await?;
+
+    if let Some(usage) = resp.usage {
+        println!("Vision API usage: {:?}", usage);
+    }
+    resp.choices
+        .pop()
+        .and_then(|choice| choice.message.content)
+        .context("Missing a response")
+}
+
+/// Extracts the text from an image, using the vision model
+pub async fn get_ocr(image_url: &str) -> anyhow::Result<String> {
+    let instruction = get_prompt("ocr").unwrap_or_else(|_| {
+        "Transcribe all of the text in this image exactly as it appears, preserving line breaks. \
+         Reply with only the text.  If there is no text, reply with \"(no text found)\"."
+            .to_string()
+    });
+    get_vision(image_url, &instruction).await
+}
+
+/// An image from DALL-E, rehosted so the link doesn't expire
+#[derive(Debug, Clone)]
+pub struct GeneratedImage {
+    pub url: String,
+    /// A small preview, for people who'd rather not download the whole HD image
+    pub thumbnail: Option<String>,
+    /// The prompt that was actually drawn, since DALL-E 3 rewrites the one it's given
+    pub revised_prompt: Option<String>,
+}
+
+impl GeneratedImage {
+    /// The link to the image, and to its preview if there is one, for posting in a channel
+    pub fn links(&self) -> String {
+        match &self.thumbnail {
+            Some(thumbnail) => format!("{} (preview: {thumbnail})", self.url),
+            None => self.url.clone(),
+        }
+    }
+}
+
+/// Asks the moderation endpoint about some text
+///
+/// Returns the categories that it was flagged for, like "violence/graphic", which is empty if
+/// it's fine.
+pub async fn moderate(text: &str) -> anyhow::Result<Vec<String>> {
+    let (client, api_key) = openai_client()?;
+
+    let req = CreateModerationRequest {
+        input: ModerationInput::String(text.to_string()),
+        model: None,
+    };
+    let resp = client.moderations().create(req.clone()).await;
+    audit::record("moderations", &req, &resp);
+    api_key.record(&resp, None);
+    let resp = resp?;
+
+    Ok(resp
+        .results
+        .iter()
+        .filter(|result| result.flagged)
+        .flat_map(|result| {
+            serde_json::to_value(&result.categories)
+                .map(|categories| flagged_categories(&categories))
+                .unwrap_or_default()
+        })
+        .collect())
+}
+
+/// The names of the categories that are true, in a moderation result's categories
+fn flagged_categories(categories: &serde_json::Value) -> Vec<String> {
+    let Some(categories) = categories.as_object() else {
+        return Vec::new();
+    };
+    categories
+        .iter()
+        .filter(|(_, flagged)| flagged.as_bool() == Some(true))
+        .map(|(name, _)| name.clone())
+        .collect()
+}
+
+/// What one HD image from DALL-E 3 costs, in dollars
+pub const IMAGE_PRICE: f64 = 0.08;
+
+/// Draws an image, for `nick` if it's on someone's behalf
+pub async fn get_image(prompt: &str, nick: Option<&str>) -> anyhow::Result<GeneratedImage> {
+    let (client, api_key) = openai_client()?;
+
+    let req = CreateImageRequest {
+        prompt: prompt.to_string(),
+        model: Some(async_openai::types::ImageModel::DallE3),
+        n: Some(1),
+        quality: Some(ImageQuality::HD),
+        user: nick.map(billing_user),
+        ..Default::default()
+    };
+    let resp = client.images().create(req.clone()).await;
+    audit::record("images", &req, &resp);
+    api_key.record(&resp, Some(IMAGE_PRICE));
+    let resp = resp?;
+
+    for data in resp.data {
+        if let Image::Url {
+            url,
+            revised_prompt,
+        } = &*data
+        {
+            // download and rehost
+            let resp = OpenAiService::shared()
+                .downloads()
+                .get(url)
+                .timeout(Duration::from_secs(60))
+                .send()
+                .await?;
+            let data = resp.bytes().await?.to_vec();
+
+            let thumbnail = match images::make_thumbnail(&data) {
+                Ok(thumb) => upload_content(thumb, "image/jpeg").await.ok(),
+                Err(e) => {
+                    println!("Failed to make a thumbnail for a generated image: {e}");
+                    None
+                }
+            };
+            let rehosted_url = upload_content(data, "image/png").await?;
+            return Ok(GeneratedImage {
+                url: rehosted_url,
+                thumbnail,
+                revised_prompt: revised_prompt.clone(),
+            });
+        } else {
+            bail!("Image data returned as b64json, not url")
+        }
+    }
+
+    anyhow::bail!("unknown error")
+}
+
+/// The TTS API rejects inputs longer than this
+const TTS_MAX_INPUT_CHARS: usize = 4096;
+/// Roughly how fast the TTS voices speak
+const TTS_CHARS_PER_SECOND: u32 = 15;
+/// Shortest piece of a streamed reply that's spoken on its own, so that a reply isn't spoken in
+/// lots of tiny requests
+const TTS_MIN_CHUNK_CHARS: usize = 200;
+
+/// What the TTS model charges for a million characters, in dollars
+const TTS_PRICE_PER_MILLION_CHARS: f64 = 30.0;
+
+const TTS_VOICE: Voice = Voice::Echo;
+const TTS_MODEL: SpeechModel = SpeechModel::Tts1Hd;
+
+/// Splits text into pieces no longer than `max_chars`, preferring to split between sentences
+pub fn split_for_tts(text: &str, max_chars: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut current = String::new();
+    for sentence in text.split_inclusive(['.', '!', '?', '\n']) {
+        if current.len() + sentence.len() > max_chars && !current.is_empty() {
+            chunks.push(std::mem::take(&mut current));
+        }
+        if sentence.len() > max_chars {
+            // a single enormous sentence, so fall back to splitting between words
+            chunks.extend(
+                textwrap::wrap(sentence, max_chars)
+                    .into_iter()
+                    .map(|s| s.into_owned()),
+            );
+        } else {
+            current.push_str(sentence);
+        }
+    }
+    if !current.trim().is_empty() {
+        chunks.push(current);
+    }
+    chunks.retain(|c| !c.trim().is_empty());
+    chunks
+}
+
+/// Roughly what speaking some text costs, in dollars (less if it's cut off, or was spoken
+/// before)
+pub fn tts_cost(text: &str) -> f64 {
+    text.chars().count() as f64 * TTS_PRICE_PER_MILLION_CHARS / 1_000_000.0
+}
+
+/// Collects a reply as it streams in, and hands out pieces of it that end between sentences
+#[derive(Debug, Default)]
+struct SentenceChunker {
+    pending: String,
+}
+
+impl SentenceChunker {
+    /// Adds some of the reply, returning the sentences that are ready to be spoken, if there are
+    /// enough of them
+    fn push(&mut self, text: &str) -> Option<String> {
+        self.pending.push_str(text);
+        // a sentence is only over once there's a space after its full stop, since "3." could
+        // still turn out to be "3.5"
+        let end = self
+            .pending
+            .char_indices()
+            .filter(|&(i, c)| {
+                c == '\n' || (c.is_whitespace() && self.pending[..i].ends_with(['.', '!', '?']))
+            })
+            .map(|(i, c)| i + c.len_utf8())
+            .last()?;
+        if end < TTS_MIN_CHUNK_CHARS {
+            return None;
+        }
+        let rest = self.pending.split_off(end);
+        Some(std::mem::replace(&mut self.pending, rest))
+    }
+
+    /// The rest of the reply, once it's all arrived
+    fn finish(self) -> Option<String> {
+        (!self.pending.trim().is_empty()).then_some(self.pending)
+    }
+}
+
+/// Makes sure some text won't take longer than the configured maximum to speak
+///
+/// Long text is either summarized or cut off at a sentence boundary, depending on the config.
+async fn fit_tts_duration(text: &str, cfg: &TtsConfig) -> anyhow::Result<String> {
+    let max_chars = (cfg.max_duration_secs * TTS_CHARS_PER_SECOND) as usize;
+    if text.len() <= max_chars {
+        return Ok(text.to_string());
+    }
+
+    let text = if cfg.summarize_long {
+        let prompt = format!(
+            "Summarize the following so that it can be read aloud in under {} seconds. \
+             Reply with only the summary.\n\n{text}",
+            cfg.max_duration_secs
+        );
+        let (summary, _) = get_completion(&prompt, 1024).await?;
+        if summary.len() <= max_chars {
+            return Ok(summary);
+        }
+        summary
+    } else {
+        text.to_string()
+    };
+
+    Ok(split_for_tts(&text, max_chars)
+        .into_iter()
+        .next()
+        .unwrap_or_default())
+}
+
+/// Where URLs of previously generated speech are remembered
+const TTS_CACHE_PATH: &str = "tts_cache.json";
+
+/// Maps a cache key (see `tts_cache_key`) to the URL of the uploaded speech
+static TTS_CACHE: Mutex<Option<HashMap<String, String>>> = Mutex::new(None);
+
+fn tts_cache_key(voice: &Voice, model: &SpeechModel, text: &str) -> String {
+    format!("{voice:?}/{model:?}/{:x}", md5::compute(text.trim()))
+}
+
+fn with_tts_cache<T>(f: impl FnOnce(&mut HashMap<String, String>) -> T) -> T {
+    let mut cache = TTS_CACHE.lock().expect("tts cache lock is poisoned");
+    let cache = cache.get_or_insert_with(|| {
+        File::open(TTS_CACHE_PATH)
+            .ok()
+            .and_then(|f| serde_json::from_reader(f).ok())
+            .unwrap_or_default()
+    });
+    f(cache)
+}
 
-//     Ok(resp)
-// }
+fn tts_cache_insert(key: String, url: &str) {
+    with_tts_cache(|cache| {
+        cache.insert(key, url.to_string());
+        let saved = File::create(TTS_CACHE_PATH)
+            .map_err(anyhow::Error::from)
+            .and_then(|f| Ok(serde_json::to_writer(f, cache)?));
+        if let Err(e) = saved {
+            println!("Failed to save TTS cache: {e}");
+        }
+    });
+}
 
-/// Get the chat completions for the given chat messages
-///
-/// This can return multiple chat messages if a function was called
-pub async fn get_chat(
-    messages: Vec<ChatCompletionRequestMessage>,
-    model: Option<&'static str>,
-    temp: Option<f32>,
-) -> anyhow::Result<Vec<ChatCompletionResponseMessage>> {
-    let _start = std::time::Instant::now();
-    println!(
-        "Sending chat completion request ({} total messages) {:?}",
-        messages.len(),
-        messages.last()
+/// Speaks a piece of text that fits in one TTS request, as Ogg Opus
+async fn speak(
+    client: &async_openai::Client<OpenAIConfig>,
+    api_key: &PickedKey,
+    text: String,
+) -> anyhow::Result<bytes::Bytes> {
+    let req = async_openai::types::CreateSpeechRequest {
+        input: text,
+        model: TTS_MODEL,
+        voice: TTS_VOICE,
+        response_format: Some(async_openai::types::SpeechResponseFormat::Opus),
+        speed: None,
+    };
+    let resp = client.audio().speech(req.clone()).await;
+    audit::record(
+        "speech",
+        &req,
+        &resp
+            .as_ref()
+            .map(|r| format!("{} bytes of audio", r.bytes.len())),
     );
-    let now = Utc::now();
+    api_key.record(&resp, None);
+    Ok(resp?.bytes)
+}
 
-    let mut m = vec![ChatCompletionRequestMessage::System(
-        ChatCompletionRequestSystemMessage {
-            role: async_openai::types::Role::System,
-            content: format!(
-                "{}. Current date: {}",
-                get_prompt("system")?,
-                now.date_naive()
-            ),
-            name: None,
-        },
-    )];
+/// Speaks the pieces of a reply as they arrive, and uploads the audio once they're all done
+///
+/// Like `fit_tts_duration`, the reply is cut off at a sentence once it's `max_chars` long.
+async fn speak_chunks(
+    mut chunks: mpsc::UnboundedReceiver<String>,
+    max_chars: usize,
+) -> anyhow::Result<String> {
+    let (client, api_key) = openai_client()?;
+    let mut speaking = Vec::new();
+    let mut spoken = 0;
+    while let Some(chunk) = chunks.recv().await {
+        let remaining = max_chars.saturating_sub(spoken);
+        let (chunk, cut_off) = if chunk.len() <= remaining {
+            (chunk, false)
+        } else if remaining > 0 {
+            let start = split_for_tts(&chunk, remaining).into_iter().next();
+            (start.unwrap_or_default(), true)
+        } else {
+            break;
+        };
+        spoken += chunk.len();
+        for piece in split_for_tts(&chunk, TTS_MAX_INPUT_CHARS) {
+            // each piece starts being spoken right away, rather than after the ones before it
+            let (client, api_key) = (client.clone(), api_key.clone());
+            speaking.push(tokio::spawn(async move {
+                speak(&client, &api_key, piece).await
+            }));
+        }
+        if cut_off {
+            break;
+        }
+    }
 
-    m.extend(messages);
+    let mut audio = Vec::new();
+    for piece in speaking {
+        audio.extend_from_slice(&piece.await??);
+    }
+    if audio.is_empty() {
+        bail!("Nothing to say");
+    }
+    let rehosted_url = upload_content(audio, "audio/ogg").await?;
+    Ok(format!("{rehosted_url}.ogg"))
+}
 
-    let cfg = OpenAIConfig::new().with_api_key(crate::secrets::OPENAPI_KEY);
-    let client = async_openai::Client::with_config(cfg);
+/// Returns a URL to the uploaded speech
+///
+/// Text that's too long for a single TTS request is spoken in pieces, and the audio is joined
+/// together (Ogg streams can be chained one after another).  Text that was spoken before reuses
+/// the earlier upload, instead of being generated and uploaded again.
+pub async fn get_tts(text: &str) -> anyhow::Result<String> {
+    let key = tts_cache_key(&TTS_VOICE, &TTS_MODEL, text);
+    if let Some(url) = with_tts_cache(|cache| cache.get(&key).cloned()) {
+        return Ok(url);
+    }
 
-    let mut resp = client
-        .chat()
-        .create(CreateChatCompletionRequest {
-            messages: m,
-            model: model.unwrap_or("gpt-4o").to_string(),
-            max_tokens: Some(4096),
-            temperature: temp,
-            ..Default::default()
-        })
-        .await?;
+    let text = fit_tts_duration(text, &get_config()?.tts).await?;
 
-    if let Some(usage) = resp.usage {
-        println!("Chat API usage: {:?}", usage);
+    let (client, api_key) = openai_client()?;
+
+    let mut audio = Vec::new();
+    for chunk in split_for_tts(&text, TTS_MAX_INPUT_CHARS) {
+        audio.extend_from_slice(&speak(&client, &api_key, chunk).await?);
+    }
+    if audio.is_empty() {
+        bail!("Nothing to say");
     }
-    let resp_msg = resp.choices.pop().context("Missing a response")?.message;
 
-    Ok(vec![resp_msg])
+    let rehosted_url = upload_content(audio, "audio/ogg").await?;
+    let url = format!("{rehosted_url}.ogg");
+    tts_cache_insert(key, &url);
+
+    Ok(url)
 }
 
-pub async fn get_image(prompt: &str) -> anyhow::Result<String> {
-    let cfg = OpenAIConfig::new().with_api_key(crate::secrets::OPENAPI_KEY);
-    let client = async_openai::Client::with_config(cfg);
+/// Opus is always timed at 48kHz, whatever it was recorded at
+const OPUS_SAMPLE_RATE: f64 = 48_000.0;
 
-    let resp = client
-        .images()
-        .create(CreateImageRequest {
-            prompt: prompt.to_string(),
-            model: Some(async_openai::types::ImageModel::DallE3),
-            n: Some(1),
-            quality: Some(ImageQuality::HD),
-            ..Default::default()
-        })
-        .await?;
+/// Longest chapter title, before it's cut off
+const CHAPTER_TITLE_CHARS: usize = 40;
 
-    for data in resp.data {
-        if let Image::Url {
-            url,
-            revised_prompt: _,
-        } = &*data
-        {
-            // download and rehost
-            let client = reqwest::Client::builder()
-                .connect_timeout(Duration::from_secs(30))
-                .timeout(Duration::from_secs(60))
-                .build()?;
-            let resp = client.get(url).send().await?;
+/// How long some Ogg Opus audio from the TTS API is, going by its last page's granule position
+fn opus_duration(audio: &[u8]) -> Option<f64> {
+    let page = audio.windows(4).rposition(|w| w == b"OggS")?;
+    let granule = audio.get(page + 6..page + 14)?;
+    let samples = i64::from_le_bytes(granule.try_into().ok()?);
+    (samples >= 0).then(|| samples as f64 / OPUS_SAMPLE_RATE)
+}
 
-            let rehosted_url = upload_content(resp.bytes().await?.to_vec(), "image/png").await?;
-            return Ok(rehosted_url);
+/// Splits text into the paragraphs it's spoken in, keeping headings with what follows them
+fn paragraphs(text: &str) -> Vec<String> {
+    let is_heading =
+        |para: &str| !para.contains('\n') && (para.starts_with('#') || para.ends_with(':'));
+    let mut paragraphs = Vec::new();
+    let mut heading: Option<String> = None;
+    for para in text.split("\n\n").map(str::trim).filter(|p| !p.is_empty()) {
+        let para = match heading.take() {
+            Some(heading) => format!("{heading}\n{para}"),
+            None => para.to_string(),
+        };
+        if is_heading(&para) {
+            heading = Some(para);
         } else {
-            bail!("Image data returned as b64json, not url")
+            paragraphs.push(para);
         }
     }
-
-    anyhow::bail!("unknown error")
+    paragraphs.extend(heading);
+    paragraphs
 }
 
-/// Returns a URL to the uploaded speech
-pub async fn get_tts(text: &str) -> anyhow::Result<String> {
-    let cfg = OpenAIConfig::new().with_api_key(crate::secrets::OPENAPI_KEY);
-    let client = async_openai::Client::with_config(cfg);
+/// A time in the audio, like 1:05 or 1:02:05
+fn timestamp(secs: f64) -> String {
+    let secs = secs as u64;
+    if secs >= 3600 {
+        format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+    } else {
+        format!("{}:{:02}", secs / 60, secs % 60)
+    }
+}
 
-    let resp = client
-        .audio()
-        .speech(async_openai::types::CreateSpeechRequest {
-            input: text.into(),
-            model: async_openai::types::SpeechModel::Tts1Hd,
-            voice: async_openai::types::Voice::Echo,
-            response_format: Some(async_openai::types::SpeechResponseFormat::Opus),
-            speed: None,
+/// The list of where each paragraph starts, with the start of each as its title
+fn chapter_list(paragraphs: &[String], starts: &[f64]) -> String {
+    paragraphs
+        .iter()
+        .zip(starts)
+        .map(|(para, start)| {
+            let first = para.lines().next().unwrap_or_default();
+            let first = first.trim_start_matches('#').trim();
+            let mut title: String = first.chars().take(CHAPTER_TITLE_CHARS).collect();
+            if title.len() < first.len() {
+                title = format!("{}…", title.trim_end());
+            }
+            format!("{} {title}\n", timestamp(*start))
         })
-        .await?;
+        .collect()
+}
 
-    let rehosted_url = upload_content(resp.bytes.to_vec(), "audio/ogg").await?;
+/// Speaks each paragraph on its own, returning a message listing the links
+async fn speak_paragraphs(paragraphs: &[String]) -> anyhow::Result<String> {
+    let mut links = Vec::new();
+    for (i, para) in paragraphs.iter().enumerate() {
+        links.push(format!("{}. {}", i + 1, get_tts(para).await?));
+    }
+    Ok(format!("In {} parts: {}", links.len(), links.join(" ")))
+}
 
-    Ok(format!("{rehosted_url}.ogg"))
+/// Speaks all the paragraphs into one file, and uploads a list of where each starts alongside
+async fn speak_chapters(paragraphs: &[String]) -> anyhow::Result<String> {
+    let (client, api_key) = openai_client()?;
+    let mut audio = Vec::new();
+    let mut starts = Vec::new();
+    let mut elapsed = 0.0;
+    for para in paragraphs {
+        starts.push(elapsed);
+        for chunk in split_for_tts(para, TTS_MAX_INPUT_CHARS) {
+            let spoken = speak(&client, &api_key, chunk).await?;
+            // each piece is its own Ogg stream, timed from zero
+            elapsed += opus_duration(&spoken).unwrap_or_default();
+            audio.extend_from_slice(&spoken);
+        }
+    }
+    let url = upload_content(audio, "audio/ogg").await?;
+    let chapters = chapter_list(paragraphs, &starts).into_bytes();
+    let chapters_url = upload_content(chapters, "text/plain; charset=utf-8").await?;
+    Ok(format!("{url}.ogg (chapters: {chapters_url})"))
+}
+
+/// Speaks some text, returning what to post about it: a link to the audio, or to its parts,
+/// depending on the configured `TtsLayout`
+pub async fn get_tts_reply(text: &str) -> anyhow::Result<String> {
+    let cfg = get_config()?.tts;
+    if cfg.layout == TtsLayout::Single {
+        return get_tts(text).await;
+    }
+    let text = fit_tts_duration(text, &cfg).await?;
+    let parts = paragraphs(&text);
+    match cfg.layout {
+        TtsLayout::Paragraphs if parts.len() > 1 => speak_paragraphs(&parts).await,
+        TtsLayout::Chapters if parts.len() > 1 => speak_chapters(&parts).await,
+        _ => get_tts(&text).await,
+    }
 }
 
 pub async fn get_translation(audio_url: &str, prompt: Option<String>) -> anyhow::Result<String> {
     // filename is the name of the file to be translated
     let filename = audio_url.split('/').last().unwrap_or("unknown.ogg");
 
-    let client = reqwest::Client::builder()
-        .connect_timeout(Duration::from_secs(2))
-        .timeout(Duration::from_secs(10))
-        .user_agent("anna/1.0.0")
-        .build()
-        .unwrap();
-
     // download the audio adnd store as a Bytes object
-    let resp = client.get(audio_url).send().await?;
+    let resp = OpenAiService::shared()
+        .downloads()
+        .get(audio_url)
+        .timeout(Duration::from_secs(10))
+        .send()
+        .await?;
 
     // make sure content type is audio:
     let ct = resp
@@ -203,26 +1907,65 @@ pub async fn get_translation(audio_url: &str, prompt: Option<String>) -> anyhow:
     };
     // dbg!(&translation_request);
 
-    let cfg = OpenAIConfig::new().with_api_key(crate::secrets::OPENAPI_KEY);
-    let client = async_openai::Client::with_config(cfg);
+    let (client, api_key) = openai_client()?;
 
-    let resp = client.audio().translate(translation_request).await?;
+    let audit_req = serde_json::json!({
+        "model": "whisper-1",
+        "file": filename,
+        "prompt": &translation_request.prompt,
+    });
+    let resp = client.audio().translate(translation_request).await;
+    audit::record("translations", &audit_req, &resp.as_ref().map(|r| &r.text));
+    api_key.record(&resp, None);
+    let resp = resp?;
 
     Ok(resp.text)
 }
 
-pub async fn get_transcription(audio_url: &str, prompt: Option<String>) -> anyhow::Result<String> {
+/// Whisper refuses audio files larger than this
+pub const WHISPER_MAX_BYTES: u64 = 25 * 1024 * 1024;
+
+/// Default limit on what's sent to a local transcription server
+const LOCAL_TRANSCRIPTION_MAX_BYTES: u64 = 200 * 1024 * 1024;
+
+/// The largest audio file that the configured backend will transcribe
+pub fn transcription_max_bytes() -> u64 {
+    let transcription = get_config().unwrap_or_default().transcription;
+    match transcription.backend {
+        TranscriptionBackend::OpenAI => WHISPER_MAX_BYTES,
+        TranscriptionBackend::Local => transcription
+            .max_bytes
+            .unwrap_or(LOCAL_TRANSCRIPTION_MAX_BYTES),
+    }
+}
+
+pub async fn get_transcription(
+    audio_url: &str,
+    prompt: Option<String>,
+    progress: &mut Progress,
+) -> anyhow::Result<String> {
+    let (filename, audio) = download_audio(audio_url, transcription_max_bytes(), progress).await?;
+    transcribe_with_progress(&filename, audio, prompt, progress).await
+}
+
+/// Downloads an audio (or video) file, returning its name and contents
+///
+/// Files bigger than `max_bytes` are refused, whether or not the server says how big they are.
+pub async fn download_audio(
+    audio_url: &str,
+    max_bytes: u64,
+    progress: &mut Progress,
+) -> anyhow::Result<(String, bytes::Bytes)> {
     // filename is the name of the file to be translated
     let filename = audio_url.split('/').last().unwrap_or("unknown.ogg");
 
-    let client = reqwest::Client::builder()
-        .connect_timeout(Duration::from_secs(2))
-        .timeout(Duration::from_secs(10))
-        .build()
-        .unwrap();
-
-    // download the audio adnd store as a Bytes object
-    let resp = client.get(audio_url).send().await?;
+    // long recordings can take a while to come down
+    let mut resp = OpenAiService::shared()
+        .downloads()
+        .get(audio_url)
+        .timeout(Duration::from_secs(5 * 60))
+        .send()
+        .await?;
 
     // make sure content type is audio:
     let ct = resp
@@ -232,26 +1975,683 @@ pub async fn get_transcription(audio_url: &str, prompt: Option<String>) -> anyho
     if !matches!(ct, Some(s) if s.starts_with("audio/") || s.starts_with("video/")) {
         bail!("Content type is not audio")
     }
+    let too_big = || {
+        format!(
+            "That's more than {} of audio",
+            megabytes(max_bytes as usize)
+        )
+    };
+    if matches!(resp.content_length(), Some(len) if len > max_bytes) {
+        bail!(too_big());
+    }
 
-    let audio = resp.bytes().await?;
+    let mut audio = Vec::new();
+    while let Some(chunk) = resp.chunk().await? {
+        if (audio.len() + chunk.len()) as u64 > max_bytes {
+            bail!(too_big());
+        }
+        audio.extend_from_slice(&chunk);
+        progress.update(format!("downloaded {}…", megabytes(audio.len())));
+    }
 
-    let translation_request = CreateTranscriptionRequest {
-        file: AudioInput::from_bytes(filename.into(), audio),
-        model: "whisper-1".into(),
-        prompt,
-        response_format: Some(AudioResponseFormat::Json),
-        temperature: None,
-        language: None,
-        timestamp_granularities: None,
+    Ok((filename.to_string(), audio.into()))
+}
+
+/// Transcribes an audio file on disk
+pub async fn transcribe_file(
+    path: &std::path::Path,
+    prompt: Option<String>,
+    progress: &mut Progress,
+) -> anyhow::Result<String> {
+    let audio = tokio::fs::read(path)
+        .await
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let filename = path
+        .file_name()
+        .and_then(|f| f.to_str())
+        .unwrap_or("unknown.ogg");
+    transcribe_with_progress(filename, audio.into(), prompt, progress).await
+}
+
+/// Like [transcribe_bytes], but keeps saying that it's still working on it
+pub async fn transcribe_with_progress(
+    filename: &str,
+    audio: bytes::Bytes,
+    prompt: Option<String>,
+    progress: &mut Progress,
+) -> anyhow::Result<String> {
+    let max_bytes = transcription_max_bytes();
+    if audio.len() as u64 > max_bytes {
+        bail!(
+            "That's {} of audio, and whisper only takes {}",
+            megabytes(audio.len()),
+            megabytes(max_bytes as usize)
+        );
+    }
+    let notice = format!("transcribing {} of audio…", megabytes(audio.len()));
+    progress
+        .wait_for(&notice, transcribe_bytes(filename, audio, prompt))
+        .await
+}
+
+/// "12MB"
+pub fn megabytes(bytes: usize) -> String {
+    format!("{}MB", bytes / (1024 * 1024))
+}
+
+/// Where transcriptions are sent, according to the config
+struct TranscriptionClient {
+    client: async_openai::Client<OpenAIConfig>,
+    /// Only set when the audio goes to OpenAI
+    api_key: Option<PickedKey>,
+    model: String,
+    backend: TranscriptionBackend,
+}
+
+fn transcription_client() -> anyhow::Result<TranscriptionClient> {
+    let transcription = get_config()?.transcription;
+    Ok(match transcription.backend {
+        TranscriptionBackend::OpenAI => {
+            let (client, api_key) = openai_client()?;
+            TranscriptionClient {
+                client,
+                api_key: Some(api_key),
+                model: "whisper-1".to_string(),
+                backend: transcription.backend,
+            }
+        }
+        TranscriptionBackend::Local => {
+            let api_base = transcription
+                .api_base
+                .context("The local transcription backend needs an api_base")?;
+            // local servers ignore the key, but the client insists on sending one
+            let cfg = OpenAIConfig::new()
+                .with_api_base(api_base)
+                .with_api_key("local");
+            let http_client = OpenAiService::shared().api_client(Default::default())?;
+            TranscriptionClient {
+                client: async_openai::Client::with_config(cfg).with_http_client(http_client),
+                api_key: None,
+                model: transcription
+                    .model
+                    .unwrap_or_else(|| "whisper-1".to_string()),
+                backend: transcription.backend,
+            }
+        }
+    })
+}
+
+impl TranscriptionClient {
+    fn request(
+        &self,
+        filename: &str,
+        audio: bytes::Bytes,
+        prompt: Option<String>,
+        response_format: AudioResponseFormat,
+    ) -> (CreateTranscriptionRequest, serde_json::Value) {
+        let request = CreateTranscriptionRequest {
+            file: AudioInput::from_bytes(filename.into(), audio),
+            model: self.model.clone(),
+            prompt,
+            response_format: Some(response_format),
+            temperature: None,
+            language: None,
+            timestamp_granularities: None,
+        };
+        let audit_req = serde_json::json!({
+            "model": self.model,
+            "backend": self.backend,
+            "file": filename,
+            "prompt": &request.prompt,
+        });
+        (request, audit_req)
+    }
+
+    fn record<T: Serialize, E: std::fmt::Display>(
+        &self,
+        audit_req: &serde_json::Value,
+        resp: &Result<T, E>,
+    ) {
+        audit::record("transcriptions", audit_req, resp);
+        if let Some(api_key) = &self.api_key {
+            api_key.record(resp, None);
+        }
+    }
+}
+
+/// Transcribes audio that's already been downloaded (or read from disk)
+///
+/// This goes to whichever backend is configured.  `filename` is only used to guess the format of
+/// the audio.
+pub async fn transcribe_bytes(
+    filename: &str,
+    audio: bytes::Bytes,
+    prompt: Option<String>,
+) -> anyhow::Result<String> {
+    let client = transcription_client()?;
+    let (req, audit_req) = client.request(filename, audio, prompt, AudioResponseFormat::Json);
+    let resp = client.client.audio().transcribe(req).await;
+    client.record(&audit_req, &resp.as_ref().map(|r| &r.text));
+
+    Ok(resp?.text)
+}
+
+/// A stretch of a transcript, and when it starts (in seconds from the start of the audio)
+#[derive(Debug, Clone, PartialEq)]
+pub struct TranscriptSegment {
+    pub start: f32,
+    pub text: String,
+}
+
+/// A transcript that says when everything was said
+#[derive(Debug, Clone)]
+pub struct TimedTranscript {
+    /// How long the audio was, in seconds
+    pub duration: f32,
+    pub segments: Vec<TranscriptSegment>,
+}
+
+/// Like [transcribe_bytes], but with timestamps
+pub async fn transcribe_timed(
+    filename: &str,
+    audio: bytes::Bytes,
+    prompt: Option<String>,
+) -> anyhow::Result<TimedTranscript> {
+    let client = transcription_client()?;
+    let (mut req, audit_req) =
+        client.request(filename, audio, prompt, AudioResponseFormat::VerboseJson);
+    req.timestamp_granularities = Some(vec![TimestampGranularity::Segment]);
+    let resp = client.client.audio().transcribe_verbose_json(req).await;
+    client.record(&audit_req, &resp.as_ref().map(|r| &r.text));
+    let resp = resp?;
+
+    let segments = match resp.segments {
+        Some(segments) => segments
+            .into_iter()
+            .map(|s| TranscriptSegment {
+                start: s.start,
+                text: s.text.trim().to_string(),
+            })
+            .collect(),
+        // some local servers leave out the segments, so there's at least the text
+        None => vec![TranscriptSegment {
+            start: 0.0,
+            text: resp.text.trim().to_string(),
+        }],
     };
-    // dbg!(&translation_request);
+    Ok(TimedTranscript {
+        duration: resp.duration,
+        segments,
+    })
+}
 
-    let cfg = OpenAIConfig::new().with_api_key(crate::secrets::OPENAPI_KEY);
-    let client = async_openai::Client::with_config(cfg);
+/// Returns one embedding for each of the given texts, in the same order
+pub async fn get_embeddings(texts: Vec<String>) -> anyhow::Result<Vec<Vec<f32>>> {
+    let (client, api_key) = openai_client()?;
+
+    let req = async_openai::types::CreateEmbeddingRequest {
+        model: crate::embeddings::EMBEDDING_MODEL.to_string(),
+        input: async_openai::types::EmbeddingInput::StringArray(texts),
+        encoding_format: None,
+        user: None,
+        dimensions: None,
+    };
+    let resp = client.embeddings().create(req.clone()).await;
+    // the embeddings themselves would just bloat the log
+    audit::record(
+        "embeddings",
+        &req,
+        &resp
+            .as_ref()
+            .map(|r| format!("{} embeddings", r.data.len())),
+    );
+    api_key.record(&resp, None);
+    let mut resp = resp?;
 
-    let resp = client.audio().transcribe(translation_request).await?;
+    resp.data.sort_by_key(|e| e.index);
+    Ok(resp.data.into_iter().map(|e| e.embedding).collect())
+}
 
-    Ok(resp.text)
+#[test]
+fn test_resolve_model_name() {
+    let openai = |name| resolve_model_name(BackendKind::OpenAI, name);
+    let openrouter = |name| resolve_model_name(BackendKind::OpenRouter, name);
+
+    assert_eq!(openai("gpt-4o").unwrap(), "gpt-4o");
+    assert_eq!(openai("openai/gpt-4o-mini").unwrap(), "gpt-4o-mini");
+    assert!(openai("mistralai/mistral-large").is_err());
+    assert!(openai(" ").is_err());
+
+    assert_eq!(openrouter("gpt-4o").unwrap(), "openai/gpt-4o");
+    assert_eq!(openrouter("gemini").unwrap(), "google/gemini-pro-1.5");
+    assert_eq!(
+        openrouter("meta-llama/llama-3-8b-instruct").unwrap(),
+        "meta-llama/llama-3-8b-instruct"
+    );
+}
+
+#[test]
+fn test_chat_request_temperature() {
+    let body = |kind, model: &str, temp| {
+        let backend = BackendConfig {
+            kind,
+            ..Default::default()
+        };
+        let options = ChatOptions {
+            model: Some(model.to_string()),
+            temp,
+            ..Default::default()
+        };
+        let req = request_with_system_prompt(&backend, "Be nice", Vec::new(), &options).unwrap();
+        serde_json::to_value(req).unwrap()
+    };
+
+    let req = body(BackendKind::OpenAI, "gpt-4o", Some(0.5));
+    assert_eq!(req["model"], "gpt-4o");
+    assert_eq!(req["temperature"], 0.5);
+    assert!(req["messages"][0]["content"]
+        .as_str()
+        .unwrap()
+        .starts_with("Be nice. Current date: "));
+    assert!(body(BackendKind::OpenAI, "gpt-4o", None)
+        .get("temperature")
+        .is_none());
+
+    // these reject any temperature, so it's left out rather than failing the request
+    assert!(body(BackendKind::OpenAI, "o1-mini", Some(0.5))
+        .get("temperature")
+        .is_none());
+    assert!(body(BackendKind::OpenRouter, "openai/o3-mini", Some(1.5))
+        .get("temperature")
+        .is_none());
+    assert_eq!(
+        body(BackendKind::OpenRouter, "claude", Some(1.5))["temperature"],
+        1.5
+    );
+    assert!(supports_temperature("gpt-4o1"));
+}
+
+#[test]
+fn test_describe_images() {
+    assert!(supports_vision("gpt-4o"));
+    assert!(supports_vision("openai/gpt-4-turbo"));
+    assert!(!supports_vision("gpt-4"));
+    assert!(!supports_vision("o3-mini"));
+    assert!(!supports_vision("deepseek/deepseek-chat"));
+
+    let image = |url: &str| -> ChatCompletionRequestMessageContentPart {
+        ChatCompletionRequestMessageContentPartImage {
+            r#type: "image_url".into(),
+            image_url: url.into(),
+        }
+        .into()
+    };
+    let mut messages = vec![ChatCompletionRequestMessage::User(
+        ChatCompletionRequestUserMessage {
+            content: ChatCompletionRequestUserMessageContent::Array(vec![
+                ChatCompletionRequestMessageContentPartText::from("<achin> look".to_string())
+                    .into(),
+                image("https://example.com/cat.png"),
+                image("https://example.com/gone.png"),
+            ]),
+            role: async_openai::types::Role::User,
+            name: Some("achin".to_string()),
+        },
+    )];
+    assert_eq!(
+        image_urls(&messages),
+        [
+            "https://example.com/cat.png",
+            "https://example.com/gone.png"
+        ]
+    );
+
+    let descriptions = HashMap::from([(
+        "https://example.com/cat.png".to_string(),
+        "A cat asleep on a keyboard".to_string(),
+    )]);
+    replace_images(&mut messages, &descriptions);
+    assert!(image_urls(&messages).is_empty());
+    let ChatCompletionRequestMessage::User(user) = &messages[0] else {
+        panic!("not a user message");
+    };
+    let ChatCompletionRequestUserMessageContent::Text(text) = &user.content else {
+        panic!("images weren't replaced");
+    };
+    assert_eq!(
+        text,
+        "<achin> look\n[image: A cat asleep on a keyboard]\n[an image that couldn't be shown]"
+    );
+}
+
+#[test]
+fn test_image_descriptions() {
+    let mut cache = ImageDescriptions::default();
+    let now = Instant::now();
+    cache.insert("cat".to_string(), Some("A cat".to_string()), now);
+    cache.insert("gone".to_string(), None, now);
+    assert!(!cache.needs_describing("cat", now + DESCRIBE_RETRY));
+    // failures are only retried after a while
+    assert!(!cache.needs_describing("gone", now));
+    assert!(cache.needs_describing("gone", now + DESCRIBE_RETRY));
+    assert!(cache.needs_describing("new", now));
+
+    let urls = ["cat".to_string(), "gone".to_string()];
+    let later = now + Duration::from_secs(1);
+    assert_eq!(cache.lookup(&urls, later).len(), 1);
+    // the least recently used description is the one forgotten
+    for i in 0..MAX_IMAGE_DESCRIPTIONS - 2 {
+        cache.insert(i.to_string(), Some(i.to_string()), now);
+    }
+    cache.insert("one more".to_string(), Some("dog".to_string()), later);
+    assert_eq!(cache.entries.len(), MAX_IMAGE_DESCRIPTIONS);
+    assert!(cache.entries.contains_key("cat"));
+    assert!(cache.entries.contains_key("gone"));
+}
+
+#[test]
+fn test_reasoning_request_body() {
+    let request = |model: &str| {
+        let backend = BackendConfig::default();
+        let options = ChatOptions {
+            model: Some(model.to_string()),
+            temp: Some(0.5),
+            ..Default::default()
+        };
+        request_with_system_prompt(&backend, "Be nice", Vec::new(), &options).unwrap()
+    };
+
+    let req = request("o3-mini");
+    assert_eq!(req.max_tokens, Some(25_000));
+    let body = request_body(&req, Some("high")).unwrap();
+    assert!(body.get("max_tokens").is_none());
+    assert_eq!(body["max_completion_tokens"], 25_000);
+    assert_eq!(body["reasoning_effort"], "high");
+    assert_eq!(body["messages"][0]["role"], "system");
+
+    // the early ones don't take a system prompt or an effort
+    let body = request_body(&request("o1-mini-2024-09-12"), Some("high")).unwrap();
+    assert!(body.get("reasoning_effort").is_none());
+    assert_eq!(body["messages"][0]["role"], "user");
+
+    // and everything else is sent just as async_openai would send it
+    let req = request("gpt-4o");
+    assert_eq!(req.max_tokens, Some(4096));
+    assert_eq!(
+        request_body(&req, Some("high")).unwrap(),
+        serde_json::to_value(&req).unwrap()
+    );
+}
+
+#[test]
+fn test_render_system_prompt() {
+    let now = "2024-06-01T12:00:00Z".parse::<DateTime<Utc>>().unwrap();
+    let vars = PromptVars {
+        channel: Some("##em32".to_string()),
+        botname: Some("Charbot9000".to_string()),
+        users_present: vec!["achin".to_string(), "agrif".to_string()],
+        ..Default::default()
+    };
+    assert_eq!(
+        render_system_prompt(
+            "You are {botname} in {channel} on {date}, with {users_present}. {unknown}",
+            &vars,
+            now
+        ),
+        "You are Charbot9000 in ##em32 on 2024-06-01, with achin, agrif. {unknown}"
+    );
+    assert_eq!(
+        render_system_prompt("Be nice", &PromptVars::default(), now),
+        "Be nice. Current date: 2024-06-01"
+    );
+    let vars = PromptVars {
+        users_present: vec!["achin".to_string(), "agrif".to_string()],
+        ..Default::default()
+    };
+    assert_eq!(
+        render_system_prompt("Be nice", &vars, now),
+        "Be nice. Current date: 2024-06-01\nPeople in the channel right now (only address these \
+         by name): achin, agrif"
+    );
+    let vars = PromptVars {
+        description: Some("Minecraft map rendering".to_string()),
+        topic: Some("Overviewer 0.19 is out".to_string()),
+        ..Default::default()
+    };
+    assert_eq!(
+        render_system_prompt("Be nice", &vars, now),
+        "Be nice. Current date: 2024-06-01\nAbout this channel: Minecraft map rendering\n\
+         The channel's topic is: Overviewer 0.19 is out"
+    );
+    assert_eq!(
+        render_system_prompt("Be nice on {date}. Topic: {topic}", &vars, now),
+        "Be nice on 2024-06-01. Topic: Overviewer 0.19 is out\n\
+         About this channel: Minecraft map rendering"
+    );
+}
+
+#[test]
+fn test_billing() -> anyhow::Result<()> {
+    assert_eq!(billing_user("achin"), billing_user("Achin"));
+    assert_ne!(billing_user("achin"), billing_user("achin_"));
+    assert!(!billing_user("achin").contains("achin"));
+
+    let mut headers = reqwest::header::HeaderMap::new();
+    let account = OpenAIAccountConfig {
+        organization: None,
+        project: Some("proj_abc".to_string()),
+    };
+    add_account_headers(&account, &mut headers)?;
+    assert_eq!(headers.len(), 1);
+    assert_eq!(headers["openai-project"], "proj_abc");
+    Ok(())
+}
+
+#[test]
+fn test_fit_to_context() {
+    let request = |model: &str, messages: usize, chars: usize| CreateChatCompletionRequest {
+        model: model.to_string(),
+        max_tokens: Some(4096),
+        messages: (0..messages)
+            .map(|_| {
+                ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
+                    content: "x".repeat(chars).into(),
+                    role: async_openai::types::Role::User,
+                    name: None,
+                })
+            })
+            .collect(),
+        ..Default::default()
+    };
+
+    let mut req = request("gpt-4o", 10, 400);
+    assert_eq!(fit_to_context(&mut req).unwrap(), None);
+
+    // about 12k tokens doesn't fit in gpt-4's 8k, but does in gpt-4o
+    let mut req = request("openai/gpt-4", 4, 12_000);
+    assert!(fit_to_context(&mut req).unwrap().is_some());
+    assert_eq!(req.model, "openai/gpt-4o");
+    assert_eq!(req.messages.len(), 4);
+
+    // too long even for gpt-4o, so the oldest messages go (but never the first or last)
+    let mut req = request("gpt-4o", 6, 120_000);
+    let note = fit_to_context(&mut req).unwrap().unwrap();
+    assert_eq!(req.messages.len(), 4);
+    assert!(note.contains("2 oldest messages"));
+
+    let mut req = request("gpt-4o", 2, 600_000);
+    assert!(fit_to_context(&mut req).is_err());
+
+    let mut req = request("mistralai/mistral-large", 2, 600_000);
+    assert_eq!(fit_to_context(&mut req).unwrap(), None);
+}
+
+#[test]
+fn test_describe_usage() {
+    let usage = CompletionUsage {
+        prompt_tokens: 1000,
+        completion_tokens: 200,
+        total_tokens: 1200,
+    };
+    // $2.50 a million in, and $10 a million out
+    assert_eq!(chat_cost("gpt-4o-2024-08-06", &usage), Some(0.0045));
+    assert_eq!(
+        describe_usage("gpt-4o", Some(&usage)),
+        "1000 tokens in, 200 out, ~$0.0045"
+    );
+    assert_eq!(
+        describe_usage("mistralai/mistral-large", Some(&usage)),
+        "1000 tokens in, 200 out, no price known for mistralai/mistral-large"
+    );
+    assert_eq!(
+        describe_usage("gpt-4o", None),
+        "the API didn't say how many tokens that took"
+    );
+}
+
+#[test]
+fn test_estimates() {
+    assert_eq!(
+        input_price_per_million("gpt-4o-mini-2024-07-18"),
+        Some(0.15)
+    );
+    assert_eq!(input_price_per_million("openai/gpt-4o"), Some(2.5));
+    assert_eq!(input_price_per_million("mistralai/mistral-large"), None);
+
+    let messages = vec![
+        ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
+            content: "12345678".to_string().into(),
+            role: async_openai::types::Role::User,
+            name: None,
+        }),
+        ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
+            content: ChatCompletionRequestUserMessageContent::Array(vec![
+                ChatCompletionRequestMessageContentPartImage {
+                    r#type: "image_url".into(),
+                    image_url: "https://example.com/cat.png".into(),
+                }
+                .into(),
+            ]),
+            role: async_openai::types::Role::User,
+            name: None,
+        }),
+    ];
+    assert_eq!(
+        estimate_request_tokens(&messages),
+        (4 + 2) + (4 + IMAGE_TOKEN_ESTIMATE)
+    );
+}
+
+#[test]
+fn test_flagged_categories() {
+    let categories = serde_json::json!({
+        "hate": false,
+        "sexual": true,
+        "violence/graphic": true,
+    });
+    assert_eq!(
+        flagged_categories(&categories),
+        ["sexual", "violence/graphic"]
+    );
+    assert!(flagged_categories(&serde_json::json!(null)).is_empty());
+}
+
+#[test]
+fn test_tts_cache_key() {
+    let key = tts_cache_key(&Voice::Echo, &SpeechModel::Tts1Hd, "Hello there");
+    assert_eq!(
+        key,
+        tts_cache_key(&Voice::Echo, &SpeechModel::Tts1Hd, " Hello there\n")
+    );
+    assert_ne!(
+        key,
+        tts_cache_key(&Voice::Alloy, &SpeechModel::Tts1Hd, "Hello there")
+    );
+    assert_ne!(
+        key,
+        tts_cache_key(&Voice::Echo, &SpeechModel::Tts1, "Hello there")
+    );
+}
+
+#[test]
+fn test_sentence_chunker() {
+    let mut chunker = SentenceChunker::default();
+    let sentence = "This is a sentence that goes on for a little while. ";
+    let mut chunks = Vec::new();
+    // the reply streams in a few characters at a time
+    let reply = sentence.repeat(10) + "And the end";
+    for piece in reply.as_bytes().chunks(7) {
+        if let Some(chunk) = chunker.push(std::str::from_utf8(piece).unwrap()) {
+            assert!(chunk.len() >= TTS_MIN_CHUNK_CHARS);
+            assert!(chunk.ends_with(". "));
+            chunks.push(chunk);
+        }
+    }
+    chunks.extend(chunker.finish());
+    assert!(chunks.len() > 1);
+    assert_eq!(chunks.concat(), reply);
+
+    // numbers aren't the ends of sentences
+    let mut chunker = SentenceChunker::default();
+    assert_eq!(
+        chunker.push(&format!("{} 3.", "x".repeat(TTS_MIN_CHUNK_CHARS))),
+        None
+    );
+    assert_eq!(chunker.push("5 is bigger"), None);
+    assert!(chunker.finish().unwrap().ends_with("3.5 is bigger"));
+}
+
+#[test]
+fn test_tts_paragraphs() {
+    let text = "# Intro\n\nFirst things first.\n\n\nThen this.\nAnd that.\n\nIn short:";
+    assert_eq!(
+        paragraphs(text),
+        [
+            "# Intro\nFirst things first.",
+            "Then this.\nAnd that.",
+            "In short:"
+        ]
+    );
+
+    let chapters = chapter_list(&paragraphs(text), &[0.0, 65.4, 3725.0]);
+    assert_eq!(chapters, "0:00 Intro\n1:05 Then this.\n1:02:05 In short:\n");
+    let long = ["x".repeat(CHAPTER_TITLE_CHARS + 5)];
+    assert_eq!(
+        chapter_list(&long, &[0.0]),
+        format!("0:00 {}…\n", "x".repeat(CHAPTER_TITLE_CHARS))
+    );
+}
+
+#[test]
+fn test_opus_duration() {
+    // the header of the last page of a stream, with 2.5 seconds of samples
+    let mut page = b"OggS\0\x04".to_vec();
+    page.extend_from_slice(&120_000i64.to_le_bytes());
+    page.extend_from_slice(&[0; 16]);
+    let mut audio = b"OggS\0\x02".to_vec();
+    audio.extend_from_slice(&0i64.to_le_bytes());
+    audio.extend_from_slice(&[0; 40]);
+    audio.extend_from_slice(&page);
+    assert_eq!(opus_duration(&audio), Some(2.5));
+    assert_eq!(opus_duration(b"not audio"), None);
+}
+
+#[test]
+fn test_split_for_tts() {
+    let text = "First sentence. Second sentence! A third one? Yes.";
+    assert_eq!(split_for_tts(text, 100), vec![text.to_string()]);
+
+    let chunks = split_for_tts(text, 20);
+    assert_eq!(
+        chunks,
+        vec!["First sentence.", " Second sentence!", " A third one? Yes."]
+    );
+    assert!(chunks.iter().all(|c| c.len() <= 20));
+
+    let long_sentence = "word ".repeat(100);
+    let chunks = split_for_tts(&long_sentence, 64);
+    assert!(chunks.len() > 1);
+    assert!(chunks.iter().all(|c| c.len() <= 64));
 }
 
 #[tokio::test]
@@ -411,3 +2811,18 @@ async fn test_embedding() {
 
     dbg!(res);
 }
+
+#[test]
+fn test_generated_image_links() {
+    let mut image = GeneratedImage {
+        url: "https://up.example.com/cat.png".to_string(),
+        thumbnail: Some("https://up.example.com/cat-small.jpg".to_string()),
+        revised_prompt: None,
+    };
+    assert_eq!(
+        image.links(),
+        "https://up.example.com/cat.png (preview: https://up.example.com/cat-small.jpg)"
+    );
+    image.thumbnail = None;
+    assert_eq!(image.links(), "https://up.example.com/cat.png");
+}
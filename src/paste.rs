@@ -0,0 +1,223 @@
+use anyhow::{bail, Context};
+use url::Url;
+
+use crate::{get_prompt, summarize_long_text};
+
+/// Messages longer than this are taken to be something pasted, rather than something said
+pub const LONG_PASTE_CHARS: usize = 2000;
+
+/// Longest line that's taken to be the question asked about a paste
+const QUESTION_CHARS: usize = 300;
+
+/// Most of a paste that's fetched
+const MAX_PASTE_BYTES: usize = 512 * 1024;
+
+/// What's asked when a paste comes without a question
+const DEFAULT_QUESTION: &str = "What's this about?";
+
+/// Something long that was pasted along with a question
+#[derive(Debug, Clone, PartialEq)]
+pub enum Paste {
+    /// Pasted into the message itself (only possible on networks other than IRC)
+    Text(String),
+    /// A link to a paste service, as the URL of the raw text
+    Link(String),
+}
+
+/// The URL of the raw text of a paste, if this is a link to a paste service
+pub fn raw_url(link: &str) -> Option<String> {
+    let url = Url::parse(link).ok()?;
+    let host = url.host_str()?.trim_start_matches("www.");
+    let path: Vec<&str> = url.path_segments()?.filter(|s| !s.is_empty()).collect();
+    let raw = match (host, path.as_slice()) {
+        ("pastebin.com", ["raw", id]) | ("pastebin.com", [id]) => {
+            format!("https://pastebin.com/raw/{id}")
+        }
+        ("dpaste.org", [id, "raw"]) | ("dpaste.org", [id]) => {
+            format!("https://dpaste.org/{id}/raw")
+        }
+        ("dpaste.com", [id]) => format!("https://dpaste.com/{}.txt", id.trim_end_matches(".txt")),
+        ("bpa.st", ["raw", id]) | ("bpa.st", [id]) => format!("https://bpa.st/raw/{id}"),
+        ("gist.github.com", [user, id]) => {
+            format!("https://gist.githubusercontent.com/{user}/{id}/raw")
+        }
+        ("gist.githubusercontent.com", [_, _, "raw", ..]) | ("paste.rs", [_]) => link.to_string(),
+        ("termbin.com", [_]) | ("ix.io", [_]) | ("0x0.st", [_]) => link.to_string(),
+        _ => return None,
+    };
+    Some(raw)
+}
+
+/// Picks a long paste out of a question to the bot, returning what's being asked about it
+///
+/// A long message is split into the question and the paste, where the question is a short
+/// first line ending in `?` or `:`, or a short last line ending in `?`.
+pub fn find(msg: &str) -> Option<(&str, Paste)> {
+    if msg.chars().count() <= LONG_PASTE_CHARS {
+        let link = msg.split_whitespace().find_map(raw_url)?;
+        return Some((msg, Paste::Link(link)));
+    }
+    let msg = msg.trim();
+    let is_question = |line: &str, endings: &[char]| {
+        !line.is_empty()
+            && line.chars().count() <= QUESTION_CHARS
+            && line.ends_with(endings)
+            && line.len() < msg.len()
+    };
+    let first = msg.lines().next().unwrap_or_default().trim();
+    let last = msg.lines().last().unwrap_or_default().trim();
+    let (question, paste) = if is_question(first, &['?', ':']) {
+        (first, msg[first.len()..].trim())
+    } else if is_question(last, &['?']) {
+        (last, msg[..msg.len() - last.len()].trim())
+    } else {
+        (DEFAULT_QUESTION, msg)
+    };
+    Some((question, Paste::Text(paste.to_string())))
+}
+
+/// Fetches the text of a paste, cut off at `MAX_PASTE_BYTES`
+pub async fn fetch(client: &reqwest::Client, raw_url: &str) -> anyhow::Result<String> {
+    let mut resp = client.get(raw_url).send().await?.error_for_status()?;
+    let content_type = resp
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|ct| ct.to_str().ok())
+        .unwrap_or("text/plain");
+    if !content_type.starts_with("text/") {
+        bail!("That paste isn't text ({content_type})");
+    }
+    let mut body = Vec::new();
+    while let Some(chunk) = resp.chunk().await? {
+        body.extend_from_slice(&chunk);
+        if body.len() >= MAX_PASTE_BYTES {
+            body.truncate(MAX_PASTE_BYTES);
+            break;
+        }
+    }
+    Ok(String::from_utf8_lossy(&body).into_owned())
+}
+
+/// A linked paste as it goes into the context, cut off at `LONG_PASTE_CHARS`
+pub fn for_context(url: &str, text: &str) -> String {
+    let text = text.trim();
+    let total = text.chars().count();
+    if total <= LONG_PASTE_CHARS {
+        return format!("[paste at {url}]\n{text}");
+    }
+    let start: String = text.chars().take(LONG_PASTE_CHARS).collect();
+    format!("[paste at {url}]\n{start}\n[cut off, {total} characters in all]")
+}
+
+/// The text of a paste, fetching it if it was linked
+pub async fn text(client: &reqwest::Client, paste: &Paste) -> anyhow::Result<String> {
+    match paste {
+        Paste::Text(text) => Ok(text.clone()),
+        Paste::Link(url) => fetch(client, url)
+            .await
+            .with_context(|| format!("Couldn't fetch {url}")),
+    }
+}
+
+/// A paste's `text` as it goes into the context, summarized with the question in mind if it's
+/// long
+pub async fn digest(
+    paste: &Paste,
+    text: &str,
+    nick: &str,
+    question: &str,
+) -> anyhow::Result<String> {
+    if text.trim().is_empty() {
+        bail!("That paste is empty");
+    }
+    if text.trim().chars().count() <= LONG_PASTE_CHARS {
+        return Ok(match paste {
+            Paste::Text(_) => format!("What {nick} pasted:\n{text}"),
+            Paste::Link(url) => for_context(url, text),
+        });
+    }
+    let instruction = get_prompt("summarize-paste").unwrap_or_else(|_| {
+        "Summarize the text below, which someone pasted into a chat, so that someone who hasn't \
+         read it can answer their question about it.  Keep any details the question depends \
+         on, like error messages, names and numbers, exactly as they are."
+            .to_string()
+    });
+    let instruction = format!("{instruction}\n\nTheir question: {question}");
+    let summary = summarize_long_text(text, &instruction).await?;
+    Ok(format!("Summary of the long text {nick} pasted: {summary}"))
+}
+
+#[test]
+fn test_raw_url() {
+    assert_eq!(
+        raw_url("https://pastebin.com/aBcD1234").as_deref(),
+        Some("https://pastebin.com/raw/aBcD1234")
+    );
+    assert_eq!(
+        raw_url("https://dpaste.org/XyZ").as_deref(),
+        Some("https://dpaste.org/XyZ/raw")
+    );
+    assert_eq!(
+        raw_url("https://dpaste.com/ABCD").as_deref(),
+        Some("https://dpaste.com/ABCD.txt")
+    );
+    assert_eq!(
+        raw_url("https://gist.github.com/achin/0123abcd").as_deref(),
+        Some("https://gist.githubusercontent.com/achin/0123abcd/raw")
+    );
+    assert_eq!(
+        raw_url("https://paste.rs/abc").as_deref(),
+        Some("https://paste.rs/abc")
+    );
+    assert_eq!(
+        raw_url("https://0x0.st/Hx3a.txt").as_deref(),
+        Some("https://0x0.st/Hx3a.txt")
+    );
+    assert_eq!(raw_url("https://example.com/abc"), None);
+    assert_eq!(raw_url("https://pastebin.com/"), None);
+    assert_eq!(raw_url("not a link"), None);
+}
+
+#[test]
+fn test_find_paste() {
+    assert_eq!(find("what's up?"), None);
+    assert_eq!(
+        find("what's wrong here? https://pastebin.com/aBcD1234"),
+        Some((
+            "what's wrong here? https://pastebin.com/aBcD1234",
+            Paste::Link("https://pastebin.com/raw/aBcD1234".to_string())
+        ))
+    );
+
+    let log = vec!["error: something broke"; 200].join("\n");
+    let asked_first = format!("why does this fail?\n{log}");
+    let (question, paste) = find(&asked_first).unwrap();
+    assert_eq!(question, "why does this fail?");
+    assert_eq!(paste, Paste::Text(log.clone()));
+
+    let asked_last = format!("{log}\nany idea what this is?");
+    let (question, paste) = find(&asked_last).unwrap();
+    assert_eq!(question, "any idea what this is?");
+    assert_eq!(paste, Paste::Text(log.clone()));
+
+    let (question, paste) = find(&log).unwrap();
+    assert_eq!(question, DEFAULT_QUESTION);
+    assert_eq!(paste, Paste::Text(log));
+}
+
+#[test]
+fn test_paste_for_context() {
+    let url = "https://dpaste.org/XyZ";
+    assert_eq!(
+        for_context(url, "fn main() {}\n"),
+        "[paste at https://dpaste.org/XyZ]\nfn main() {}"
+    );
+    let long = "x".repeat(LONG_PASTE_CHARS + 10);
+    let cut = for_context(url, &long);
+    assert!(cut.contains(&"x".repeat(LONG_PASTE_CHARS)));
+    assert!(!cut.contains(&"x".repeat(LONG_PASTE_CHARS + 1)));
+    assert!(cut.ends_with(&format!(
+        "[cut off, {} characters in all]",
+        LONG_PASTE_CHARS + 10
+    )));
+}
@@ -1,21 +1,765 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fs::File,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
 use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
 use wasmtime::{
     component::{Component, Linker},
-    Config, Engine, Store,
+    Config, Engine, Store, Trap, UpdateDeadline,
 };
 
+use crate::openai::OpenAiService;
+
 wasmtime::component::bindgen!({
     world: "foo",
     async: true
 });
 
-pub struct HostImports;
+/// What a plugin is allowed to do, configured per plugin in `config.json`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PluginPolicy {
+    /// Hosts that `http-fetch` may contact.  Subdomains of a listed host are also allowed.
+    pub allowed_domains: Vec<String>,
+    /// Responses larger than this are rejected
+    pub max_response_bytes: usize,
+    /// Total time allowed for a single fetch, in seconds
+    pub http_timeout_secs: u64,
+    /// How many tokens of chat completions the plugin may use per day
+    pub daily_token_budget: u32,
+    /// Total size of all keys and values the plugin may keep in its key-value store
+    pub kv_quota_bytes: usize,
+}
+
+impl Default for PluginPolicy {
+    fn default() -> Self {
+        Self {
+            allowed_domains: Vec::new(),
+            max_response_bytes: 512 * 1024,
+            http_timeout_secs: 10,
+            daily_token_budget: 0,
+            kv_quota_bytes: 64 * 1024,
+        }
+    }
+}
+
+impl PluginPolicy {
+    pub fn allows_url(&self, url: &url::Url) -> bool {
+        if url.scheme() != "https" {
+            return false;
+        }
+        let Some(host) = url.host_str() else {
+            return false;
+        };
+        self.allowed_domains.iter().any(|domain| {
+            host.eq_ignore_ascii_case(domain)
+                || host
+                    .to_ascii_lowercase()
+                    .ends_with(&format!(".{}", domain.to_ascii_lowercase()))
+        })
+    }
+}
+
+/// Most redirects a plugin's fetch will follow
+const MAX_REDIRECTS: usize = 5;
+
+/// Where plugins' key-value stores are saved, one file per plugin
+const PLUGIN_DATA_DIR: &str = "plugin_data";
+
+/// A small persistent key-value store, saved to disk after every write
+pub struct KvStore {
+    path: PathBuf,
+    data: BTreeMap<String, String>,
+    quota_bytes: usize,
+}
+
+impl KvStore {
+    pub fn open(path: impl Into<PathBuf>, quota_bytes: usize) -> anyhow::Result<Self> {
+        let path = path.into();
+        let data = if path.exists() {
+            serde_json::from_reader(File::open(&path)?)?
+        } else {
+            BTreeMap::new()
+        };
+        Ok(Self {
+            path,
+            data,
+            quota_bytes,
+        })
+    }
+
+    /// Number of bytes used by all keys and values
+    pub fn used_bytes(&self) -> usize {
+        self.data.iter().map(|(k, v)| k.len() + v.len()).sum()
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.data.get(key).map(|s| s.as_str())
+    }
+
+    pub fn set(&mut self, key: &str, value: String) -> anyhow::Result<()> {
+        let existing = self.data.get(key).map(|v| key.len() + v.len()).unwrap_or(0);
+        let new_size = self.used_bytes() - existing + key.len() + value.len();
+        if new_size > self.quota_bytes {
+            anyhow::bail!(
+                "Storage quota exceeded ({new_size} of {} bytes)",
+                self.quota_bytes
+            );
+        }
+        self.data.insert(key.to_string(), value);
+        self.save()
+    }
+
+    pub fn delete(&mut self, key: &str) -> anyhow::Result<()> {
+        if self.data.remove(key).is_some() {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    fn save(&self) -> anyhow::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        serde_json::to_writer(File::create(&self.path)?, &self.data)?;
+        Ok(())
+    }
+}
+
+/// Tokens each plugin has spent on chat completions, and the day they were spent on
+///
+/// This is kept by the `PluginManager` rather than in a plugin's store, so reloading a plugin (or
+/// crashing it) doesn't give it a fresh budget.
+#[derive(Clone, Default)]
+pub struct TokenUsage(Arc<Mutex<HashMap<String, (chrono::NaiveDate, u32)>>>);
+
+impl TokenUsage {
+    /// Tokens the plugin has spent on `day`
+    fn used(&self, name: &str, day: chrono::NaiveDate) -> u32 {
+        let usage = self.0.lock().expect("token usage lock is poisoned");
+        match usage.get(name) {
+            Some((counted, used)) if *counted == day => *used,
+            _ => 0,
+        }
+    }
+
+    /// Counts tokens the plugin spent on `day`, returning its total for the day
+    fn add(&self, name: &str, day: chrono::NaiveDate, tokens: u32) -> u32 {
+        let mut usage = self.0.lock().expect("token usage lock is poisoned");
+        let entry = usage.entry(name.to_string()).or_insert((day, 0));
+        if entry.0 != day {
+            *entry = (day, 0);
+        }
+        entry.1 = entry.1.saturating_add(tokens);
+        entry.1
+    }
+}
+
+pub struct HostImports {
+    /// Name of the plugin, used in log messages
+    name: String,
+    policy: PluginPolicy,
+    client: reqwest::Client,
+    /// For the plugin's chat completions
+    openai: OpenAiService,
+    /// Tokens spent on chat completions, shared with the manager
+    usage: TokenUsage,
+    kv: KvStore,
+    /// Time spent waiting on host imports since the deadline was last checked, which doesn't
+    /// count against the plugin's deadline
+    waited: Duration,
+}
+
+impl HostImports {
+    pub fn new(
+        name: impl Into<String>,
+        policy: PluginPolicy,
+        openai: OpenAiService,
+        usage: TokenUsage,
+    ) -> anyhow::Result<Self> {
+        let name = name.into();
+        let kv = KvStore::open(
+            Path::new(PLUGIN_DATA_DIR).join(format!("{name}.json")),
+            policy.kv_quota_bytes,
+        )?;
+        // every hop of a redirect has to be on the allowlist, not just the first
+        let redirects = policy.clone();
+        let client = crate::http::client_builder()?
+            .connect_timeout(Duration::from_secs(2))
+            .timeout(Duration::from_secs(policy.http_timeout_secs))
+            .redirect(reqwest::redirect::Policy::custom(move |attempt| {
+                if attempt.previous().len() >= MAX_REDIRECTS {
+                    attempt.error("Too many redirects")
+                } else if redirects.allows_url(attempt.url()) {
+                    attempt.follow()
+                } else {
+                    let url = attempt.url().to_string();
+                    attempt.error(format!(
+                        "Redirected to {url}, which is not on the allowlist"
+                    ))
+                }
+            }))
+            .user_agent("anna/1.0.0")
+            .build()?;
+        Ok(Self {
+            name,
+            policy,
+            client,
+            openai,
+            usage,
+            kv,
+            waited: Duration::ZERO,
+        })
+    }
+
+    /// Called when the plugin's epoch deadline is reached
+    ///
+    /// The deadline is pushed back by however long the plugin spent waiting on the host, so slow
+    /// fetches and completions don't count as the plugin running too long.
+    fn deadline_reached(&mut self) -> anyhow::Result<UpdateDeadline> {
+        let waited = std::mem::take(&mut self.waited);
+        let ticks = (waited.as_millis() / EPOCH_TICK.as_millis()) as u64;
+        if ticks == 0 {
+            return Err(Trap::Interrupt.into());
+        }
+        Ok(UpdateDeadline::Continue(ticks))
+    }
+
+    async fn complete(&mut self, prompt: &str, max_tokens: u32) -> Result<String, String> {
+        let today = chrono::Utc::now().date_naive();
+
+        // refuse requests whose worst case would overshoot the budget, counting the prompt (at
+        // about 4 characters a token) as well as the longest reply
+        let remaining = self
+            .policy
+            .daily_token_budget
+            .saturating_sub(self.usage.used(&self.name, today));
+        let prompt_tokens = u32::try_from(prompt.len().div_ceil(4)).unwrap_or(u32::MAX);
+        if max_tokens == 0 || prompt_tokens.saturating_add(max_tokens) > remaining {
+            return Err(format!(
+                "Token budget exhausted ({remaining} of {} tokens left today)",
+                self.policy.daily_token_budget
+            ));
+        }
+
+        let max_tokens = max_tokens.min(u16::MAX as u32) as u16;
+        let (reply, used) = self
+            .openai
+            .completion(prompt, max_tokens)
+            .await
+            .map_err(|e| e.to_string())?;
+        let total = self.usage.add(&self.name, today, used);
+        println!(
+            "Plugin {} used {used} tokens ({total} of {} today)",
+            self.name, self.policy.daily_token_budget
+        );
+
+        Ok(reply)
+    }
+
+    async fn fetch(&self, url: &str) -> Result<host::HttpResponse, String> {
+        let url = url::Url::parse(url).map_err(|e| format!("Invalid URL: {e}"))?;
+        if !self.policy.allows_url(&url) {
+            return Err(format!("{url} is not on the allowlist for this plugin"));
+        }
+
+        let mut resp = self
+            .client
+            .get(url)
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let status = resp.status().as_u16();
+        let content_type = resp
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|ct| ct.to_str().ok().map(|s| s.to_owned()));
+
+        let limit = self.policy.max_response_bytes;
+        if matches!(resp.content_length(), Some(len) if len as usize > limit) {
+            return Err(format!("Response is larger than {limit} bytes"));
+        }
+
+        // content-length can lie (or be missing), so enforce the limit while reading
+        let mut body = Vec::new();
+        while let Some(chunk) = resp.chunk().await.map_err(|e| e.to_string())? {
+            if body.len() + chunk.len() > limit {
+                return Err(format!("Response is larger than {limit} bytes"));
+            }
+            body.extend_from_slice(&chunk);
+        }
+
+        Ok(host::HttpResponse {
+            status,
+            content_type,
+            body,
+        })
+    }
+}
 
 #[async_trait]
 impl host::Host for HostImports {
     async fn gen_random_integer(&mut self) -> anyhow::Result<u32> {
         Ok(42)
     }
+
+    async fn http_fetch(
+        &mut self,
+        url: String,
+    ) -> anyhow::Result<Result<host::HttpResponse, String>> {
+        let started = Instant::now();
+        let resp = self.fetch(&url).await;
+        self.waited += started.elapsed();
+        if let Err(e) = &resp {
+            println!("Plugin {} failed to fetch {url}: {e}", self.name);
+        }
+        Ok(resp)
+    }
+
+    async fn chat_completion(
+        &mut self,
+        prompt: String,
+        max_tokens: u32,
+    ) -> anyhow::Result<Result<String, String>> {
+        let started = Instant::now();
+        let reply = self.complete(&prompt, max_tokens).await;
+        self.waited += started.elapsed();
+        Ok(reply)
+    }
+
+    async fn kv_get(&mut self, key: String) -> anyhow::Result<Option<String>> {
+        Ok(self.kv.get(&key).map(|s| s.to_string()))
+    }
+
+    async fn kv_set(&mut self, key: String, value: String) -> anyhow::Result<Result<(), String>> {
+        Ok(self.kv.set(&key, value).map_err(|e| e.to_string()))
+    }
+
+    async fn kv_delete(&mut self, key: String) -> anyhow::Result<()> {
+        if let Err(e) = self.kv.delete(&key) {
+            println!("Plugin {} failed to delete {key}: {e}", self.name);
+        }
+        Ok(())
+    }
+}
+
+/// Fuel given to a plugin for each call into it.  Running out traps the call.
+const FUEL_PER_CALL: u64 = 500_000_000;
+/// How often the engine's epoch is incremented
+const EPOCH_TICK: Duration = Duration::from_millis(100);
+/// How many epoch ticks a single call may run for before it's interrupted
+const EPOCH_TICKS_PER_CALL: u64 = 50;
+/// After this many consecutive failures, a plugin is no longer restarted automatically
+const MAX_CONSECUTIVE_FAILURES: u32 = 5;
+/// Upper bound on how long to wait before restarting a crashed plugin
+const MAX_RESTART_BACKOFF: Duration = Duration::from_secs(60 * 60);
+
+/// A loaded plugin component, along with the store that holds all of its state
+pub struct Plugin {
+    store: Store<HostImports>,
+    bindings: ChatPlugin,
+    /// Number of failed calls since the last successful one
+    failures: u32,
+}
+
+impl Plugin {
+    /// Refills the plugin's fuel and resets its deadline before calling into it
+    fn prepare_call(&mut self) -> anyhow::Result<()> {
+        self.store.set_fuel(FUEL_PER_CALL)?;
+        self.store.data_mut().waited = Duration::ZERO;
+        self.store.set_epoch_deadline(EPOCH_TICKS_PER_CALL);
+        Ok(())
+    }
+
+    async fn call_handle(&mut self, line: &str, target: &MsgTarget) -> anyhow::Result<()> {
+        self.prepare_call()?;
+        self.bindings
+            .call_handle(&mut self.store, line, target)
+            .await
+    }
+}
+
+/// A plugin that trapped (or failed to instantiate) and was torn down
+struct Crashed {
+    failures: u32,
+    /// When to try instantiating it again.  None if the plugin is unhealthy and needs a manual reload.
+    retry_at: Option<Instant>,
+}
+
+impl Crashed {
+    fn new(failures: u32) -> Self {
+        let retry_at = (failures < MAX_CONSECUTIVE_FAILURES).then(|| {
+            let backoff = Duration::from_secs(1 << failures.min(12)).min(MAX_RESTART_BACKOFF);
+            Instant::now() + backoff
+        });
+        Self { failures, retry_at }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PluginStatus {
+    Loaded,
+    NotLoaded,
+    Disabled,
+    /// Crashed, and will be restarted after the given delay
+    Restarting(Duration),
+    /// Crashed too many times in a row, and won't be restarted until reloaded
+    Unhealthy,
+}
+
+impl std::fmt::Display for PluginStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PluginStatus::Loaded => write!(f, "loaded"),
+            PluginStatus::NotLoaded => write!(f, "not loaded"),
+            PluginStatus::Disabled => write!(f, "disabled"),
+            PluginStatus::Restarting(after) => {
+                write!(f, "crashed, restarting in {}s", after.as_secs())
+            }
+            PluginStatus::Unhealthy => write!(f, "unhealthy"),
+        }
+    }
+}
+
+/// Keeps track of all the plugins in the plugin directory
+///
+/// Each plugin is a `<name>.wasm` component.  Reloading a plugin throws away its store and
+/// instantiates it again from disk, so a new build can be dropped in without restarting the bot.
+///
+/// Every call into a plugin is limited by fuel and by an epoch deadline, so an infinite loop
+/// traps instead of hanging the bot.  A plugin that traps is torn down and restarted with
+/// exponential backoff, and is marked unhealthy if it keeps failing.
+pub struct PluginManager {
+    engine: Engine,
+    linker: Linker<HostImports>,
+    dir: PathBuf,
+    loaded: BTreeMap<String, Plugin>,
+    /// Plugins that have been disabled at runtime, and won't be loaded by `load_all`
+    disabled: BTreeSet<String>,
+    crashed: BTreeMap<String, Crashed>,
+    /// Handed to every plugin, for its chat completions
+    openai: OpenAiService,
+    /// What every plugin has spent of its token budget, which outlives their instances
+    usage: TokenUsage,
+}
+
+impl PluginManager {
+    pub fn new(dir: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let mut config = Config::new();
+        config.wasm_component_model(true);
+        config.async_support(true);
+        config.consume_fuel(true);
+        config.epoch_interruption(true);
+        let engine = Engine::new(&config)?;
+
+        // drives the epoch deadlines for every plugin store
+        let ticker = engine.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(EPOCH_TICK);
+            ticker.increment_epoch();
+        });
+
+        let mut linker = Linker::new(&engine);
+        ChatPlugin::add_to_linker(&mut linker, |state: &mut HostImports| state)?;
+
+        Ok(Self {
+            engine,
+            linker,
+            dir: dir.into(),
+            loaded: BTreeMap::new(),
+            disabled: BTreeSet::new(),
+            crashed: BTreeMap::new(),
+            openai: OpenAiService::shared().clone(),
+            usage: TokenUsage::default(),
+        })
+    }
+
+    /// Names of all plugins found in the plugin directory
+    pub fn available(&self) -> Vec<String> {
+        let Ok(entries) = std::fs::read_dir(&self.dir) else {
+            return Vec::new();
+        };
+        let mut names: Vec<String> = entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "wasm"))
+            .filter_map(|path| path.file_stem()?.to_str().map(|s| s.to_string()))
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// Loads every available plugin that isn't already loaded, disabled or crashed
+    pub async fn load_all(&mut self) {
+        for name in self.available() {
+            if self.loaded.contains_key(&name)
+                || self.disabled.contains(&name)
+                || self.crashed.contains_key(&name)
+            {
+                continue;
+            }
+            match self.instantiate(&name).await {
+                Ok(plugin) => {
+                    println!("Loaded plugin {name}");
+                    self.loaded.insert(name, plugin);
+                }
+                Err(e) => println!("Failed to load plugin {name}: {e}"),
+            }
+        }
+    }
+
+    /// Returns the status of every known plugin
+    pub fn list(&self) -> Vec<(String, PluginStatus)> {
+        let mut names: BTreeSet<String> = self.available().into_iter().collect();
+        names.extend(self.loaded.keys().cloned());
+        names.extend(self.crashed.keys().cloned());
+        let now = Instant::now();
+        names
+            .into_iter()
+            .map(|name| {
+                let status = if self.loaded.contains_key(&name) {
+                    PluginStatus::Loaded
+                } else if self.disabled.contains(&name) {
+                    PluginStatus::Disabled
+                } else if let Some(crashed) = self.crashed.get(&name) {
+                    match crashed.retry_at {
+                        Some(at) => PluginStatus::Restarting(at.saturating_duration_since(now)),
+                        None => PluginStatus::Unhealthy,
+                    }
+                } else {
+                    PluginStatus::NotLoaded
+                };
+                (name, status)
+            })
+            .collect()
+    }
+
+    /// Tears down the plugin (if it's loaded) and instantiates it again from disk
+    ///
+    /// This also re-enables a disabled or unhealthy plugin.
+    pub async fn reload(&mut self, name: &str) -> anyhow::Result<()> {
+        // drop the old store first, so nothing from the old instance is kept around
+        self.loaded.remove(name);
+        self.disabled.remove(name);
+        self.crashed.remove(name);
+
+        let plugin = self.instantiate(name).await?;
+        self.loaded.insert(name.to_string(), plugin);
+        Ok(())
+    }
+
+    /// Unloads a plugin and keeps it from being loaded again until it's reloaded
+    ///
+    /// Returns false if the plugin wasn't loaded
+    pub fn disable(&mut self, name: &str) -> bool {
+        self.disabled.insert(name.to_string());
+        self.crashed.remove(name);
+        self.loaded.remove(name).is_some()
+    }
+
+    /// Passes a line of chat to every loaded plugin
+    ///
+    /// `channel` is None for private messages to the bot
+    pub async fn handle(&mut self, line: &str, channel: Option<&str>) {
+        self.restart_crashed().await;
+
+        let target = match channel {
+            Some(channel) => MsgTarget::Channel(channel.to_string()),
+            None => MsgTarget::Myself,
+        };
+        let mut failed = Vec::new();
+        for (name, plugin) in self.loaded.iter_mut() {
+            match plugin.call_handle(line, &target).await {
+                Ok(()) => plugin.failures = 0,
+                Err(e) => {
+                    println!("Plugin {name} failed to handle message: {e:?}");
+                    failed.push(name.clone());
+                }
+            }
+        }
+
+        // a trap can leave the instance in a bad state, so never call into it again
+        for name in failed {
+            if let Some(plugin) = self.loaded.remove(&name) {
+                self.mark_crashed(name, plugin.failures + 1);
+            }
+        }
+    }
+
+    /// Re-instantiates crashed plugins whose backoff has expired
+    async fn restart_crashed(&mut self) {
+        let now = Instant::now();
+        let due: Vec<(String, u32)> = self
+            .crashed
+            .iter()
+            .filter(|(_, crashed)| matches!(crashed.retry_at, Some(at) if at <= now))
+            .map(|(name, crashed)| (name.clone(), crashed.failures))
+            .collect();
+
+        for (name, failures) in due {
+            self.crashed.remove(&name);
+            match self.instantiate(&name).await {
+                Ok(mut plugin) => {
+                    println!("Restarted plugin {name}");
+                    // only a successful call resets the failure count.  The token budget isn't
+                    // reset either, since the new instance shares the manager's `usage`.
+                    plugin.failures = failures;
+                    self.loaded.insert(name, plugin);
+                }
+                Err(e) => {
+                    println!("Failed to restart plugin {name}: {e}");
+                    self.mark_crashed(name, failures + 1);
+                }
+            }
+        }
+    }
+
+    fn mark_crashed(&mut self, name: String, failures: u32) {
+        let crashed = Crashed::new(failures);
+        if crashed.retry_at.is_none() {
+            println!("Plugin {name} failed {failures} times in a row, marking it unhealthy");
+        }
+        self.crashed.insert(name, crashed);
+    }
+
+    async fn instantiate(&self, name: &str) -> anyhow::Result<Plugin> {
+        let path = self.dir.join(format!("{name}.wasm"));
+        // compiling is slow and synchronous, so keep it off the async runtime
+        let engine = self.engine.clone();
+        let component =
+            tokio::task::spawn_blocking(move || Component::from_file(&engine, path)).await??;
+
+        let policy = crate::config::get_config()?.plugin_policy(name);
+        let mut store = Store::new(
+            &self.engine,
+            HostImports::new(name, policy, self.openai.clone(), self.usage.clone())?,
+        );
+        // instantiation runs guest code too, so it needs fuel and a deadline
+        store.set_fuel(FUEL_PER_CALL)?;
+        store.fuel_async_yield_interval(Some(10_000))?;
+        store.epoch_deadline_callback(|mut store| store.data_mut().deadline_reached());
+        store.set_epoch_deadline(EPOCH_TICKS_PER_CALL);
+
+        let (bindings, _) =
+            ChatPlugin::instantiate_async(&mut store, &component, &self.linker).await?;
+
+        Ok(Plugin {
+            store,
+            bindings,
+            failures: 0,
+        })
+    }
+}
+
+#[test]
+fn test_crash_backoff() {
+    let crashed = Crashed::new(1);
+    let wait = crashed.retry_at.unwrap() - Instant::now();
+    assert!(wait <= Duration::from_secs(2));
+    assert!(wait > Duration::from_secs(1));
+
+    let crashed = Crashed::new(MAX_CONSECUTIVE_FAILURES - 1);
+    assert!(crashed.retry_at.is_some());
+
+    let crashed = Crashed::new(MAX_CONSECUTIVE_FAILURES);
+    assert!(crashed.retry_at.is_none());
+}
+
+#[test]
+fn test_kv_store() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("plugin.json");
+
+    let mut kv = KvStore::open(&path, 16)?;
+    assert_eq!(kv.get("count"), None);
+    kv.set("count", "1".into())?;
+    assert_eq!(kv.get("count"), Some("1"));
+    // overwriting a key only counts the new value against the quota
+    kv.set("count", "1234567890a".into())?;
+    assert!(kv.set("other", "x".into()).is_err());
+    kv.delete("count")?;
+    kv.set("other", "x".into())?;
+
+    // the data survives reopening the store
+    let kv = KvStore::open(&path, 16)?;
+    assert_eq!(kv.get("other"), Some("x"));
+    assert_eq!(kv.get("count"), None);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_token_budget() -> anyhow::Result<()> {
+    let policy = PluginPolicy {
+        daily_token_budget: 100,
+        ..Default::default()
+    };
+    let usage = TokenUsage::default();
+    let openai = OpenAiService::shared().clone();
+    let mut imports = HostImports::new("budgeted", policy, openai, usage.clone())?;
+
+    // a long prompt counts against the budget, not just the reply
+    let long = "x".repeat(400);
+    assert!(imports.complete(&long, 10).await.is_err());
+
+    let today = chrono::Utc::now().date_naive();
+    assert_eq!(usage.add("budgeted", today, 95), 95);
+    assert!(imports.complete("hi", 10).await.is_err());
+    // a new day starts the count over
+    let tomorrow = today.succ_opt().unwrap();
+    assert_eq!(usage.used("budgeted", tomorrow), 0);
+    assert_eq!(usage.add("budgeted", tomorrow, 5), 5);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_budget_survives_restart() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let mut manager = PluginManager::new(dir.path())?;
+    let policy = PluginPolicy {
+        daily_token_budget: 100,
+        ..Default::default()
+    };
+    let (openai, usage) = (manager.openai.clone(), manager.usage.clone());
+    let instance = || HostImports::new("crashy", policy.clone(), openai.clone(), usage.clone());
+    let first = instance()?;
+    let today = chrono::Utc::now().date_naive();
+    first.usage.add("crashy", today, 100);
+
+    // it traps, and is instantiated again the way `restart_crashed` does it
+    drop(first);
+    manager.mark_crashed("crashy".to_string(), 1);
+    let mut restarted = instance()?;
+    let refused = restarted.complete("hi", 1).await.unwrap_err();
+    assert!(
+        refused.starts_with("Token budget exhausted (0 of 100"),
+        "{refused}"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_plugin_policy() {
+    let policy = PluginPolicy {
+        allowed_domains: vec!["wttr.in".into(), "example.com".into()],
+        ..Default::default()
+    };
+
+    let allowed = |s: &str| policy.allows_url(&url::Url::parse(s).unwrap());
+    assert!(allowed("https://wttr.in/Paris?format=j1"));
+    assert!(allowed("https://api.example.com/foo"));
+    assert!(allowed("https://EXAMPLE.com/"));
+    assert!(!allowed("http://wttr.in/"));
+    assert!(!allowed("https://notexample.com/"));
+    assert!(!allowed("https://example.com.evil.net/"));
+
+    assert!(!PluginPolicy::default().allows_url(&url::Url::parse("https://wttr.in").unwrap()));
 }
 
 #[tokio::test]
@@ -29,7 +773,15 @@ async fn test() -> anyhow::Result<()> {
     let mut linker = Linker::new(&engine);
     ChatPlugin::add_to_linker(&mut linker, |state: &mut HostImports| state)?;
 
-    let mut store = Store::new(&engine, HostImports);
+    let mut store = Store::new(
+        &engine,
+        HostImports::new(
+            "my-component",
+            PluginPolicy::default(),
+            OpenAiService::shared().clone(),
+            TokenUsage::default(),
+        )?,
+    );
 
     let (bindings, _) = ChatPlugin::instantiate_async(&mut store, &component, &linker).await?;
 
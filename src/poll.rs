@@ -0,0 +1,235 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use anyhow::{bail, Context};
+use async_openai::types::{
+    ChatCompletionRequestMessage, ChatCompletionRequestSystemMessage,
+    ChatCompletionRequestUserMessage, ChatCompletionRequestUserMessageContent,
+};
+use chrono::{DateTime, Utc};
+
+use crate::{get_prompt, openai};
+
+/// How long a poll stays open for voting
+pub const POLL_MINUTES: i64 = 5;
+
+/// The open poll in each channel, if there is one
+static POLLS: Mutex<Option<HashMap<String, Poll>>> = Mutex::new(None);
+
+/// A question the channel is voting on
+#[derive(Debug, Clone)]
+pub struct Poll {
+    pub question: String,
+    pub options: Vec<String>,
+    /// Which option each nick (lowercased) picked.  Voting again changes the vote.
+    votes: HashMap<String, usize>,
+    pub ends: DateTime<Utc>,
+    /// Whether to have the model comment on the results
+    pub snark: bool,
+}
+
+impl Poll {
+    /// Parses `[--snark] "question" a|b|c`
+    pub fn parse(args: &str, now: DateTime<Utc>) -> anyhow::Result<Self> {
+        let usage = "Usage: !poll [--snark] \"question\" option a|option b|...";
+        let args = args.trim();
+        let (snark, args) = match args.strip_prefix("--snark") {
+            Some(rest) => (true, rest.trim_start()),
+            None => (false, args),
+        };
+        let rest = args.strip_prefix('"').context(usage)?;
+        let (question, options) = rest
+            .split_once('"')
+            .context("Unterminated quote around the question")?;
+        let options: Vec<String> = options
+            .split('|')
+            .map(|o| o.trim().to_string())
+            .filter(|o| !o.is_empty())
+            .collect();
+        if question.trim().is_empty() || options.len() < 2 {
+            bail!("{usage}");
+        }
+        if options.len() > 26 {
+            bail!("That's too many options");
+        }
+        Ok(Self {
+            question: question.trim().to_string(),
+            options,
+            votes: HashMap::new(),
+            ends: now + chrono::Duration::minutes(POLL_MINUTES),
+            snark,
+        })
+    }
+
+    /// Works out which option a vote is for: its letter, its number, or the option itself
+    fn choice(&self, vote: &str) -> Option<usize> {
+        let vote = vote.trim();
+        let mut chars = vote.chars();
+        if let (Some(c), None) = (chars.next(), chars.next()) {
+            if c.is_ascii_alphabetic() {
+                let idx = (c.to_ascii_lowercase() as u8 - b'a') as usize;
+                return (idx < self.options.len()).then_some(idx);
+            }
+        }
+        if let Ok(n) = vote.parse::<usize>() {
+            return (1..=self.options.len()).contains(&n).then(|| n - 1);
+        }
+        self.options
+            .iter()
+            .position(|o| o.eq_ignore_ascii_case(vote))
+    }
+
+    /// The question and the options, the way they're announced
+    pub fn announcement(&self) -> String {
+        let options: Vec<String> = self
+            .options
+            .iter()
+            .enumerate()
+            .map(|(idx, o)| format!("{}) {o}", letter(idx)))
+            .collect();
+        format!(
+            "Poll: {} — {}  (vote with !vote <letter> in the next {POLL_MINUTES} minutes)",
+            self.question,
+            options.join("  ")
+        )
+    }
+
+    /// How many votes each option got, in the order they were given
+    fn tally(&self) -> Vec<usize> {
+        let mut counts = vec![0; self.options.len()];
+        for &choice in self.votes.values() {
+            counts[choice] += 1;
+        }
+        counts
+    }
+
+    /// "Results for "lunch?": a) tacos 3  b) pizza 1  (4 votes, tacos wins)"
+    pub fn results(&self) -> String {
+        let counts = self.tally();
+        let options: Vec<String> = self
+            .options
+            .iter()
+            .zip(&counts)
+            .enumerate()
+            .map(|(idx, (o, count))| format!("{}) {o} {count}", letter(idx)))
+            .collect();
+        let total: usize = counts.iter().sum();
+        let most = counts.iter().copied().max().unwrap_or(0);
+        let winners: Vec<&str> = self
+            .options
+            .iter()
+            .zip(&counts)
+            .filter(|(_, &count)| count == most)
+            .map(|(o, _)| o.as_str())
+            .collect();
+        let outcome = match (total, winners.as_slice()) {
+            (0, _) => "nobody voted".to_string(),
+            (_, [winner]) => format!("{total} votes, {winner} wins"),
+            _ => format!("{total} votes, tie between {}", winners.join(" and ")),
+        };
+        format!(
+            "Results for \"{}\": {}  ({outcome})",
+            self.question,
+            options.join("  ")
+        )
+    }
+}
+
+fn letter(idx: usize) -> char {
+    (b'a' + idx as u8) as char
+}
+
+/// Opens a poll in a channel, unless one is already running there
+pub fn start(channel: &str, poll: Poll) -> anyhow::Result<()> {
+    let mut polls = POLLS.lock().expect("polls lock is poisoned");
+    let polls = polls.get_or_insert_with(HashMap::new);
+    if polls.contains_key(channel) {
+        bail!("There's already a poll running here");
+    }
+    polls.insert(channel.to_string(), poll);
+    Ok(())
+}
+
+/// Records a vote in a channel's poll
+///
+/// Returns the option that was voted for, or None if there's no poll or the vote doesn't match
+/// any of its options.
+pub fn vote(channel: &str, nick: &str, vote: &str) -> Option<String> {
+    let mut polls = POLLS.lock().expect("polls lock is poisoned");
+    let poll = polls.as_mut()?.get_mut(channel)?;
+    let choice = poll.choice(vote)?;
+    poll.votes.insert(nick.to_lowercase(), choice);
+    Some(poll.options[choice].clone())
+}
+
+/// Whether a channel has a poll running, so plain replies should be checked for votes
+pub fn is_running(channel: &str) -> bool {
+    POLLS
+        .lock()
+        .expect("polls lock is poisoned")
+        .as_ref()
+        .is_some_and(|polls| polls.contains_key(channel))
+}
+
+/// Closes a channel's poll, returning it with all its votes
+pub fn finish(channel: &str) -> Option<Poll> {
+    POLLS
+        .lock()
+        .expect("polls lock is poisoned")
+        .as_mut()?
+        .remove(channel)
+}
+
+/// Has the model say something about how a poll turned out
+pub async fn analyze(poll: &Poll) -> anyhow::Result<String> {
+    let instruction = get_prompt("poll").unwrap_or_else(|_| {
+        "An IRC channel just voted on a poll.  Comment on the outcome in one or two snarky, \
+         but not mean, sentences."
+            .to_string()
+    });
+    let messages = vec![
+        ChatCompletionRequestMessage::System(ChatCompletionRequestSystemMessage {
+            content: instruction,
+            role: async_openai::types::Role::System,
+            name: None,
+        }),
+        ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
+            content: ChatCompletionRequestUserMessageContent::Text(poll.results()),
+            role: async_openai::types::Role::User,
+            name: None,
+        }),
+    ];
+    let resp = openai::get_chat(messages, Some("gpt-4o-mini"), Some(1.0)).await?;
+    resp.first()
+        .and_then(|m| m.content.as_deref())
+        .map(|s| s.trim().to_string())
+        .context("No analysis in response")
+}
+
+#[test]
+fn test_poll() {
+    let mut poll = Poll::parse("\"What's for lunch?\" tacos | pizza|sushi", Utc::now()).unwrap();
+    assert!(!poll.snark);
+    assert_eq!(poll.options, ["tacos", "pizza", "sushi"]);
+    assert_eq!(poll.choice("B"), Some(1));
+    assert_eq!(poll.choice("3"), Some(2));
+    assert_eq!(poll.choice("Tacos"), Some(0));
+    assert_eq!(poll.choice("d"), None);
+    assert_eq!(poll.choice("0"), None);
+
+    for (nick, choice) in [("achin", 0), ("agrif", 1), ("ion", 0), ("ACHIN", 1)] {
+        poll.votes.insert(nick.to_lowercase(), choice);
+    }
+    assert_eq!(
+        poll.results(),
+        "Results for \"What's for lunch?\": a) tacos 1  b) pizza 2  c) sushi 0  (3 votes, pizza wins)"
+    );
+
+    assert!(
+        Poll::parse("--snark \"Tabs?\" yes|no", Utc::now())
+            .unwrap()
+            .snark
+    );
+    assert!(Poll::parse("\"Tabs?\" yes", Utc::now()).is_err());
+    assert!(Poll::parse("Tabs? yes|no", Utc::now()).is_err());
+    assert!(Poll::parse("\"Tabs? yes|no", Utc::now()).is_err());
+}
@@ -0,0 +1,173 @@
+use std::{collections::HashMap, fs::File, path::Path, sync::Mutex};
+
+use anyhow::Context;
+use async_openai::types::{
+    ChatCompletionRequestMessage, ChatCompletionRequestSystemMessage,
+    ChatCompletionRequestUserMessage, ChatCompletionRequestUserMessageContent,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{get_prompt, openai};
+
+const PROFILES_PATH: &str = "profiles.json";
+
+/// How many new lines from someone are collected before their profile is rewritten
+const LINES_PER_UPDATE: usize = 40;
+
+/// Longest profile that's kept, in characters, so they can't crowd out the rest of the prompt
+const MAX_PROFILE_CHARS: usize = 600;
+
+/// Held while the profiles file is being rewritten, so concurrent updates don't get lost
+static PROFILES_LOCK: Mutex<()> = Mutex::new(());
+
+/// Lines from each nick that haven't made it into their profile yet
+static PENDING: Mutex<Option<HashMap<String, Vec<String>>>> = Mutex::new(None);
+
+/// What the bot has picked up about a regular, from the things they've said
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Profile {
+    /// The nick, as it was written when the profile was last updated
+    pub nick: String,
+    /// A few sentences on their interests, projects and preferences
+    pub summary: String,
+    pub updated: DateTime<Utc>,
+}
+
+fn load_all() -> anyhow::Result<HashMap<String, Profile>> {
+    if !Path::new(PROFILES_PATH).exists() {
+        return Ok(HashMap::new());
+    }
+    let file = File::open(PROFILES_PATH)?;
+    Ok(serde_json::from_reader(file)?)
+}
+
+fn save_all(all: &HashMap<String, Profile>) -> anyhow::Result<()> {
+    let output = File::create(PROFILES_PATH)?;
+    serde_json::to_writer_pretty(output, all)?;
+    Ok(())
+}
+
+/// Gets the profile for a nick (which isn't case sensitive), if there is one
+pub fn get(nick: &str) -> Option<Profile> {
+    let _lock = PROFILES_LOCK.lock().expect("profiles lock is poisoned");
+    load_all().ok()?.remove(&nick.to_lowercase())
+}
+
+/// Forgets everything about a nick, including lines that haven't been profiled yet
+///
+/// Returns false if there was no profile.
+pub fn clear(nick: &str) -> anyhow::Result<bool> {
+    let nick = nick.to_lowercase();
+    if let Some(pending) = PENDING.lock().expect("pending lock is poisoned").as_mut() {
+        pending.remove(&nick);
+    }
+    let _lock = PROFILES_LOCK.lock().expect("profiles lock is poisoned");
+    let mut all = load_all()?;
+    let removed = all.remove(&nick).is_some();
+    if removed {
+        save_all(&all)?;
+    }
+    Ok(removed)
+}
+
+/// Notes something an opted-in nick said
+///
+/// Once enough lines have built up, they're handed back to be folded into the profile with
+/// `update`.
+pub fn observe(nick: &str, line: &str) -> Option<Vec<String>> {
+    let mut pending = PENDING.lock().expect("pending lock is poisoned");
+    let lines = pending
+        .get_or_insert_with(HashMap::new)
+        .entry(nick.to_lowercase())
+        .or_default();
+    lines.push(line.to_string());
+    if lines.len() < LINES_PER_UPDATE {
+        return None;
+    }
+    Some(std::mem::take(lines))
+}
+
+/// Asks the model to fold some new lines from a nick into what's already known about them
+pub async fn update(nick: &str, lines: &[String]) -> anyhow::Result<()> {
+    let instruction = get_prompt("profile").unwrap_or_else(|_| {
+        "You keep short notes about the regulars of an IRC channel.  Given the current notes \
+         about someone and some new things they've said, rewrite the notes in at most three \
+         sentences, covering their interests, the projects they work on, and their preferences.  \
+         Only include things they've said about themselves; leave out anything private or \
+         sensitive.  Reply with only the notes."
+            .to_string()
+    });
+    let current = get(nick)
+        .map(|p| p.summary)
+        .unwrap_or_else(|| "(none yet)".to_string());
+    let messages = vec![
+        ChatCompletionRequestMessage::System(ChatCompletionRequestSystemMessage {
+            content: instruction,
+            role: async_openai::types::Role::System,
+            name: None,
+        }),
+        ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
+            content: ChatCompletionRequestUserMessageContent::Text(format!(
+                "Current notes about {nick}: {current}\n\nNew messages from {nick}:\n{}",
+                lines.join("\n")
+            )),
+            role: async_openai::types::Role::User,
+            name: None,
+        }),
+    ];
+    let resp = openai::get_chat(messages, Some("gpt-4o-mini"), Some(0.3)).await?;
+    let summary = resp
+        .first()
+        .and_then(|m| m.content.as_deref())
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .context("No profile in response")?;
+    let summary: String = summary.chars().take(MAX_PROFILE_CHARS).collect();
+
+    let _lock = PROFILES_LOCK.lock().expect("profiles lock is poisoned");
+    let mut all = load_all()?;
+    all.insert(
+        nick.to_lowercase(),
+        Profile {
+            nick: nick.to_string(),
+            summary,
+            updated: Utc::now(),
+        },
+    );
+    save_all(&all)
+}
+
+/// Whether `text` mentions `nick` as a whole word
+fn mentions(text: &str, nick: &str) -> bool {
+    // the characters IRC allows in nicks, besides letters and digits
+    let is_nick_char = |c: char| c.is_alphanumeric() || "-_[]\\`^{}|".contains(c);
+    text.split(|c: char| !is_nick_char(c))
+        .any(|word| word.eq_ignore_ascii_case(nick))
+}
+
+/// Extra instructions for the system prompt, with the profile of everyone `text` asks about
+pub fn directives_for(text: &str) -> Vec<String> {
+    let all = {
+        let _lock = PROFILES_LOCK.lock().expect("profiles lock is poisoned");
+        load_all().unwrap_or_default()
+    };
+    let mut profiles: Vec<Profile> = all
+        .into_values()
+        .filter(|p| mentions(text, &p.nick))
+        .collect();
+    profiles.sort_by(|a, b| a.nick.cmp(&b.nick));
+    profiles
+        .into_iter()
+        .map(|p| format!("What you know about {}: {}", p.nick, p.summary))
+        .collect()
+}
+
+#[test]
+fn test_mentions() {
+    assert!(mentions("who is achin?", "achin"));
+    assert!(mentions("what does Tunabrain work on", "tunabrain"));
+    assert!(mentions("ask [ion] about it", "[ion]"));
+    assert!(!mentions("achinsky says hi", "achin"));
+    assert!(!mentions("the ionosphere", "ion"));
+}
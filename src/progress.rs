@@ -0,0 +1,95 @@
+use std::{
+    future::Future,
+    time::{Duration, Instant},
+};
+
+/// Jobs quicker than this don't say anything, and notices are at least this far apart
+const NOTICE_INTERVAL: Duration = Duration::from_secs(20);
+
+/// Passes along occasional notices about how a job is going
+pub struct Progress {
+    started: Instant,
+    last_notice: Instant,
+    notify: Box<dyn Fn(String) + Send + Sync>,
+}
+
+impl Progress {
+    pub fn new(notify: impl Fn(String) + Send + Sync + 'static) -> Self {
+        let now = Instant::now();
+        Self {
+            started: now,
+            last_notice: now,
+            notify: Box::new(notify),
+        }
+    }
+
+    /// For when nobody is waiting to hear about it
+    pub fn silent() -> Self {
+        Self::new(|_| {})
+    }
+
+    /// Passes the notice on, unless the job just started or we've said something recently
+    pub fn update(&mut self, notice: impl Into<String>) {
+        self.update_at(notice.into(), Instant::now());
+    }
+
+    fn update_at(&mut self, notice: String, now: Instant) {
+        if now.duration_since(self.last_notice) >= NOTICE_INTERVAL {
+            self.last_notice = now;
+            (self.notify)(notice);
+        }
+    }
+
+    /// Waits for something that doesn't say how it's going, saying `notice` every so often
+    pub async fn wait_for<T>(&mut self, notice: &str, fut: impl Future<Output = T>) -> T {
+        tokio::pin!(fut);
+        let mut ticks = tokio::time::interval(Duration::from_secs(5));
+        loop {
+            tokio::select! {
+                out = &mut fut => return out,
+                _ = ticks.tick() => self.update(notice),
+            }
+        }
+    }
+
+    /// "took 2m05s", if the job took long enough for anyone to care
+    pub fn elapsed_note(&self) -> Option<String> {
+        elapsed_note(self.started.elapsed())
+    }
+}
+
+fn elapsed_note(elapsed: Duration) -> Option<String> {
+    if elapsed < NOTICE_INTERVAL {
+        return None;
+    }
+    let secs = elapsed.as_secs();
+    Some(if secs >= 60 {
+        format!("took {}m{:02}s", secs / 60, secs % 60)
+    } else {
+        format!("took {secs}s")
+    })
+}
+
+#[test]
+fn test_progress() {
+    use std::sync::{Arc, Mutex};
+
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let mut progress = Progress::new({
+        let seen = seen.clone();
+        move |notice| seen.lock().unwrap().push(notice)
+    });
+    let start = progress.started;
+    progress.update_at("too soon".into(), start + Duration::from_secs(1));
+    progress.update_at("first".into(), start + Duration::from_secs(21));
+    progress.update_at("too soon again".into(), start + Duration::from_secs(30));
+    progress.update_at("second".into(), start + Duration::from_secs(41));
+    assert_eq!(*seen.lock().unwrap(), vec!["first", "second"]);
+
+    assert_eq!(elapsed_note(Duration::from_secs(3)), None);
+    assert_eq!(elapsed_note(Duration::from_secs(42)).unwrap(), "took 42s");
+    assert_eq!(
+        elapsed_note(Duration::from_secs(125)).unwrap(),
+        "took 2m05s"
+    );
+}
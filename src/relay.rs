@@ -0,0 +1,124 @@
+use std::sync::OnceLock;
+
+use regex::Regex;
+
+use crate::{config::RelayConfig, formatting};
+
+/// A line a relay bot passed along, with who actually said it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Relayed {
+    pub nick: String,
+    pub text: String,
+}
+
+/// Matches `[nick] text` and `<nick> text`, which is what most bridges send
+fn default_regex() -> &'static Regex {
+    static REGEX: OnceLock<Regex> = OnceLock::new();
+    REGEX.get_or_init(|| {
+        Regex::new(r"^[\[<](?P<nick>[^\]>]+)[\]>]:? (?P<text>.*)$")
+            .expect("the relay regex is valid")
+    })
+}
+
+/// Bridges also put zero-width spaces in nicks, so relaying them doesn't ping anyone on IRC
+fn clean_nick(nick: &str) -> String {
+    formatting::strip(nick)
+        .chars()
+        .filter(|c| {
+            !matches!(
+                c,
+                '\u{200b}' | '\u{200c}' | '\u{200d}' | '\u{2060}' | '\u{feff}'
+            )
+        })
+        .collect::<String>()
+        .trim()
+        .to_string()
+}
+
+/// Finds who really sent a line, if it was passed along by one of the relay bots
+///
+/// Returns None for lines that aren't from a relay bot, and for lines from one that aren't
+/// relayed messages (like join notices), which stay attributed to the bot.  A relay bot
+/// configured with a host is only recognized on that host.
+pub fn unwrap(
+    relays: &[RelayConfig],
+    nick: &str,
+    host: Option<&str>,
+    msg: &str,
+) -> Option<Relayed> {
+    let relay = relays.iter().find(|r| {
+        r.nick.eq_ignore_ascii_case(nick) && (r.host.is_none() || r.host.as_deref() == host)
+    })?;
+    let regex = relay.pattern.as_ref().unwrap_or_else(default_regex);
+    // bridges like to color the nicks
+    let msg = formatting::strip(msg);
+    let caps = regex.captures(&msg)?;
+    let nick = clean_nick(caps.name("nick")?.as_str());
+    let text = caps.name("text")?.as_str().trim();
+    if nick.is_empty() || nick.contains(' ') || text.is_empty() {
+        return None;
+    }
+    Some(Relayed {
+        nick,
+        text: text.to_string(),
+    })
+}
+
+#[test]
+fn test_unwrap() {
+    let relays = vec![
+        RelayConfig {
+            nick: "matrixbridge".to_string(),
+            host: None,
+            pattern: None,
+        },
+        RelayConfig {
+            nick: "discord-relay".to_string(),
+            host: Some("discord/relay".to_string()),
+            pattern: Some(Regex::new(r"^(?P<nick>\S+) says: (?P<text>.*)$").unwrap()),
+        },
+    ];
+    let relayed = Relayed {
+        nick: "bob".to_string(),
+        text: "!chat hi there".to_string(),
+    };
+    assert_eq!(
+        unwrap(&relays, "MatrixBridge", None, "[bob] !chat hi there"),
+        Some(relayed.clone())
+    );
+    assert_eq!(
+        unwrap(
+            &relays,
+            "matrixbridge",
+            None,
+            "<\x0303b\u{200b}ob\x0f> !chat hi there"
+        ),
+        Some(relayed.clone())
+    );
+    assert_eq!(
+        unwrap(
+            &relays,
+            "discord-relay",
+            Some("discord/relay"),
+            "bob says: !chat hi there"
+        ),
+        Some(relayed)
+    );
+    // join notices and the like stay the bot's own
+    assert_eq!(
+        unwrap(&relays, "matrixbridge", None, "bob joined the room"),
+        None
+    );
+    // and other people can't pretend to be relaying
+    assert_eq!(unwrap(&relays, "mallory", None, "[achin] !quit"), None);
+    // including by taking the bot's nick while it's away
+    assert_eq!(
+        unwrap(
+            &relays,
+            "discord-relay",
+            Some("mallory.example"),
+            "achin says: !quit"
+        ),
+        None
+    );
+}
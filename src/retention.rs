@@ -0,0 +1,196 @@
+use std::{collections::VecDeque, sync::Mutex};
+
+use chrono::{DateTime, Duration, Utc};
+
+use crate::ChatMessageThing;
+
+/// Where the current time comes from, so that age-based trimming can be tested
+pub trait Clock: std::fmt::Debug + Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// A clock that only moves when told to
+#[derive(Debug)]
+pub struct ManualClock(Mutex<DateTime<Utc>>);
+
+impl ManualClock {
+    pub fn new(now: DateTime<Utc>) -> Self {
+        Self(Mutex::new(now))
+    }
+    pub fn advance(&self, by: Duration) {
+        *self.0.lock().expect("clock lock is poisoned") += by;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.0.lock().expect("clock lock is poisoned")
+    }
+}
+
+/// How long messages (and the images in them) are kept in a conversation
+///
+/// Images are only sent to the API while they're recent, to keep the context small and requests
+/// fast.  Messages are dropped entirely once they're older than `message_max_age`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Retention {
+    image_max_age: Duration,
+    message_max_age: Duration,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Self {
+            image_max_age: Duration::hours(1),
+            message_max_age: Duration::hours(48),
+        }
+    }
+}
+
+impl Retention {
+    pub fn with_image_max_age(mut self, age: Duration) -> Self {
+        self.image_max_age = age;
+        self
+    }
+    pub fn with_message_max_age(mut self, age: Duration) -> Self {
+        self.message_max_age = age;
+        self
+    }
+    /// Whether images in a message from `date` are still sent to the API
+    pub fn sends_images(&self, date: DateTime<Utc>, now: DateTime<Utc>) -> bool {
+        now - date < self.image_max_age
+    }
+    /// Whether a message from `date` is old enough to be dropped
+    pub fn is_expired(&self, date: DateTime<Utc>, now: DateTime<Utc>) -> bool {
+        now - date > self.message_max_age
+    }
+    /// Drops expired messages from the front of a conversation
+    pub fn trim(&self, messages: &mut VecDeque<ChatMessageThing>, now: DateTime<Utc>) {
+        while messages
+            .front()
+            .is_some_and(|cmt| self.is_expired(cmt.date, now))
+        {
+            messages.pop_front();
+        }
+    }
+    /// Drops messages from the front of a conversation until it's estimated to fit in
+    /// `max_tokens`, keeping at least the newest message
+    pub fn trim_to_budget(
+        &self,
+        messages: &mut VecDeque<ChatMessageThing>,
+        max_tokens: usize,
+        now: DateTime<Utc>,
+    ) {
+        let mut total: usize = messages
+            .iter()
+            .map(|cmt| cmt.estimate_tokens(self, now))
+            .sum();
+        while total > max_tokens && messages.len() > 1 {
+            if let Some(cmt) = messages.pop_front() {
+                total -= cmt.estimate_tokens(self, now);
+            }
+        }
+    }
+}
+
+/// Parses how far back to go, like `90m`, `6h`, `2d` or `1w`
+pub fn parse_age(value: &str) -> Option<Duration> {
+    let split = value.find(|c: char| !c.is_ascii_digit())?;
+    let (amount, unit) = value.split_at(split);
+    let amount: i64 = amount.parse().ok()?;
+    match unit {
+        "m" | "min" => Duration::try_minutes(amount),
+        "h" => Duration::try_hours(amount),
+        "d" => Duration::try_days(amount),
+        "w" => Duration::try_weeks(amount),
+        _ => None,
+    }
+}
+
+#[test]
+fn test_parse_age() {
+    assert_eq!(parse_age("90m"), Some(Duration::minutes(90)));
+    assert_eq!(parse_age("6h"), Some(Duration::hours(6)));
+    assert_eq!(parse_age("2d"), Some(Duration::days(2)));
+    assert_eq!(parse_age("1w"), Some(Duration::weeks(1)));
+    assert_eq!(parse_age("6"), None);
+    assert_eq!(parse_age("h"), None);
+    assert_eq!(parse_age("6 hours"), None);
+}
+
+#[test]
+fn test_retention_boundaries() {
+    use async_openai::types::{ChatCompletionRequestMessage, ChatCompletionRequestSystemMessage};
+
+    let retention = Retention::default();
+    let now = Utc::now();
+    assert!(retention.sends_images(now, now));
+    assert!(retention.sends_images(now - Duration::minutes(59), now));
+    assert!(!retention.sends_images(now - Duration::hours(1), now));
+    assert!(!retention.is_expired(now - Duration::hours(48), now));
+    assert!(retention.is_expired(now - Duration::hours(48) - Duration::seconds(1), now));
+
+    let retention = retention
+        .with_image_max_age(Duration::minutes(5))
+        .with_message_max_age(Duration::hours(1));
+    assert!(!retention.sends_images(now - Duration::minutes(5), now));
+    assert!(retention.is_expired(now - Duration::minutes(61), now));
+
+    let msg = |minutes_ago: i64| {
+        ChatMessageThing::new_at(
+            ChatCompletionRequestMessage::System(ChatCompletionRequestSystemMessage {
+                content: format!("{minutes_ago}"),
+                role: async_openai::types::Role::System,
+                name: None,
+            }),
+            now - Duration::minutes(minutes_ago),
+        )
+    };
+    let mut messages = VecDeque::from([msg(90), msg(61), msg(60), msg(1)]);
+    retention.trim(&mut messages, now);
+    let left: Vec<_> = messages
+        .iter()
+        .filter_map(|m| m.get_as_irc_format())
+        .collect();
+    assert_eq!(left, ["60", "1"]);
+
+    retention.trim(&mut messages, now + Duration::days(1));
+    assert!(messages.is_empty());
+}
+
+#[test]
+fn test_trim_to_budget() {
+    use async_openai::types::{ChatCompletionRequestMessage, ChatCompletionRequestSystemMessage};
+
+    let retention = Retention::default();
+    let now = Utc::now();
+    // 4 tokens of overhead plus 10 of text each
+    let msg = |n: usize| {
+        ChatMessageThing::new_at(
+            ChatCompletionRequestMessage::System(ChatCompletionRequestSystemMessage {
+                content: format!("{n}").repeat(40),
+                role: async_openai::types::Role::System,
+                name: None,
+            }),
+            now,
+        )
+    };
+    let mut messages: VecDeque<_> = (1..=5).map(msg).collect();
+    retention.trim_to_budget(&mut messages, 50, now);
+    assert_eq!(messages.len(), 3);
+    assert!(messages[0].get_as_irc_format().unwrap().starts_with('3'));
+
+    // the newest message is kept even if it's over budget on its own
+    retention.trim_to_budget(&mut messages, 1, now);
+    assert_eq!(messages.len(), 1);
+    assert!(messages[0].get_as_irc_format().unwrap().starts_with('5'));
+}
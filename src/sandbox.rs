@@ -0,0 +1,258 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use wasmtime::{
+    component::{Component, Linker},
+    Config, Engine, Store, StoreLimits, StoreLimitsBuilder, Trap,
+};
+use wasmtime_wasi::{
+    bindings::Command,
+    pipe::{MemoryInputPipe, MemoryOutputPipe},
+    I32Exit, ResourceTable, WasiCtx, WasiCtxBuilder, WasiView,
+};
+
+use crate::config::get_config;
+
+/// Fuel for a single run.  Interpreters burn a fair bit of this just starting up.
+const FUEL_PER_RUN: u64 = 5_000_000_000;
+/// How often the engine's epoch is incremented
+const EPOCH_TICK: Duration = Duration::from_millis(100);
+/// How many epoch ticks a run may take before it's interrupted
+const EPOCH_TICKS_PER_RUN: u64 = 100;
+/// Largest linear memory a snippet may use
+const MAX_MEMORY_BYTES: usize = 256 * 1024 * 1024;
+/// How much of stdout and stderr is kept
+const MAX_OUTPUT_BYTES: usize = 64 * 1024;
+
+/// How to run snippets in one language, configured in `config.json` under `runners`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunnerConfig {
+    /// A WASI command component, like a build of CPython or QuickJS
+    pub component: PathBuf,
+    /// Arguments to run it with, starting with the program name.  Any `{code}` is replaced by
+    /// the snippet; if there isn't one, the snippet is given on stdin instead.
+    #[serde(default)]
+    pub args: Vec<String>,
+}
+
+impl RunnerConfig {
+    /// The arguments for running a snippet, and whether it goes on stdin
+    fn args_for(&self, code: &str) -> (Vec<String>, bool) {
+        let on_stdin = !self.args.iter().any(|a| a.contains("{code}"));
+        let args = self
+            .args
+            .iter()
+            .map(|a| a.replace("{code}", code))
+            .collect();
+        (args, on_stdin)
+    }
+}
+
+struct SandboxState {
+    ctx: WasiCtx,
+    table: ResourceTable,
+    limits: StoreLimits,
+}
+
+impl WasiView for SandboxState {
+    fn table(&mut self) -> &mut ResourceTable {
+        &mut self.table
+    }
+
+    fn ctx(&mut self) -> &mut WasiCtx {
+        &mut self.ctx
+    }
+}
+
+/// The engine shared by all runs, with fuel and epoch deadlines turned on
+fn sandbox_engine() -> &'static Engine {
+    static ENGINE: OnceLock<Engine> = OnceLock::new();
+    ENGINE.get_or_init(|| {
+        let mut config = Config::new();
+        config.wasm_component_model(true);
+        config.async_support(true);
+        config.consume_fuel(true);
+        config.epoch_interruption(true);
+        let engine = Engine::new(&config).expect("Failed to create wasmtime engine");
+
+        let ticker = engine.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(EPOCH_TICK);
+            ticker.increment_epoch();
+        });
+
+        engine
+    })
+}
+
+/// Compiles a component, or gets it from the cache
+///
+/// Interpreter components are big and slow to compile, so each one is only compiled once.
+async fn load_component(path: &Path) -> anyhow::Result<Component> {
+    static CACHE: Mutex<Option<HashMap<PathBuf, Component>>> = Mutex::new(None);
+    if let Some(component) = CACHE
+        .lock()
+        .expect("component cache lock is poisoned")
+        .get_or_insert_with(HashMap::new)
+        .get(path)
+    {
+        return Ok(component.clone());
+    }
+
+    let owned = path.to_path_buf();
+    let component =
+        tokio::task::spawn_blocking(move || Component::from_file(sandbox_engine(), owned))
+            .await??;
+    CACHE
+        .lock()
+        .expect("component cache lock is poisoned")
+        .get_or_insert_with(HashMap::new)
+        .insert(path.to_path_buf(), component.clone());
+    Ok(component)
+}
+
+/// What a snippet printed, and how it ended
+#[derive(Debug)]
+pub struct RunOutput {
+    pub stdout: String,
+    pub stderr: String,
+    /// Why the run failed, if it did
+    pub failure: Option<String>,
+}
+
+impl std::fmt::Display for RunOutput {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let stdout = self.stdout.trim_end();
+        match &self.failure {
+            None if stdout.is_empty() => write!(f, "(no output)"),
+            None => write!(f, "{stdout}"),
+            Some(failure) => {
+                if !stdout.is_empty() {
+                    writeln!(f, "{stdout}")?;
+                }
+                // the last line of stderr is usually the one that says what went wrong
+                match self.stderr.lines().rev().find(|l| !l.trim().is_empty()) {
+                    Some(line) => write!(f, "{failure}: {}", line.trim()),
+                    None => write!(f, "{failure}"),
+                }
+            }
+        }
+    }
+}
+
+/// Languages that `!run` knows about
+pub fn languages() -> Vec<String> {
+    let mut langs: Vec<String> = get_config()
+        .map(|c| c.runners.into_keys().collect())
+        .unwrap_or_default();
+    langs.sort();
+    langs
+}
+
+/// Runs a snippet of code in a sandbox, with no network or filesystem access
+///
+/// Runs are limited by fuel, memory, and wall-clock time.  Hitting any of these limits is
+/// reported in the output, rather than as an error.
+pub async fn run(lang: &str, code: &str) -> anyhow::Result<RunOutput> {
+    let Some(runner) = get_config()?.runners.remove(lang) else {
+        bail!(
+            "I don't know how to run {lang:?} (try one of: {})",
+            languages().join(", ")
+        );
+    };
+    let component = load_component(&runner.component).await?;
+    let engine = sandbox_engine();
+
+    let stdout = MemoryOutputPipe::new(MAX_OUTPUT_BYTES);
+    let stderr = MemoryOutputPipe::new(MAX_OUTPUT_BYTES);
+    let (args, on_stdin) = runner.args_for(code);
+    let mut builder = WasiCtxBuilder::new();
+    builder
+        .args(&args)
+        .stdout(stdout.clone())
+        .stderr(stderr.clone())
+        .allow_tcp(false)
+        .allow_udp(false)
+        .allow_ip_name_lookup(false);
+    if on_stdin {
+        builder.stdin(MemoryInputPipe::new(code.to_string()));
+    }
+    let state = SandboxState {
+        ctx: builder.build(),
+        table: ResourceTable::new(),
+        limits: StoreLimitsBuilder::new()
+            .memory_size(MAX_MEMORY_BYTES)
+            .build(),
+    };
+
+    let mut store = Store::new(engine, state);
+    store.limiter(|state| &mut state.limits);
+    store.set_fuel(FUEL_PER_RUN)?;
+    store.fuel_async_yield_interval(Some(10_000))?;
+    store.epoch_deadline_trap();
+    store.set_epoch_deadline(EPOCH_TICKS_PER_RUN);
+
+    let mut linker = Linker::new(engine);
+    wasmtime_wasi::add_to_linker_async(&mut linker)?;
+    let result = match Command::instantiate_async(&mut store, &component, &linker).await {
+        Ok((command, _)) => command.wasi_cli_run().call_run(&mut store).await,
+        Err(e) => Err(e),
+    };
+
+    let failure = match result {
+        Ok(Ok(())) => None,
+        Ok(Err(())) => Some("Failed".to_string()),
+        Err(e) => match (e.downcast_ref::<I32Exit>(), e.downcast_ref::<Trap>()) {
+            (Some(I32Exit(0)), _) => None,
+            (Some(I32Exit(code)), _) => Some(format!("Exited with status {code}")),
+            (_, Some(Trap::OutOfFuel)) => Some("Ran out of fuel".to_string()),
+            (_, Some(Trap::Interrupt)) => Some("Took too long".to_string()),
+            _ => Some(format!("Crashed ({e})")),
+        },
+    };
+    Ok(RunOutput {
+        stdout: String::from_utf8_lossy(&stdout.contents()).into_owned(),
+        stderr: String::from_utf8_lossy(&stderr.contents()).into_owned(),
+        failure,
+    })
+}
+
+#[test]
+fn test_run_output() {
+    let runner = RunnerConfig {
+        component: PathBuf::from("python.wasm"),
+        args: vec!["python".to_string(), "-c".to_string(), "{code}".to_string()],
+    };
+    assert_eq!(
+        runner.args_for("print(1)"),
+        (vec!["python".into(), "-c".into(), "print(1)".into()], false)
+    );
+    let runner = RunnerConfig {
+        component: PathBuf::from("qjs.wasm"),
+        args: vec!["qjs".to_string()],
+    };
+    assert_eq!(runner.args_for("1 + 1"), (vec!["qjs".into()], true));
+
+    let output = |stdout: &str, stderr: &str, failure: Option<&str>| RunOutput {
+        stdout: stdout.to_string(),
+        stderr: stderr.to_string(),
+        failure: failure.map(|f| f.to_string()),
+    };
+    assert_eq!(output("", "", None).to_string(), "(no output)");
+    assert_eq!(output("2\n", "", None).to_string(), "2");
+    assert_eq!(
+        output(
+            "partial\n",
+            "Traceback (most recent call last):\nZeroDivisionError: division by zero\n",
+            Some("Exited with status 1")
+        )
+        .to_string(),
+        "partial\nExited with status 1: ZeroDivisionError: division by zero"
+    );
+}
@@ -0,0 +1,184 @@
+use std::{collections::HashMap, fs::File, time::Duration};
+
+use anyhow::{bail, Context};
+
+use crate::{
+    config::get_config,
+    openai::{self, resolve_model_name},
+    plugins::PluginStatus,
+    uploaders, NumbatComponent,
+};
+
+/// The outcome of one part of the self-test
+pub struct Check {
+    pub name: &'static str,
+    /// What was found, or what went wrong
+    pub result: anyhow::Result<String>,
+}
+
+impl std::fmt::Display for Check {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.result {
+            Ok(found) => write!(f, "✓ {}: {found}", self.name),
+            Err(e) => write!(f, "✗ {}: {e:#}", self.name),
+        }
+    }
+}
+
+/// Checks everything the bot depends on, in the order it's worth fixing them
+///
+/// `plugins` is the status of each plugin, once they've all had a chance to load.
+pub async fn run(plugins: Vec<(String, PluginStatus)>) -> Vec<Check> {
+    vec![
+        Check {
+            name: "config",
+            result: check_config(),
+        },
+        Check {
+            name: "prompts",
+            result: check_prompts(),
+        },
+        Check {
+            name: "api",
+            result: check_api().await,
+        },
+        Check {
+            name: "upload",
+            result: check_uploaders().await,
+        },
+        Check {
+            name: "numbat",
+            result: NumbatComponent::new("numbat_component.wasm")
+                .await
+                .map(|_| "loads".to_string()),
+        },
+        Check {
+            name: "plugins",
+            result: check_plugins(&plugins),
+        },
+    ]
+}
+
+/// "5/6 checks passed (failed: api)"
+pub fn summary(checks: &[Check]) -> String {
+    let failed: Vec<&str> = checks
+        .iter()
+        .filter(|check| check.result.is_err())
+        .map(|check| check.name)
+        .collect();
+    let passed = checks.len() - failed.len();
+    if failed.is_empty() {
+        format!("All {passed} checks passed")
+    } else {
+        format!(
+            "{passed}/{} checks passed (failed: {})",
+            checks.len(),
+            failed.join(", ")
+        )
+    }
+}
+
+fn check_config() -> anyhow::Result<String> {
+    let config = get_config().context("config.json is invalid")?;
+    Ok(format!("{:?} backend", config.backend.kind))
+}
+
+fn check_prompts() -> anyhow::Result<String> {
+    let file = File::open("prompts.json").context("Failed to open prompts.json")?;
+    let prompts: HashMap<String, String> =
+        serde_json::from_reader(file).context("prompts.json is invalid")?;
+    if !prompts.contains_key("system") {
+        bail!("There's no system prompt");
+    }
+    Ok(format!("{} prompts", prompts.len()))
+}
+
+/// Makes sure the key works, and that the models people can pick are really there
+async fn check_api() -> anyhow::Result<String> {
+    let config = get_config()?;
+    let available = openai::list_models()
+        .await
+        .context("Failed to list models")?;
+
+    let mut wanted = vec!["gpt-4o".to_string()];
+    wanted.extend(config.models.everyone.iter().filter(|m| *m != "*").cloned());
+    let mut missing = Vec::new();
+    for name in wanted {
+        let model = resolve_model_name(config.backend.kind, &name)?;
+        if !available.contains(&model) && !missing.contains(&model) {
+            missing.push(model);
+        }
+    }
+    if !missing.is_empty() {
+        bail!(
+            "The key works, but these models are missing: {}",
+            missing.join(", ")
+        );
+    }
+    Ok(format!("key works, {} models available", available.len()))
+}
+
+/// Makes sure every paste service answers, without actually uploading anything
+async fn check_uploaders() -> anyhow::Result<String> {
+    let client = crate::http::client_builder()?
+        .timeout(Duration::from_secs(10))
+        .build()?;
+    let mut unreachable = Vec::new();
+    let uploaders = uploaders();
+    for uploader in &uploaders {
+        // any answer at all means it's up, even if it doesn't like being asked this way
+        if let Err(e) = client.head(&uploader.url).send().await {
+            unreachable.push(format!("{} ({e})", uploader.url));
+        }
+    }
+    if unreachable.len() == uploaders.len() {
+        bail!("Nothing is reachable: {}", unreachable.join(", "));
+    }
+    if unreachable.is_empty() {
+        Ok(format!("{} reachable", uploaders.len()))
+    } else {
+        Ok(format!("unreachable: {}", unreachable.join(", ")))
+    }
+}
+
+fn check_plugins(plugins: &[(String, PluginStatus)]) -> anyhow::Result<String> {
+    if plugins.is_empty() {
+        return Ok("none installed".to_string());
+    }
+    let broken: Vec<String> = plugins
+        .iter()
+        .filter(|(_, status)| !matches!(status, PluginStatus::Loaded | PluginStatus::Disabled))
+        .map(|(name, status)| format!("{name} ({status})"))
+        .collect();
+    if !broken.is_empty() {
+        bail!("{}", broken.join(", "));
+    }
+    let loaded = plugins
+        .iter()
+        .filter(|(_, status)| matches!(status, PluginStatus::Loaded))
+        .count();
+    Ok(format!("{loaded} loaded"))
+}
+
+#[test]
+fn test_summary() {
+    let check = |name, ok| Check {
+        name,
+        result: if ok {
+            Ok("fine".to_string())
+        } else {
+            Err(anyhow::anyhow!("broken"))
+        },
+    };
+    assert_eq!(
+        summary(&[check("config", true), check("api", true)]),
+        "All 2 checks passed"
+    );
+    let checks = [
+        check("config", true),
+        check("api", false),
+        check("numbat", false),
+    ];
+    assert_eq!(summary(&checks), "1/3 checks passed (failed: api, numbat)");
+    assert_eq!(checks[1].to_string(), "✗ api: broken");
+}
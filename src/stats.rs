@@ -0,0 +1,289 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Duration, Timelike, Utc};
+
+use crate::{retention::Retention, ChatMessageThing};
+
+/// Activity statistics for a channel, computed from its stored history
+#[derive(Debug)]
+pub struct ChannelStats {
+    /// Message counts per nick over the last day, busiest first
+    pub by_user_day: Vec<(String, usize)>,
+    /// Message counts per nick over the last week, busiest first
+    pub by_user_week: Vec<(String, usize)>,
+    /// Message counts per hour of the day (UTC), over the last week
+    pub by_hour: [usize; 24],
+    /// Number of links per domain, most linked first
+    pub domains: Vec<(String, usize)>,
+    /// How far back the stored history goes
+    pub history_span: Duration,
+}
+
+fn sorted_counts(counts: HashMap<String, usize>) -> Vec<(String, usize)> {
+    let mut counts: Vec<_> = counts.into_iter().collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    counts
+}
+
+impl ChannelStats {
+    pub fn compute<'a>(
+        messages: impl IntoIterator<Item = &'a ChatMessageThing>,
+        now: DateTime<Utc>,
+    ) -> Self {
+        let mut by_user_day = HashMap::new();
+        let mut by_user_week = HashMap::new();
+        let mut domains = HashMap::new();
+        let mut by_hour = [0; 24];
+        let mut oldest = now;
+
+        for cmt in messages {
+            oldest = oldest.min(cmt.date);
+            let age = now - cmt.date;
+            if age > Duration::days(7) {
+                continue;
+            }
+            let Some(nick) = cmt.get_sender() else {
+                continue;
+            };
+            *by_user_week.entry(nick.to_string()).or_insert(0) += 1;
+            if age <= Duration::days(1) {
+                *by_user_day.entry(nick.to_string()).or_insert(0) += 1;
+            }
+            by_hour[cmt.date.hour() as usize] += 1;
+
+            let text = cmt.get_as_irc_format().unwrap_or_default();
+            for word in text.split_ascii_whitespace() {
+                if !(word.starts_with("https://") || word.starts_with("http://")) {
+                    continue;
+                }
+                if let Some(host) = url::Url::parse(word).ok().and_then(|u| {
+                    u.host_str()
+                        .map(|h| h.trim_start_matches("www.").to_string())
+                }) {
+                    *domains.entry(host).or_insert(0) += 1;
+                }
+            }
+        }
+
+        Self {
+            by_user_day: sorted_counts(by_user_day),
+            by_user_week: sorted_counts(by_user_week),
+            by_hour,
+            domains: sorted_counts(domains),
+            history_span: now - oldest,
+        }
+    }
+
+    /// The busiest hours of the day (UTC), busiest first
+    pub fn busiest_hours(&self, n: usize) -> Vec<(u32, usize)> {
+        let mut hours: Vec<(u32, usize)> = self
+            .by_hour
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(hour, count)| (hour as u32, *count))
+            .collect();
+        hours.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        hours.truncate(n);
+        hours
+    }
+
+    fn span_note(&self) -> String {
+        if self.history_span < Duration::days(7) {
+            format!(" (history only covers {}h)", self.history_span.num_hours())
+        } else {
+            String::new()
+        }
+    }
+
+    /// A single line summary, short enough for IRC
+    pub fn summary(&self) -> String {
+        let list = |counts: &[(String, usize)]| {
+            counts
+                .iter()
+                .take(5)
+                .map(|(name, count)| format!("{name} {count}"))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        let hours = self
+            .busiest_hours(3)
+            .iter()
+            .map(|(hour, _)| format!("{hour:02}h"))
+            .collect::<Vec<_>>()
+            .join(" ");
+        format!(
+            "24h: {} | 7d{}: {} | busiest (UTC): {} | links: {}",
+            list(&self.by_user_day),
+            self.span_note(),
+            list(&self.by_user_week),
+            hours,
+            list(&self.domains),
+        )
+    }
+
+    /// A full plain-text report, meant to be uploaded
+    pub fn report(&self) -> String {
+        let mut s = String::new();
+        s.push_str("Messages in the last 24 hours:\n");
+        for (name, count) in &self.by_user_day {
+            s.push_str(&format!("  {name:<20} {count}\n"));
+        }
+        s.push_str(&format!(
+            "\nMessages in the last 7 days{}:\n",
+            self.span_note()
+        ));
+        for (name, count) in &self.by_user_week {
+            s.push_str(&format!("  {name:<20} {count}\n"));
+        }
+        s.push_str("\nMessages by hour (UTC):\n");
+        let max = self.by_hour.iter().copied().max().unwrap_or(0).max(1);
+        for (hour, count) in self.by_hour.iter().enumerate() {
+            let bar = "#".repeat(count * 40 / max);
+            s.push_str(&format!("  {hour:02}h {count:>5} {bar}\n"));
+        }
+        s.push_str("\nMost linked domains:\n");
+        for (domain, count) in &self.domains {
+            s.push_str(&format!("  {domain:<30} {count}\n"));
+        }
+        s
+    }
+}
+
+/// What's currently in a channel's context, as sent to the model
+#[derive(Debug)]
+pub struct ContextInfo {
+    pub messages: usize,
+    pub estimated_tokens: usize,
+    /// Messages with images attached
+    pub with_images: usize,
+    /// Images that are recent enough to still be sent to the model
+    pub recent_images: usize,
+    pub oldest: Option<Duration>,
+    /// The channel's token budget, if it has one
+    pub budget: Option<usize>,
+}
+
+impl ContextInfo {
+    pub fn compute<'a>(
+        messages: impl IntoIterator<Item = &'a ChatMessageThing>,
+        retention: &Retention,
+        now: DateTime<Utc>,
+    ) -> Self {
+        let mut info = Self {
+            messages: 0,
+            estimated_tokens: 0,
+            with_images: 0,
+            recent_images: 0,
+            oldest: None,
+            budget: None,
+        };
+        for cmt in messages {
+            info.messages += 1;
+            info.estimated_tokens += cmt.estimate_tokens(retention, now);
+            let images = cmt.image_count();
+            if images > 0 {
+                info.with_images += 1;
+                if retention.sends_images(cmt.date, now) {
+                    info.recent_images += images;
+                }
+            }
+            let age = now - cmt.date;
+            info.oldest = Some(info.oldest.map_or(age, |oldest| oldest.max(age)));
+        }
+        info
+    }
+}
+
+impl std::fmt::Display for ContextInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.messages == 0 {
+            return write!(f, "The context is empty");
+        }
+        write!(
+            f,
+            "{} messages, ~{} tokens",
+            self.messages, self.estimated_tokens
+        )?;
+        if let Some(budget) = self.budget {
+            write!(
+                f,
+                " of a {budget} budget ({}%)",
+                self.estimated_tokens * 100 / budget.max(1)
+            )?;
+        }
+        write!(
+            f,
+            ", {} with images ({} images still sent to the model)",
+            self.with_images, self.recent_images
+        )?;
+        if let Some(oldest) = self.oldest {
+            write!(
+                f,
+                ", oldest is {}h{:02}m old",
+                oldest.num_hours(),
+                oldest.num_minutes() % 60
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_channel_stats() {
+    use async_openai::types::{
+        ChatCompletionRequestMessage, ChatCompletionRequestUserMessage,
+        ChatCompletionRequestUserMessageContent,
+    };
+
+    let now = Utc::now();
+    let msg = |nick: &str, text: &str, hours_ago: i64| ChatMessageThing {
+        date: now - Duration::hours(hours_ago),
+        msg: ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
+            content: ChatCompletionRequestUserMessageContent::Text(format!("<{nick}> {text}")),
+            role: async_openai::types::Role::User,
+            name: Some(nick.to_string()),
+        }),
+        archived_images: Vec::new(),
+        feedback: Vec::new(),
+        model: None,
+    };
+    let messages = vec![
+        msg("agrif", "look https://www.github.com/foo", 30),
+        msg(
+            "achin",
+            "hi https://github.com/bar and https://i.imgur.com/x.png",
+            2,
+        ),
+        msg("achin", "hello", 1),
+        msg("agrif", "old", 24 * 8),
+    ];
+
+    let stats = ChannelStats::compute(&messages, now);
+    assert_eq!(stats.by_user_day, vec![("achin".to_string(), 2)]);
+    assert_eq!(
+        stats.by_user_week,
+        vec![("achin".to_string(), 2), ("agrif".to_string(), 1)]
+    );
+    assert_eq!(stats.domains[0], ("github.com".to_string(), 2));
+    assert_eq!(stats.by_hour.iter().sum::<usize>(), 3);
+    assert!(stats
+        .summary()
+        .starts_with("24h: achin 2 | 7d: achin 2, agrif 1"));
+
+    let info = ContextInfo::compute(&messages[..3], &Retention::default(), now);
+    assert_eq!(info.messages, 3);
+    assert_eq!(info.with_images, 0);
+    assert!(info.estimated_tokens > 0);
+    assert!(info.to_string().ends_with("oldest is 30h00m old"));
+    let budgeted = ContextInfo {
+        budget: Some(info.estimated_tokens * 2),
+        ..info
+    };
+    assert!(budgeted.to_string().contains(" tokens of a "));
+    assert!(budgeted.to_string().contains(" budget (50%)"));
+    assert_eq!(
+        ContextInfo::compute(std::iter::empty(), &Retention::default(), now).to_string(),
+        "The context is empty"
+    );
+}
@@ -0,0 +1,172 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use anyhow::bail;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::ChatMessageThing;
+
+/// Threads nobody has talked in for this long are ended on their own
+const IDLE_HOURS: i64 = 6;
+
+/// A side conversation with the bot, kept apart from the channel's own
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatThread {
+    pub messages: VecDeque<ChatMessageThing>,
+    pub last_active: DateTime<Utc>,
+}
+
+/// A channel's threads, and who's talking in which
+///
+/// Everyone starts out in the channel's own conversation, and stays there until they start or
+/// pick a thread.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Threads {
+    threads: BTreeMap<String, ChatThread>,
+    /// The thread each (lowercased) nick is in
+    current: HashMap<String, String>,
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 32
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+}
+
+impl Threads {
+    /// Starts a new thread, and puts `nick` in it
+    pub fn start(&mut self, nick: &str, name: &str, now: DateTime<Utc>) -> anyhow::Result<()> {
+        self.expire(now);
+        if !is_valid_name(name) {
+            bail!("Thread names are letters, numbers, - and _");
+        }
+        if self.threads.contains_key(name) {
+            bail!("There's already a thread called {name} (join it with !thread use {name})");
+        }
+        self.threads.insert(
+            name.to_string(),
+            ChatThread {
+                messages: VecDeque::new(),
+                last_active: now,
+            },
+        );
+        self.current.insert(nick.to_lowercase(), name.to_string());
+        Ok(())
+    }
+
+    /// Puts `nick` in an existing thread
+    pub fn join(&mut self, nick: &str, name: &str, now: DateTime<Utc>) -> anyhow::Result<()> {
+        self.expire(now);
+        let Some(thread) = self.threads.get_mut(name) else {
+            bail!("There's no thread called {name}");
+        };
+        thread.last_active = now;
+        self.current.insert(nick.to_lowercase(), name.to_string());
+        Ok(())
+    }
+
+    /// Takes `nick` back to the channel's conversation, returning the thread they were in
+    pub fn leave(&mut self, nick: &str) -> Option<String> {
+        self.current.remove(&nick.to_lowercase())
+    }
+
+    /// Ends the thread `nick` is in, for everyone in it
+    pub fn end(&mut self, nick: &str) -> Option<String> {
+        let name = self.current.get(&nick.to_lowercase())?.clone();
+        self.remove(&name);
+        Some(name)
+    }
+
+    fn remove(&mut self, name: &str) {
+        self.threads.remove(name);
+        self.current.retain(|_, thread| thread != name);
+    }
+
+    /// Ends the threads that have been idle too long, returning their names
+    pub fn expire(&mut self, now: DateTime<Utc>) -> Vec<String> {
+        let idle: Vec<String> = self
+            .threads
+            .iter()
+            .filter(|(_, thread)| now - thread.last_active > Duration::hours(IDLE_HOURS))
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in &idle {
+            self.remove(name);
+        }
+        idle
+    }
+
+    /// The thread `nick` is talking in, if they're in one that's still going
+    pub fn current(&mut self, nick: &str, now: DateTime<Utc>) -> Option<String> {
+        self.expire(now);
+        self.current.get(&nick.to_lowercase()).cloned()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&ChatThread> {
+        self.threads.get(name)
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut ChatThread> {
+        self.threads.get_mut(name)
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut ChatThread> {
+        self.threads.values_mut()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.threads.is_empty()
+    }
+}
+
+impl std::fmt::Display for Threads {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.threads.is_empty() {
+            return write!(f, "No threads");
+        }
+        let threads: Vec<String> = self
+            .threads
+            .iter()
+            .map(|(name, thread)| {
+                let people = self.current.values().filter(|t| *t == name).count();
+                format!(
+                    "{name} ({} messages, {people} in it)",
+                    thread.messages.len()
+                )
+            })
+            .collect();
+        write!(f, "{}", threads.join(", "))
+    }
+}
+
+#[test]
+fn test_threads() {
+    let now = Utc::now();
+    let mut threads = Threads::default();
+    assert_eq!(threads.current("achin", now), None);
+
+    threads.start("achin", "rust", now).unwrap();
+    assert!(threads.start("agrif", "rust", now).is_err());
+    assert!(threads.start("agrif", "no spaces", now).is_err());
+    assert!(threads.join("agrif", "dinner", now).is_err());
+    threads.join("Agrif", "rust", now).unwrap();
+    assert_eq!(threads.current("agrif", now).as_deref(), Some("rust"));
+    assert_eq!(threads.to_string(), "rust (0 messages, 2 in it)");
+
+    assert_eq!(threads.leave("agrif").as_deref(), Some("rust"));
+    assert_eq!(threads.current("agrif", now), None);
+    assert_eq!(threads.current("achin", now).as_deref(), Some("rust"));
+
+    threads.start("agrif", "dinner", now).unwrap();
+    assert_eq!(threads.end("agrif").as_deref(), Some("dinner"));
+    assert_eq!(threads.end("agrif"), None);
+    assert_eq!(threads.current("achin", now).as_deref(), Some("rust"));
+
+    // idle threads end on their own
+    let later = now + Duration::hours(IDLE_HOURS) + Duration::minutes(1);
+    assert_eq!(threads.current("achin", later), None);
+    assert!(threads.is_empty());
+}
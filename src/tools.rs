@@ -0,0 +1,88 @@
+use anyhow::{bail, Context};
+use async_openai::types::{ChatCompletionTool, ChatCompletionToolType, FunctionObject};
+use schemars::{schema_for, JsonSchema};
+use serde::de::DeserializeOwned;
+
+use crate::{
+    fx::{self, FxInput},
+    localtime::{self, TimeInput},
+    wttr::{self, WeatherInput},
+};
+
+/// Most rounds of tool calls while answering one request.  After that the model has to answer
+/// with what it's got.
+pub const MAX_ROUNDS: usize = 4;
+
+fn tool<T: JsonSchema>(name: &str, description: &str) -> ChatCompletionTool {
+    ChatCompletionTool {
+        r#type: ChatCompletionToolType::Function,
+        function: FunctionObject {
+            name: name.to_string(),
+            description: Some(description.to_string()),
+            parameters: Some(serde_json::to_value(schema_for!(T)).expect("schemas are JSON")),
+        },
+    }
+}
+
+/// The tools the model is offered in channels with the tools feature on
+pub fn definitions() -> Vec<ChatCompletionTool> {
+    vec![
+        tool::<WeatherInput>(
+            "get_weather",
+            "Gets the current weather and the forecast for a city",
+        ),
+        tool::<TimeInput>(
+            "get_time",
+            "Gets the local time somewhere, given a place or a timezone",
+        ),
+        tool::<FxInput>(
+            "convert_currency",
+            "Converts an amount of money to another currency at today's reference rate",
+        ),
+    ]
+}
+
+fn parse<T: DeserializeOwned>(name: &str, arguments: &str) -> anyhow::Result<T> {
+    serde_json::from_str(arguments).with_context(|| format!("Bad arguments for {name}"))
+}
+
+async fn run(name: &str, arguments: &str) -> anyhow::Result<String> {
+    match name {
+        "get_weather" => wttr::weather_for_tool(&parse(name, arguments)?).await,
+        "get_time" => localtime::time_for_tool(&parse(name, arguments)?).await,
+        "convert_currency" => fx::fx_for_tool(&parse(name, arguments)?).await,
+        _ => bail!("There's no tool called {name}"),
+    }
+}
+
+/// Runs a tool the model called, returning what to tell it
+///
+/// Errors are passed back to the model too, so it can tell whoever asked what went wrong.
+pub async fn call(name: &str, arguments: &str) -> String {
+    match run(name, arguments).await {
+        Ok(output) => output,
+        Err(e) => {
+            println!("The {name} tool failed with {arguments}: {e:#}");
+            format!("Error: {e:#}")
+        }
+    }
+}
+
+#[test]
+fn test_definitions() {
+    for tool in definitions() {
+        let parameters = tool.function.parameters.unwrap();
+        assert_eq!(parameters["type"], "object", "{}", tool.function.name);
+    }
+}
+
+#[tokio::test]
+async fn test_bad_calls() {
+    assert_eq!(
+        call("launch_missiles", "{}").await,
+        "Error: There's no tool called launch_missiles"
+    );
+    assert!(call("get_weather", "not json")
+        .await
+        .starts_with("Error: Bad arguments for get_weather"));
+}
@@ -0,0 +1,145 @@
+use std::sync::OnceLock;
+
+use async_openai::types::ChatCompletionRequestMessage;
+use chrono::{DateTime, Utc};
+use regex::Regex;
+
+use crate::{images::escape_html, ChatMessageThing};
+
+/// Longest stretch of conversation a transcript can cover
+pub const MAX_HOURS: u32 = 48;
+
+fn link_regex() -> &'static Regex {
+    static REGEX: OnceLock<Regex> = OnceLock::new();
+    REGEX.get_or_init(|| Regex::new(r#"https?://[^\s<>"]+"#).expect("the link regex is valid"))
+}
+
+/// Escapes a line for HTML, and turns the links in it into actual links
+fn linkify(text: &str) -> String {
+    let mut html = String::new();
+    let mut last = 0;
+    for link in link_regex().find_iter(text) {
+        html.push_str(&escape_html(&text[last..link.start()]));
+        let url = escape_html(link.as_str());
+        html.push_str(&format!("<a href=\"{url}\">{url}</a>"));
+        last = link.end();
+    }
+    html.push_str(&escape_html(&text[last..]));
+    html
+}
+
+/// Who said a message, and what they said
+fn speaker<'a>(msg: &'a ChatMessageThing, botname: &'a str) -> Option<(&'a str, &'a str)> {
+    let text = msg.get_as_irc_format()?;
+    match &msg.msg {
+        ChatCompletionRequestMessage::User(_) => {
+            // user messages are stored as "<nick> text"
+            let (nick, text) = text.strip_prefix('<')?.split_once("> ")?;
+            Some((nick, text))
+        }
+        ChatCompletionRequestMessage::Assistant(_) => Some((botname, text)),
+        // summaries and the like, which aren't anyone's
+        ChatCompletionRequestMessage::System(_) => Some(("", text)),
+        _ => None,
+    }
+}
+
+/// A page with a stretch of a channel's conversation on it, for uploading with `upload_content`
+pub fn render(channel: &str, messages: &[ChatMessageThing], botname: &str) -> String {
+    let mut lines = String::new();
+    for msg in messages {
+        let Some((nick, text)) = speaker(msg, botname) else {
+            continue;
+        };
+        let class = match nick {
+            "" => "note",
+            nick if nick == botname => "bot",
+            _ => "user",
+        };
+        lines.push_str(&format!(
+            "<div class=\"{class}\"><span class=\"time\">{}</span> \
+             <span class=\"nick\">{}</span> <span class=\"text\">{}</span>",
+            msg.date.format("%H:%M"),
+            escape_html(nick),
+            linkify(text)
+        ));
+        for image in &msg.archived_images {
+            let url = escape_html(&image.url);
+            let preview = escape_html(image.thumbnail.as_ref().unwrap_or(&image.url));
+            lines.push_str(&format!(
+                "<br><a href=\"{url}\"><img src=\"{preview}\" alt=\"an image\"></a>"
+            ));
+        }
+        lines.push_str("</div>\n");
+    }
+    let channel = escape_html(channel);
+    let date = messages
+        .first()
+        .map(|msg| msg.date)
+        .unwrap_or_else(Utc::now)
+        .format("%Y-%m-%d");
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+         <title>{channel}, {date}</title>\n\
+         <style>body {{ max-width: 60em; margin: 1em auto; font-family: sans-serif; }} \
+         div {{ margin: 0.3em 0; white-space: pre-wrap; }} .time {{ color: #888; }} \
+         .nick {{ font-weight: bold; }} .bot .nick {{ color: #a040a0; }} \
+         .note {{ font-style: italic; color: #666; }} img {{ max-width: 256px; }}</style>\n\
+         </head>\n<body>\n<h1>{channel}, {date}</h1>\n{lines}</body>\n</html>\n"
+    )
+}
+
+/// The messages from the last `hours` hours
+pub fn recent<'a>(
+    messages: impl IntoIterator<Item = &'a ChatMessageThing>,
+    hours: u32,
+    now: DateTime<Utc>,
+) -> Vec<ChatMessageThing> {
+    let since = now - chrono::Duration::hours(hours.into());
+    messages
+        .into_iter()
+        .filter(|msg| msg.date >= since)
+        .cloned()
+        .collect()
+}
+
+#[test]
+fn test_render_transcript() {
+    use async_openai::types::{
+        ChatCompletionRequestAssistantMessage, ChatCompletionRequestUserMessage,
+    };
+
+    let now: DateTime<Utc> = "2024-03-01T12:00:00Z".parse().unwrap();
+    let user = ChatMessageThing::new_at(
+        ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
+            content: "<achin> draw a <cat> https://example.com/a?b&c".into(),
+            role: async_openai::types::Role::User,
+            name: Some("achin".to_string()),
+        }),
+        now - chrono::Duration::minutes(5),
+    );
+    #[allow(deprecated)]
+    let bot = ChatMessageThing::new_at(
+        ChatCompletionRequestMessage::Assistant(ChatCompletionRequestAssistantMessage {
+            content: Some("Here you go".to_string()),
+            role: async_openai::types::Role::Assistant,
+            name: None,
+            tool_calls: None,
+            function_call: None,
+        }),
+        now - chrono::Duration::minutes(4),
+    );
+    let old = ChatMessageThing {
+        date: now - chrono::Duration::hours(3),
+        ..user.clone()
+    };
+
+    let messages = recent(&[old, user, bot], 1, now);
+    assert_eq!(messages.len(), 2);
+    let html = render("#test", &messages, "anna");
+    assert!(html.contains("<title>#test, 2024-03-01</title>"));
+    assert!(html.contains("<span class=\"nick\">achin</span>"));
+    assert!(html.contains("draw a &lt;cat&gt;"));
+    assert!(html.contains("<a href=\"https://example.com/a?b&amp;c\">"));
+    assert!(html.contains("<div class=\"bot\"><span class=\"time\">11:56</span>"));
+}
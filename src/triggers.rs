@@ -0,0 +1,127 @@
+use chrono::{DateTime, Duration, Utc};
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+
+/// A pattern that makes the bot consider interjecting when someone in the channel says something
+/// that matches it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "SavedTrigger", into = "SavedTrigger")]
+pub struct InterjectionTrigger {
+    /// A case-insensitive regex.  Plain keywords work too.
+    pub pattern: String,
+    /// Minimum time between two firings of this trigger
+    pub cooldown_minutes: i64,
+    pub last_fired: Option<DateTime<Utc>>,
+    /// The compiled pattern, or None if a saved pattern no longer compiles
+    regex: Option<Regex>,
+}
+
+/// How a trigger is saved; the pattern is compiled again when it's loaded
+#[derive(Serialize, Deserialize)]
+struct SavedTrigger {
+    pattern: String,
+    cooldown_minutes: i64,
+    #[serde(default)]
+    last_fired: Option<DateTime<Utc>>,
+}
+
+impl From<SavedTrigger> for InterjectionTrigger {
+    fn from(saved: SavedTrigger) -> Self {
+        let regex = match compile(&saved.pattern) {
+            Ok(regex) => Some(regex),
+            Err(e) => {
+                println!("Trigger /{}/ doesn't compile: {e}", saved.pattern);
+                None
+            }
+        };
+        Self {
+            pattern: saved.pattern,
+            cooldown_minutes: saved.cooldown_minutes,
+            last_fired: saved.last_fired,
+            regex,
+        }
+    }
+}
+
+impl From<InterjectionTrigger> for SavedTrigger {
+    fn from(trigger: InterjectionTrigger) -> Self {
+        Self {
+            pattern: trigger.pattern,
+            cooldown_minutes: trigger.cooldown_minutes,
+            last_fired: trigger.last_fired,
+        }
+    }
+}
+
+fn compile(pattern: &str) -> Result<Regex, regex::Error> {
+    RegexBuilder::new(pattern).case_insensitive(true).build()
+}
+
+impl InterjectionTrigger {
+    pub fn new(pattern: &str, cooldown_minutes: i64) -> anyhow::Result<Self> {
+        let regex = compile(pattern)?;
+        Ok(Self {
+            pattern: pattern.to_string(),
+            cooldown_minutes: cooldown_minutes.max(0),
+            last_fired: None,
+            regex: Some(regex),
+        })
+    }
+
+    pub fn is_cooling_down(&self, now: DateTime<Utc>) -> bool {
+        let Some(last) = self.last_fired else {
+            return false;
+        };
+        // a cooldown too long to represent never ends
+        match Duration::try_minutes(self.cooldown_minutes).and_then(|d| last.checked_add_signed(d))
+        {
+            Some(until) => now < until,
+            None => true,
+        }
+    }
+
+    /// Checks a message against this trigger
+    ///
+    /// Returns true if it matches and the trigger isn't cooling down, and starts the cooldown.
+    pub fn fire(&mut self, msg: &str, now: DateTime<Utc>) -> bool {
+        if self.is_cooling_down(now) {
+            return false;
+        }
+        let Some(re) = &self.regex else {
+            return false;
+        };
+        if re.is_match(msg) {
+            self.last_fired = Some(now);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[test]
+fn test_trigger_cooldown() {
+    assert!(InterjectionTrigger::new("minecraft (", 10).is_err());
+
+    let mut trigger = InterjectionTrigger::new(r"minecraft\s+render", 10).unwrap();
+    let now = Utc::now();
+    assert!(!trigger.fire("anyone seen my cat?", now));
+    assert!(trigger.fire("how do I get a Minecraft  render of my base?", now));
+    // cooling down
+    assert!(!trigger.fire("minecraft render", now + Duration::minutes(9)));
+    assert!(trigger.fire("minecraft render", now + Duration::minutes(10)));
+
+    // huge cooldowns don't overflow
+    let mut trigger = InterjectionTrigger::new("minecraft", i64::MAX).unwrap();
+    assert!(trigger.fire("minecraft", now));
+    assert!(!trigger.fire("minecraft", now + Duration::days(365)));
+}
+
+#[test]
+fn test_trigger_reload() {
+    let trigger = InterjectionTrigger::new("minecraft", 10).unwrap();
+    let saved = serde_json::to_value(&trigger).unwrap();
+    assert_eq!(saved["pattern"], "minecraft");
+    let mut trigger: InterjectionTrigger = serde_json::from_value(saved).unwrap();
+    assert!(trigger.fire("MINECRAFT", Utc::now()));
+}
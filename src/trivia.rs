@@ -0,0 +1,323 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use anyhow::{bail, Context};
+use async_openai::types::{
+    ChatCompletionRequestMessage, ChatCompletionRequestSystemMessage,
+    ChatCompletionRequestUserMessage, ChatCompletionRequestUserMessageContent,
+};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+
+use crate::{get_prompt, openai};
+
+/// How many questions are in a game
+pub const ROUNDS: usize = 5;
+
+/// How long the channel gets to answer each question
+pub const ANSWER_SECS: u64 = 45;
+
+/// How long after a game ends before another can be started in the same channel
+const COOLDOWN_MINUTES: i64 = 10;
+
+/// The game running in each channel
+static GAMES: Mutex<Option<HashMap<String, Game>>> = Mutex::new(None);
+
+/// When the last game in each channel ended, for the cooldown
+static LAST_GAME: Mutex<Option<HashMap<String, DateTime<Utc>>>> = Mutex::new(None);
+
+/// Tells games apart, so a game that was stopped doesn't carry on when a new one starts
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+struct Game {
+    id: u64,
+    topic: Option<String>,
+    /// Whoever started the game, who can also stop it
+    started_by: String,
+    /// Questions that have already been asked, so they don't come up again
+    asked: Vec<String>,
+    /// The question that's waiting for an answer, if there is one
+    current: Option<Question>,
+    /// Points for each nick, keyed by the lowercased nick
+    scores: HashMap<String, (String, u32)>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Question {
+    question: String,
+    answer: String,
+}
+
+/// Starts a game in a channel, returning the id to pass to `next_question`
+pub fn start(
+    channel: &str,
+    nick: &str,
+    topic: Option<String>,
+    now: DateTime<Utc>,
+) -> anyhow::Result<u64> {
+    let last = LAST_GAME
+        .lock()
+        .expect("last game lock is poisoned")
+        .as_ref()
+        .and_then(|last| last.get(channel).copied());
+    if let Some(last) = last {
+        let wait = last + chrono::Duration::minutes(COOLDOWN_MINUTES) - now;
+        if wait > chrono::Duration::zero() {
+            bail!(
+                "Another game can be started in {} minutes",
+                wait.num_minutes() + 1
+            );
+        }
+    }
+
+    let mut games = GAMES.lock().expect("games lock is poisoned");
+    let games = games.get_or_insert_with(HashMap::new);
+    if games.contains_key(channel) {
+        bail!("There's already a game running here");
+    }
+    let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+    games.insert(
+        channel.to_string(),
+        Game {
+            id,
+            topic,
+            started_by: nick.to_string(),
+            asked: Vec::new(),
+            current: None,
+            scores: HashMap::new(),
+        },
+    );
+    Ok(id)
+}
+
+/// Comes up with the next question of a game, and returns it to be announced
+///
+/// Returns None once the game is over, or if it was stopped.
+pub async fn next_question(channel: &str, id: u64) -> anyhow::Result<Option<String>> {
+    let (topic, asked) = {
+        let games = GAMES.lock().expect("games lock is poisoned");
+        match games.as_ref().and_then(|games| games.get(channel)) {
+            Some(game) if game.id == id && game.asked.len() < ROUNDS => {
+                (game.topic.clone(), game.asked.clone())
+            }
+            _ => return Ok(None),
+        }
+    };
+    let question = generate_question(topic.as_deref(), &asked).await?;
+
+    let mut games = GAMES.lock().expect("games lock is poisoned");
+    let Some(game) = games
+        .as_mut()
+        .and_then(|games| games.get_mut(channel))
+        .filter(|game| game.id == id)
+    else {
+        return Ok(None);
+    };
+    game.asked.push(question.question.clone());
+    let announcement = format!(
+        "Question {}/{ROUNDS}: {}",
+        game.asked.len(),
+        question.question
+    );
+    game.current = Some(question);
+    Ok(Some(announcement))
+}
+
+/// Whether a game's question is still waiting for someone to get it
+pub fn is_waiting(channel: &str, id: u64) -> bool {
+    let games = GAMES.lock().expect("games lock is poisoned");
+    games
+        .as_ref()
+        .and_then(|games| games.get(channel))
+        .is_some_and(|game| game.id == id && game.current.is_some())
+}
+
+/// Checks whether a channel message answers the current question
+///
+/// Returns the announcement if it's right.
+pub fn guess(channel: &str, nick: &str, msg: &str) -> Option<String> {
+    let mut games = GAMES.lock().expect("games lock is poisoned");
+    let game = games.as_mut()?.get_mut(channel)?;
+    if !is_correct(msg, &game.current.as_ref()?.answer) {
+        return None;
+    }
+    let answer = game.current.take()?.answer;
+    let (_, score) = game
+        .scores
+        .entry(nick.to_lowercase())
+        .or_insert_with(|| (nick.to_string(), 0));
+    *score += 1;
+    Some(format!("{nick} got it: {answer} ({nick} has {score})"))
+}
+
+/// Gives up on the current question, returning the announcement of what the answer was
+pub fn expire(channel: &str, id: u64) -> Option<String> {
+    let mut games = GAMES.lock().expect("games lock is poisoned");
+    let game = games.as_mut()?.get_mut(channel)?;
+    if game.id != id {
+        return None;
+    }
+    let question = game.current.take()?;
+    Some(format!("Time's up!  The answer was: {}", question.answer))
+}
+
+/// Whether `nick` started the game running in a channel
+pub fn started_by(channel: &str, nick: &str) -> bool {
+    let games = GAMES.lock().expect("games lock is poisoned");
+    games
+        .as_ref()
+        .and_then(|games| games.get(channel))
+        .is_some_and(|game| game.started_by.eq_ignore_ascii_case(nick))
+}
+
+/// Ends the game in a channel, returning the final scores to announce
+///
+/// With an `id`, only that game is ended, rather than whichever one is running.
+pub fn finish(channel: &str, id: Option<u64>, now: DateTime<Utc>) -> Option<String> {
+    let game = {
+        let mut games = GAMES.lock().expect("games lock is poisoned");
+        let games = games.as_mut()?;
+        if id.is_some_and(|id| games.get(channel).is_some_and(|game| game.id != id)) {
+            return None;
+        }
+        games.remove(channel)?
+    };
+    LAST_GAME
+        .lock()
+        .expect("last game lock is poisoned")
+        .get_or_insert_with(HashMap::new)
+        .insert(channel.to_string(), now);
+
+    let mut scores: Vec<(String, u32)> = game.scores.into_values().collect();
+    scores.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    let Some(&(_, best)) = scores.first() else {
+        return Some("Game over!  Nobody got any of them".to_string());
+    };
+    let winners: Vec<&str> = scores
+        .iter()
+        .filter(|(_, score)| *score == best)
+        .map(|(nick, _)| nick.as_str())
+        .collect();
+    let table: Vec<String> = scores
+        .iter()
+        .map(|(nick, score)| format!("{nick} {score}"))
+        .collect();
+    let winner = match winners.as_slice() {
+        [winner] => format!("{winner} wins"),
+        _ => format!("it's a tie between {}", winners.join(" and ")),
+    };
+    Some(format!("Game over, {winner}!  ({})", table.join(", ")))
+}
+
+async fn generate_question(topic: Option<&str>, asked: &[String]) -> anyhow::Result<Question> {
+    let instruction = get_prompt("trivia").unwrap_or_else(|_| {
+        "You're hosting a trivia game in an IRC channel.  Write one trivia question with a short, \
+         unambiguous answer (a name, a number, or a few words), that's neither too easy nor too \
+         obscure.  Reply with only JSON like {\"question\": \"...\", \"answer\": \"...\"}"
+            .to_string()
+    });
+    let mut request = match topic {
+        Some(topic) => format!("The topic is: {topic}"),
+        None => "Any topic is fine.".to_string(),
+    };
+    if !asked.is_empty() {
+        request.push_str("\n\nDon't repeat any of these questions:\n");
+        request.push_str(&asked.join("\n"));
+    }
+    let messages = vec![
+        ChatCompletionRequestMessage::System(ChatCompletionRequestSystemMessage {
+            content: instruction,
+            role: async_openai::types::Role::System,
+            name: None,
+        }),
+        ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
+            content: ChatCompletionRequestUserMessageContent::Text(request),
+            role: async_openai::types::Role::User,
+            name: None,
+        }),
+    ];
+    let resp = openai::get_chat(messages, Some("gpt-4o"), Some(1.0)).await?;
+    let content = resp
+        .first()
+        .and_then(|m| m.content.as_deref())
+        .context("No question in response")?;
+    // the model likes to put JSON in a code block, even when asked not to
+    let json = content
+        .trim()
+        .trim_start_matches("```json")
+        .trim_matches('`')
+        .trim();
+    let question: Question =
+        serde_json::from_str(json).with_context(|| format!("Unexpected reply: {content}"))?;
+    if question.question.trim().is_empty() || normalize(&question.answer).is_empty() {
+        bail!("The question or its answer is empty");
+    }
+    Ok(question)
+}
+
+/// Lowercases, drops punctuation, and drops a leading "the", "a" or "an"
+fn normalize(s: &str) -> String {
+    let s: String = s
+        .to_lowercase()
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { ' ' })
+        .collect();
+    let mut words: Vec<&str> = s.split_whitespace().collect();
+    if words.len() > 1 && matches!(words[0], "the" | "a" | "an") {
+        words.remove(0);
+    }
+    words.join(" ")
+}
+
+/// Number of single character edits to get from one string to another
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut row = vec![i + 1];
+        for (j, &cb) in b.iter().enumerate() {
+            let substitution = prev[j] + usize::from(ca != cb);
+            row.push(substitution.min(prev[j + 1] + 1).min(row[j] + 1));
+        }
+        prev = row;
+    }
+    prev[b.len()]
+}
+
+/// Whether a guess is close enough to the answer, allowing a typo for every five letters
+///
+/// The answer can also be part of a longer guess, like "I think it's Paris".
+fn is_correct(guess: &str, answer: &str) -> bool {
+    let (guess, answer) = (normalize(guess), normalize(answer));
+    if guess.is_empty() || answer.is_empty() {
+        return false;
+    }
+    let allowed = answer.chars().count() / 5;
+    let words: Vec<&str> = guess.split(' ').collect();
+    let answer_words = answer.split(' ').count();
+    edit_distance(&guess, &answer) <= allowed
+        || words
+            .windows(answer_words)
+            .any(|w| edit_distance(&w.join(" "), &answer) <= allowed)
+}
+
+#[test]
+fn test_is_correct() {
+    assert!(is_correct("Paris", "Paris"));
+    assert!(is_correct("paris!", "Paris"));
+    assert!(is_correct("i think it's paris", "Paris"));
+    // the whole answer is needed, not just part of it
+    assert!(!is_correct("Jupiter", "The planet Jupiter"));
+    assert!(is_correct("the beatles", "Beatles"));
+    assert!(is_correct("Missisippi", "Mississippi"));
+    assert!(is_correct("George Washington", "george washington"));
+    assert!(!is_correct("1968", "1969"));
+    assert!(!is_correct("London", "Paris"));
+    assert!(!is_correct("", "Paris"));
+    assert_eq!(edit_distance("kitten", "sitting"), 3);
+}
@@ -0,0 +1,291 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{config::UploaderConfig, uploaders};
+
+const LEDGER_PATH: &str = "uploads.jsonl";
+
+/// Something that was uploaded with `upload_content`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Upload {
+    pub url: String,
+    pub date: DateTime<Utc>,
+    pub content_type: String,
+    /// The URL of the paste service it went to
+    pub uploader: String,
+    /// When the service was asked to delete it on its own
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<DateTime<Utc>>,
+    /// What the service wants for deleting it, if anything
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
+/// A JSON-lines file with every upload that's still around, as far as we know
+struct Ledger {
+    path: PathBuf,
+}
+
+/// Opens a ledger file for writing, readable only by us since it holds deletion tokens
+fn open_private(options: &mut OpenOptions, path: &Path) -> io::Result<fs::File> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        let file = options.mode(0o600).open(path)?;
+        // the ledger may have been made before it was kept private
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        Ok(file)
+    }
+    #[cfg(not(unix))]
+    options.open(path)
+}
+
+impl Ledger {
+    /// Keeps other processes (like `purge-uploads` running alongside the bot) from changing the
+    /// ledger until the returned file is dropped
+    ///
+    /// The lock is on a file of its own, since saving replaces the ledger.
+    fn lock(&self) -> anyhow::Result<fs::File> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.path.with_extension("jsonl.lock"))?;
+        file.lock()?;
+        Ok(file)
+    }
+
+    fn append(&self, upload: &Upload) -> anyhow::Result<()> {
+        let mut file = open_private(OpenOptions::new().create(true).append(true), &self.path)?;
+        writeln!(file, "{}", serde_json::to_string(upload)?)?;
+        Ok(())
+    }
+
+    fn load(&self) -> anyhow::Result<Vec<Upload>> {
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+        Ok(BufReader::new(fs::File::open(&self.path)?)
+            .lines()
+            .map_while(Result::ok)
+            .filter_map(|line| serde_json::from_str(&line).ok())
+            .collect())
+    }
+
+    fn save(&self, uploads: &[Upload]) -> anyhow::Result<()> {
+        let tmp = self.path.with_extension("jsonl.tmp");
+        let mut file = open_private(
+            OpenOptions::new().create(true).write(true).truncate(true),
+            &tmp,
+        )?;
+        for upload in uploads {
+            writeln!(file, "{}", serde_json::to_string(upload)?)?;
+        }
+        fs::rename(tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+/// Adds an upload to the ledger
+///
+/// This never fails; problems writing the ledger are only printed.
+pub fn record(upload: Upload) {
+    let ledger = Ledger {
+        path: PathBuf::from(LEDGER_PATH),
+    };
+    if let Err(e) = ledger.lock().and_then(|_lock| ledger.append(&upload)) {
+        println!("Failed to record the upload of {}: {e}", upload.url);
+    }
+}
+
+/// What a purge should do with an upload
+#[derive(Debug, PartialEq)]
+enum Action<'a> {
+    Keep,
+    /// It's gone already, so only the ledger entry needs removing
+    Forget,
+    Delete(&'a UploaderConfig),
+    /// It's old enough, but there's no way to delete it
+    Undeletable,
+}
+
+fn plan<'a>(
+    upload: &Upload,
+    cutoff: DateTime<Utc>,
+    now: DateTime<Utc>,
+    uploaders: &'a [UploaderConfig],
+) -> Action<'a> {
+    if upload.expires.is_some_and(|expires| expires <= now) {
+        return Action::Forget;
+    }
+    if upload.date > cutoff {
+        return Action::Keep;
+    }
+    match uploaders.iter().find(|u| u.url == upload.uploader) {
+        Some(uploader) if uploader.deletable => Action::Delete(uploader),
+        _ => Action::Undeletable,
+    }
+}
+
+/// How a purge went
+#[derive(Debug, Default, PartialEq)]
+pub struct PurgeReport {
+    pub deleted: usize,
+    /// Ones the service already expired
+    pub expired: usize,
+    pub failed: usize,
+    /// Old enough to purge, but their services can't delete them
+    pub undeletable: usize,
+}
+
+impl std::fmt::Display for PurgeReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} deleted, {} already expired, {} failed, {} can't be deleted",
+            self.deleted, self.expired, self.failed, self.undeletable
+        )
+    }
+}
+
+async fn delete(
+    client: &reqwest::Client,
+    uploader: &UploaderConfig,
+    upload: &Upload,
+) -> anyhow::Result<()> {
+    let mut request = client.delete(&upload.url);
+    if let (Some(header), Some(token)) = (&uploader.token_header, &upload.token) {
+        request = request.header(header, token);
+    }
+    let resp = request.send().await?;
+    // it's just as gone if it was already deleted some other way
+    if resp.status() != reqwest::StatusCode::NOT_FOUND {
+        resp.error_for_status()?;
+    }
+    Ok(())
+}
+
+/// Deletes the uploads older than `older_than` from the services that can delete them, and
+/// takes them (and any that have expired) out of the ledger
+pub async fn purge(older_than: Duration) -> anyhow::Result<PurgeReport> {
+    let ledger = Ledger {
+        path: PathBuf::from(LEDGER_PATH),
+    };
+    let uploads = ledger.load()?;
+    let uploaders = uploaders();
+    let now = Utc::now();
+    let cutoff = now - older_than;
+    let client = crate::http::client_builder()?
+        .timeout(std::time::Duration::from_secs(30))
+        .build()?;
+
+    let mut report = PurgeReport::default();
+    let mut gone = Vec::new();
+    for upload in &uploads {
+        match plan(upload, cutoff, now, &uploaders) {
+            Action::Keep => (),
+            Action::Forget => {
+                report.expired += 1;
+                gone.push(upload.url.clone());
+            }
+            Action::Delete(uploader) => match delete(&client, uploader, upload).await {
+                Ok(()) => {
+                    report.deleted += 1;
+                    gone.push(upload.url.clone());
+                }
+                Err(e) => {
+                    println!("Failed to delete {}: {e}", upload.url);
+                    report.failed += 1;
+                }
+            },
+            Action::Undeletable => report.undeletable += 1,
+        }
+    }
+
+    // reloaded, since the bot may have uploaded more in the meantime
+    let _lock = ledger.lock()?;
+    let mut uploads = ledger.load()?;
+    uploads.retain(|upload| !gone.contains(&upload.url));
+    ledger.save(&uploads)?;
+    Ok(report)
+}
+
+#[test]
+fn test_ledger() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let ledger = Ledger {
+        path: dir.path().join("uploads.jsonl"),
+    };
+    assert!(ledger.load()?.is_empty());
+
+    let upload = Upload {
+        url: "https://up.example.com/abc".to_string(),
+        date: "2024-03-01T10:00:00Z".parse()?,
+        content_type: "text/plain; charset=utf-8".to_string(),
+        uploader: "https://up.example.com".to_string(),
+        expires: None,
+        token: Some("secret".to_string()),
+    };
+    ledger.append(&upload)?;
+    ledger.append(&Upload {
+        url: "https://up.example.com/def".to_string(),
+        ..upload.clone()
+    })?;
+    assert_eq!(ledger.load()?.len(), 2);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(&ledger.path)?.permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    ledger.save(&[upload.clone()])?;
+    assert_eq!(ledger.load()?, [upload]);
+    Ok(())
+}
+
+#[test]
+fn test_purge_plan() {
+    let now: DateTime<Utc> = "2024-03-31T00:00:00Z".parse().unwrap();
+    let cutoff = now - Duration::days(30);
+    let deletable = UploaderConfig {
+        url: "https://0x0.example".to_string(),
+        method: Default::default(),
+        expires_header: Some("Expires".to_string()),
+        deletable: true,
+        token_header: Some("X-Token".to_string()),
+    };
+    let uploaders = [deletable.clone()];
+    let upload = |uploader: &str, days_ago: i64, expires: Option<i64>| Upload {
+        url: "https://0x0.example/abc.txt".to_string(),
+        date: now - Duration::days(days_ago),
+        content_type: "text/plain".to_string(),
+        uploader: uploader.to_string(),
+        expires: expires.map(|days| now - Duration::days(days_ago) + Duration::days(days)),
+        token: None,
+    };
+
+    let action = |upload: &Upload| plan(upload, cutoff, now, &uploaders);
+    assert_eq!(
+        action(&upload("https://0x0.example", 3, None)),
+        Action::Keep
+    );
+    assert_eq!(
+        action(&upload("https://0x0.example", 40, None)),
+        Action::Delete(&deletable)
+    );
+    assert_eq!(
+        action(&upload("https://0x0.example", 3, Some(1))),
+        Action::Forget
+    );
+    assert_eq!(
+        action(&upload("https://up.em32.site", 40, None)),
+        Action::Undeletable
+    );
+}
@@ -0,0 +1,293 @@
+use async_openai::types::{
+    ChatCompletionRequestMessage, ChatCompletionRequestSystemMessage,
+    ChatCompletionRequestUserMessage, ChatCompletionRequestUserMessageContent,
+};
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    http::{header, HeaderMap, StatusCode},
+    routing::post,
+    Router,
+};
+use hmac::{Hmac, Mac};
+use serde_json::Value;
+use sha2::Sha256;
+
+use crate::{
+    api::{tokens_match, ApiError, ApiState},
+    config::{get_config, WebhookConfig},
+    get_prompt, openai,
+};
+
+/// Longest announcement that's posted, so a noisy payload can't flood a channel
+const MAX_LINE_CHARS: usize = 400;
+
+/// Payloads that aren't recognized are given to the model up to this size
+const MAX_SUMMARIZED_BYTES: usize = 8000;
+
+/// Webhooks check their own signatures, so these don't need the API token
+pub(crate) fn router() -> Router<ApiState> {
+    Router::new().route("/hooks/:name", post(receive))
+}
+
+async fn receive(
+    State(state): State<ApiState>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<StatusCode, ApiError> {
+    let Some(hook) = get_config()?
+        .api
+        .webhooks
+        .into_iter()
+        .find(|hook| hook.name == name)
+    else {
+        return Ok(StatusCode::NOT_FOUND);
+    };
+    if hook.secret.is_empty() || !is_authentic(&hook.secret, &headers, &body) {
+        return Ok(StatusCode::UNAUTHORIZED);
+    }
+    let Ok(payload) = serde_json::from_slice::<Value>(&body) else {
+        return Ok(StatusCode::BAD_REQUEST);
+    };
+    let event = headers
+        .get("X-GitHub-Event")
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_string());
+
+    // summarizing can take longer than the sender will wait, so the reply doesn't wait for it
+    tokio::spawn(async move {
+        let Some(text) = announcement(&hook, event.as_deref(), &payload).await else {
+            return;
+        };
+        let line = format!("[{}] {}", hook.name, one_line(&text));
+        for channel in &hook.channels {
+            if let Err(e) = state.control.send(channel, &line) {
+                println!(
+                    "Failed to relay the {} webhook to {channel}: {e}",
+                    hook.name
+                );
+            }
+        }
+    });
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// What to post for a payload, or None if it isn't worth posting
+async fn announcement(
+    hook: &WebhookConfig,
+    event: Option<&str>,
+    payload: &Value,
+) -> Option<String> {
+    if event == Some("workflow_run") && payload["action"] != "completed" {
+        // a workflow is announced when it's done, not every time it changes
+        return None;
+    }
+    if let Some(text) = describe(event, payload) {
+        return Some(text);
+    }
+    let raw = payload.to_string();
+    if !hook.summarize {
+        return Some(raw);
+    }
+    match summarize(&raw).await {
+        Ok(summary) => Some(summary),
+        Err(e) => {
+            println!("Failed to summarize the {} webhook: {e}", hook.name);
+            Some(raw)
+        }
+    }
+}
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|idx| u8::from_str_radix(hex.get(idx..idx + 2)?, 16).ok())
+        .collect()
+}
+
+/// Checks a post's signature (`sha256=<hex HMAC of the body>`), or its bearer token for senders
+/// that can't sign
+fn is_authentic(secret: &str, headers: &HeaderMap, body: &[u8]) -> bool {
+    let signature = headers
+        .get("X-Hub-Signature-256")
+        .or_else(|| headers.get("X-Signature-256"))
+        .and_then(|v| v.to_str().ok());
+    if let Some(signature) = signature {
+        let signature = signature.strip_prefix("sha256=").unwrap_or(signature);
+        let Some(signature) = decode_hex(signature) else {
+            return false;
+        };
+        let mut mac =
+            HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+        mac.update(body);
+        return mac.verify_slice(&signature).is_ok();
+    }
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .is_some_and(|token| tokens_match(token, secret))
+}
+
+/// Describes the payloads of services we know about, in a line
+fn describe(event: Option<&str>, payload: &Value) -> Option<String> {
+    let str_at = |pointer: &str| payload.pointer(pointer).and_then(|v| v.as_str());
+    let first_line = |text: &str| text.lines().next().unwrap_or_default().to_string();
+
+    if let Some(event) = event {
+        let repo = str_at("/repository/full_name").unwrap_or("?");
+        let who = str_at("/sender/login").unwrap_or("someone");
+        return match event {
+            "ping" => Some(format!("Connected to {repo}")),
+            "push" => {
+                let commits = payload["commits"].as_array().map_or(0, |c| c.len());
+                let branch = str_at("/ref")?.trim_start_matches("refs/heads/");
+                let message = str_at("/head_commit/message").map(first_line);
+                Some(format!(
+                    "{who} pushed {commits} commit{} to {repo} ({branch}): {} {}",
+                    if commits == 1 { "" } else { "s" },
+                    message.unwrap_or_default(),
+                    str_at("/compare").unwrap_or_default()
+                ))
+            }
+            "pull_request" => Some(format!(
+                "{who} {} PR #{} in {repo}: {} {}",
+                str_at("/action")?,
+                payload.pointer("/pull_request/number")?,
+                str_at("/pull_request/title")?,
+                str_at("/pull_request/html_url").unwrap_or_default()
+            )),
+            "issues" => Some(format!(
+                "{who} {} issue #{} in {repo}: {} {}",
+                str_at("/action")?,
+                payload.pointer("/issue/number")?,
+                str_at("/issue/title")?,
+                str_at("/issue/html_url").unwrap_or_default()
+            )),
+            "workflow_run" => Some(format!(
+                "{} {} in {repo} ({}) {}",
+                str_at("/workflow_run/name")?,
+                str_at("/workflow_run/conclusion").unwrap_or("finished"),
+                str_at("/workflow_run/head_branch").unwrap_or("?"),
+                str_at("/workflow_run/html_url").unwrap_or_default()
+            )),
+            _ => None,
+        };
+    }
+
+    // Grafana alerts
+    if payload["alerts"].is_array() {
+        let title = str_at("/title")?;
+        let message = str_at("/message").map(first_line).unwrap_or_default();
+        return Some(format!("{title} {message}"));
+    }
+    // anything that just sends some text, like Slack-style hooks
+    str_at("/text")
+        .or_else(|| str_at("/message"))
+        .map(|text| text.to_string())
+}
+
+/// Asks the model to squash a payload it doesn't know into a line
+async fn summarize(raw: &str) -> anyhow::Result<String> {
+    let instruction = get_prompt("webhook").unwrap_or_else(|_| {
+        "Below is a webhook payload from some service.  Describe what happened in one short line \
+         suitable for an IRC channel, including a link if there is one.  Reply with only the line."
+            .to_string()
+    });
+    let mut end = raw.len().min(MAX_SUMMARIZED_BYTES);
+    while !raw.is_char_boundary(end) {
+        end -= 1;
+    }
+    let messages = vec![
+        ChatCompletionRequestMessage::System(ChatCompletionRequestSystemMessage {
+            content: instruction,
+            role: async_openai::types::Role::System,
+            name: None,
+        }),
+        ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
+            content: ChatCompletionRequestUserMessageContent::Text(raw[..end].to_string()),
+            role: async_openai::types::Role::User,
+            name: None,
+        }),
+    ];
+    let resp = openai::get_chat(messages, Some("gpt-4o-mini"), Some(0.3)).await?;
+    resp.first()
+        .and_then(|m| m.content.clone())
+        .ok_or_else(|| anyhow::anyhow!("No summary in response"))
+}
+
+/// Flattens text onto one line, cutting it short if it's too long for IRC
+fn one_line(text: &str) -> String {
+    let line = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if line.chars().count() <= MAX_LINE_CHARS {
+        return line;
+    }
+    let mut line: String = line.chars().take(MAX_LINE_CHARS - 1).collect();
+    line.push('…');
+    line
+}
+
+#[test]
+fn test_is_authentic() {
+    let body = br#"{"zen": "Keep it logically awesome."}"#;
+    let mut mac = HmacSha256::new_from_slice(b"hunter2").unwrap();
+    mac.update(body);
+    let signature: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        "X-Hub-Signature-256",
+        format!("sha256={signature}").parse().unwrap(),
+    );
+    assert!(is_authentic("hunter2", &headers, body));
+    assert!(!is_authentic("hunter3", &headers, body));
+    assert!(!is_authentic("hunter2", &headers, b"{}"));
+
+    let mut headers = HeaderMap::new();
+    headers.insert(header::AUTHORIZATION, "Bearer hunter2".parse().unwrap());
+    assert!(is_authentic("hunter2", &headers, body));
+    assert!(!is_authentic("hunter2", &HeaderMap::new(), body));
+}
+
+#[test]
+fn test_describe() {
+    let push = serde_json::json!({
+        "ref": "refs/heads/main",
+        "compare": "https://github.com/eminence/anna/compare/abc...def",
+        "commits": [{}, {}],
+        "head_commit": {"message": "Fix the thing\n\nIt was broken"},
+        "repository": {"full_name": "eminence/anna"},
+        "sender": {"login": "achin"},
+    });
+    assert_eq!(
+        describe(Some("push"), &push).unwrap(),
+        "achin pushed 2 commits to eminence/anna (main): Fix the thing \
+         https://github.com/eminence/anna/compare/abc...def"
+    );
+    assert_eq!(describe(Some("star"), &push), None);
+
+    let grafana = serde_json::json!({
+        "title": "[FIRING:1] Disk full",
+        "message": "Disk is 99% full\nmore details",
+        "alerts": [],
+    });
+    assert_eq!(
+        describe(None, &grafana).unwrap(),
+        "[FIRING:1] Disk full Disk is 99% full"
+    );
+    assert_eq!(describe(None, &serde_json::json!({"foo": 1})), None);
+
+    assert_eq!(one_line("a\n  b\tc"), "a b c");
+    assert_eq!(one_line(&"x".repeat(500)).chars().count(), MAX_LINE_CHARS);
+}
@@ -1,7 +1,29 @@
-use anyhow::Context;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+use chrono::{DateTime, Utc};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
+use crate::openai::OpenAiService;
+
+/// How long a lookup is reused for, since wttr.in rate-limits aggressively
+const CACHE_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// How long a geocoding or wttr.in request can take
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Raw reports, and when they were fetched
+static CACHE: Mutex<Option<TtlCache<(DateTime<Utc>, WeatherOutput)>>> = Mutex::new(None);
+
+/// What `weather_for_tool` answered, so the model asking again (as it tends to in one
+/// conversation) gets the same answer without any lookups
+static TOOL_CACHE: Mutex<Option<TtlCache<String>>> = Mutex::new(None);
+
 #[derive(JsonSchema, Serialize, Deserialize, Debug)]
 pub struct WeatherInput {
     #[serde(default)]
@@ -12,13 +34,37 @@ pub struct WeatherInput {
     pub country: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+impl WeatherInput {
+    /// Parses a location typed on IRC, like "Springfield, IL" or "--country=GB Richmond"
+    pub fn parse(s: &str) -> Self {
+        let mut country = String::new();
+        let mut rest = Vec::new();
+        for word in s.split_whitespace() {
+            match word.strip_prefix("--country=") {
+                Some(c) => country = c.to_string(),
+                None => rest.push(word),
+            }
+        }
+        let rest = rest.join(" ");
+        let (city, state) = rest.split_once(',').unwrap_or((&rest, ""));
+        Self {
+            city: city.trim().to_string(),
+            state: state.trim().to_string(),
+            country,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct WeatherOutput {
     pub current_condition: Vec<CurrentCondition>,
     pub nearest_area: Vec<Area>,
+    /// The forecast for today and the next couple of days
+    #[serde(default)]
+    pub weather: Vec<DailyWeather>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct CurrentCondition {
     #[serde(rename = "temp_C")]
     pub temp_c: String,
@@ -33,12 +79,12 @@ pub struct CurrentCondition {
     pub winddir16_point: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct WeatherDesc {
     pub value: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Area {
     #[serde(rename = "areaName")]
     pub area_name: Vec<WeatherDesc>,
@@ -46,6 +92,25 @@ pub struct Area {
     pub region: Vec<WeatherDesc>,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DailyWeather {
+    pub date: String,
+    #[serde(default)]
+    pub astronomy: Vec<Astronomy>,
+}
+
+/// Times are local to the place, like "06:45 AM"
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Astronomy {
+    pub sunrise: String,
+    pub sunset: String,
+    pub moonrise: String,
+    pub moonset: String,
+    pub moon_phase: String,
+    /// Percent of the moon that's lit
+    pub moon_illumination: String,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct WeatherOutputForChat {
     pub temp_c: String,
@@ -57,9 +122,359 @@ pub struct WeatherOutputForChat {
     pub humidity: String,
     pub windspeed_kmph: String,
     pub wind_direction: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sunrise: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sunset: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub moon_phase: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub moon_illumination: Option<String>,
+    /// When wttr.in was asked, since the report can be from the cache
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub as_of: Option<DateTime<Utc>>,
 }
 
-pub async fn get_weather(input: &WeatherInput) -> anyhow::Result<WeatherOutputForChat> {
+impl WeatherOutputForChat {
+    /// "Springfield, Illinois: sunrise 06:45 AM, sunset 07:12 PM", for !sun
+    pub fn sun(&self) -> String {
+        let (Some(sunrise), Some(sunset)) = (&self.sunrise, &self.sunset) else {
+            return "wttr.in didn't say when the sun rises there".to_string();
+        };
+        let mut out = String::new();
+        if let Some(location) = &self.location {
+            out.push_str(&format!("{location}: "));
+        }
+        out.push_str(&format!("sunrise {sunrise}, sunset {sunset}"));
+        out
+    }
+
+    /// "Waxing Crescent, 45% illuminated", for !moon
+    pub fn moon(&self) -> String {
+        match (&self.moon_phase, &self.moon_illumination) {
+            (Some(phase), Some(illumination)) => format!("{phase}, {illumination}% illuminated"),
+            (Some(phase), None) => phase.clone(),
+            _ => "wttr.in didn't say what the moon is doing".to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for WeatherOutputForChat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(location) = &self.location {
+            write!(f, "{location}: ")?;
+        }
+        if let Some(description) = &self.description {
+            write!(f, "{description}, ")?;
+        }
+        write!(
+            f,
+            "{}°C ({}°F), humidity {}%, wind {} km/h {}",
+            self.temp_c, self.temp_f, self.humidity, self.windspeed_kmph, self.wind_direction
+        )
+    }
+}
+
+/// A place found by the geocoder
+#[derive(Deserialize, Debug, Clone)]
+struct Place {
+    name: String,
+    latitude: f64,
+    longitude: f64,
+    /// The state, province, or similar
+    admin1: Option<String>,
+    country: Option<String>,
+    country_code: Option<String>,
+    population: Option<u64>,
+    /// Like "Europe/Paris"
+    timezone: Option<String>,
+}
+
+/// The postal codes for US states (and DC), which the geocoder doesn't know about
+const US_STATES: &[(&str, &str)] = &[
+    ("AL", "Alabama"),
+    ("AK", "Alaska"),
+    ("AZ", "Arizona"),
+    ("AR", "Arkansas"),
+    ("CA", "California"),
+    ("CO", "Colorado"),
+    ("CT", "Connecticut"),
+    ("DE", "Delaware"),
+    ("DC", "District of Columbia"),
+    ("FL", "Florida"),
+    ("GA", "Georgia"),
+    ("HI", "Hawaii"),
+    ("ID", "Idaho"),
+    ("IL", "Illinois"),
+    ("IN", "Indiana"),
+    ("IA", "Iowa"),
+    ("KS", "Kansas"),
+    ("KY", "Kentucky"),
+    ("LA", "Louisiana"),
+    ("ME", "Maine"),
+    ("MD", "Maryland"),
+    ("MA", "Massachusetts"),
+    ("MI", "Michigan"),
+    ("MN", "Minnesota"),
+    ("MS", "Mississippi"),
+    ("MO", "Missouri"),
+    ("MT", "Montana"),
+    ("NE", "Nebraska"),
+    ("NV", "Nevada"),
+    ("NH", "New Hampshire"),
+    ("NJ", "New Jersey"),
+    ("NM", "New Mexico"),
+    ("NY", "New York"),
+    ("NC", "North Carolina"),
+    ("ND", "North Dakota"),
+    ("OH", "Ohio"),
+    ("OK", "Oklahoma"),
+    ("OR", "Oregon"),
+    ("PA", "Pennsylvania"),
+    ("RI", "Rhode Island"),
+    ("SC", "South Carolina"),
+    ("SD", "South Dakota"),
+    ("TN", "Tennessee"),
+    ("TX", "Texas"),
+    ("UT", "Utah"),
+    ("VT", "Vermont"),
+    ("VA", "Virginia"),
+    ("WA", "Washington"),
+    ("WV", "West Virginia"),
+    ("WI", "Wisconsin"),
+    ("WY", "Wyoming"),
+];
+
+fn us_state_name(code: &str) -> Option<&'static str> {
+    US_STATES
+        .iter()
+        .find(|(c, _)| c.eq_ignore_ascii_case(code))
+        .map(|(_, name)| *name)
+}
+
+impl Place {
+    fn describe(&self) -> String {
+        [
+            Some(&self.name),
+            self.admin1.as_ref(),
+            self.country.as_ref(),
+        ]
+        .into_iter()
+        .flatten()
+        .map(|s| s.as_str())
+        .collect::<Vec<_>>()
+        .join(", ")
+    }
+    fn in_country(&self, country: &str) -> bool {
+        let matches =
+            |s: &Option<String>| s.as_ref().is_some_and(|s| s.eq_ignore_ascii_case(country));
+        matches(&self.country_code) || matches(&self.country)
+    }
+    /// Whether this place matches a qualifier like "IL" or "Illinois" or "France"
+    fn matches_qualifier(&self, qualifier: &str) -> bool {
+        if self.in_country(qualifier) {
+            return true;
+        }
+        let Some(admin1) = &self.admin1 else {
+            return false;
+        };
+        // "MI" is Michigan, not any state whose name starts with it
+        if self.in_country("US") {
+            if let Some(state) = us_state_name(qualifier) {
+                return admin1.eq_ignore_ascii_case(state);
+            }
+        }
+        admin1.to_lowercase().starts_with(&qualifier.to_lowercase())
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct GeocodeResponse {
+    #[serde(default)]
+    results: Vec<Place>,
+}
+
+async fn geocode(name: &str) -> anyhow::Result<Vec<Place>> {
+    let mut url = url::Url::parse("https://geocoding-api.open-meteo.com/v1/search")?;
+    url.query_pairs_mut()
+        .append_pair("name", name)
+        .append_pair("count", "10");
+    let resp = OpenAiService::shared()
+        .downloads()
+        .get(url)
+        .timeout(LOOKUP_TIMEOUT)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(resp.json::<GeocodeResponse>().await?.results)
+}
+
+#[derive(Debug)]
+enum Choice {
+    One(Place),
+    Ambiguous(Vec<Place>),
+    NotFound,
+}
+
+/// Picks the place that someone most likely meant
+///
+/// Places are assumed to be in order of relevance, like the geocoder returns them.  Without a
+/// state or country to go on, a name is ambiguous unless one place is much bigger than the rest
+/// (everyone means the Paris in France).
+fn choose_place(places: Vec<Place>, name: &str, qualifier: &str, country: &str) -> Choice {
+    let mut candidates: Vec<Place> = places
+        .into_iter()
+        .filter(|p| country.is_empty() || p.in_country(country))
+        .filter(|p| qualifier.is_empty() || p.matches_qualifier(qualifier))
+        .collect();
+    if candidates.iter().any(|p| p.name.eq_ignore_ascii_case(name)) {
+        candidates.retain(|p| p.name.eq_ignore_ascii_case(name));
+    }
+    if candidates.len() <= 1 || !qualifier.is_empty() || !country.is_empty() {
+        return candidates
+            .into_iter()
+            .next()
+            .map_or(Choice::NotFound, Choice::One);
+    }
+
+    let population = |p: &Place| p.population.unwrap_or(0);
+    if population(&candidates[0]) >= 10 * population(&candidates[1]).max(1) {
+        return Choice::One(candidates.remove(0));
+    }
+    candidates.truncate(3);
+    Choice::Ambiguous(candidates)
+}
+
+/// "a, b or c"
+pub fn or_list(items: &[String]) -> String {
+    match items {
+        [] => String::new(),
+        [one] => one.clone(),
+        [rest @ .., last] => format!("{} or {last}", rest.join(", ")),
+    }
+}
+
+/// The result of looking up the weather somewhere
+#[derive(Debug)]
+pub enum WeatherLookup {
+    Found(WeatherOutputForChat),
+    /// The location matched several places, so here are the likeliest ones
+    Ambiguous(Vec<String>),
+}
+
+/// The result of looking up a place, for things other than the weather
+#[derive(Debug)]
+pub enum PlaceLookup {
+    Found {
+        /// Like "Springfield, Illinois, United States"
+        name: String,
+        /// Like "America/Chicago"
+        timezone: Option<String>,
+    },
+    /// The location matched several places, so here are the likeliest ones
+    Ambiguous(Vec<String>),
+    NotFound,
+}
+
+/// Finds the place someone most likely meant, the same way the weather commands do
+pub async fn locate(input: &WeatherInput) -> anyhow::Result<PlaceLookup> {
+    let places = geocode(&input.city).await?;
+    Ok(
+        match choose_place(places, &input.city, &input.state, &input.country) {
+            Choice::One(place) => PlaceLookup::Found {
+                name: place.describe(),
+                timezone: place.timezone,
+            },
+            Choice::Ambiguous(places) => {
+                PlaceLookup::Ambiguous(places.iter().map(Place::describe).collect())
+            }
+            Choice::NotFound => PlaceLookup::NotFound,
+        },
+    )
+}
+
+/// Values that are kept for a while, then looked up again
+struct TtlCache<T> {
+    ttl: Duration,
+    entries: HashMap<String, (Instant, T)>,
+}
+
+impl<T: Clone> TtlCache<T> {
+    fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: HashMap::new(),
+        }
+    }
+    fn get(&self, key: &str, now: Instant) -> Option<T> {
+        self.entries
+            .get(key)
+            .filter(|(added, _)| now.duration_since(*added) < self.ttl)
+            .map(|(_, value)| value.clone())
+    }
+    fn insert(&mut self, key: String, value: T, now: Instant) {
+        let ttl = self.ttl;
+        self.entries
+            .retain(|_, (added, _)| now.duration_since(*added) < ttl);
+        self.entries.insert(key, (now, value));
+    }
+}
+
+/// Normalizes a location, so that "Paris ", "paris" and "PARIS" share a cache entry
+fn cache_key(location: &str) -> String {
+    location
+        .split(|c: char| c.is_whitespace() || c == '+' || c == ',')
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// The wttr.in report URL for a location
+///
+/// The location is one path segment, so a `/` or `?` in it can't change what's asked for.
+fn weather_url(location: &str) -> url::Url {
+    let mut url = url::Url::parse("https://wttr.in/").expect("the wttr.in URL is valid");
+    url.path_segments_mut()
+        .expect("the wttr.in URL has a path")
+        .pop_if_empty()
+        .push(&location.split_whitespace().collect::<Vec<_>>().join("+"));
+    url.query_pairs_mut().append_pair("format", "j1");
+    url
+}
+
+/// Gets the raw wttr.in report for a location, and when it was fetched, reusing recent lookups
+async fn fetch_weather(location: &str) -> anyhow::Result<(DateTime<Utc>, WeatherOutput)> {
+    let key = cache_key(location);
+    let cached = CACHE
+        .lock()
+        .expect("weather cache lock is poisoned")
+        .as_ref()
+        .and_then(|cache| cache.get(&key, Instant::now()));
+    if let Some(weather) = cached {
+        return Ok(weather);
+    }
+
+    let url = weather_url(location);
+    dbg!(&url);
+    let req = OpenAiService::shared()
+        .downloads()
+        .get(url)
+        .timeout(LOOKUP_TIMEOUT)
+        .send()
+        .await?
+        .error_for_status()?;
+    let resp = (Utc::now(), req.json::<WeatherOutput>().await?);
+    CACHE
+        .lock()
+        .expect("weather cache lock is poisoned")
+        .get_or_insert_with(|| TtlCache::new(CACHE_TTL))
+        .insert(key, resp.clone(), Instant::now());
+    Ok(resp)
+}
+
+/// Looks up the weather, or says which places were meant if it's not clear
+pub async fn lookup_weather(input: &WeatherInput) -> anyhow::Result<WeatherLookup> {
     dbg!(&input);
     let fields = [
         input.city.as_str(),
@@ -67,17 +482,41 @@ pub async fn get_weather(input: &WeatherInput) -> anyhow::Result<WeatherOutputFo
         input.country.as_str(),
     ];
 
-    let url = format!("https://wttr.in/{}?format=j1", fields.join("+")).replace(" ", "%20");
-    dbg!(&url);
+    let geocoded = if input.city.is_empty() {
+        // no location at all, so wttr.in guesses from where we are
+        Ok(Vec::new())
+    } else {
+        geocode(&input.city).await
+    };
+    let place = match geocoded {
+        Ok(places) => match choose_place(places, &input.city, &input.state, &input.country) {
+            Choice::One(place) => Some(place),
+            Choice::Ambiguous(places) => {
+                return Ok(WeatherLookup::Ambiguous(
+                    places.iter().map(Place::describe).collect(),
+                ))
+            }
+            Choice::NotFound => None,
+        },
+        Err(e) => {
+            // wttr.in can usually make sense of the name on its own
+            println!("Failed to geocode {:?}: {e}", input.city);
+            None
+        }
+    };
+    let query = match &place {
+        Some(place) => format!("{:.4},{:.4}", place.latitude, place.longitude),
+        None => fields.join(" "),
+    };
 
-    let req = reqwest::get(&url).await?;
-    let mut resp = req.json::<WeatherOutput>().await?;
+    let (as_of, mut resp) = fetch_weather(&query).await?;
     dbg!(&resp);
 
     let mut current = resp
         .current_condition
         .pop()
         .context("No current condition")?;
+    let today = resp.weather.first_mut().and_then(|day| day.astronomy.pop());
 
     let output = WeatherOutputForChat {
         temp_c: current.temp_c,
@@ -86,15 +525,190 @@ pub async fn get_weather(input: &WeatherInput) -> anyhow::Result<WeatherOutputFo
         windspeed_kmph: current.windspeed_kmph,
         wind_direction: current.winddir16_point,
         description: current.weather_desc.pop().map(|desc| desc.value),
-        location: resp.nearest_area.pop().map(|mut area| {
-            format!(
-                "{}, {}",
-                area.area_name.pop().unwrap().value,
-                area.region.pop().unwrap().value
-            )
+        location: place.map(|place| place.describe()).or_else(|| {
+            // otherwise say where wttr.in decided we meant
+            resp.nearest_area.pop().map(|mut area| {
+                [area.area_name.pop(), area.region.pop(), area.country.pop()]
+                    .into_iter()
+                    .flatten()
+                    .map(|desc| desc.value)
+                    .filter(|s| !s.is_empty())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            })
         }),
+        sunrise: today.as_ref().map(|a| a.sunrise.clone()),
+        sunset: today.as_ref().map(|a| a.sunset.clone()),
+        moon_phase: today.as_ref().map(|a| a.moon_phase.clone()),
+        moon_illumination: today.map(|a| a.moon_illumination),
+        as_of: Some(as_of),
     };
-    Ok(output)
+    Ok(WeatherLookup::Found(output))
+}
+
+pub async fn get_weather(input: &WeatherInput) -> anyhow::Result<WeatherOutputForChat> {
+    match lookup_weather(input).await? {
+        WeatherLookup::Found(weather) => Ok(weather),
+        WeatherLookup::Ambiguous(places) => {
+            bail!(
+                "{:?} is ambiguous, it could be {}",
+                input.city,
+                or_list(&places)
+            )
+        }
+    }
+}
+
+/// The weather as compact JSON, for answering the model's weather tool
+///
+/// The same question within `CACHE_TTL` gets the same answer, and its `as_of` says how fresh
+/// that is.
+pub async fn weather_for_tool(input: &WeatherInput) -> anyhow::Result<String> {
+    let key = cache_key(&format!("{} {} {}", input.city, input.state, input.country));
+    let cached = TOOL_CACHE
+        .lock()
+        .expect("weather tool cache lock is poisoned")
+        .as_ref()
+        .and_then(|cache| cache.get(&key, Instant::now()));
+    if let Some(json) = cached {
+        return Ok(json);
+    }
+
+    let json = serde_json::to_string(&get_weather(input).await?)?;
+    TOOL_CACHE
+        .lock()
+        .expect("weather tool cache lock is poisoned")
+        .get_or_insert_with(|| TtlCache::new(CACHE_TTL))
+        .insert(key, json.clone(), Instant::now());
+    Ok(json)
+}
+
+#[test]
+fn test_weather_cache() {
+    assert_eq!(cache_key(" Paris,  France "), "paris france");
+    assert_eq!(cache_key("paris+FRANCE"), "paris france");
+    assert_eq!(
+        weather_url("Paris,  France").as_str(),
+        "https://wttr.in/Paris,+France?format=j1"
+    );
+    assert_eq!(
+        weather_url("../moon?format=3#").as_str(),
+        "https://wttr.in/..%2Fmoon%3Fformat=3%23?format=j1"
+    );
+
+    let start = Instant::now();
+    let mut cache = TtlCache::new(Duration::from_secs(60));
+    cache.insert("paris".to_string(), 1, start);
+    assert_eq!(cache.get("paris", start + Duration::from_secs(59)), Some(1));
+    assert_eq!(cache.get("paris", start + Duration::from_secs(60)), None);
+    assert_eq!(cache.get("london", start), None);
+
+    // expired entries are cleaned out when something new is added
+    cache.insert("london".to_string(), 2, start + Duration::from_secs(61));
+    assert_eq!(cache.entries.len(), 1);
+}
+
+#[test]
+fn test_choose_place() {
+    let place = |name: &str, admin1: &str, code: &str, population| Place {
+        name: name.to_string(),
+        latitude: 0.0,
+        longitude: 0.0,
+        admin1: Some(admin1.to_string()),
+        country: None,
+        country_code: Some(code.to_string()),
+        population: Some(population),
+        timezone: None,
+    };
+    let springfields = || {
+        vec![
+            place("Springfield", "Missouri", "US", 166_000),
+            place("Springfield", "Massachusetts", "US", 155_000),
+            place("Springfield", "Illinois", "US", 116_000),
+            place("Springfield", "Minnesota", "US", 2_000),
+            place("Springfield", "Queensland", "AU", 20_000),
+        ]
+    };
+    let chosen = |choice| match choice {
+        Choice::One(place) => place.describe(),
+        other => panic!("expected one place, got {other:?}"),
+    };
+
+    match choose_place(springfields(), "springfield", "", "") {
+        Choice::Ambiguous(places) => assert_eq!(places.len(), 3),
+        other => panic!("expected a choice, got {other:?}"),
+    }
+    assert_eq!(
+        chosen(choose_place(springfields(), "Springfield", "IL", "")),
+        "Springfield, Illinois"
+    );
+    assert_eq!(
+        chosen(choose_place(springfields(), "Springfield", "MA", "")),
+        "Springfield, Massachusetts"
+    );
+    assert_eq!(
+        chosen(choose_place(springfields(), "Springfield", "Minn", "")),
+        "Springfield, Minnesota"
+    );
+    assert_eq!(
+        chosen(choose_place(springfields(), "Springfield", "", "au")),
+        "Springfield, Queensland"
+    );
+    assert!(matches!(
+        choose_place(springfields(), "Springfield", "", "FR"),
+        Choice::NotFound
+    ));
+    let paris = vec![
+        place("Paris", "Île-de-France", "FR", 2_100_000),
+        place("Paris", "Texas", "US", 25_000),
+    ];
+    assert_eq!(
+        chosen(choose_place(paris, "Paris", "", "")),
+        "Paris, Île-de-France"
+    );
+
+    let input = WeatherInput::parse("--country=US  Springfield, IL");
+    assert_eq!(
+        (
+            input.city.as_str(),
+            input.state.as_str(),
+            input.country.as_str()
+        ),
+        ("Springfield", "IL", "US")
+    );
+    assert_eq!(
+        or_list(&["a".to_string(), "b".to_string(), "c".to_string()]),
+        "a, b or c"
+    );
+}
+
+#[test]
+fn test_parse_astronomy() {
+    let json = r#"{
+        "current_condition": [],
+        "nearest_area": [],
+        "weather": [{
+            "date": "2026-10-16",
+            "maxtempC": "18",
+            "astronomy": [{
+                "moon_illumination": "45",
+                "moon_phase": "Waxing Crescent",
+                "moonrise": "10:03 AM",
+                "moonset": "08:12 PM",
+                "sunrise": "06:45 AM",
+                "sunset": "07:12 PM"
+            }]
+        }]
+    }"#;
+    let output: WeatherOutput = serde_json::from_str(json).unwrap();
+    let astronomy = &output.weather[0].astronomy[0];
+    assert_eq!(astronomy.sunrise, "06:45 AM");
+    assert_eq!(astronomy.moon_phase, "Waxing Crescent");
+
+    // the forecast isn't needed for the current weather
+    let output: WeatherOutput =
+        serde_json::from_str(r#"{"current_condition": [], "nearest_area": []}"#).unwrap();
+    assert!(output.weather.is_empty());
 }
 
 #[tokio::test]
@@ -108,4 +722,9 @@ async fn test_get_weather() {
     let weather = get_weather(&input).await.unwrap();
     let json = serde_json::to_string(&weather).unwrap();
     println!("{}", json);
+
+    // asking again gets the same report, as of when it was first fetched
+    let first = weather_for_tool(&input).await.unwrap();
+    assert!(first.contains("\"as_of\":"));
+    assert_eq!(weather_for_tool(&input).await.unwrap(), first);
 }
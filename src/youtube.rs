@@ -0,0 +1,263 @@
+use std::path::Path;
+
+use anyhow::{bail, Context};
+use serde::Deserialize;
+use tokio::process::Command;
+
+use crate::{get_prompt, openai, summarize_long_text};
+
+/// Longest video whose audio is downloaded to be transcribed, when it doesn't have captions
+const MAX_TRANSCRIBED_SECS: u64 = 2 * 60 * 60;
+
+/// The bits of `yt-dlp --dump-json` that we care about
+#[derive(Debug, Deserialize)]
+pub struct VideoInfo {
+    pub title: String,
+    /// Length of the video in seconds (missing for live streams)
+    pub duration: Option<f64>,
+    pub channel: Option<String>,
+    pub uploader: Option<String>,
+}
+
+impl VideoInfo {
+    /// A one-line description of the video, for announcing in a channel
+    pub fn announcement(&self) -> String {
+        let mut s = format!("YouTube: {}", self.title);
+        if let Some(duration) = self.duration {
+            s.push_str(&format!(" [{}]", format_duration(duration as u64)));
+        }
+        if let Some(channel) = self.channel.as_ref().or(self.uploader.as_ref()) {
+            s.push_str(&format!(" by {channel}"));
+        }
+        s
+    }
+}
+
+fn format_duration(secs: u64) -> String {
+    let (h, m, s) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if h > 0 {
+        format!("{h}:{m:02}:{s:02}")
+    } else {
+        format!("{m}:{s:02}")
+    }
+}
+
+/// Returns the video ID if this looks like a link to a YouTube video
+pub fn video_id(url: &str) -> Option<String> {
+    let url = url::Url::parse(url).ok()?;
+    let host = url
+        .host_str()?
+        .trim_start_matches("www.")
+        .trim_start_matches("m.");
+    let id = match host {
+        "youtu.be" => url.path_segments()?.next()?.to_string(),
+        "youtube.com" | "music.youtube.com" => {
+            let mut segments = url.path_segments()?;
+            match segments.next()? {
+                "watch" => url
+                    .query_pairs()
+                    .find(|(k, _)| k == "v")
+                    .map(|(_, v)| v.into_owned())?,
+                "shorts" | "live" | "embed" => segments.next()?.to_string(),
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+    let valid = !id.is_empty()
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    valid.then_some(id)
+}
+
+/// Finds the first YouTube video linked in a message
+pub fn find_video(msg: &str) -> Option<String> {
+    msg.split_ascii_whitespace()
+        .filter(|word| word.starts_with("https://") || word.starts_with("http://"))
+        .find_map(video_id)
+}
+
+fn watch_url(id: &str) -> String {
+    format!("https://www.youtube.com/watch?v={id}")
+}
+
+async fn yt_dlp(args: &[&str]) -> anyhow::Result<Vec<u8>> {
+    let output = Command::new("yt-dlp")
+        .args(args)
+        .kill_on_drop(true)
+        .output()
+        .await
+        .context("Failed to run yt-dlp")?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        bail!(
+            "yt-dlp failed: {}",
+            stderr.lines().last().unwrap_or("unknown error")
+        );
+    }
+    Ok(output.stdout)
+}
+
+pub async fn get_info(id: &str) -> anyhow::Result<VideoInfo> {
+    let json = yt_dlp(&[
+        "--dump-json",
+        "--skip-download",
+        "--no-playlist",
+        &watch_url(id),
+    ])
+    .await?;
+    Ok(serde_json::from_slice(&json)?)
+}
+
+/// Turns a WebVTT subtitle file into plain text
+///
+/// Automatic captions repeat each line several times as it scrolls by, so repeated lines are
+/// dropped.
+fn vtt_to_text(vtt: &str) -> String {
+    let mut lines: Vec<String> = Vec::new();
+    for line in vtt.lines() {
+        let line = line.trim();
+        if line.is_empty()
+            || line == "WEBVTT"
+            || line.contains("-->")
+            || line.starts_with("Kind:")
+            || line.starts_with("Language:")
+            || line.starts_with("NOTE")
+        {
+            continue;
+        }
+        // strip inline tags like <c> and <00:00:01.234>
+        let mut text = String::new();
+        let mut in_tag = false;
+        for c in line.chars() {
+            match c {
+                '<' => in_tag = true,
+                '>' => in_tag = false,
+                c if !in_tag => text.push(c),
+                _ => (),
+            }
+        }
+        let text = text.trim();
+        if !text.is_empty() && lines.last().map(|l| l.as_str()) != Some(text) {
+            lines.push(text.to_string());
+        }
+    }
+    lines.join("\n")
+}
+
+/// Finds the first file in a directory with the given extension
+fn find_file(dir: &Path, ext: &str) -> Option<std::path::PathBuf> {
+    std::fs::read_dir(dir)
+        .ok()?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .find(|p| p.extension().is_some_and(|e| e == ext))
+}
+
+/// Gets a transcript of a video
+///
+/// English captions (uploaded or automatic) are used when available.  Otherwise the audio is
+/// downloaded and transcribed with Whisper.
+pub async fn get_transcript(id: &str) -> anyhow::Result<String> {
+    let dir = tempfile::tempdir()?;
+    let output = dir.path().join("video.%(ext)s");
+    let output = output.to_str().context("Temp dir isn't valid UTF-8")?;
+    let url = watch_url(id);
+
+    yt_dlp(&[
+        "--skip-download",
+        "--write-subs",
+        "--write-auto-subs",
+        "--sub-langs",
+        "en.*,en",
+        "--sub-format",
+        "vtt",
+        "--no-playlist",
+        "-o",
+        output,
+        &url,
+    ])
+    .await?;
+    if let Some(subs) = find_file(dir.path(), "vtt") {
+        let text = vtt_to_text(&std::fs::read_to_string(subs)?);
+        if !text.is_empty() {
+            return Ok(text);
+        }
+    }
+
+    // no captions, so fall back to transcribing the audio ourselves, as long as there isn't too
+    // much of it to download
+    let max_filesize = openai::transcription_max_bytes().to_string();
+    let max_duration = format!("duration<{MAX_TRANSCRIBED_SECS}");
+    yt_dlp(&[
+        "--max-filesize",
+        &max_filesize,
+        "--match-filter",
+        &max_duration,
+        "--format",
+        "bestaudio[ext=webm]/bestaudio",
+        "--extract-audio",
+        "--audio-format",
+        "opus",
+        "--audio-quality",
+        "32K",
+        "--no-playlist",
+        "-o",
+        output,
+        &url,
+    ])
+    .await?;
+    // yt-dlp skips videos that are too long (or big) without failing
+    let audio = find_file(dir.path(), "opus")
+        .context("This video has no captions, and is too long to transcribe")?;
+    if std::fs::metadata(&audio)?.len() > openai::transcription_max_bytes() {
+        bail!("This video has no captions, and is too long to transcribe");
+    }
+    let audio = std::fs::read(audio)?;
+    openai::transcribe_bytes("audio.ogg", audio.into(), None).await
+}
+
+/// Summarizes a video from its transcript
+pub async fn summarize(id: &str) -> anyhow::Result<String> {
+    let info = get_info(id).await?;
+    let transcript = get_transcript(id).await?;
+    let instruction = get_prompt("yt-summary").unwrap_or_else(|_| {
+        "Summarize the transcript of a YouTube video below in a few sentences.  Keep it brief, \
+         and don't repeat the title."
+            .to_string()
+    });
+    let instruction = format!("{instruction}\n\nThe video is titled {:?}.", info.title);
+    summarize_long_text(&transcript, &instruction).await
+}
+
+#[test]
+fn test_video_id() {
+    let id = Some("dQw4w9WgXcQ".to_string());
+    assert_eq!(video_id("https://www.youtube.com/watch?v=dQw4w9WgXcQ"), id);
+    assert_eq!(video_id("https://youtube.com/watch?t=42&v=dQw4w9WgXcQ"), id);
+    assert_eq!(video_id("https://youtu.be/dQw4w9WgXcQ?si=abc"), id);
+    assert_eq!(video_id("https://m.youtube.com/shorts/dQw4w9WgXcQ"), id);
+    assert_eq!(video_id("https://www.youtube.com/@somechannel"), None);
+    assert_eq!(video_id("https://example.com/watch?v=dQw4w9WgXcQ"), None);
+
+    assert_eq!(
+        find_video("check this out https://youtu.be/dQw4w9WgXcQ lol"),
+        id
+    );
+    assert_eq!(find_video("no links here"), None);
+}
+
+#[test]
+fn test_vtt_to_text() {
+    let vtt = "WEBVTT\nKind: captions\nLanguage: en\n\n\
+               00:00:00.000 --> 00:00:02.000\n\
+               never gonna<00:00:01.000><c> give</c>\n\n\
+               00:00:02.000 --> 00:00:03.000\n\
+               never gonna give\n\
+               you up\n";
+    assert_eq!(vtt_to_text(vtt), "never gonna give\nyou up");
+
+    assert_eq!(format_duration(212), "3:32");
+    assert_eq!(format_duration(3725), "1:02:05");
+}